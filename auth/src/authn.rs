@@ -20,10 +20,13 @@
 //!
 //! ```
 //! use genbu_auth::authn::*;
+//! use secrecy::SecretString;
 //! use uuid::Uuid;
 //!
-//! let jwt = create_jwt(Uuid::new_v4());
-//! assert!(jwt.is_ok());
+//! let secret = SecretString::new(String::from("a-secret-which-is-long-enough-to-be-used"));
+//! let id = Uuid::new_v4();
+//! let jwt = create_jwt(id, &secret).unwrap();
+//! assert_eq!(validate_jwt(&jwt, &secret).unwrap().sub, id);
 //! ```
 
 use std::ops::Add;
@@ -34,7 +37,7 @@ use jsonwebtoken::{DecodingKey, EncodingKey, Header, Validation};
 use password_hash::SaltString;
 use rand_core::OsRng;
 use secrecy::{ExposeSecret, SecretString};
-use serde::{Deserialize, Serialize};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use thiserror::Error;
 use time::{ext::NumericalDuration, OffsetDateTime};
 use unicode_normalization::UnicodeNormalization;
@@ -83,7 +86,10 @@ pub fn verify_password(password: &SecretString, hash: &str) -> Result<bool, Hash
     }
 }
 
+/// Claims of a session JWT. Unknown fields are rejected, so tokens signed for other purposes with
+/// the same secret can't be used as a session.
 #[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct Claims {
     pub sub: Uuid,
     pub exp: i64,
@@ -119,37 +125,57 @@ pub struct JWTError {
     source: jsonwebtoken::errors::Error,
 }
 
-/// Creates a JWT for the given id.
+/// Creates a JWT for the given id, signed with `secret`.
 ///
 /// # Errors
 ///
 /// This function will return an error only if the internal crypto libary errors, which can only
 /// happen if the supplied secret is invalid.
 #[tracing::instrument(name = "Create new JSON-WebToken", skip_all)]
-pub fn create_jwt(id: Uuid) -> Result<String, JWTError> {
-    let claims = Claims::new(id);
+pub fn create_jwt(id: Uuid, secret: &SecretString) -> Result<String, JWTError> {
+    encode_claims(&Claims::new(id), secret)
+}
+
+/// Decodes a JWT signed with `secret` and returns the claims.
+///
+/// # Errors
+///
+/// This function will return an error if the decoding key if invalid, the crypto library errors
+/// internally or the JWT was tampered with.
+#[tracing::instrument(name = "Validate JSON-WebToken", skip_all)]
+pub fn validate_jwt(jwt: &str, secret: &SecretString) -> Result<Claims, JWTError> {
+    decode_claims(jwt, secret)
+}
+
+/// Signs arbitrary claims with `secret`. This is used for short-lived tokens other than the
+/// session JWT, the claims therefore have to contain an `exp` field.
+///
+/// # Errors
+///
+/// This function will return an error only if the internal crypto libary errors or the claims
+/// can't be serialized.
+pub fn encode_claims<T: Serialize>(claims: &T, secret: &SecretString) -> Result<String, JWTError> {
     jsonwebtoken::encode(
         &Header::default(),
-        &claims,
-        &EncodingKey::from_secret(
-            b"secret", //TODO: Make this
-                      //configurable
-        ),
+        claims,
+        &EncodingKey::from_secret(secret.expose_secret().as_bytes()),
     )
     .map_err(Into::into)
 }
 
-/// Decodes a JWT and returns the claims.
+/// Decodes a token created with [`encode_claims`] and returns its claims.
 ///
 /// # Errors
 ///
-/// This function will return an error if the decoding key if invalid, the crypto library errors
-/// internally or the JWT was tampered with.
-#[tracing::instrument(name = "Validate JSON-WebToken", skip_all)]
-pub fn validate_jwt(jwt: &str) -> Result<Claims, JWTError> {
-    match jsonwebtoken::decode::<Claims>(
-        jwt,
-        &DecodingKey::from_secret(b"secret"), // TODO: Make this configurable
+/// This function will return an error if the token expired, was tampered with or the claims don't
+/// match `T`.
+pub fn decode_claims<T: DeserializeOwned>(
+    token: &str,
+    secret: &SecretString,
+) -> Result<T, JWTError> {
+    match jsonwebtoken::decode::<T>(
+        token,
+        &DecodingKey::from_secret(secret.expose_secret().as_bytes()),
         &Validation::default(),
     ) {
         Ok(data) => Ok(data.claims),
@@ -0,0 +1 @@
+alter table file alter column lock type uuid using lock::uuid;
@@ -0,0 +1,2 @@
+-- WOPI clients send arbitrary lock strings of up to 1024 characters
+alter table file alter column lock type text;
@@ -0,0 +1,7 @@
+drop index file_bucket_path;
+
+alter table file
+    drop column bucket,
+    drop column size,
+    drop column mime_type,
+    drop column checksum;
@@ -0,0 +1,8 @@
+-- Every stored object is registered as a file, so it can be referenced by its id
+alter table file
+    add column bucket bucket not null default 'userfiles',
+    add column size int8 not null default 0,
+    add column mime_type text,
+    add column checksum text;
+
+create unique index file_bucket_path on file (bucket, path);
@@ -0,0 +1,5 @@
+drop index file_parent_id;
+
+alter table file
+    drop column is_folder,
+    drop column parent_id;
@@ -0,0 +1,6 @@
+-- Folders are catalog entries as well, every entry points to the folder containing it
+alter table file
+    add column is_folder boolean not null default false,
+    add column parent_id uuid references file(id) on delete cascade;
+
+create index file_parent_id on file (parent_id);
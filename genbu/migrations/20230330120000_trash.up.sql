@@ -0,0 +1,13 @@
+-- Deleted files are moved into the trash of their owner, until they are restored or purged
+create table trash (
+    id uuid primary key,
+    owner uuid not null references "user"(id) on delete cascade,
+    original_path text not null,
+    trash_path text not null unique,
+    is_folder boolean not null,
+    deleted_by uuid not null references "user"(id) on delete cascade,
+    deleted_at timestamptz not null default now()
+);
+
+create index trash_owner on trash (owner);
+create index trash_deleted_at on trash (deleted_at);
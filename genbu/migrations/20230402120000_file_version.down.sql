@@ -0,0 +1 @@
+drop table file_version;
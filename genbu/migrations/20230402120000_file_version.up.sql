@@ -0,0 +1,14 @@
+-- Previous contents of a file, which are kept when the file is overwritten
+create table file_version (
+    id uuid primary key,
+    file_id uuid not null references file(id) on delete cascade,
+    bucket bucket not null,
+    path text not null,
+    size int8 not null,
+    checksum text,
+    modified_at timestamptz not null,
+    created_at timestamptz not null default now()
+);
+
+create index file_version_file_id on file_version (file_id, created_at);
+create index file_version_created_at on file_version (created_at);
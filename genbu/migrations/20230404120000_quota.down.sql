@@ -0,0 +1,3 @@
+drop table storage_usage;
+drop table group_quota;
+drop table user_quota;
@@ -0,0 +1,21 @@
+-- Quotas limit the bytes the files of a user may take up, a quota of the user itself takes
+-- precedence over the quotas of their groups
+create table user_quota (
+    user_id uuid primary key references "user"(id) on delete cascade,
+    max_bytes int8 not null check (max_bytes >= 0)
+);
+
+create table group_quota (
+    group_id uuid primary key references "group"(group_id) on delete cascade,
+    max_bytes int8 not null check (max_bytes >= 0)
+);
+
+-- Bytes taken up by the files of every user, which is updated whenever a file is written or
+-- purged
+create table storage_usage (
+    user_id uuid primary key references "user"(id) on delete cascade,
+    used_bytes int8 not null default 0
+);
+
+insert into storage_usage (user_id, used_bytes)
+select created_by, sum(size) from file group by created_by;
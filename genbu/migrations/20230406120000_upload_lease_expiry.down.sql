@@ -0,0 +1 @@
+drop index upload_lease_expires_at;
@@ -0,0 +1,2 @@
+-- Expired, incomplete leases are swept periodically
+create index upload_lease_expires_at on upload_lease (expires_at) where not completed;
@@ -0,0 +1,6 @@
+alter table upload_lease
+    drop column part_checksums,
+    drop column checksum_algorithm,
+    drop column chunk_size;
+
+drop type checksum_algorithm;
@@ -0,0 +1,7 @@
+create type checksum_algorithm as enum ('sha256', 'crc32c');
+
+-- Clients which declare checksums choose the part size the checksums were computed for
+alter table upload_lease
+    add column chunk_size int8,
+    add column checksum_algorithm checksum_algorithm,
+    add column part_checksums text[] not null default '{}';
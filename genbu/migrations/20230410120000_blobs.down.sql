@@ -0,0 +1,4 @@
+alter table file
+    drop column blob_id;
+
+drop table blob;
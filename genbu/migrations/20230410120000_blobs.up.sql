@@ -0,0 +1,18 @@
+-- Contents which are stored once for every file with the same content
+create table blob (
+    id uuid primary key,
+    bucket bucket not null,
+    hash text not null,
+    size int8 not null,
+    ref_count int8 not null check (ref_count >= 0),
+    created_at timestamptz not null default now(),
+    unique (bucket, hash)
+);
+
+create index blob_unreferenced on blob (id) where ref_count = 0;
+
+-- Blobs can't be deleted while a file still points at them
+alter table file
+    add column blob_id uuid references blob(id);
+
+create index file_blob_id on file (blob_id);
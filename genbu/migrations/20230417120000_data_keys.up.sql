@@ -0,0 +1,11 @@
+-- Keys which encrypt the stored objects of a user, the nil uuid owns the key of shared objects
+create table data_key (
+    owner uuid primary key,
+    master_key_id text not null,
+    -- Nonce followed by the data key encrypted with the master key
+    wrapped_key bytea not null,
+    created_at timestamptz not null default now(),
+    rotated_at timestamptz
+);
+
+create index data_key_master_key_id on data_key (master_key_id);
@@ -0,0 +1,3 @@
+alter table upload_lease
+    drop column patched_until,
+    drop column segments;
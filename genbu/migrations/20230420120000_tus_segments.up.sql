@@ -0,0 +1,4 @@
+-- Data of tus uploads, which isn't stored as a part yet, and requests which continue them
+alter table upload_lease
+    add column segments int8[] not null default '{}',
+    add column patched_until timestamptz;
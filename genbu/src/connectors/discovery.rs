@@ -0,0 +1,399 @@
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use parking_lot::Mutex;
+use ring::signature::{RsaPublicKeyComponents, RSA_PKCS1_2048_8192_SHA256};
+use thiserror::Error;
+use time::OffsetDateTime;
+
+use crate::config::WopiConfig;
+
+use super::urlencode;
+
+/// How long a fetched discovery document is used before it's fetched again. Office servers only
+/// change their discovery on updates or when they rotate their proof keys.
+const DISCOVERY_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// Minimum age of the cached discovery document before a failed proof check fetches it again,
+/// so forged requests can't be used to flood the office server.
+const PROOF_KEY_REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Requests with an older `X-WOPI-TimeStamp` are rejected, as required by the WOPI spec.
+const MAX_PROOF_AGE: time::Duration = time::Duration::minutes(20);
+
+/// .NET ticks (100ns intervals since 0001-01-01) at the unix epoch.
+const UNIX_EPOCH_TICKS: i64 = 621_355_968_000_000_000;
+
+#[derive(Debug, Error)]
+pub enum DiscoveryError {
+    #[error("unable to fetch the discovery document")]
+    Request(#[from] reqwest::Error),
+
+    #[error("discovery document isn't valid xml")]
+    Parse(#[from] roxmltree::Error),
+
+    #[error("discovery document contains an invalid proof key")]
+    InvalidProofKey(#[from] base64::DecodeError),
+}
+
+/// A single action, e.g. `view` or `edit`, an office server offers for a file type.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Action {
+    pub name: String,
+    /// File extension without the leading `.`, if the action is registered for an extension
+    pub ext: Option<String>,
+    /// Set if the action is registered for a MIME type instead of an extension
+    pub mime: Option<String>,
+    pub url_src: String,
+    pub default: bool,
+}
+
+impl Action {
+    /// Returns the url which opens the file behind `wopi_src` with this action. Placeholders of
+    /// the `urlsrc` template aren't supported and therefore removed.
+    #[must_use]
+    pub fn launch_url(&self, wopi_src: &str) -> String {
+        let mut url = remove_placeholders(&self.url_src);
+        if !(url.ends_with('?') || url.ends_with('&')) {
+            url.push(if url.contains('?') { '&' } else { '?' });
+        }
+        url.push_str("WOPISrc=");
+        url.push_str(&urlencode(wopi_src));
+        url
+    }
+}
+
+/// Public RSA key, which the office server signs its requests with.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ProofKey {
+    modulus: Vec<u8>,
+    exponent: Vec<u8>,
+}
+
+impl ProofKey {
+    fn parse(modulus: &str, exponent: &str) -> Result<Self, DiscoveryError> {
+        Ok(Self {
+            modulus: decode_unsigned(modulus)?,
+            exponent: decode_unsigned(exponent)?,
+        })
+    }
+
+    fn verify(&self, message: &[u8], signature: &[u8]) -> bool {
+        RsaPublicKeyComponents {
+            n: &self.modulus,
+            e: &self.exponent,
+        }
+        .verify(&RSA_PKCS1_2048_8192_SHA256, message, signature)
+        .is_ok()
+    }
+}
+
+/// The signed parts of a request of an office server.
+#[derive(Clone, Debug)]
+pub struct WopiProof {
+    pub access_token: String,
+    /// Absolute url of the request, including the query
+    pub url: String,
+    /// Value of the `X-WOPI-TimeStamp` header in .NET ticks
+    pub timestamp: i64,
+    /// Decoded `X-WOPI-Proof` header
+    pub proof: Vec<u8>,
+    /// Decoded `X-WOPI-ProofOld` header
+    pub proof_old: Option<Vec<u8>>,
+}
+
+impl WopiProof {
+    /// Returns the bytes the office server signed. Every value is prefixed with its length as
+    /// 32 bit big endian integer.
+    fn message(&self) -> Vec<u8> {
+        let url = self.url.to_uppercase();
+        let mut message = Vec::with_capacity(self.access_token.len() + url.len() + 20);
+        for value in [
+            self.access_token.as_bytes(),
+            url.as_bytes(),
+            &self.timestamp.to_be_bytes(),
+        ] {
+            let len = u32::try_from(value.len()).unwrap_or(u32::MAX);
+            message.extend_from_slice(&len.to_be_bytes());
+            message.extend_from_slice(value);
+        }
+        message
+    }
+
+    /// Checks that the request was signed at most `MAX_PROOF_AGE` before `now`.
+    #[must_use]
+    pub fn is_fresh(&self, now: OffsetDateTime) -> bool {
+        let nanos = (i128::from(self.timestamp) - i128::from(UNIX_EPOCH_TICKS)) * 100;
+        OffsetDateTime::from_unix_timestamp_nanos(nanos)
+            .is_ok_and(|signed_at| (now - signed_at).abs() <= MAX_PROOF_AGE)
+    }
+}
+
+/// Parsed `/hosting/discovery` document of an office server.
+#[derive(Clone, Debug, Default)]
+pub struct Discovery {
+    pub actions: Vec<Action>,
+    pub proof_key: Option<ProofKey>,
+    /// Previous proof key, which is still accepted while the office server rotates its keys
+    pub old_proof_key: Option<ProofKey>,
+}
+
+impl Discovery {
+    pub fn parse(xml: &str) -> Result<Self, DiscoveryError> {
+        let doc = roxmltree::Document::parse(xml)?;
+        let actions = doc
+            .descendants()
+            .filter(|node| node.has_tag_name("action"))
+            .filter_map(|node| {
+                // Apps are either named after a MIME type or after the application (e.g. `writer`)
+                let app = node
+                    .parent_element()
+                    .and_then(|app| app.attribute("name"))
+                    .filter(|name| name.contains('/'));
+                Some(Action {
+                    name: node.attribute("name")?.to_owned(),
+                    ext: node
+                        .attribute("ext")
+                        .filter(|ext| !ext.is_empty())
+                        .map(str::to_lowercase),
+                    mime: app.map(ToOwned::to_owned),
+                    url_src: node.attribute("urlsrc")?.to_owned(),
+                    default: node.attribute("default") == Some("true"),
+                })
+            })
+            .collect();
+
+        let (mut proof_key, mut old_proof_key) = (None, None);
+        if let Some(node) = doc
+            .descendants()
+            .find(|node| node.has_tag_name("proof-key"))
+        {
+            if let (Some(modulus), Some(exponent)) =
+                (node.attribute("modulus"), node.attribute("exponent"))
+            {
+                proof_key = Some(ProofKey::parse(modulus, exponent)?);
+            }
+            if let (Some(modulus), Some(exponent)) =
+                (node.attribute("oldmodulus"), node.attribute("oldexponent"))
+            {
+                old_proof_key = Some(ProofKey::parse(modulus, exponent)?);
+            }
+        }
+        Ok(Self {
+            actions,
+            proof_key,
+            old_proof_key,
+        })
+    }
+
+    /// Checks whether the request was signed by the office server. Signatures made with the
+    /// previous key are accepted as well, so requests don't fail while keys are rotated.
+    #[must_use]
+    pub fn verify_proof(&self, proof: &WopiProof) -> bool {
+        let Some(current) = &self.proof_key else {
+            return false;
+        };
+        let message = proof.message();
+        current.verify(&message, &proof.proof)
+            || proof
+                .proof_old
+                .as_ref()
+                .is_some_and(|proof_old| current.verify(&message, proof_old))
+            || self
+                .old_proof_key
+                .as_ref()
+                .is_some_and(|old| old.verify(&message, &proof.proof))
+    }
+
+    /// Returns the action with the given name for a file. Actions registered for the extension are
+    /// preferred over those for the MIME type.
+    #[must_use]
+    pub fn find_action(
+        &self,
+        name: &str,
+        ext: Option<&str>,
+        mime: Option<&str>,
+    ) -> Option<&Action> {
+        let ext = ext.map(str::to_lowercase);
+        let named = || self.actions.iter().filter(move |a| a.name == name);
+        named()
+            .find(|a| ext.is_some() && a.ext == ext)
+            .or_else(|| named().find(|a| mime.is_some() && a.mime.as_deref() == mime))
+    }
+}
+
+/// Parsed discovery document and the time it was fetched at.
+type CachedDiscovery = Arc<Mutex<Option<(Instant, Arc<Discovery>)>>>;
+
+/// Client for the discovery document of an office server, which caches the parsed document.
+#[derive(Clone, Debug)]
+pub struct WopiDiscovery {
+    client: reqwest::Client,
+    url: String,
+    cache: CachedDiscovery,
+}
+
+impl WopiDiscovery {
+    #[must_use]
+    pub fn new(config: &WopiConfig) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            url: config.discovery_url.clone(),
+            cache: Arc::default(),
+        }
+    }
+
+    /// Returns the discovery document, which is only fetched if the cached one is outdated.
+    pub async fn get(&self) -> Result<Arc<Discovery>, DiscoveryError> {
+        self.get_max_age(DISCOVERY_TTL).await
+    }
+
+    async fn get_max_age(&self, max_age: Duration) -> Result<Arc<Discovery>, DiscoveryError> {
+        if let Some((fetched_at, discovery)) = self.cache.lock().as_ref() {
+            if fetched_at.elapsed() < max_age {
+                return Ok(discovery.clone());
+            }
+        }
+        self.refresh().await
+    }
+
+    /// Checks the proof of a request against the proof keys of the office server. If the check
+    /// fails, the keys might have been rotated, so the discovery document is fetched again.
+    pub async fn verify_proof(&self, proof: &WopiProof) -> Result<bool, DiscoveryError> {
+        let discovery = self.get().await?;
+        if discovery.verify_proof(proof) {
+            return Ok(true);
+        }
+        let refreshed = self.get_max_age(PROOF_KEY_REFRESH_INTERVAL).await?;
+        Ok(!Arc::ptr_eq(&discovery, &refreshed) && refreshed.verify_proof(proof))
+    }
+
+    /// Fetches the discovery document, regardless of the cached one.
+    #[tracing::instrument(skip(self), fields(url = %self.url))]
+    pub async fn refresh(&self) -> Result<Arc<Discovery>, DiscoveryError> {
+        let xml = self
+            .client
+            .get(&self.url)
+            .send()
+            .await?
+            .error_for_status()?
+            .text()
+            .await?;
+        let discovery = Arc::new(Discovery::parse(&xml)?);
+        *self.cache.lock() = Some((Instant::now(), discovery.clone()));
+        Ok(discovery)
+    }
+}
+
+/// Removes the optional `<name=VALUE&>` placeholders of an `urlsrc` template.
+fn remove_placeholders(url_src: &str) -> String {
+    let mut url = String::with_capacity(url_src.len());
+    let mut rest = url_src;
+    while let Some(start) = rest.find('<') {
+        url.push_str(&rest[..start]);
+        rest = rest[start..]
+            .find('>')
+            .map_or("", |end| &rest[start + end + 1..]);
+    }
+    url.push_str(rest);
+    url
+}
+
+/// Decodes a base64 encoded big endian integer without its leading zeros.
+fn decode_unsigned(value: &str) -> Result<Vec<u8>, base64::DecodeError> {
+    let bytes = STANDARD.decode(value)?;
+    let start = bytes.iter().position(|b| *b != 0).unwrap_or(bytes.len());
+    Ok(bytes[start..].to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DISCOVERY: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<wopi-discovery>
+  <net-zone name="external-http">
+    <app name="writer">
+      <action default="true" ext="odt" name="edit" urlsrc="http://office:9980/browser/dist/cool.html?"/>
+      <action ext="docx" name="view" urlsrc="http://office:9980/browser/dist/cool.html?&lt;ui=UI_LLCC&amp;&gt;"/>
+    </app>
+    <app name="application/vnd.oasis.opendocument.text">
+      <action default="true" ext="" name="edit" urlsrc="http://office:9980/browser/dist/cool.html?"/>
+    </app>
+  </net-zone>
+</wopi-discovery>"#;
+
+    const PROOF_DISCOVERY: &str = r#"<wopi-discovery>
+  <proof-key exponent="AQAB" modulus="j+3BX5BbcDSsNme+/ZQ3D8OTFFZauRzC5N8c3PK7fgNTcW19ObxW/TWDTGvdC2t2ulwSzvTu4691gNvxM10PX3F+6JZYUQ1+r711NzeoUEgHGb5e8nCPrTQr+AuQzGGQX290HTRda4k4tuYJ1x20798XH/0iGTUp7fGECcBww45pgkSOJc1PRNYyrWNXEbdYxxIifGTF7tjx/On309Kv3ne9ZzDpKtJQfRszGBrq0y8Qfz2EaeKl+n8ebFMHleIeMYkBk/OsaygTyaiEEp666sr/y4D/L2iO0F59v43zS5c6on9AJMw1gAftpAA9QMqz6XT5MnD3qnkLumXaAYCqQw==" oldexponent="AQAB" oldmodulus="rJnGuPmyOTwp8tQHs/awHvVfqonqMWThHbmVqmQWzyz+lMbGYAQDldAsOzgU/+YXq0bmGK3H6eII/YOOa2WSLwPYk7JhB0kQBGrdka9OM72/VTN5sLXrHHLAcETIGyVv/jb+82iw1xaE2iy+VncpMdcKmanKe9cnoDjTBtjicV6noSX9BcMR6INhz9W0iMioWwIlapZcN5nJK4XGYniyD84ujErJsx5Gsi+1p3AOrM4LcAm3XsxT/Yc2UJ1yF3Lkhp0fCCyY7I9VxT8Z+aC+padgwcbCm7oriDRKeaBl2dxUhHUK4Ci+tdXbTlWYaAd5+8ZiMfFpEor8pVlpIqm0WQ=="/>
+</wopi-discovery>"#;
+
+    /// Signed with the current key of `PROOF_DISCOVERY`
+    const PROOF: &str = "OF9fa0fgqu6MTYfVckpFQLDDElx/Yq9iN/0tq/IcnCxuc1ESscGU2OkBlIqaUYylvTH+pBIRaPc3UPvb8AllQw1nzMCm4zsL9msQvlrVaOhtpSvo0DeCyDPeVmuX83x9YJUS5gM4dfA+R+lLJRRyjhR8NQOJ35Ba0oAaBKDq2adv2SdW9AksLXLjDdDQCeoksdgB+vmrfIg7lJzk8HREkHOm1G1RKfl3yaAh0Lg0Z4Q8JPrIC97lvOG/XEVN2jmpdVPGvRN4sBDewla6mgcNe6ZhKedxhHd0ytMIRHopgrAfkddKZnlmIPXqygWnwQFcEI+zyw9avEoX1nViFIg59A==";
+
+    /// Signed with the old key of `PROOF_DISCOVERY`
+    const PROOF_OLD_KEY: &str = "nlw3o/62PgS2C5JquI3ejyUa3QbeRMQAwiZcr1OVRg9nYfr9rw3Q9WgDl4W6xDdudayMpNSaBnm5O7WsHvryiYGxzzPCgHo529v2Wb3X6Cx0I2T1C8KjP/1xh97BTK1sR0AbRuJfuOeBI84j/f2eaQA272iAE1/XkVL+e4NNdEqn7IP/3fkeccLTrmiMy8En4h8gvNeQ5qModbeb1NLigOQvJZT5Nt9l7r2XPte7hMCwrdT7iR5hNVIafQmzO9LM/yHgzX/z+EAouylKHdC9mIRTzrA2WZhndI1iwXFzpdLq22h/YWLH605u4aUx53wY4FR+KX16oofiilOCG09A7Q==";
+
+    fn proof(proof: &str, proof_old: Option<&str>) -> WopiProof {
+        WopiProof {
+            access_token: "token".to_owned(),
+            url: "http://genbu:8080/api/wopi/files/1?access_token=token".to_owned(),
+            // 2023-10-15 20:53:20 UTC
+            timestamp: 638_330_000_000_000_000,
+            proof: STANDARD.decode(proof).unwrap(),
+            proof_old: proof_old.map(|p| STANDARD.decode(p).unwrap()),
+        }
+    }
+
+    #[test]
+    fn find_actions() {
+        let discovery = Discovery::parse(DISCOVERY).unwrap();
+        assert_eq!(discovery.actions.len(), 3);
+        assert!(discovery.find_action("edit", Some("ODT"), None).is_some());
+        assert!(discovery.find_action("edit", Some("docx"), None).is_none());
+        let by_mime = discovery
+            .find_action(
+                "edit",
+                Some("unknown"),
+                Some("application/vnd.oasis.opendocument.text"),
+            )
+            .unwrap();
+        assert_eq!(by_mime.ext, None);
+    }
+
+    #[test]
+    fn launch_url() {
+        let discovery = Discovery::parse(DISCOVERY).unwrap();
+        let action = discovery.find_action("view", Some("docx"), None).unwrap();
+        assert_eq!(
+            action.launch_url("http://genbu:8080/api/wopi/files/1"),
+            "http://office:9980/browser/dist/cool.html?WOPISrc=http%3A%2F%2Fgenbu%3A8080%2Fapi%2Fwopi%2Ffiles%2F1"
+        );
+    }
+
+    #[test]
+    fn verify_proofs() {
+        let discovery = Discovery::parse(PROOF_DISCOVERY).unwrap();
+        assert!(discovery.verify_proof(&proof(PROOF, None)));
+        // Office servers sign with the old key while they rotate their keys
+        assert!(discovery.verify_proof(&proof(PROOF_OLD_KEY, None)));
+        assert!(discovery.verify_proof(&proof(PROOF_OLD_KEY, Some(PROOF))));
+
+        let mut forged = proof(PROOF, None);
+        forged.url = "http://genbu:8080/api/wopi/files/2?access_token=token".to_owned();
+        assert!(!discovery.verify_proof(&forged));
+        assert!(!Discovery::parse(DISCOVERY)
+            .unwrap()
+            .verify_proof(&proof(PROOF, None)));
+    }
+
+    #[test]
+    fn proof_freshness() {
+        let proof = proof(PROOF, None);
+        let signed_at = OffsetDateTime::from_unix_timestamp(1_697_403_200).unwrap();
+        assert!(proof.is_fresh(signed_at + time::Duration::minutes(5)));
+        assert!(!proof.is_fresh(signed_at + time::Duration::minutes(21)));
+    }
+}
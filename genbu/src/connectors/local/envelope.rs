@@ -0,0 +1,253 @@
+use std::io;
+
+use bytes::Bytes;
+use futures::stream;
+use ring::{
+    aead::{MAX_TAG_LEN, NONCE_LEN},
+    rand::{SecureRandom, SystemRandom},
+};
+use tokio::{fs::File, io::AsyncReadExt};
+
+use crate::stores::files::{
+    encryption::{DataKey, EncryptionError},
+    storage::ObjectStream,
+};
+
+/// Size in bytes of the plaintext of every chunk but the last one of a sealed object. Objects are
+/// sealed in chunks, so they can be streamed without loading them into memory.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Size of a full chunk on disk, which is followed by its authentication tag.
+const SEALED_CHUNK_SIZE: usize = CHUNK_SIZE + MAX_TAG_LEN;
+
+/// Size of the random nonce prefix at the start of a sealed object. The nonce of a chunk is the
+/// prefix followed by the index of the chunk.
+const PREFIX_LEN: usize = NONCE_LEN - 4;
+
+fn nonce(prefix: &[u8; PREFIX_LEN], index: u32) -> [u8; NONCE_LEN] {
+    let mut nonce = [0; NONCE_LEN];
+    nonce[..PREFIX_LEN].copy_from_slice(prefix);
+    nonce[PREFIX_LEN..].copy_from_slice(&index.to_be_bytes());
+    nonce
+}
+
+fn invalid_data() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "unable to decrypt object")
+}
+
+/// Returns the size of the plaintext of a sealed object of `sealed_size` bytes.
+#[must_use]
+pub const fn plaintext_size(sealed_size: u64) -> u64 {
+    let body = sealed_size.saturating_sub(PREFIX_LEN as u64);
+    let mut chunks = body / SEALED_CHUNK_SIZE as u64;
+    // Even an empty object has a final chunk
+    if chunks == 0 || !body.is_multiple_of(SEALED_CHUNK_SIZE as u64) {
+        chunks += 1;
+    }
+    body.saturating_sub(chunks * MAX_TAG_LEN as u64)
+}
+
+/// Encrypts an object chunk by chunk while it's written.
+pub struct Sealer {
+    key: DataKey,
+    prefix: [u8; PREFIX_LEN],
+    index: u32,
+    buf: Vec<u8>,
+}
+
+impl Sealer {
+    /// Starts sealing an object. The returned header has to be written before the sealed chunks.
+    pub fn new(key: DataKey) -> Result<(Self, Vec<u8>), EncryptionError> {
+        let mut prefix = [0; PREFIX_LEN];
+        SystemRandom::new().fill(&mut prefix)?;
+        let sealer = Self {
+            key,
+            prefix,
+            index: 0,
+            buf: Vec::with_capacity(SEALED_CHUNK_SIZE),
+        };
+        Ok((sealer, prefix.to_vec()))
+    }
+
+    fn seal_chunk(&mut self, last: bool) -> Result<Vec<u8>, EncryptionError> {
+        let mut chunk = std::mem::replace(&mut self.buf, Vec::with_capacity(SEALED_CHUNK_SIZE));
+        self.key
+            .seal_chunk(nonce(&self.prefix, self.index), last, &mut chunk)?;
+        self.index = self.index.checked_add(1).ok_or(EncryptionError::Crypto)?;
+        Ok(chunk)
+    }
+
+    /// Adds data to the object and returns the chunks, which were sealed by now. A full chunk is
+    /// held back until more data follows, because the final chunk is sealed differently.
+    pub fn update(&mut self, mut data: &[u8]) -> Result<Vec<u8>, EncryptionError> {
+        let mut sealed = Vec::new();
+        while !data.is_empty() {
+            if self.buf.len() == CHUNK_SIZE {
+                sealed.extend_from_slice(&self.seal_chunk(false)?);
+            }
+            let (head, tail) = data.split_at((CHUNK_SIZE - self.buf.len()).min(data.len()));
+            self.buf.extend_from_slice(head);
+            data = tail;
+        }
+        Ok(sealed)
+    }
+
+    /// Seals the final chunk of the object.
+    pub fn finish(mut self) -> Result<Vec<u8>, EncryptionError> {
+        self.seal_chunk(true)
+    }
+}
+
+/// Encrypts a whole object.
+pub fn seal(key: DataKey, data: &[u8]) -> Result<Vec<u8>, EncryptionError> {
+    let (mut sealer, mut sealed) = Sealer::new(key)?;
+    sealed.extend_from_slice(&sealer.update(data)?);
+    sealed.extend_from_slice(&sealer.finish()?);
+    Ok(sealed)
+}
+
+/// Decrypts a whole object, which was sealed with `key`.
+pub fn open(key: &DataKey, sealed: &[u8]) -> Result<Vec<u8>, EncryptionError> {
+    if sealed.len() <= PREFIX_LEN {
+        return Err(EncryptionError::Crypto);
+    }
+    let (prefix, body) = sealed.split_at(PREFIX_LEN);
+    let prefix = prefix.try_into().map_err(|_| EncryptionError::Crypto)?;
+    let chunk_count = body.len().div_ceil(SEALED_CHUNK_SIZE);
+    let mut data = Vec::with_capacity(body.len());
+    for (index, chunk) in body.chunks(SEALED_CHUNK_SIZE).enumerate() {
+        let last = index + 1 == chunk_count;
+        let index = u32::try_from(index).map_err(|_| EncryptionError::Crypto)?;
+        let mut chunk = chunk.to_vec();
+        data.extend_from_slice(key.open_chunk(nonce(&prefix, index), last, &mut chunk)?);
+    }
+    Ok(data)
+}
+
+struct Opening {
+    key: DataKey,
+    file: File,
+    prefix: [u8; PREFIX_LEN],
+    index: u32,
+    /// Sealed bytes which weren't read yet
+    remaining: u64,
+}
+
+/// Decrypts a sealed object of `sealed_size` bytes chunk by chunk while it's read.
+pub async fn open_stream(
+    key: DataKey,
+    mut file: File,
+    sealed_size: u64,
+) -> io::Result<ObjectStream> {
+    if sealed_size <= PREFIX_LEN as u64 {
+        return Err(invalid_data());
+    }
+    let mut prefix = [0; PREFIX_LEN];
+    file.read_exact(&mut prefix).await?;
+    let opening = Opening {
+        key,
+        file,
+        prefix,
+        index: 0,
+        remaining: sealed_size - PREFIX_LEN as u64,
+    };
+    Ok(Box::pin(stream::try_unfold(
+        opening,
+        |mut opening| async move {
+            if opening.remaining == 0 {
+                return Ok(None);
+            }
+            let len = opening.remaining.min(SEALED_CHUNK_SIZE as u64);
+            let last = len == opening.remaining;
+            let mut chunk = vec![0; usize::try_from(len).map_err(|_| invalid_data())?];
+            opening.file.read_exact(&mut chunk).await?;
+            let nonce = nonce(&opening.prefix, opening.index);
+            let plaintext_len = opening
+                .key
+                .open_chunk(nonce, last, &mut chunk)
+                .map_err(|_| invalid_data())?
+                .len();
+            chunk.truncate(plaintext_len);
+            opening.remaining -= len;
+            opening.index = opening.index.checked_add(1).ok_or_else(invalid_data)?;
+            Ok(Some((Bytes::from(chunk), opening)))
+        },
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use base64::{engine::general_purpose::STANDARD, Engine};
+    use secrecy::SecretString;
+
+    use crate::{
+        config::EncryptionConfig,
+        connectors::memory::MemStore,
+        stores::{
+            files::encryption::{Keyring, MasterKeys, KEY_LEN},
+            Uuid,
+        },
+    };
+
+    use super::*;
+
+    async fn data_key() -> DataKey {
+        let master_key = SecretString::new(STANDARD.encode([1; KEY_LEN]));
+        let config = EncryptionConfig {
+            master_keys: HashMap::from([("k1".to_owned(), master_key)]),
+            active_key: Some("k1".to_owned()),
+        };
+        let master_keys = MasterKeys::from_config(&config).unwrap().unwrap();
+        Keyring::new(master_keys, MemStore::new())
+            .data_key(Uuid::new_v4())
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn seal_and_open_chunks() {
+        let key = data_key().await;
+        for size in [
+            0,
+            1,
+            CHUNK_SIZE,
+            CHUNK_SIZE + 1,
+            2 * CHUNK_SIZE,
+            3 * CHUNK_SIZE - 7,
+        ] {
+            let data: Vec<u8> = (0..size).map(|i| (i % 251) as u8).collect();
+            let (mut sealer, mut sealed) = Sealer::new(key.clone()).unwrap();
+            // Data arrives in pieces which don't line up with the chunks
+            for piece in data.chunks(1000) {
+                sealed.extend_from_slice(&sealer.update(piece).unwrap());
+            }
+            sealed.extend_from_slice(&sealer.finish().unwrap());
+
+            assert_eq!(plaintext_size(sealed.len() as u64), size as u64, "{size}");
+            assert_eq!(open(&key, &sealed).unwrap(), data, "{size}");
+            assert_eq!(
+                open(&key, &seal(key.clone(), &data).unwrap()).unwrap(),
+                data
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn reject_tampering() {
+        let key = data_key().await;
+        let sealed = seal(key.clone(), &vec![7; 2 * CHUNK_SIZE + 10]).unwrap();
+
+        let mut flipped = sealed.clone();
+        flipped[PREFIX_LEN + 3] ^= 1;
+        assert!(open(&key, &flipped).is_err());
+
+        // Dropping the final chunk leaves a chunk, which wasn't sealed as the last one
+        let truncated = &sealed[..PREFIX_LEN + 2 * SEALED_CHUNK_SIZE];
+        assert!(open(&key, truncated).is_err());
+
+        let other_key = data_key().await;
+        assert!(open(&other_key, &sealed).is_err());
+    }
+}
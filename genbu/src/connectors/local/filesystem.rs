@@ -0,0 +1,144 @@
+use std::{io, path::PathBuf};
+
+use time::OffsetDateTime;
+use tokio::fs;
+
+use crate::stores::{
+    files::{
+        filesystem::{Filesystem, FilesystemError, SResult, Userfile},
+        storage::Bucket,
+    },
+    Uuid,
+};
+
+use super::LocalStore;
+
+fn map_io_err(err: io::Error) -> FilesystemError {
+    FilesystemError::Other(Box::new(err))
+}
+
+/// Copies a file, or a directory with everything inside it.
+async fn copy_recursive(from: PathBuf, to: PathBuf) -> io::Result<()> {
+    let mut pending = vec![(from, to)];
+    while let Some((from, to)) = pending.pop() {
+        if !fs::metadata(&from).await?.is_dir() {
+            fs::copy(&from, &to).await?;
+            continue;
+        }
+        fs::create_dir(&to).await?;
+        let mut entries = fs::read_dir(&from).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            pending.push((entry.path(), to.join(entry.file_name())));
+        }
+    }
+    Ok(())
+}
+
+impl LocalStore {
+    /// Returns the locations of `from` and `to`, after checking that `from` exists and `to`
+    /// doesn't.
+    async fn transfer_paths(&self, from: &str, to: &str) -> SResult<(PathBuf, PathBuf)> {
+        if self.exists(to).await? {
+            return Err(FilesystemError::FileAlreadyExists(to.to_owned()));
+        }
+        if !self.exists(from).await? {
+            return Err(FilesystemError::NotFound(from.to_owned()));
+        }
+        let target = self.object_path(Bucket::UserFiles, to)?;
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent).await.map_err(map_io_err)?;
+        }
+        Ok((self.object_path(Bucket::UserFiles, from)?, target))
+    }
+}
+
+#[async_trait::async_trait]
+impl Filesystem for LocalStore {
+    /// Lists the direct children of `base_path` with the same semantics as an S3 listing with a
+    /// `\` delimiter, i.e. the part after the last delimiter is a name prefix.
+    async fn list(&self, user_id: Uuid, base_path: &str) -> SResult<Vec<Userfile>> {
+        let (dir_key, name_prefix) = base_path.rsplit_once('\\').unwrap_or(("", base_path));
+        let dir = if dir_key.is_empty() {
+            self.bucket_path(Bucket::UserFiles)
+        } else {
+            self.object_path(Bucket::UserFiles, dir_key)?
+        };
+
+        let mut entries = match fs::read_dir(dir).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(map_io_err(e)),
+        };
+        let key_prefix = if dir_key.is_empty() {
+            String::new()
+        } else {
+            format!("{dir_key}\\")
+        };
+
+        let mut files = Vec::new();
+        while let Some(entry) = entries.next_entry().await.map_err(map_io_err)? {
+            let Some(name) = entry.file_name().to_str().map(ToOwned::to_owned) else {
+                continue;
+            };
+            if !name.starts_with(name_prefix) {
+                continue;
+            }
+            let metadata = entry.metadata().await.map_err(map_io_err)?;
+            files.push(if metadata.is_dir() {
+                Userfile {
+                    name: format!("{key_prefix}{name}\\"),
+                    last_modified: None,
+                    owner: user_id,
+                    size: None,
+                    is_folder: true,
+                }
+            } else {
+                Userfile {
+                    name: format!("{key_prefix}{name}"),
+                    last_modified: metadata.modified().ok().map(OffsetDateTime::from),
+                    owner: user_id,
+                    size: self.content_size(metadata.len()).try_into().ok(),
+                    is_folder: false,
+                }
+            });
+        }
+        Ok(files)
+    }
+
+    async fn delete(&mut self, path: &str) -> SResult<()> {
+        let path = self.object_path(Bucket::UserFiles, path)?;
+        let res = match fs::metadata(&path).await {
+            Ok(metadata) if metadata.is_dir() => fs::remove_dir_all(path).await,
+            Ok(_) => fs::remove_file(path).await,
+            Err(e) => Err(e),
+        };
+        match res {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(map_io_err(e)),
+            _ => Ok(()),
+        }
+    }
+
+    async fn mkdir(&mut self, path: &str) -> SResult<()> {
+        let path = self.object_path(Bucket::UserFiles, path)?;
+        fs::create_dir_all(path).await.map_err(map_io_err)
+    }
+
+    async fn exists(&self, path: &str) -> SResult<bool> {
+        let path = self.object_path(Bucket::UserFiles, path)?;
+        match fs::metadata(path).await {
+            Ok(_) => Ok(true),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(map_io_err(e)),
+        }
+    }
+
+    async fn copy(&mut self, from: &str, to: &str) -> SResult<()> {
+        let (from, to) = self.transfer_paths(from, to).await?;
+        copy_recursive(from, to).await.map_err(map_io_err)
+    }
+
+    async fn rename(&mut self, from: &str, to: &str) -> SResult<()> {
+        let (from, to) = self.transfer_paths(from, to).await?;
+        fs::rename(from, to).await.map_err(map_io_err)
+    }
+}
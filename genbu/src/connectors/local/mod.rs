@@ -0,0 +1,173 @@
+use std::{
+    error::Error,
+    io,
+    path::{Component, Path, PathBuf},
+};
+
+use async_trait::async_trait;
+
+use crate::{
+    config::GenbuConfig,
+    stores::{
+        files::{
+            encryption::{DataKey, Keyring},
+            filesystem::FilesystemError,
+            storage::{Bucket, FileError, BUCKETS},
+        },
+        Reset, Setup, Uuid,
+    },
+};
+
+use self::presign::Presigner;
+
+pub mod envelope;
+pub mod filesystem;
+pub mod presign;
+pub mod storage;
+
+/// Directory below the root which holds the parts of unfinished multipart uploads.
+const UPLOADS_DIR: &str = ".uploads";
+
+/// A file storage which keeps every bucket in a directory on the local disk. Keys use the same
+/// `\` delimiter as S3, every segment maps to one directory level.
+#[derive(Clone, Debug)]
+pub struct LocalStore {
+    root: PathBuf,
+    presigner: Presigner,
+    /// Objects are sealed with the data key of their owner, if it's set
+    keyring: Option<Keyring>,
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("invalid object key `{0}`")]
+pub struct InvalidKey(String);
+
+impl From<InvalidKey> for FileError {
+    fn from(value: InvalidKey) -> Self {
+        Self::Other(Box::new(value))
+    }
+}
+
+impl From<InvalidKey> for FilesystemError {
+    fn from(value: InvalidKey) -> Self {
+        Self::Other(Box::new(value))
+    }
+}
+
+fn map_io_err(err: io::Error) -> FileError {
+    FileError::Other(Box::new(err))
+}
+
+impl LocalStore {
+    #[must_use]
+    pub fn new(config: &GenbuConfig) -> Self {
+        Self {
+            root: config.storage.local.root.clone(),
+            presigner: Presigner::new(config.auth.jwt_secret.clone()),
+            keyring: None,
+        }
+    }
+
+    /// Encrypts every object, which is stored afterwards, with the data key of its owner.
+    #[must_use]
+    pub fn with_keyring(mut self, keyring: Keyring) -> Self {
+        self.keyring = Some(keyring);
+        self
+    }
+
+    /// Returns the data key of the object at `key`, if objects are encrypted.
+    async fn data_key(&self, key: &str) -> Result<Option<DataKey>, FileError> {
+        match &self.keyring {
+            Some(keyring) => Ok(Some(keyring.object_key(key).await?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Returns the size of the content of an object, which takes up `len` bytes on disk.
+    fn content_size(&self, len: u64) -> u64 {
+        if self.keyring.is_some() {
+            envelope::plaintext_size(len)
+        } else {
+            len
+        }
+    }
+
+    fn bucket_path(&self, bucket: Bucket) -> PathBuf {
+        self.root.join(bucket.to_bucket_name())
+    }
+
+    /// Maps an object key to its location on disk. Keys which would escape the bucket directory
+    /// are rejected.
+    fn object_path(&self, bucket: Bucket, key: &str) -> Result<PathBuf, InvalidKey> {
+        let mut path = self.bucket_path(bucket);
+        for segment in key.split('\\') {
+            let mut components = Path::new(segment).components();
+            match (components.next(), components.next()) {
+                (Some(Component::Normal(c)), None) if !segment.contains('/') => path.push(c),
+                _ => return Err(InvalidKey(key.to_owned())),
+            }
+        }
+        Ok(path)
+    }
+
+    /// Upload ids are generated by this store, but are sent back by clients and therefore have
+    /// to be validated before touching the disk.
+    fn upload_path(&self, upload_id: &str) -> Result<PathBuf, InvalidKey> {
+        let id = Uuid::parse_str(upload_id).map_err(|_| InvalidKey(upload_id.to_owned()))?;
+        Ok(self.root.join(UPLOADS_DIR).join(id.to_string()))
+    }
+}
+
+#[async_trait]
+impl Reset for LocalStore {
+    #[cfg(debug_assertions)]
+    async fn reset(&mut self) -> Result<(), Box<dyn Error>> {
+        match tokio::fs::remove_dir_all(&self.root).await {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(Box::new(e)),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[async_trait]
+impl Setup for LocalStore {
+    async fn setup(&mut self) -> Result<(), Box<dyn Error>> {
+        for bucket in BUCKETS {
+            tokio::fs::create_dir_all(self.bucket_path(bucket)).await?;
+        }
+        tokio::fs::create_dir_all(self.root.join(UPLOADS_DIR)).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn store() -> LocalStore {
+        LocalStore::new(&GenbuConfig::default())
+    }
+
+    #[test]
+    fn nested_keys() {
+        let path = store()
+            .object_path(Bucket::UserFiles, "user\\folder\\file.txt")
+            .unwrap();
+        assert!(path.ends_with("userfiles/user/folder/file.txt"));
+    }
+
+    #[test]
+    fn reject_traversal() {
+        let store = store();
+        for key in [
+            "user\\..\\other",
+            "user\\",
+            "user/../../etc",
+            "\\file",
+            "user\\.",
+        ] {
+            assert!(store.object_path(Bucket::UserFiles, key).is_err(), "{key}");
+        }
+        assert!(store.upload_path("../avatars").is_err());
+    }
+}
@@ -0,0 +1,135 @@
+use genbu_auth::authn::{decode_claims, encode_claims};
+use secrecy::SecretString;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use time::{Duration, OffsetDateTime};
+
+use crate::stores::files::storage::{
+    Bucket, ChecksumAlgorithm, FileError, PartChecksums, PresignError,
+};
+
+/// How long a presigned url stays valid, this matches the S3 presigning config.
+const PRESIGN_TTL: Duration = Duration::minutes(30);
+
+/// Path of the route which serves presigned urls for file storages without native presigning.
+pub const SIGNED_ROUTE: &str = "/api/storage";
+
+/// Returns the `ETag` of a part uploaded through a presigned url.
+#[must_use]
+pub fn part_e_tag(data: &[u8]) -> String {
+    format!("\"{}\"", hex::encode(Sha256::digest(data)))
+}
+
+/// Compares two `ETag`s, ignoring the quotes which some clients strip.
+#[must_use]
+pub fn e_tag_matches(a: &str, b: &str) -> bool {
+    a.trim_matches('"') == b.trim_matches('"')
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("part {0} is missing or doesn't match the uploaded data")]
+pub struct InvalidPart(pub i32);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PresignedMethod {
+    Get,
+    Put,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PresignClaims {
+    pub method: PresignedMethod,
+    pub bucket: Bucket,
+    pub key: String,
+    /// Only set for parts of a multipart upload
+    pub upload_id: Option<String>,
+    pub part_number: Option<i32>,
+    /// Checksum the uploaded part has to match, if the client declared one
+    #[serde(default)]
+    pub checksum: Option<(ChecksumAlgorithm, String)>,
+    pub exp: i64,
+}
+
+impl PresignClaims {
+    /// Checks the data sent to a presigned upload url against the checksum, which the url was
+    /// signed with. This is what S3 does for the checksum headers of a presigned request.
+    pub fn verify_checksum(&self, data: &[u8]) -> Result<(), FileError> {
+        match (&self.checksum, self.part_number) {
+            (Some((algorithm, checksum)), Some(part_number))
+                if algorithm.encoded_digest(data) != *checksum =>
+            {
+                Err(FileError::BadDigest(part_number))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Creates and validates signed, expiring tokens, which stand in for S3 presigned urls. The
+/// resulting urls point to [`SIGNED_ROUTE`] on this server.
+#[derive(Clone, Debug)]
+pub struct Presigner {
+    secret: SecretString,
+}
+
+impl Presigner {
+    #[must_use]
+    pub const fn new(secret: SecretString) -> Self {
+        Self { secret }
+    }
+
+    fn sign(&self, claims: &PresignClaims) -> Result<String, FileError> {
+        let token = encode_claims(claims, &self.secret)
+            .map_err(|e| FileError::Presigning(PresignError::Other(Box::new(e))))?;
+        Ok(format!("{SIGNED_ROUTE}?token={token}"))
+    }
+
+    fn expires_at() -> i64 {
+        (OffsetDateTime::now_utc() + PRESIGN_TTL).unix_timestamp()
+    }
+
+    pub fn download_url(&self, bucket: Bucket, key: &str) -> Result<String, FileError> {
+        self.sign(&PresignClaims {
+            method: PresignedMethod::Get,
+            bucket,
+            key: key.to_owned(),
+            upload_id: None,
+            part_number: None,
+            checksum: None,
+            exp: Self::expires_at(),
+        })
+    }
+
+    pub fn upload_part_url(
+        &self,
+        bucket: Bucket,
+        key: &str,
+        upload_id: &str,
+        part_number: i32,
+        checksums: Option<&PartChecksums>,
+    ) -> Result<String, FileError> {
+        let checksum = checksums.and_then(|checksums| {
+            let checksum = checksums.part(part_number)?;
+            Some((checksums.algorithm, checksum.to_owned()))
+        });
+        self.sign(&PresignClaims {
+            method: PresignedMethod::Put,
+            bucket,
+            key: key.to_owned(),
+            upload_id: Some(upload_id.to_owned()),
+            part_number: Some(part_number),
+            checksum,
+            exp: Self::expires_at(),
+        })
+    }
+
+    /// Returns the claims of `token` if it is valid and was issued for `method`.
+    pub fn verify(&self, token: &str, method: PresignedMethod) -> Result<PresignClaims, FileError> {
+        let claims: PresignClaims = decode_claims(token, &self.secret)
+            .map_err(|_| FileError::Presigning(PresignError::InvalidToken))?;
+        if claims.method != method {
+            return Err(FileError::Presigning(PresignError::InvalidToken));
+        }
+        Ok(claims)
+    }
+}
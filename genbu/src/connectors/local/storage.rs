@@ -0,0 +1,490 @@
+use std::{
+    io::{self, SeekFrom},
+    ops::Range,
+    path::Path,
+    time::UNIX_EPOCH,
+};
+
+use bytes::Bytes;
+use futures::TryStreamExt;
+use time::OffsetDateTime;
+use tokio::{
+    fs,
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
+};
+use tokio_util::io::ReaderStream;
+
+use crate::stores::{
+    files::{
+        encryption::{key_owner, DataKey, EncryptionError},
+        storage::{
+            chunk_count, slice_object, Bucket, FileError, InvalidPartSize, Object, ObjectMeta,
+            ObjectStream, Part, PartChecksums, PresignError, SizeMismatch,
+        },
+        FileStorage,
+    },
+    Uuid,
+};
+
+use super::{
+    envelope::{self, Sealer},
+    map_io_err,
+    presign::{e_tag_matches, part_e_tag, InvalidPart, PresignedMethod},
+    LocalStore,
+};
+
+/// File inside an upload directory which records the bucket and key the upload belongs to.
+const UPLOAD_TARGET: &str = "target";
+
+fn upload_target(bucket: Bucket, key: &str) -> String {
+    format!("{}\n{key}", bucket.to_bucket_name())
+}
+
+/// Moves a completely written file to its final location, so readers never see partial data.
+async fn persist(tmp: &Path, target: &Path) -> io::Result<()> {
+    if let Some(parent) = target.parent() {
+        fs::create_dir_all(parent).await?;
+    }
+    fs::rename(tmp, target).await
+}
+
+/// Decrypts a part of a multipart upload, if it was sealed with `data_key`.
+fn open_part(data: Vec<u8>, data_key: Option<&DataKey>) -> Result<Vec<u8>, FileError> {
+    match data_key {
+        Some(key) => Ok(envelope::open(key, &data)?),
+        None => Ok(data),
+    }
+}
+
+fn sealing_err(_: EncryptionError) -> io::Error {
+    io::Error::other("unable to seal object")
+}
+
+/// Writes an object to a file. The object is sealed while it's written, if it's encrypted.
+struct ObjectWriter {
+    out: fs::File,
+    sealer: Option<Sealer>,
+}
+
+impl ObjectWriter {
+    async fn create(path: &Path, data_key: Option<DataKey>) -> io::Result<Self> {
+        let mut out = fs::File::create(path).await?;
+        let sealer = match data_key {
+            Some(key) => {
+                let (sealer, header) = Sealer::new(key).map_err(sealing_err)?;
+                out.write_all(&header).await?;
+                Some(sealer)
+            }
+            None => None,
+        };
+        Ok(Self { out, sealer })
+    }
+
+    async fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        let sealed;
+        let data = match &mut self.sealer {
+            Some(sealer) => {
+                sealed = sealer.update(data).map_err(sealing_err)?;
+                &sealed
+            }
+            None => data,
+        };
+        self.out.write_all(data).await
+    }
+
+    async fn finish(mut self) -> io::Result<()> {
+        if let Some(sealer) = self.sealer {
+            let last_chunk = sealer.finish().map_err(sealing_err)?;
+            self.out.write_all(&last_chunk).await?;
+        }
+        self.out.sync_all().await
+    }
+}
+
+impl LocalStore {
+    async fn check_upload_target(
+        &self,
+        upload_path: &Path,
+        bucket: Bucket,
+        key: &str,
+        upload_id: &str,
+    ) -> Result<(), FileError> {
+        // An upload which belongs to another object is treated like a missing one
+        match fs::read_to_string(upload_path.join(UPLOAD_TARGET)).await {
+            Ok(target) if target == upload_target(bucket, key) => Ok(()),
+            Ok(_) => Err(FileError::NotFound(upload_id.to_owned())),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                Err(FileError::NotFound(upload_id.to_owned()))
+            }
+            Err(e) => Err(map_io_err(e)),
+        }
+    }
+
+    /// Writes a file, which is sealed with `data_key` if it's given, so readers never see
+    /// partial data.
+    async fn write_atomic(
+        &self,
+        target: &Path,
+        data: &[u8],
+        data_key: Option<DataKey>,
+    ) -> Result<(), FileError> {
+        let tmp = self
+            .root
+            .join(super::UPLOADS_DIR)
+            .join(Uuid::new_v4().to_string());
+        let res = match data_key {
+            Some(key) => {
+                let sealed = envelope::seal(key, data)?;
+                fs::write(&tmp, sealed).await
+            }
+            None => fs::write(&tmp, data).await,
+        };
+        res.map_err(map_io_err)?;
+        persist(&tmp, target).await.map_err(map_io_err)
+    }
+
+    /// Opens the file of an object and returns it with its size on disk.
+    async fn open_object(&self, bucket: Bucket, name: &str) -> Result<(fs::File, u64), FileError> {
+        let path = self.object_path(bucket, name)?;
+        let file = match fs::File::open(path).await {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                return Err(FileError::NotFound(name.to_owned()))
+            }
+            Err(e) => return Err(map_io_err(e)),
+        };
+        let size = file.metadata().await.map_err(map_io_err)?.len();
+        Ok((file, size))
+    }
+}
+
+#[async_trait::async_trait]
+impl FileStorage for LocalStore {
+    async fn delete_file(&mut self, bucket: Bucket, name: &str) -> Result<(), FileError> {
+        let path = self.object_path(bucket, name)?;
+        match fs::remove_file(path).await {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(map_io_err(e)),
+            _ => Ok(()),
+        }
+    }
+
+    async fn get_download_url(&self, bucket: Bucket, name: &str) -> Result<String, FileError> {
+        self.object_path(bucket, name)?;
+        self.presigner.download_url(bucket, name)
+    }
+
+    async fn get_presigned_upload_urls(
+        &self,
+        bucket: Bucket,
+        file: &str,
+        file_size: u64,
+        chunk_size: u64,
+        checksums: Option<&PartChecksums>,
+    ) -> Result<(Vec<String>, String), FileError> {
+        let upload_id = self.create_multipart_upload(bucket, file).await?;
+        let uris = (1..=chunk_count(file_size, chunk_size))
+            .map(|part_number| {
+                let part_number: i32 = part_number
+                    .try_into()
+                    .map_err(|_| FileError::Other(InvalidPartSize.into()))?;
+                self.presigner
+                    .upload_part_url(bucket, file, &upload_id, part_number, checksums)
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok((uris, upload_id))
+    }
+
+    async fn create_multipart_upload(
+        &self,
+        bucket: Bucket,
+        file: &str,
+    ) -> Result<String, FileError> {
+        self.object_path(bucket, file)?;
+
+        let upload_id = Uuid::new_v4().to_string();
+        let upload_path = self.upload_path(&upload_id)?;
+        fs::create_dir_all(&upload_path).await.map_err(map_io_err)?;
+        fs::write(upload_path.join(UPLOAD_TARGET), upload_target(bucket, file))
+            .await
+            .map_err(map_io_err)?;
+        Ok(upload_id)
+    }
+
+    async fn upload_part(
+        &self,
+        bucket: Bucket,
+        file: &str,
+        upload_id: &str,
+        part_number: i32,
+        data: Bytes,
+    ) -> Result<String, FileError> {
+        let upload_path = self.upload_path(upload_id)?;
+        self.check_upload_target(&upload_path, bucket, file, upload_id)
+            .await?;
+
+        // Parts are sealed like the object they belong to
+        let data_key = self.data_key(file).await?;
+        self.write_atomic(&upload_path.join(part_number.to_string()), &data, data_key)
+            .await?;
+        Ok(part_e_tag(&data))
+    }
+
+    async fn finish_multipart_upload(
+        &self,
+        bucket: Bucket,
+        file: &str,
+        upload_id: &str,
+        mut parts: Vec<Part>,
+        checksums: Option<&PartChecksums>,
+    ) -> Result<(), FileError> {
+        let upload_path = self.upload_path(upload_id)?;
+        let object_path = self.object_path(bucket, file)?;
+        self.check_upload_target(&upload_path, bucket, file, upload_id)
+            .await?;
+
+        parts.sort_by_key(|part| part.part_number);
+        let data_key = self.data_key(file).await?;
+        let tmp = upload_path.join("complete");
+        let mut out = ObjectWriter::create(&tmp, data_key.clone())
+            .await
+            .map_err(map_io_err)?;
+        for part in parts {
+            let data = match fs::read(upload_path.join(part.part_number.to_string())).await {
+                Ok(data) => open_part(data, data_key.as_ref())?,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                    return Err(FileError::Other(Box::new(InvalidPart(part.part_number))))
+                }
+                Err(e) => return Err(map_io_err(e)),
+            };
+            if !e_tag_matches(&part_e_tag(&data), &part.e_tag) {
+                return Err(FileError::Other(Box::new(InvalidPart(part.part_number))));
+            }
+            // Objects on disk have no metadata to report a checksum with, so the parts are
+            // checked against the declared checksums instead
+            if let Some(checksums) = checksums {
+                if checksums.part(part.part_number)
+                    != Some(checksums.algorithm.encoded_digest(&data).as_str())
+                {
+                    return Err(FileError::BadDigest(part.part_number));
+                }
+            }
+            out.write(&data).await.map_err(map_io_err)?;
+        }
+        out.finish().await.map_err(map_io_err)?;
+
+        persist(&tmp, &object_path).await.map_err(map_io_err)?;
+        fs::remove_dir_all(&upload_path).await.map_err(map_io_err)
+    }
+
+    async fn abort_multipart_upload(
+        &self,
+        bucket: Bucket,
+        file: &str,
+        upload_id: &str,
+    ) -> Result<(), FileError> {
+        let upload_path = self.upload_path(upload_id)?;
+        self.check_upload_target(&upload_path, bucket, file, upload_id)
+            .await?;
+        fs::remove_dir_all(&upload_path).await.map_err(map_io_err)
+    }
+
+    async fn list_parts(
+        &self,
+        bucket: Bucket,
+        file: &str,
+        upload_id: &str,
+    ) -> Result<Vec<Part>, FileError> {
+        let upload_path = self.upload_path(upload_id)?;
+        self.check_upload_target(&upload_path, bucket, file, upload_id)
+            .await?;
+
+        let data_key = self.data_key(file).await?;
+        let mut parts = Vec::new();
+        let mut entries = fs::read_dir(&upload_path).await.map_err(map_io_err)?;
+        loop {
+            let entry = entries.next_entry().await.map_err(map_io_err)?;
+            let Some(entry) = entry else {
+                break;
+            };
+            // Parts are named after their part number, every other file belongs to the upload
+            let Some(part_number) = entry
+                .file_name()
+                .to_str()
+                .and_then(|name| name.parse::<i32>().ok())
+            else {
+                continue;
+            };
+            let data = fs::read(entry.path()).await.map_err(map_io_err)?;
+            let data = open_part(data, data_key.as_ref())?;
+            parts.push(Part {
+                e_tag: part_e_tag(&data),
+                part_number,
+            });
+        }
+        parts.sort_by_key(|part| part.part_number);
+        Ok(parts)
+    }
+
+    async fn get_upload_part_urls(
+        &self,
+        bucket: Bucket,
+        file: &str,
+        upload_id: &str,
+        part_numbers: &[i32],
+        checksums: Option<&PartChecksums>,
+    ) -> Result<Vec<String>, FileError> {
+        let upload_path = self.upload_path(upload_id)?;
+        self.check_upload_target(&upload_path, bucket, file, upload_id)
+            .await?;
+        part_numbers
+            .iter()
+            .map(|part_number| {
+                self.presigner
+                    .upload_part_url(bucket, file, upload_id, *part_number, checksums)
+            })
+            .collect()
+    }
+
+    async fn upload(&mut self, bucket: Bucket, name: &str, data: Vec<u8>) -> Result<(), FileError> {
+        let path = self.object_path(bucket, name)?;
+        let data_key = self.data_key(name).await?;
+        self.write_atomic(&path, &data, data_key).await
+    }
+
+    async fn upload_stream(
+        &mut self,
+        bucket: Bucket,
+        name: &str,
+        mut data: ObjectStream,
+        size: u64,
+    ) -> Result<(), FileError> {
+        let path = self.object_path(bucket, name)?;
+        let tmp = self
+            .root
+            .join(super::UPLOADS_DIR)
+            .join(Uuid::new_v4().to_string());
+        let data_key = self.data_key(name).await?;
+        let written = async {
+            let mut out = ObjectWriter::create(&tmp, data_key).await?;
+            let mut written = 0;
+            while let Some(chunk) = data.try_next().await? {
+                written += chunk.len() as u64;
+                out.write(&chunk).await?;
+            }
+            out.finish().await?;
+            Ok::<_, io::Error>(written)
+        }
+        .await;
+        match written {
+            Ok(written) if written == size => persist(&tmp, &path).await.map_err(map_io_err),
+            res => {
+                let _ = fs::remove_file(&tmp).await;
+                Err(match res {
+                    Ok(written) => FileError::Other(Box::new(SizeMismatch(written, size))),
+                    Err(e) => map_io_err(e),
+                })
+            }
+        }
+    }
+
+    async fn copy_object(&mut self, bucket: Bucket, from: &str, to: &str) -> Result<(), FileError> {
+        // Sealed objects can only be copied as they are, if both keys belong to the same owner
+        if self.keyring.is_some() && key_owner(from) != key_owner(to) {
+            let object = self.get_object(bucket, from).await?;
+            return self
+                .upload_stream(bucket, to, object.body, object.size)
+                .await;
+        }
+        let source = self.object_path(bucket, from)?;
+        let target = self.object_path(bucket, to)?;
+        let tmp = self
+            .root
+            .join(super::UPLOADS_DIR)
+            .join(Uuid::new_v4().to_string());
+        match fs::copy(source, &tmp).await {
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                return Err(FileError::NotFound(from.to_owned()))
+            }
+            Err(e) => return Err(map_io_err(e)),
+        }
+        persist(&tmp, &target).await.map_err(map_io_err)
+    }
+
+    async fn put_signed(&self, token: &str, data: Bytes) -> Result<String, FileError> {
+        let claims = self.presigner.verify(token, PresignedMethod::Put)?;
+        claims.verify_checksum(&data)?;
+        let (Some(upload_id), Some(part_number)) = (claims.upload_id, claims.part_number) else {
+            return Err(FileError::Presigning(PresignError::InvalidToken));
+        };
+        self.upload_part(claims.bucket, &claims.key, &upload_id, part_number, data)
+            .await
+    }
+
+    async fn get_object(&self, bucket: Bucket, name: &str) -> Result<Object, FileError> {
+        let (file, size) = self.open_object(bucket, name).await?;
+        let data_key = self.data_key(name).await?;
+        match data_key {
+            Some(key) => Ok(Object {
+                size: envelope::plaintext_size(size),
+                body: envelope::open_stream(key, file, size)
+                    .await
+                    .map_err(map_io_err)?,
+            }),
+            None => Ok(Object {
+                size,
+                body: Box::pin(ReaderStream::new(file)),
+            }),
+        }
+    }
+
+    async fn get_object_range(
+        &self,
+        bucket: Bucket,
+        name: &str,
+        range: Range<u64>,
+    ) -> Result<Object, FileError> {
+        if self.keyring.is_some() {
+            // Sealed objects are opened chunk by chunk from their start
+            return Ok(slice_object(self.get_object(bucket, name).await?, range));
+        }
+        let (mut file, size) = self.open_object(bucket, name).await?;
+        let (start, end) = (range.start.min(size), range.end.min(size));
+        file.seek(SeekFrom::Start(start))
+            .await
+            .map_err(map_io_err)?;
+        let size = end.saturating_sub(start);
+        Ok(Object {
+            size,
+            body: Box::pin(ReaderStream::new(file.take(size))),
+        })
+    }
+
+    async fn head_object(&self, bucket: Bucket, name: &str) -> Result<ObjectMeta, FileError> {
+        let path = self.object_path(bucket, name)?;
+        let metadata = match fs::metadata(path).await {
+            Ok(metadata) if metadata.is_file() => metadata,
+            Ok(_) => return Err(FileError::NotFound(name.to_owned())),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                return Err(FileError::NotFound(name.to_owned()))
+            }
+            Err(e) => return Err(map_io_err(e)),
+        };
+        let modified = metadata.modified().ok();
+        // Files are rewritten as a whole, so their modification time and size change with them
+        let e_tag = modified
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map(|t| format!("\"{:x}-{:x}\"", t.as_nanos(), metadata.len()));
+        Ok(ObjectMeta {
+            size: self.content_size(metadata.len()),
+            last_modified: modified.map(OffsetDateTime::from),
+            e_tag,
+            checksum: None,
+        })
+    }
+
+    async fn get_signed(&self, token: &str) -> Result<Object, FileError> {
+        let claims = self.presigner.verify(token, PresignedMethod::Get)?;
+        self.get_object(claims.bucket, &claims.key).await
+    }
+}
@@ -1,22 +1,78 @@
 use async_trait::async_trait;
+use bytes::{Bytes, BytesMut};
+use futures::TryStreamExt;
 use parking_lot::Mutex;
-use std::{collections::HashMap, sync::Arc};
+use secrecy::SecretString;
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, BTreeSet, HashMap},
+    sync::Arc,
+};
 use time::{Duration, OffsetDateTime};
 
-use crate::stores::{
-    files::{
-        database::{DBFile, DBFileError, DBFileStore, FileLock, FileResult, LeaseID},
-        UploadLease, UploadLeaseError, UploadLeaseStore,
+use crate::{
+    config::GenbuConfig,
+    connectors::local::presign::{
+        e_tag_matches, part_e_tag, InvalidPart, PresignedMethod, Presigner,
+    },
+    stores::{
+        files::{
+            blobs::{Blob, BlobStore},
+            database::{DBFile, DBFileError, DBFileStore, FileLock, FileResult, LeaseID},
+            encryption::{KeyStore, WrappedKey},
+            filesystem::{self, moved_key, Filesystem, FilesystemError, Userfile},
+            quota::QuotaStore,
+            storage::{
+                self, chunk_count, Bucket, ChecksumAlgorithm, FileError, InvalidPartSize, Object,
+                ObjectMeta, ObjectStream, Part, PartChecksums, PresignError, SizeMismatch,
+            },
+            trash::{TrashEntry, TrashStore},
+            versions::{FileVersion, VersionStore},
+            FileStorage, UploadLease, UploadLeaseError, UploadLeaseStore,
+        },
+        groups::GroupStore,
+        users::{SResult, User, UserError, UserStore, UserUpdate},
+        DataStore, Reset, Setup, Uuid,
     },
-    users::{SResult, User, UserError, UserStore, UserUpdate},
-    DataStore, Reset, Setup, Uuid,
 };
 
-#[derive(Clone, Default)]
+#[derive(Clone)]
+struct MemObject {
+    data: Bytes,
+    last_modified: OffsetDateTime,
+    /// Checksum of the part checksums, like S3 reports it for uploads with checksums
+    checksum: Option<String>,
+}
+
+#[derive(Clone)]
+struct MemUpload {
+    bucket: Bucket,
+    key: String,
+    parts: BTreeMap<i32, Bytes>,
+    checksum_algorithm: Option<ChecksumAlgorithm>,
+}
+
+/// A store which keeps everything in memory. It implements every store trait, including the
+/// file storage, so the whole server can run without any external service.
+#[derive(Clone)]
 pub struct MemStore {
     users: Arc<Mutex<HashMap<Uuid, User>>>,
     upload: Arc<Mutex<HashMap<LeaseID, UploadLease>>>,
     db_files: Arc<Mutex<HashMap<LeaseID, DBFile>>>,
+    trash: Arc<Mutex<HashMap<Uuid, TrashEntry>>>,
+    versions: Arc<Mutex<HashMap<Uuid, FileVersion>>>,
+    blobs: Arc<Mutex<HashMap<Uuid, Blob>>>,
+    data_keys: Arc<Mutex<HashMap<Uuid, WrappedKey>>>,
+    /// Names of the groups by their id
+    groups: Arc<Mutex<HashMap<Uuid, String>>>,
+    /// Group ids of every user, who is a member of a group
+    group_members: Arc<Mutex<HashMap<Uuid, BTreeSet<Uuid>>>>,
+    user_quotas: Arc<Mutex<HashMap<Uuid, i64>>>,
+    group_quotas: Arc<Mutex<HashMap<Uuid, i64>>>,
+    usage: Arc<Mutex<HashMap<Uuid, i64>>>,
+    objects: Arc<Mutex<HashMap<(Bucket, String), MemObject>>>,
+    multipart_uploads: Arc<Mutex<HashMap<String, MemUpload>>>,
+    presigner: Presigner,
 }
 
 impl MemStore {
@@ -24,6 +80,67 @@ impl MemStore {
     pub fn new() -> Self {
         Self::default()
     }
+
+    fn start_upload(
+        &self,
+        bucket: Bucket,
+        name: &str,
+        checksum_algorithm: Option<ChecksumAlgorithm>,
+    ) -> String {
+        let upload_id = Uuid::new_v4().to_string();
+        self.multipart_uploads.lock().insert(
+            upload_id.clone(),
+            MemUpload {
+                bucket,
+                key: name.to_owned(),
+                parts: BTreeMap::new(),
+                checksum_algorithm,
+            },
+        );
+        upload_id
+    }
+
+    /// Adds a user to a group, which is added as well if it doesn't exist yet. Groups are
+    /// managed outside of genbu, so there is no store trait to add them.
+    pub fn add_group_member(&self, group_id: Uuid, name: &str, user_id: Uuid) {
+        self.groups.lock().insert(group_id, name.to_owned());
+        self.group_members
+            .lock()
+            .entry(user_id)
+            .or_default()
+            .insert(group_id);
+    }
+
+    fn group_ids(&self, user_id: Uuid) -> BTreeSet<Uuid> {
+        self.group_members
+            .lock()
+            .get(&user_id)
+            .cloned()
+            .unwrap_or_default()
+    }
+}
+
+impl Default for MemStore {
+    fn default() -> Self {
+        Self {
+            users: Arc::default(),
+            upload: Arc::default(),
+            db_files: Arc::default(),
+            trash: Arc::default(),
+            versions: Arc::default(),
+            blobs: Arc::default(),
+            data_keys: Arc::default(),
+            groups: Arc::default(),
+            group_members: Arc::default(),
+            user_quotas: Arc::default(),
+            group_quotas: Arc::default(),
+            usage: Arc::default(),
+            objects: Arc::default(),
+            multipart_uploads: Arc::default(),
+            // Presigned urls only have to be valid for the lifetime of this store
+            presigner: Presigner::new(SecretString::new(Uuid::new_v4().to_string())),
+        }
+    }
 }
 
 #[async_trait]
@@ -87,7 +204,20 @@ impl UserStore for MemStore {
         if let Some(update_avatar) = update.avatar {
             user.avatar = Some(update_avatar);
         }
-        Ok(self.users.lock().insert(user.id, user))
+        self.users.lock().insert(user.id, user.clone());
+        Ok(Some(user))
+    }
+}
+
+#[async_trait]
+impl GroupStore for MemStore {
+    async fn get_group_names(&self, user_id: Uuid) -> SResult<Vec<String>> {
+        let group_ids = self.group_ids(user_id);
+        let groups = self.groups.lock();
+        Ok(group_ids
+            .iter()
+            .filter_map(|id| groups.get(id).cloned())
+            .collect())
     }
 }
 
@@ -117,6 +247,15 @@ impl UploadLeaseStore for MemStore {
             .cloned()
             .collect())
     }
+    async fn get_expired(&self, expired_before: OffsetDateTime) -> UploadResult<Vec<UploadLease>> {
+        Ok(self
+            .upload
+            .lock()
+            .values()
+            .filter(|lease| !lease.completed && lease.expires_at < expired_before)
+            .cloned()
+            .collect())
+    }
 
     async fn mark_completed(&mut self, id: &LeaseID) -> UploadResult<Option<UploadLease>> {
         let mut upload = self.upload.lock();
@@ -128,6 +267,33 @@ impl UploadLeaseStore for MemStore {
         upload.insert(*id, lease.clone());
         Ok(Some(lease))
     }
+
+    async fn start_patch(
+        &mut self,
+        id: &LeaseID,
+        until: OffsetDateTime,
+    ) -> UploadResult<Option<UploadLease>> {
+        let mut upload = self.upload.lock();
+        let Some(lease) = upload.get_mut(id) else {
+            return Ok(None);
+        };
+        let now = OffsetDateTime::now_utc();
+        if lease.completed || lease.patched_until.is_some_and(|t| t > now) {
+            return Ok(None);
+        }
+        lease.patched_until = Some(until);
+        Ok(Some(lease.clone()))
+    }
+
+    async fn end_patch(&mut self, id: &LeaseID, segments: Option<&[i64]>) -> UploadResult<()> {
+        if let Some(lease) = self.upload.lock().get_mut(id) {
+            lease.patched_until = None;
+            if let Some(segments) = segments {
+                lease.segments = segments.to_vec();
+            }
+        }
+        Ok(())
+    }
 }
 
 #[async_trait]
@@ -140,10 +306,36 @@ impl DBFileStore for MemStore {
                 .map(Clone::clone),
         )
     }
+    async fn get_dbfile_by_path(&self, bucket: Bucket, path: &str) -> FileResult<Option<DBFile>> {
+        Ok(self
+            .db_files
+            .lock()
+            .values()
+            .find(|file| file.bucket == bucket && file.path == path)
+            .cloned())
+    }
     async fn add_dbfile(&mut self, file: &DBFile) -> FileResult<DBFile> {
         self.db_files.lock().insert(file.id, file.clone());
         FileResult::Ok(file.clone())
     }
+    async fn register_dbfile(&mut self, file: &DBFile) -> FileResult<DBFile> {
+        let mut db_files = self.db_files.lock();
+        let existing = db_files
+            .values_mut()
+            .find(|f| f.bucket == file.bucket && f.path == file.path);
+        if let Some(existing) = existing {
+            if existing.is_folder || file.is_folder {
+                return Err(DBFileError::Conflict(file.path.clone()));
+            }
+            existing.size = file.size;
+            existing.mime_type = file.mime_type.clone();
+            existing.checksum = file.checksum.clone();
+            existing.blob_id = file.blob_id;
+            return Ok(existing.clone());
+        }
+        db_files.insert(file.id, file.clone());
+        Ok(file.clone())
+    }
     async fn lock(&mut self, file_id: Uuid, lock: FileLock) -> FileResult<Option<()>> {
         let mut db_files = self.db_files.lock();
         let Some(entr) = db_files.get_mut(&LeaseID(file_id)) else {
@@ -168,16 +360,664 @@ impl DBFileStore for MemStore {
         Ok(Some(()))
     }
     async fn unlock(&mut self, file_id: Uuid, lock: FileLock) -> FileResult<Option<()>> {
-        todo!()
+        let mut db_files = self.db_files.lock();
+        let Some(entr) = db_files.get_mut(&LeaseID(file_id)) else {
+            return Ok(None);
+        };
+        entr.unlock(lock)
+            .map_err(|l| DBFileError::Locked(Some(l.clone())))?;
+        Ok(Some(()))
     }
     async fn extend_lock(&mut self, file_id: Uuid, lock: FileLock) -> FileResult<Option<()>> {
-        todo!()
+        let mut db_files = self.db_files.lock();
+        let Some(entr) = db_files.get_mut(&LeaseID(file_id)) else {
+            return Ok(None);
+        };
+        entr.extend_lock(lock)
+            .map_err(|l| DBFileError::Locked(Some(l.clone())))?;
+        Ok(Some(()))
+    }
+    async fn relock(
+        &mut self,
+        file_id: Uuid,
+        old_lock: FileLock,
+        lock: FileLock,
+    ) -> FileResult<Option<()>> {
+        let mut db_files = self.db_files.lock();
+        let Some(entr) = db_files.get_mut(&LeaseID(file_id)) else {
+            return Ok(None);
+        };
+        entr.relock(&old_lock, lock)
+            .map_err(|l| DBFileError::Locked(l.cloned()))?;
+        Ok(Some(()))
+    }
+    async fn rename_dbfile(&mut self, file_id: Uuid, path: &str) -> FileResult<Option<DBFile>> {
+        let mut db_files = self.db_files.lock();
+        let Some(entr) = db_files.get_mut(&LeaseID(file_id)) else {
+            return Ok(None);
+        };
+        entr.path = path.to_owned();
+        Ok(Some(entr.clone()))
+    }
+    async fn set_dbfile_blob(
+        &mut self,
+        file_id: Uuid,
+        checksum: &str,
+        blob_id: Uuid,
+    ) -> FileResult<Option<DBFile>> {
+        let mut db_files = self.db_files.lock();
+        let Some(file) = db_files.get_mut(&LeaseID(file_id)) else {
+            return Ok(None);
+        };
+        if file.blob_id.is_some() || file.is_locked() || file.checksum.as_deref() != Some(checksum)
+        {
+            return Ok(None);
+        }
+        file.blob_id = Some(blob_id);
+        Ok(Some(file.clone()))
+    }
+    async fn get_dbfiles_below(&self, bucket: Bucket, path: &str) -> FileResult<Vec<DBFile>> {
+        let mut files: Vec<_> = self
+            .db_files
+            .lock()
+            .values()
+            .filter(|file| file.bucket == bucket && moved_key(&file.path, path, "").is_some())
+            .cloned()
+            .collect();
+        files.sort_by(|a, b| a.path.cmp(&b.path));
+        Ok(files)
+    }
+    async fn get_dbfiles_without_blob(
+        &self,
+        bucket: Bucket,
+        min_size: i64,
+    ) -> FileResult<Vec<DBFile>> {
+        Ok(self
+            .db_files
+            .lock()
+            .values()
+            .filter(|file| file.bucket == bucket && !file.is_folder)
+            .filter(|file| file.blob_id.is_none() && file.size >= min_size)
+            .cloned()
+            .collect())
+    }
+    async fn move_dbfiles(
+        &mut self,
+        bucket: Bucket,
+        from: &str,
+        to: &str,
+        parent_id: Option<LeaseID>,
+    ) -> FileResult<u64> {
+        let mut moved = 0;
+        for file in self.db_files.lock().values_mut() {
+            let Some(path) = moved_key(&file.path, from, to).filter(|_| file.bucket == bucket)
+            else {
+                continue;
+            };
+            if file.path == from {
+                file.parent_id = parent_id;
+            }
+            file.path = path;
+            moved += 1;
+        }
+        Ok(moved)
+    }
+    async fn delete_dbfile(&mut self, file_id: Uuid) -> FileResult<Option<DBFile>> {
+        Ok(self.db_files.lock().remove(&LeaseID(file_id)))
+    }
+    async fn delete_dbfiles(&mut self, bucket: Bucket, path: &str) -> FileResult<u64> {
+        let mut db_files = self.db_files.lock();
+        let count = db_files.len();
+        db_files
+            .retain(|_, file| file.bucket != bucket || moved_key(&file.path, path, "").is_none());
+        Ok((count - db_files.len()) as u64)
+    }
+}
+
+#[async_trait]
+impl TrashStore for MemStore {
+    async fn add_trash_entry(&mut self, entry: &TrashEntry) -> FileResult<TrashEntry> {
+        self.trash.lock().insert(entry.id, entry.clone());
+        Ok(entry.clone())
+    }
+    async fn get_trash_entry(&self, id: Uuid) -> FileResult<Option<TrashEntry>> {
+        Ok(self.trash.lock().get(&id).cloned())
+    }
+    async fn get_trash_by_owner(&self, owner: Uuid) -> FileResult<Vec<TrashEntry>> {
+        let mut entries: Vec<_> = self
+            .trash
+            .lock()
+            .values()
+            .filter(|entry| entry.owner == owner)
+            .cloned()
+            .collect();
+        entries.sort_by_key(|entry| Reverse(entry.deleted_at));
+        Ok(entries)
+    }
+    async fn get_expired_trash(
+        &self,
+        deleted_before: OffsetDateTime,
+    ) -> FileResult<Vec<TrashEntry>> {
+        Ok(self
+            .trash
+            .lock()
+            .values()
+            .filter(|entry| entry.deleted_at < deleted_before)
+            .cloned()
+            .collect())
+    }
+    async fn delete_trash_entry(&mut self, id: Uuid) -> FileResult<Option<TrashEntry>> {
+        Ok(self.trash.lock().remove(&id))
+    }
+}
+
+#[async_trait]
+impl VersionStore for MemStore {
+    async fn add_version(&mut self, version: &FileVersion) -> FileResult<FileVersion> {
+        self.versions.lock().insert(version.id, version.clone());
+        Ok(version.clone())
+    }
+    async fn get_version(&self, id: Uuid) -> FileResult<Option<FileVersion>> {
+        Ok(self.versions.lock().get(&id).cloned())
+    }
+    async fn get_versions(&self, file_id: LeaseID) -> FileResult<Vec<FileVersion>> {
+        let mut versions: Vec<_> = self
+            .versions
+            .lock()
+            .values()
+            .filter(|version| version.file_id == file_id)
+            .cloned()
+            .collect();
+        versions.sort_by_key(|version| Reverse(version.created_at));
+        Ok(versions)
+    }
+    async fn get_versions_before(
+        &self,
+        created_before: OffsetDateTime,
+    ) -> FileResult<Vec<FileVersion>> {
+        Ok(self
+            .versions
+            .lock()
+            .values()
+            .filter(|version| version.created_at < created_before)
+            .cloned()
+            .collect())
+    }
+    async fn delete_version(&mut self, id: Uuid) -> FileResult<Option<FileVersion>> {
+        Ok(self.versions.lock().remove(&id))
+    }
+}
+
+#[async_trait]
+impl BlobStore for MemStore {
+    async fn get_blob_by_hash(&self, bucket: Bucket, hash: &str) -> FileResult<Option<Blob>> {
+        Ok(self
+            .blobs
+            .lock()
+            .values()
+            .find(|blob| blob.bucket == bucket && blob.hash == hash)
+            .cloned())
+    }
+    async fn add_blob(&mut self, blob: &Blob) -> FileResult<Blob> {
+        let mut blobs = self.blobs.lock();
+        let existing = blobs
+            .values_mut()
+            .find(|b| b.bucket == blob.bucket && b.hash == blob.hash);
+        if let Some(existing) = existing {
+            existing.ref_count += blob.ref_count;
+            return Ok(existing.clone());
+        }
+        blobs.insert(blob.id, blob.clone());
+        Ok(blob.clone())
+    }
+    async fn reference_blob(&mut self, id: Uuid) -> FileResult<Option<Blob>> {
+        Ok(self.blobs.lock().get_mut(&id).map(|blob| {
+            blob.ref_count += 1;
+            blob.clone()
+        }))
+    }
+    async fn release_blob(&mut self, id: Uuid) -> FileResult<Option<Blob>> {
+        Ok(self.blobs.lock().get_mut(&id).map(|blob| {
+            blob.ref_count = (blob.ref_count - 1).max(0);
+            blob.clone()
+        }))
+    }
+    async fn get_unreferenced_blobs(&self) -> FileResult<Vec<Blob>> {
+        Ok(self
+            .blobs
+            .lock()
+            .values()
+            .filter(|blob| blob.ref_count == 0)
+            .cloned()
+            .collect())
+    }
+    async fn delete_blob(&mut self, id: Uuid) -> FileResult<Option<Blob>> {
+        let mut blobs = self.blobs.lock();
+        if blobs.get(&id).is_none_or(|blob| blob.ref_count > 0) {
+            return Ok(None);
+        }
+        Ok(blobs.remove(&id))
+    }
+}
+
+#[async_trait]
+impl KeyStore for MemStore {
+    async fn get_wrapped_key(&self, owner: Uuid) -> FileResult<Option<WrappedKey>> {
+        Ok(self.data_keys.lock().get(&owner).cloned())
+    }
+    async fn add_wrapped_key(&mut self, key: &WrappedKey) -> FileResult<WrappedKey> {
+        Ok(self
+            .data_keys
+            .lock()
+            .entry(key.owner)
+            .or_insert_with(|| key.clone())
+            .clone())
+    }
+    async fn get_keys_not_wrapped_by(&self, master_key_id: &str) -> FileResult<Vec<WrappedKey>> {
+        Ok(self
+            .data_keys
+            .lock()
+            .values()
+            .filter(|key| key.master_key_id != master_key_id)
+            .cloned()
+            .collect())
+    }
+    async fn update_wrapped_key(&mut self, key: &WrappedKey) -> FileResult<Option<WrappedKey>> {
+        Ok(self.data_keys.lock().get_mut(&key.owner).map(|stored| {
+            *stored = key.clone();
+            stored.clone()
+        }))
+    }
+}
+
+#[async_trait]
+impl QuotaStore for MemStore {
+    async fn get_quota(&self, user_id: Uuid) -> FileResult<Option<i64>> {
+        if let Some(quota) = self.user_quotas.lock().get(&user_id) {
+            return Ok(Some(*quota));
+        }
+        let group_ids = self.group_ids(user_id);
+        let quotas = self.group_quotas.lock();
+        Ok(group_ids
+            .iter()
+            .filter_map(|id| quotas.get(id))
+            .max()
+            .copied())
+    }
+    async fn set_user_quota(&mut self, user_id: Uuid, max_bytes: Option<i64>) -> FileResult<()> {
+        let mut quotas = self.user_quotas.lock();
+        match max_bytes {
+            Some(max_bytes) => quotas.insert(user_id, max_bytes),
+            None => quotas.remove(&user_id),
+        };
+        Ok(())
+    }
+    async fn set_group_quota(&mut self, group_id: Uuid, max_bytes: Option<i64>) -> FileResult<()> {
+        let mut quotas = self.group_quotas.lock();
+        match max_bytes {
+            Some(max_bytes) => quotas.insert(group_id, max_bytes),
+            None => quotas.remove(&group_id),
+        };
+        Ok(())
+    }
+    async fn get_usage(&self, user_id: Uuid) -> FileResult<i64> {
+        Ok(self.usage.lock().get(&user_id).copied().unwrap_or_default())
+    }
+    async fn add_usage(&mut self, user_id: Uuid, delta: i64) -> FileResult<i64> {
+        let mut usage = self.usage.lock();
+        let used = usage.entry(user_id).or_default();
+        *used += delta;
+        Ok(*used)
+    }
+}
+
+#[async_trait]
+impl FileStorage for MemStore {
+    async fn delete_file(&mut self, bucket: Bucket, name: &str) -> storage::Result<()> {
+        self.objects.lock().remove(&(bucket, name.to_owned()));
+        Ok(())
+    }
+
+    async fn get_download_url(&self, bucket: Bucket, name: &str) -> storage::Result<String> {
+        self.presigner.download_url(bucket, name)
+    }
+
+    async fn get_presigned_upload_urls(
+        &self,
+        bucket: Bucket,
+        name: &str,
+        size: u64,
+        chunk_size: u64,
+        checksums: Option<&PartChecksums>,
+    ) -> storage::Result<(Vec<String>, String)> {
+        let algorithm = checksums.map(|checksums| checksums.algorithm);
+        let upload_id = self.start_upload(bucket, name, algorithm);
+        let uris = (1..=chunk_count(size, chunk_size))
+            .map(|part_number| {
+                let part_number: i32 = part_number
+                    .try_into()
+                    .map_err(|_| FileError::Other(InvalidPartSize.into()))?;
+                self.presigner
+                    .upload_part_url(bucket, name, &upload_id, part_number, checksums)
+            })
+            .collect::<storage::Result<Vec<_>>>()?;
+        Ok((uris, upload_id))
+    }
+
+    async fn create_multipart_upload(&self, bucket: Bucket, name: &str) -> storage::Result<String> {
+        Ok(self.start_upload(bucket, name, None))
+    }
+
+    async fn upload_part(
+        &self,
+        bucket: Bucket,
+        name: &str,
+        upload_id: &str,
+        part_number: i32,
+        data: Bytes,
+    ) -> storage::Result<String> {
+        let mut uploads = self.multipart_uploads.lock();
+        match uploads.get_mut(upload_id) {
+            Some(upload) if upload.bucket == bucket && upload.key == name => {
+                let e_tag = part_e_tag(&data);
+                upload.parts.insert(part_number, data);
+                Ok(e_tag)
+            }
+            _ => Err(FileError::NotFound(upload_id.to_owned())),
+        }
+    }
+
+    async fn finish_multipart_upload(
+        &self,
+        bucket: Bucket,
+        name: &str,
+        upload_id: &str,
+        mut parts: Vec<Part>,
+        _checksums: Option<&PartChecksums>,
+    ) -> storage::Result<()> {
+        let mut uploads = self.multipart_uploads.lock();
+        let Some(upload) = uploads
+            .get(upload_id)
+            .filter(|upload| upload.bucket == bucket && upload.key == name)
+        else {
+            return Err(FileError::NotFound(upload_id.to_owned()));
+        };
+
+        parts.sort_by_key(|part| part.part_number);
+        let mut data = BytesMut::new();
+        let mut part_checksums = Vec::new();
+        for part in &parts {
+            match upload.parts.get(&part.part_number) {
+                Some(d) if e_tag_matches(&part_e_tag(d), &part.e_tag) => {
+                    data.extend_from_slice(d);
+                    if let Some(algorithm) = upload.checksum_algorithm {
+                        part_checksums.push(algorithm.encoded_digest(d));
+                    }
+                }
+                _ => return Err(FileError::Other(Box::new(InvalidPart(part.part_number)))),
+            }
+        }
+        // Like S3, the checksum is computed from the received parts instead of the declared ones
+        let checksum = upload.checksum_algorithm.and_then(|algorithm| {
+            PartChecksums {
+                algorithm,
+                parts: part_checksums,
+            }
+            .object_checksum()
+        });
+        uploads.remove(upload_id);
+
+        self.objects.lock().insert(
+            (bucket, name.to_owned()),
+            MemObject {
+                data: data.freeze(),
+                last_modified: OffsetDateTime::now_utc(),
+                checksum,
+            },
+        );
+        Ok(())
+    }
+
+    async fn abort_multipart_upload(
+        &self,
+        bucket: Bucket,
+        name: &str,
+        upload_id: &str,
+    ) -> storage::Result<()> {
+        let mut uploads = self.multipart_uploads.lock();
+        match uploads.get(upload_id) {
+            Some(upload) if upload.bucket == bucket && upload.key == name => {
+                uploads.remove(upload_id);
+                Ok(())
+            }
+            _ => Err(FileError::NotFound(upload_id.to_owned())),
+        }
+    }
+
+    async fn list_parts(
+        &self,
+        bucket: Bucket,
+        name: &str,
+        upload_id: &str,
+    ) -> storage::Result<Vec<Part>> {
+        match self.multipart_uploads.lock().get(upload_id) {
+            Some(upload) if upload.bucket == bucket && upload.key == name => Ok(upload
+                .parts
+                .iter()
+                .map(|(part_number, data)| Part {
+                    e_tag: part_e_tag(data),
+                    part_number: *part_number,
+                })
+                .collect()),
+            _ => Err(FileError::NotFound(upload_id.to_owned())),
+        }
+    }
+
+    async fn get_upload_part_urls(
+        &self,
+        bucket: Bucket,
+        name: &str,
+        upload_id: &str,
+        part_numbers: &[i32],
+        checksums: Option<&PartChecksums>,
+    ) -> storage::Result<Vec<String>> {
+        match self.multipart_uploads.lock().get(upload_id) {
+            Some(upload) if upload.bucket == bucket && upload.key == name => {}
+            _ => return Err(FileError::NotFound(upload_id.to_owned())),
+        }
+        part_numbers
+            .iter()
+            .map(|part_number| {
+                self.presigner
+                    .upload_part_url(bucket, name, upload_id, *part_number, checksums)
+            })
+            .collect()
+    }
+
+    async fn upload(&mut self, bucket: Bucket, name: &str, data: Vec<u8>) -> storage::Result<()> {
+        self.objects.lock().insert(
+            (bucket, name.to_owned()),
+            MemObject {
+                data: data.into(),
+                last_modified: OffsetDateTime::now_utc(),
+                checksum: None,
+            },
+        );
+        Ok(())
+    }
+
+    async fn upload_stream(
+        &mut self,
+        bucket: Bucket,
+        name: &str,
+        mut data: ObjectStream,
+        size: u64,
+    ) -> storage::Result<()> {
+        let mut content = BytesMut::new();
+        while let Some(chunk) = data
+            .try_next()
+            .await
+            .map_err(|e| FileError::Other(Box::new(e)))?
+        {
+            content.extend_from_slice(&chunk);
+        }
+        if content.len() as u64 != size {
+            return Err(FileError::Other(Box::new(SizeMismatch(
+                content.len() as u64,
+                size,
+            ))));
+        }
+        self.upload(bucket, name, content.to_vec()).await
+    }
+
+    async fn copy_object(&mut self, bucket: Bucket, from: &str, to: &str) -> storage::Result<()> {
+        let mut objects = self.objects.lock();
+        let Some(object) = objects.get(&(bucket, from.to_owned())) else {
+            return Err(FileError::NotFound(from.to_owned()));
+        };
+        let object = MemObject {
+            data: object.data.clone(),
+            last_modified: OffsetDateTime::now_utc(),
+            checksum: object.checksum.clone(),
+        };
+        objects.insert((bucket, to.to_owned()), object);
+        Ok(())
+    }
+
+    async fn put_signed(&self, token: &str, data: Bytes) -> storage::Result<String> {
+        let claims = self.presigner.verify(token, PresignedMethod::Put)?;
+        claims.verify_checksum(&data)?;
+        let (Some(upload_id), Some(part_number)) = (claims.upload_id, claims.part_number) else {
+            return Err(FileError::Presigning(PresignError::InvalidToken));
+        };
+        self.upload_part(claims.bucket, &claims.key, &upload_id, part_number, data)
+            .await
+    }
+
+    async fn get_object(&self, bucket: Bucket, name: &str) -> storage::Result<Object> {
+        let Some(object) = self.objects.lock().get(&(bucket, name.to_owned())).cloned() else {
+            return Err(FileError::NotFound(name.to_owned()));
+        };
+        Ok(Object {
+            size: object.data.len() as u64,
+            body: Box::pin(futures::stream::iter([Ok(object.data)])),
+        })
+    }
+
+    async fn head_object(&self, bucket: Bucket, name: &str) -> storage::Result<ObjectMeta> {
+        let objects = self.objects.lock();
+        let Some(object) = objects.get(&(bucket, name.to_owned())) else {
+            return Err(FileError::NotFound(name.to_owned()));
+        };
+        Ok(ObjectMeta {
+            size: object.data.len() as u64,
+            last_modified: Some(object.last_modified),
+            e_tag: Some(part_e_tag(&object.data)),
+            checksum: object.checksum.clone(),
+        })
+    }
+
+    async fn get_signed(&self, token: &str) -> storage::Result<Object> {
+        let claims = self.presigner.verify(token, PresignedMethod::Get)?;
+        self.get_object(claims.bucket, &claims.key).await
+    }
+}
+
+#[async_trait]
+impl Filesystem for MemStore {
+    async fn list(&self, user_id: Uuid, base_path: &str) -> filesystem::SResult<Vec<Userfile>> {
+        let mut files = Vec::new();
+        let mut folders = BTreeSet::new();
+        for ((bucket, key), object) in self.objects.lock().iter() {
+            let Some(rest) = key
+                .strip_prefix(base_path)
+                .filter(|_| *bucket == Bucket::UserFiles)
+            else {
+                continue;
+            };
+            // Mirrors an S3 listing with a `\` delimiter, which skips the marker of the folder
+            if rest.is_empty() {
+                continue;
+            }
+            if let Some(i) = rest.find('\\') {
+                folders.insert(format!("{base_path}{}", &rest[..=i]));
+                continue;
+            }
+            files.push(Userfile {
+                name: key.clone(),
+                last_modified: Some(object.last_modified),
+                owner: user_id,
+                size: object.data.len().try_into().ok(),
+                is_folder: false,
+            });
+        }
+        files.extend(folders.into_iter().map(|name| Userfile {
+            name,
+            last_modified: None,
+            owner: user_id,
+            size: None,
+            is_folder: true,
+        }));
+        Ok(files)
+    }
+
+    async fn delete(&mut self, path: &str) -> filesystem::SResult<()> {
+        self.objects.lock().retain(|(bucket, key), _| {
+            *bucket != Bucket::UserFiles || moved_key(key, path, "").is_none()
+        });
+        Ok(())
+    }
+
+    async fn mkdir(&mut self, path: &str) -> filesystem::SResult<()> {
+        self.objects.lock().insert(
+            (Bucket::UserFiles, format!("{path}\\")),
+            MemObject {
+                data: Bytes::new(),
+                last_modified: OffsetDateTime::now_utc(),
+                checksum: None,
+            },
+        );
+        Ok(())
+    }
+
+    async fn exists(&self, path: &str) -> filesystem::SResult<bool> {
+        Ok(self.objects.lock().keys().any(|(bucket, key)| {
+            *bucket == Bucket::UserFiles && moved_key(key, path, "").is_some()
+        }))
+    }
+
+    async fn copy(&mut self, from: &str, to: &str) -> filesystem::SResult<()> {
+        if self.exists(to).await? {
+            return Err(FilesystemError::FileAlreadyExists(to.to_owned()));
+        }
+        let mut objects = self.objects.lock();
+        let copies: Vec<_> = objects
+            .iter()
+            .filter(|((bucket, _), _)| *bucket == Bucket::UserFiles)
+            .filter_map(|((bucket, key), object)| {
+                let target = moved_key(key, from, to)?;
+                Some(((*bucket, target), object.clone()))
+            })
+            .collect();
+        if copies.is_empty() {
+            return Err(FilesystemError::NotFound(from.to_owned()));
+        }
+        objects.extend(copies);
+        Ok(())
+    }
+
+    async fn rename(&mut self, from: &str, to: &str) -> filesystem::SResult<()> {
+        self.copy(from, to).await?;
+        self.objects.lock().retain(|(bucket, key), _| {
+            *bucket != Bucket::UserFiles || moved_key(key, from, to).is_none()
+        });
+        Ok(())
     }
 }
 
 #[async_trait]
 impl DataStore for MemStore {
-    async fn new(_: String) -> Result<Self, Box<dyn std::error::Error>> {
+    async fn new(_: &GenbuConfig) -> Result<Self, Box<dyn std::error::Error>> {
         Ok(Self::new())
     }
 }
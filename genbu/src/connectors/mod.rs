@@ -1,3 +1,19 @@
+pub mod discovery;
+pub mod local;
 pub mod memory;
 pub mod postgres;
 pub mod s3;
+
+/// Percent-encodes everything except unreserved characters, so a value can be used as part of a
+/// url.
+pub(crate) fn urlencode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                char::from(b).to_string()
+            }
+            _ => format!("%{b:02X}"),
+        })
+        .collect()
+}
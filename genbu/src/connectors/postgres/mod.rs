@@ -1,12 +1,17 @@
 use std::ops::Deref;
 
 use async_trait::async_trait;
+use secrecy::ExposeSecret;
 use sqlx::{migrate::MigrateDatabase, postgres::PgPoolOptions, PgPool};
 use tracing::instrument;
 
-use crate::stores::{
-    users::{SResult, User, UserAvatar, UserError, UserStore, UserUpdate},
-    DataStore, Reset, Setup, Uuid,
+use crate::{
+    config::GenbuConfig,
+    stores::{
+        groups::GroupStore,
+        users::{SResult, User, UserAvatar, UserError, UserStore, UserUpdate},
+        DataStore, Reset, Setup, Uuid,
+    },
 };
 
 #[derive(Clone, Debug)]
@@ -126,9 +131,29 @@ impl UserStore for PgStore {
     }
 }
 
+#[async_trait]
+impl GroupStore for PgStore {
+    #[instrument]
+    async fn get_group_names(&self, user_id: Uuid) -> SResult<Vec<String>> {
+        let res = sqlx::query!(
+            r#"
+                SELECT g.name
+                FROM "group" g
+                JOIN user_group ug ON ug.group_id = g.group_id
+                WHERE ug.user_id = $1
+            "#,
+            user_id
+        )
+        .fetch_all(&self.conn)
+        .await?;
+        Ok(res.into_iter().map(|r| r.name).collect())
+    }
+}
+
 #[async_trait]
 impl DataStore for PgStore {
-    async fn new(conn: String) -> Result<Self, Box<dyn std::error::Error>> {
+    async fn new(config: &GenbuConfig) -> Result<Self, Box<dyn std::error::Error>> {
+        let conn = config.database.url.expose_secret().clone();
         let pool = PgPoolOptions::new().connect_lazy(&conn)?;
         Ok(Self {
             conn: pool,
@@ -1,12 +1,17 @@
-use time::{Duration, OffsetDateTime};
+use time::OffsetDateTime;
 
 use crate::{
     connectors::postgres::PgStore,
     stores::{
         files::{
+            blobs::{Blob, BlobStore},
             database::{DBFile, DBFileError, FileLock, FileResult, SResult},
             database::{DBFileStore, LeaseID},
-            storage::Bucket,
+            encryption::{KeyStore, WrappedKey},
+            quota::QuotaStore,
+            storage::{Bucket, ChecksumAlgorithm},
+            trash::{TrashEntry, TrashStore},
+            versions::{FileVersion, VersionStore},
             UploadLease, UploadLeaseError, UploadLeaseStore,
         },
         Uuid,
@@ -33,16 +38,19 @@ impl UploadLeaseStore for PgStore {
     async fn add(&mut self, lease: &UploadLease) -> SResult<UploadLease> {
         let res = sqlx::query_as!(
             UploadLease,
-            r#"insert into upload_lease (id, owner, name, s3_upload_id, bucket, size, expires_at)
-                values ($1, $2, $3, $4, $5, $6, $7)
-                returning id as "id: LeaseID",owner,s3_upload_id,name,bucket as "bucket: Bucket",completed,size,created_at,expires_at"#,
+            r#"insert into upload_lease (id, owner, name, s3_upload_id, bucket, size, expires_at, chunk_size, checksum_algorithm, part_checksums)
+                values ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)
+                returning id as "id: LeaseID",owner,s3_upload_id,name,bucket as "bucket: Bucket",completed,size,created_at,expires_at,chunk_size,checksum_algorithm as "checksum_algorithm: ChecksumAlgorithm",part_checksums,segments,patched_until"#,
             lease.id as _,
             lease.owner,
             lease.name,
             lease.s3_upload_id,
             lease.bucket as _,
             lease.size,
-            lease.expires_at
+            lease.expires_at,
+            lease.chunk_size,
+            lease.checksum_algorithm as _,
+            &lease.part_checksums
         ).fetch_one(&self.conn).await?;
         Ok(res)
     }
@@ -51,7 +59,7 @@ impl UploadLeaseStore for PgStore {
         let res = sqlx::query_as!(UploadLease,
         r#"delete from "upload_lease"
             where id = $1
-            returning id as "id: LeaseID",owner,s3_upload_id,name,bucket as "bucket: Bucket",completed,size,created_at,expires_at"#,
+            returning id as "id: LeaseID",owner,s3_upload_id,name,bucket as "bucket: Bucket",completed,size,created_at,expires_at,chunk_size,checksum_algorithm as "checksum_algorithm: ChecksumAlgorithm",part_checksums,segments,patched_until"#,
             id as _
         ).fetch_optional(&self.conn).await?;
         Ok(res)
@@ -60,7 +68,7 @@ impl UploadLeaseStore for PgStore {
     async fn get(&self, id: &LeaseID) -> SResult<Option<UploadLease>> {
         let res = sqlx::query_as!(
             UploadLease,
-            r#"select id as "id: LeaseID",owner,s3_upload_id,name,bucket as "bucket: Bucket",completed,size,created_at,expires_at
+            r#"select id as "id: LeaseID",owner,s3_upload_id,name,bucket as "bucket: Bucket",completed,size,created_at,expires_at,chunk_size,checksum_algorithm as "checksum_algorithm: ChecksumAlgorithm",part_checksums,segments,patched_until
                 from "upload_lease"
                 where id = $1"#,
             id as _
@@ -73,7 +81,7 @@ impl UploadLeaseStore for PgStore {
     async fn get_by_user(&self, id: &Uuid) -> SResult<Vec<UploadLease>> {
         let res = sqlx::query_as!(
             UploadLease,
-            r#"select id as "id: LeaseID",owner,s3_upload_id,name,bucket as "bucket: Bucket",completed,size,created_at,expires_at
+            r#"select id as "id: LeaseID",owner,s3_upload_id,name,bucket as "bucket: Bucket",completed,size,created_at,expires_at,chunk_size,checksum_algorithm as "checksum_algorithm: ChecksumAlgorithm",part_checksums,segments,patched_until
                 from "upload_lease"
                 where owner = $1"#,
             id
@@ -83,6 +91,19 @@ impl UploadLeaseStore for PgStore {
         Ok(res)
     }
 
+    async fn get_expired(&self, expired_before: OffsetDateTime) -> SResult<Vec<UploadLease>> {
+        let res = sqlx::query_as!(
+            UploadLease,
+            r#"select id as "id: LeaseID",owner,s3_upload_id,name,bucket as "bucket: Bucket",completed,size,created_at,expires_at,chunk_size,checksum_algorithm as "checksum_algorithm: ChecksumAlgorithm",part_checksums,segments,patched_until
+                from "upload_lease"
+                where not completed and expires_at < $1"#,
+            expired_before
+        )
+        .fetch_all(&self.conn)
+        .await?;
+        Ok(res)
+    }
+
     async fn mark_completed(&mut self, id: &LeaseID) -> SResult<Option<UploadLease>> {
         let lease = self.get(id).await?;
         let Some(lease) = lease else {
@@ -97,12 +118,45 @@ impl UploadLeaseStore for PgStore {
             r#"update "upload_lease"
                 set completed = true
                 where id = $1
-                returning id as "id: LeaseID",owner,s3_upload_id,name,bucket as "bucket: Bucket",completed,size,created_at,expires_at
+                returning id as "id: LeaseID",owner,s3_upload_id,name,bucket as "bucket: Bucket",completed,size,created_at,expires_at,chunk_size,checksum_algorithm as "checksum_algorithm: ChecksumAlgorithm",part_checksums,segments,patched_until
             "#,
             id as _
         ).fetch_optional(&self.conn).await?;
         Ok(res)
     }
+
+    async fn start_patch(
+        &mut self,
+        id: &LeaseID,
+        until: OffsetDateTime,
+    ) -> SResult<Option<UploadLease>> {
+        // Only one request takes the lease, as the row is locked by the update
+        let res = sqlx::query_as!(
+            UploadLease,
+            r#"update "upload_lease"
+                set patched_until = $2
+                where id = $1 and not completed and (patched_until is null or patched_until < now())
+                returning id as "id: LeaseID",owner,s3_upload_id,name,bucket as "bucket: Bucket",completed,size,created_at,expires_at,chunk_size,checksum_algorithm as "checksum_algorithm: ChecksumAlgorithm",part_checksums,segments,patched_until
+            "#,
+            id as _,
+            until
+        ).fetch_optional(&self.conn).await?;
+        Ok(res)
+    }
+
+    async fn end_patch(&mut self, id: &LeaseID, segments: Option<&[i64]>) -> SResult<()> {
+        sqlx::query!(
+            r#"update "upload_lease"
+                set patched_until = null, segments = coalesce($2, segments)
+                where id = $1
+            "#,
+            id as _,
+            segments
+        )
+        .execute(&self.conn)
+        .await?;
+        Ok(())
+    }
 }
 
 impl From<sqlx::Error> for DBFileError {
@@ -125,19 +179,54 @@ impl DBFileStore for PgStore {
         let res = sqlx::query_as!(
             DBFile,
             r#"
-                insert into file (id, path, created_by)
-                values ($1, $2, $3)
-                returning id as "id: LeaseID",path,lock as "lock: FileLock",lock_expires_at,created_by,created_at
+                insert into file (id, path, bucket, size, mime_type, checksum, is_folder, parent_id, created_by, blob_id)
+                values ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)
+                returning id as "id: LeaseID",path,bucket as "bucket: Bucket",size,mime_type,checksum,is_folder,parent_id as "parent_id: LeaseID",lock as "lock: FileLock",lock_expires_at,created_by,created_at,blob_id
             "#,
             file.id as _,
             file.path,
-            file.created_by
+            file.bucket as _,
+            file.size,
+            file.mime_type,
+            file.checksum,
+            file.is_folder,
+            file.parent_id as _,
+            file.created_by,
+            file.blob_id
         )
         .fetch_one(&self.conn)
         .await?;
         Ok(res)
     }
 
+    async fn register_dbfile(&mut self, file: &DBFile) -> FileResult<DBFile> {
+        let res = sqlx::query_as!(
+            DBFile,
+            r#"
+                insert into file (id, path, bucket, size, mime_type, checksum, is_folder, parent_id, created_by, blob_id)
+                values ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)
+                on conflict (bucket, path) do update
+                set size = excluded.size, mime_type = excluded.mime_type, checksum = excluded.checksum, blob_id = excluded.blob_id
+                where file.is_folder = false and excluded.is_folder = false
+                returning id as "id: LeaseID",path,bucket as "bucket: Bucket",size,mime_type,checksum,is_folder,parent_id as "parent_id: LeaseID",lock as "lock: FileLock",lock_expires_at,created_by,created_at,blob_id
+            "#,
+            file.id as _,
+            file.path,
+            file.bucket as _,
+            file.size,
+            file.mime_type,
+            file.checksum,
+            file.is_folder,
+            file.parent_id as _,
+            file.created_by,
+            file.blob_id
+        )
+        .fetch_optional(&self.conn)
+        .await?;
+        // Nothing is returned if the path is taken by an entry which can't be updated
+        res.ok_or_else(|| DBFileError::Conflict(file.path.clone()))
+    }
+
     async fn unlock(&mut self, file_id: Uuid, lock: FileLock) -> FileResult<Option<()>> {
         // Begin transaction
         let conn = self.conn.begin().await?;
@@ -214,11 +303,9 @@ impl DBFileStore for PgStore {
             Some(f) => f,
             None => return Ok(None),
         };
-        if file.is_locked() {
-            file.extend_lock(lock)
-                .map_err(|l| DBFileError::Locked(Some(l.clone())))?;
-            return Ok(Some(()));
-        }
+        // Locking a file again with its current lock refreshes the lock
+        file.lock(lock)
+            .map_err(|l| DBFileError::Locked(Some(l.clone())))?;
 
         sqlx::query_scalar!(
             r#"
@@ -227,8 +314,8 @@ impl DBFileStore for PgStore {
                 where id = $3
                 returning id as "id: LeaseID"
             "#,
-            &lock as _,
-            OffsetDateTime::now_utc() + Duration::minutes(30),
+            &file.lock as _,
+            file.lock_expires_at,
             file_id
         )
         .fetch_optional(&self.conn)
@@ -239,12 +326,624 @@ impl DBFileStore for PgStore {
         Ok(Some(()))
     }
 
+    async fn relock(
+        &mut self,
+        file_id: Uuid,
+        old_lock: FileLock,
+        lock: FileLock,
+    ) -> FileResult<Option<()>> {
+        let mut file = match self.get_dbfile(file_id).await? {
+            Some(f) => f,
+            None => return Ok(None),
+        };
+        file.relock(&old_lock, lock)
+            .map_err(|l| DBFileError::Locked(l.cloned()))?;
+
+        // Only replace the lock if it wasn't changed since it was checked
+        let res = sqlx::query_scalar!(
+            r#"
+                update file
+                set lock = $1, lock_expires_at = $2
+                where id = $3 and lock = $4
+                returning id as "id: LeaseID"
+            "#,
+            &file.lock as _,
+            file.lock_expires_at,
+            file_id,
+            &old_lock as _
+        )
+        .fetch_optional(&self.conn)
+        .await?;
+
+        match res {
+            Some(_) => Ok(Some(())),
+            None => Err(DBFileError::Locked(None)),
+        }
+    }
+
+    async fn rename_dbfile(&mut self, file_id: Uuid, path: &str) -> FileResult<Option<DBFile>> {
+        let res = sqlx::query_as!(
+            DBFile,
+            r#"
+                update file
+                set path = $1
+                where id = $2
+                returning id as "id: LeaseID",path,bucket as "bucket: Bucket",size,mime_type,checksum,is_folder,parent_id as "parent_id: LeaseID",lock as "lock: FileLock",lock_expires_at,created_by,created_at,blob_id
+            "#,
+            path,
+            file_id
+        )
+        .fetch_optional(&self.conn)
+        .await?;
+        Ok(res)
+    }
+
+    async fn set_dbfile_blob(
+        &mut self,
+        file_id: Uuid,
+        checksum: &str,
+        blob_id: Uuid,
+    ) -> FileResult<Option<DBFile>> {
+        let res = sqlx::query_as!(
+            DBFile,
+            r#"
+                update file
+                set blob_id = $1
+                where id = $2 and checksum = $3 and blob_id is null
+                    and (lock is null or lock_expires_at is null or lock_expires_at <= now())
+                returning id as "id: LeaseID",path,bucket as "bucket: Bucket",size,mime_type,checksum,is_folder,parent_id as "parent_id: LeaseID",lock as "lock: FileLock",lock_expires_at,created_by,created_at,blob_id
+            "#,
+            blob_id,
+            file_id,
+            checksum
+        )
+        .fetch_optional(&self.conn)
+        .await?;
+        Ok(res)
+    }
+
+    async fn get_dbfiles_below(&self, bucket: Bucket, path: &str) -> FileResult<Vec<DBFile>> {
+        let res = sqlx::query_as!(DBFile, r#"
+                select id as "id: LeaseID",path,bucket as "bucket: Bucket",size,mime_type,checksum,is_folder,parent_id as "parent_id: LeaseID",lock as "lock: FileLock",lock_expires_at,created_by,created_at,blob_id
+                from file
+                where bucket = $1 and (path = $2 or left(path, length($2) + 1) = $2 || '\')
+                order by path
+            "#, bucket as _, path).fetch_all(&self.conn).await?;
+        Ok(res)
+    }
+
+    async fn get_dbfiles_without_blob(
+        &self,
+        bucket: Bucket,
+        min_size: i64,
+    ) -> FileResult<Vec<DBFile>> {
+        let res = sqlx::query_as!(DBFile, r#"
+                select id as "id: LeaseID",path,bucket as "bucket: Bucket",size,mime_type,checksum,is_folder,parent_id as "parent_id: LeaseID",lock as "lock: FileLock",lock_expires_at,created_by,created_at,blob_id
+                from file
+                where bucket = $1 and not is_folder and blob_id is null and size >= $2
+            "#, bucket as _, min_size).fetch_all(&self.conn).await?;
+        Ok(res)
+    }
+
+    async fn move_dbfiles(
+        &mut self,
+        bucket: Bucket,
+        from: &str,
+        to: &str,
+        parent_id: Option<LeaseID>,
+    ) -> FileResult<u64> {
+        let res = sqlx::query!(
+            r#"
+                update file
+                set path = $3 || substr(path, length($2) + 1),
+                    parent_id = case when path = $2 then $4 else parent_id end
+                where bucket = $1 and (path = $2 or left(path, length($2) + 1) = $2 || '\')
+            "#,
+            bucket as _,
+            from,
+            to,
+            parent_id as _
+        )
+        .execute(&self.conn)
+        .await?;
+        Ok(res.rows_affected())
+    }
+
+    async fn delete_dbfile(&mut self, file_id: Uuid) -> FileResult<Option<DBFile>> {
+        let res = sqlx::query_as!(
+            DBFile,
+            r#"
+                delete from file
+                where id = $1
+                returning id as "id: LeaseID",path,bucket as "bucket: Bucket",size,mime_type,checksum,is_folder,parent_id as "parent_id: LeaseID",lock as "lock: FileLock",lock_expires_at,created_by,created_at,blob_id
+            "#,
+            file_id
+        )
+        .fetch_optional(&self.conn)
+        .await?;
+        Ok(res)
+    }
+
+    async fn delete_dbfiles(&mut self, bucket: Bucket, path: &str) -> FileResult<u64> {
+        let res = sqlx::query!(
+            r#"
+                delete from file
+                where bucket = $1 and (path = $2 or left(path, length($2) + 1) = $2 || '\')
+            "#,
+            bucket as _,
+            path
+        )
+        .execute(&self.conn)
+        .await?;
+        Ok(res.rows_affected())
+    }
+
     async fn get_dbfile(&self, file_id: Uuid) -> FileResult<Option<DBFile>> {
         let res = sqlx::query_as!(DBFile, r#"
-                select id as "id: LeaseID",path,lock as "lock: FileLock",lock_expires_at,created_by,created_at
+                select id as "id: LeaseID",path,bucket as "bucket: Bucket",size,mime_type,checksum,is_folder,parent_id as "parent_id: LeaseID",lock as "lock: FileLock",lock_expires_at,created_by,created_at,blob_id
                 from file
                 where id = $1
             "#, file_id).fetch_optional(&self.conn).await?;
         Ok(res)
     }
+
+    async fn get_dbfile_by_path(&self, bucket: Bucket, path: &str) -> FileResult<Option<DBFile>> {
+        let res = sqlx::query_as!(DBFile, r#"
+                select id as "id: LeaseID",path,bucket as "bucket: Bucket",size,mime_type,checksum,is_folder,parent_id as "parent_id: LeaseID",lock as "lock: FileLock",lock_expires_at,created_by,created_at,blob_id
+                from file
+                where bucket = $1 and path = $2
+            "#, bucket as _, path).fetch_optional(&self.conn).await?;
+        Ok(res)
+    }
+}
+
+#[async_trait::async_trait]
+impl TrashStore for PgStore {
+    async fn add_trash_entry(&mut self, entry: &TrashEntry) -> FileResult<TrashEntry> {
+        let res = sqlx::query_as!(
+            TrashEntry,
+            r#"
+                insert into trash (id, owner, original_path, trash_path, is_folder, deleted_by, deleted_at)
+                values ($1, $2, $3, $4, $5, $6, $7)
+                returning id,owner,original_path,trash_path,is_folder,deleted_by,deleted_at
+            "#,
+            entry.id,
+            entry.owner,
+            entry.original_path,
+            entry.trash_path,
+            entry.is_folder,
+            entry.deleted_by,
+            entry.deleted_at
+        )
+        .fetch_one(&self.conn)
+        .await?;
+        Ok(res)
+    }
+
+    async fn get_trash_entry(&self, id: Uuid) -> FileResult<Option<TrashEntry>> {
+        let res = sqlx::query_as!(
+            TrashEntry,
+            r#"
+                select id,owner,original_path,trash_path,is_folder,deleted_by,deleted_at
+                from trash
+                where id = $1
+            "#,
+            id
+        )
+        .fetch_optional(&self.conn)
+        .await?;
+        Ok(res)
+    }
+
+    async fn get_trash_by_owner(&self, owner: Uuid) -> FileResult<Vec<TrashEntry>> {
+        let res = sqlx::query_as!(
+            TrashEntry,
+            r#"
+                select id,owner,original_path,trash_path,is_folder,deleted_by,deleted_at
+                from trash
+                where owner = $1
+                order by deleted_at desc
+            "#,
+            owner
+        )
+        .fetch_all(&self.conn)
+        .await?;
+        Ok(res)
+    }
+
+    async fn get_expired_trash(
+        &self,
+        deleted_before: OffsetDateTime,
+    ) -> FileResult<Vec<TrashEntry>> {
+        let res = sqlx::query_as!(
+            TrashEntry,
+            r#"
+                select id,owner,original_path,trash_path,is_folder,deleted_by,deleted_at
+                from trash
+                where deleted_at < $1
+            "#,
+            deleted_before
+        )
+        .fetch_all(&self.conn)
+        .await?;
+        Ok(res)
+    }
+
+    async fn delete_trash_entry(&mut self, id: Uuid) -> FileResult<Option<TrashEntry>> {
+        let res = sqlx::query_as!(
+            TrashEntry,
+            r#"
+                delete from trash
+                where id = $1
+                returning id,owner,original_path,trash_path,is_folder,deleted_by,deleted_at
+            "#,
+            id
+        )
+        .fetch_optional(&self.conn)
+        .await?;
+        Ok(res)
+    }
+}
+
+#[async_trait::async_trait]
+impl VersionStore for PgStore {
+    async fn add_version(&mut self, version: &FileVersion) -> FileResult<FileVersion> {
+        let res = sqlx::query_as!(
+            FileVersion,
+            r#"
+                insert into file_version (id, file_id, bucket, path, size, checksum, modified_at, created_at)
+                values ($1, $2, $3, $4, $5, $6, $7, $8)
+                returning id,file_id as "file_id: LeaseID",bucket as "bucket: Bucket",path,size,checksum,modified_at,created_at
+            "#,
+            version.id,
+            version.file_id as _,
+            version.bucket as _,
+            version.path,
+            version.size,
+            version.checksum,
+            version.modified_at,
+            version.created_at
+        )
+        .fetch_one(&self.conn)
+        .await?;
+        Ok(res)
+    }
+
+    async fn get_version(&self, id: Uuid) -> FileResult<Option<FileVersion>> {
+        let res = sqlx::query_as!(
+            FileVersion,
+            r#"
+                select id,file_id as "file_id: LeaseID",bucket as "bucket: Bucket",path,size,checksum,modified_at,created_at
+                from file_version
+                where id = $1
+            "#,
+            id
+        )
+        .fetch_optional(&self.conn)
+        .await?;
+        Ok(res)
+    }
+
+    async fn get_versions(&self, file_id: LeaseID) -> FileResult<Vec<FileVersion>> {
+        let res = sqlx::query_as!(
+            FileVersion,
+            r#"
+                select id,file_id as "file_id: LeaseID",bucket as "bucket: Bucket",path,size,checksum,modified_at,created_at
+                from file_version
+                where file_id = $1
+                order by created_at desc
+            "#,
+            file_id as _
+        )
+        .fetch_all(&self.conn)
+        .await?;
+        Ok(res)
+    }
+
+    async fn get_versions_before(
+        &self,
+        created_before: OffsetDateTime,
+    ) -> FileResult<Vec<FileVersion>> {
+        let res = sqlx::query_as!(
+            FileVersion,
+            r#"
+                select id,file_id as "file_id: LeaseID",bucket as "bucket: Bucket",path,size,checksum,modified_at,created_at
+                from file_version
+                where created_at < $1
+            "#,
+            created_before
+        )
+        .fetch_all(&self.conn)
+        .await?;
+        Ok(res)
+    }
+
+    async fn delete_version(&mut self, id: Uuid) -> FileResult<Option<FileVersion>> {
+        let res = sqlx::query_as!(
+            FileVersion,
+            r#"
+                delete from file_version
+                where id = $1
+                returning id,file_id as "file_id: LeaseID",bucket as "bucket: Bucket",path,size,checksum,modified_at,created_at
+            "#,
+            id
+        )
+        .fetch_optional(&self.conn)
+        .await?;
+        Ok(res)
+    }
+}
+
+#[async_trait::async_trait]
+impl BlobStore for PgStore {
+    async fn get_blob_by_hash(&self, bucket: Bucket, hash: &str) -> FileResult<Option<Blob>> {
+        let res = sqlx::query_as!(
+            Blob,
+            r#"
+                select id,bucket as "bucket: Bucket",hash,size,ref_count,created_at
+                from blob
+                where bucket = $1 and hash = $2
+            "#,
+            bucket as _,
+            hash
+        )
+        .fetch_optional(&self.conn)
+        .await?;
+        Ok(res)
+    }
+
+    async fn add_blob(&mut self, blob: &Blob) -> FileResult<Blob> {
+        let res = sqlx::query_as!(
+            Blob,
+            r#"
+                insert into blob (id, bucket, hash, size, ref_count, created_at)
+                values ($1, $2, $3, $4, $5, $6)
+                on conflict (bucket, hash) do update
+                set ref_count = blob.ref_count + excluded.ref_count
+                returning id,bucket as "bucket: Bucket",hash,size,ref_count,created_at
+            "#,
+            blob.id,
+            blob.bucket as _,
+            blob.hash,
+            blob.size,
+            blob.ref_count,
+            blob.created_at
+        )
+        .fetch_one(&self.conn)
+        .await?;
+        Ok(res)
+    }
+
+    async fn reference_blob(&mut self, id: Uuid) -> FileResult<Option<Blob>> {
+        let res = sqlx::query_as!(
+            Blob,
+            r#"
+                update blob
+                set ref_count = ref_count + 1
+                where id = $1
+                returning id,bucket as "bucket: Bucket",hash,size,ref_count,created_at
+            "#,
+            id
+        )
+        .fetch_optional(&self.conn)
+        .await?;
+        Ok(res)
+    }
+
+    async fn release_blob(&mut self, id: Uuid) -> FileResult<Option<Blob>> {
+        let res = sqlx::query_as!(
+            Blob,
+            r#"
+                update blob
+                set ref_count = greatest(ref_count - 1, 0)
+                where id = $1
+                returning id,bucket as "bucket: Bucket",hash,size,ref_count,created_at
+            "#,
+            id
+        )
+        .fetch_optional(&self.conn)
+        .await?;
+        Ok(res)
+    }
+
+    async fn get_unreferenced_blobs(&self) -> FileResult<Vec<Blob>> {
+        let res = sqlx::query_as!(
+            Blob,
+            r#"
+                select id,bucket as "bucket: Bucket",hash,size,ref_count,created_at
+                from blob
+                where ref_count = 0
+            "#
+        )
+        .fetch_all(&self.conn)
+        .await?;
+        Ok(res)
+    }
+
+    async fn delete_blob(&mut self, id: Uuid) -> FileResult<Option<Blob>> {
+        let res = sqlx::query_as!(
+            Blob,
+            r#"
+                delete from blob
+                where id = $1 and ref_count = 0
+                returning id,bucket as "bucket: Bucket",hash,size,ref_count,created_at
+            "#,
+            id
+        )
+        .fetch_optional(&self.conn)
+        .await?;
+        Ok(res)
+    }
+}
+
+#[async_trait::async_trait]
+impl KeyStore for PgStore {
+    async fn get_wrapped_key(&self, owner: Uuid) -> FileResult<Option<WrappedKey>> {
+        let res = sqlx::query_as!(
+            WrappedKey,
+            r#"
+                select owner,master_key_id,wrapped_key,created_at,rotated_at
+                from data_key
+                where owner = $1
+            "#,
+            owner
+        )
+        .fetch_optional(&self.conn)
+        .await?;
+        Ok(res)
+    }
+
+    async fn add_wrapped_key(&mut self, key: &WrappedKey) -> FileResult<WrappedKey> {
+        // The no-op update returns the existing row on a conflict
+        let res = sqlx::query_as!(
+            WrappedKey,
+            r#"
+                insert into data_key (owner, master_key_id, wrapped_key, created_at, rotated_at)
+                values ($1, $2, $3, $4, $5)
+                on conflict (owner) do update
+                set owner = data_key.owner
+                returning owner,master_key_id,wrapped_key,created_at,rotated_at
+            "#,
+            key.owner,
+            key.master_key_id,
+            key.wrapped_key,
+            key.created_at,
+            key.rotated_at
+        )
+        .fetch_one(&self.conn)
+        .await?;
+        Ok(res)
+    }
+
+    async fn get_keys_not_wrapped_by(&self, master_key_id: &str) -> FileResult<Vec<WrappedKey>> {
+        let res = sqlx::query_as!(
+            WrappedKey,
+            r#"
+                select owner,master_key_id,wrapped_key,created_at,rotated_at
+                from data_key
+                where master_key_id <> $1
+            "#,
+            master_key_id
+        )
+        .fetch_all(&self.conn)
+        .await?;
+        Ok(res)
+    }
+
+    async fn update_wrapped_key(&mut self, key: &WrappedKey) -> FileResult<Option<WrappedKey>> {
+        let res = sqlx::query_as!(
+            WrappedKey,
+            r#"
+                update data_key
+                set master_key_id = $2, wrapped_key = $3, rotated_at = $4
+                where owner = $1
+                returning owner,master_key_id,wrapped_key,created_at,rotated_at
+            "#,
+            key.owner,
+            key.master_key_id,
+            key.wrapped_key,
+            key.rotated_at
+        )
+        .fetch_optional(&self.conn)
+        .await?;
+        Ok(res)
+    }
+}
+
+#[async_trait::async_trait]
+impl QuotaStore for PgStore {
+    async fn get_quota(&self, user_id: Uuid) -> FileResult<Option<i64>> {
+        let res = sqlx::query!(
+            r#"
+                select coalesce(
+                    (select max_bytes from user_quota where user_id = $1),
+                    (
+                        select max(q.max_bytes)
+                        from group_quota q
+                        join user_group g on g.group_id = q.group_id
+                        where g.user_id = $1
+                    )
+                ) as max_bytes
+            "#,
+            user_id
+        )
+        .fetch_one(&self.conn)
+        .await?;
+        Ok(res.max_bytes)
+    }
+
+    async fn set_user_quota(&mut self, user_id: Uuid, max_bytes: Option<i64>) -> FileResult<()> {
+        match max_bytes {
+            Some(max_bytes) => {
+                sqlx::query!(
+                    r#"
+                        insert into user_quota (user_id, max_bytes)
+                        values ($1, $2)
+                        on conflict (user_id) do update set max_bytes = excluded.max_bytes
+                    "#,
+                    user_id,
+                    max_bytes
+                )
+                .execute(&self.conn)
+                .await?;
+            }
+            None => {
+                sqlx::query!("delete from user_quota where user_id = $1", user_id)
+                    .execute(&self.conn)
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn set_group_quota(&mut self, group_id: Uuid, max_bytes: Option<i64>) -> FileResult<()> {
+        match max_bytes {
+            Some(max_bytes) => {
+                sqlx::query!(
+                    r#"
+                        insert into group_quota (group_id, max_bytes)
+                        values ($1, $2)
+                        on conflict (group_id) do update set max_bytes = excluded.max_bytes
+                    "#,
+                    group_id,
+                    max_bytes
+                )
+                .execute(&self.conn)
+                .await?;
+            }
+            None => {
+                sqlx::query!("delete from group_quota where group_id = $1", group_id)
+                    .execute(&self.conn)
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn get_usage(&self, user_id: Uuid) -> FileResult<i64> {
+        let res = sqlx::query!(
+            "select used_bytes from storage_usage where user_id = $1",
+            user_id
+        )
+        .fetch_optional(&self.conn)
+        .await?;
+        Ok(res.map_or(0, |r| r.used_bytes))
+    }
+
+    async fn add_usage(&mut self, user_id: Uuid, delta: i64) -> FileResult<i64> {
+        let res = sqlx::query!(
+            r#"
+                insert into storage_usage (user_id, used_bytes)
+                values ($1, $2)
+                on conflict (user_id) do update
+                set used_bytes = storage_usage.used_bytes + excluded.used_bytes
+                returning used_bytes
+            "#,
+            user_id,
+            delta
+        )
+        .fetch_one(&self.conn)
+        .await?;
+        Ok(res.used_bytes)
+    }
 }
@@ -1,18 +1,29 @@
 use std::{error::Error, fmt::Debug};
 
-use aws_sdk_s3::types::SdkError;
+use aws_sdk_s3::{
+    model::{Delete, ObjectIdentifier},
+    types::{ByteStream, SdkError},
+};
 use aws_smithy_types_convert::date_time::DateTimeExt;
 
 use crate::stores::{
     files::{
-        filesystem::{Filesystem, FilesystemError, SResult, Userfile},
-        storage::Bucket,
+        filesystem::{moved_key, Filesystem, FilesystemError, SResult, Userfile},
+        storage::{Bucket, FileError},
+        FileStorage,
     },
     Uuid,
 };
 
 use super::S3Store;
 
+/// S3 deletes at most this many objects with a single `DeleteObjects` request.
+const DELETE_BATCH_SIZE: usize = 1000;
+
+#[derive(Debug, thiserror::Error)]
+#[error("unable to delete the objects {0:?}")]
+struct DeleteObjectsError(Vec<String>);
+
 fn map_sdk_err<E: Error + Send + Sync + 'static, R: Debug + Send + Sync + 'static>(
     err: SdkError<E, R>,
 ) -> FilesystemError {
@@ -22,51 +33,206 @@ fn map_sdk_err<E: Error + Send + Sync + 'static, R: Debug + Send + Sync + 'stati
     }
 }
 
-#[async_trait::async_trait]
-impl Filesystem for S3Store {
-    async fn list(&self, user_id: Uuid, base_path: &str) -> SResult<Vec<Userfile>> {
-        let resp = self
+fn map_file_err(err: FileError) -> FilesystemError {
+    match err {
+        FileError::NotFound(key) => FilesystemError::NotFound(key),
+        FileError::Connection(_) => FilesystemError::Connection(Box::new(err)),
+        _ => FilesystemError::Other(Box::new(err)),
+    }
+}
+
+impl S3Store {
+    /// Returns whether a file exists at `path`.
+    async fn head_key(&self, path: &str) -> SResult<bool> {
+        let sse = self.customer_key(path).await?;
+        let res = self
             .client
-            .list_objects_v2()
+            .head_object()
             .bucket(Bucket::UserFiles.to_bucket_name())
-            .prefix(base_path.to_owned())
-            .delimiter("\\".to_owned())
+            .key(path)
+            .set_sse_customer_algorithm(sse.algorithm())
+            .set_sse_customer_key(sse.key())
+            .set_sse_customer_key_md5(sse.key_md5())
             .send()
-            .await
-            .map_err(map_sdk_err)?;
-        Ok(resp
-            .contents
-            .unwrap_or_default()
-            .iter()
-            .map(|object| Userfile {
-                name: object.key.clone().unwrap_or_default(),
-                last_modified: object.last_modified.and_then(|t| t.to_time().ok()),
-                owner: user_id,
-                size: Some(object.size),
-                is_folder: false,
-            })
-            .chain(
-                resp.common_prefixes
+            .await;
+        match res {
+            Ok(_) => Ok(true),
+            Err(SdkError::ServiceError(err)) if err.err().is_not_found() => Ok(false),
+            Err(e) => Err(map_sdk_err(e)),
+        }
+    }
+
+    /// Returns the keys of the file or of every object inside the folder at `path`.
+    async fn keys_below(&self, path: &str) -> SResult<Vec<String>> {
+        if self.head_key(path).await? {
+            return Ok(vec![path.to_owned()]);
+        }
+        let mut keys = Vec::new();
+        let mut continuation_token = None;
+        loop {
+            let resp = self
+                .client
+                .list_objects_v2()
+                .bucket(Bucket::UserFiles.to_bucket_name())
+                .prefix(format!("{path}\\"))
+                .set_continuation_token(continuation_token)
+                .send()
+                .await
+                .map_err(map_sdk_err)?;
+            keys.extend(
+                resp.contents
+                    .unwrap_or_default()
+                    .into_iter()
+                    .filter_map(|object| object.key),
+            );
+            continuation_token = resp.next_continuation_token;
+            if !resp.is_truncated || continuation_token.is_none() {
+                return Ok(keys);
+            }
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Filesystem for S3Store {
+    async fn list(&self, user_id: Uuid, base_path: &str) -> SResult<Vec<Userfile>> {
+        let mut files = Vec::new();
+        let mut folders = Vec::new();
+        let mut continuation_token = None;
+        loop {
+            let resp = self
+                .client
+                .list_objects_v2()
+                .bucket(Bucket::UserFiles.to_bucket_name())
+                .prefix(base_path.to_owned())
+                .delimiter("\\".to_owned())
+                .set_continuation_token(continuation_token)
+                .send()
+                .await
+                .map_err(map_sdk_err)?;
+            files.extend(
+                resp.contents
                     .unwrap_or_default()
-                    .iter()
-                    .map(|common_prefix| Userfile {
-                        name: common_prefix.prefix.clone().unwrap_or_default(),
-                        last_modified: None,
+                    .into_iter()
+                    // Folder markers are listed as common prefixes of their content
+                    .filter(|object| !object.key.as_deref().unwrap_or_default().ends_with('\\'))
+                    .map(|object| Userfile {
+                        name: object.key.unwrap_or_default(),
+                        last_modified: object.last_modified.and_then(|t| t.to_time().ok()),
                         owner: user_id,
-                        size: None,
-                        is_folder: true,
+                        size: Some(object.size),
+                        is_folder: false,
                     }),
-            )
-            .collect())
+            );
+            folders.extend(resp.common_prefixes.unwrap_or_default().into_iter().map(
+                |common_prefix| Userfile {
+                    name: common_prefix.prefix.unwrap_or_default(),
+                    last_modified: None,
+                    owner: user_id,
+                    size: None,
+                    is_folder: true,
+                },
+            ));
+            continuation_token = resp.next_continuation_token;
+            if !resp.is_truncated || continuation_token.is_none() {
+                files.append(&mut folders);
+                return Ok(files);
+            }
+        }
     }
+
     async fn delete(&mut self, path: &str) -> SResult<()> {
+        let keys = self.keys_below(path).await?;
+        for batch in keys.chunks(DELETE_BATCH_SIZE) {
+            let delete = Delete::builder()
+                .set_objects(Some(
+                    batch
+                        .iter()
+                        .map(|key| ObjectIdentifier::builder().key(key).build())
+                        .collect(),
+                ))
+                .quiet(true)
+                .build();
+            let resp = self
+                .client
+                .delete_objects()
+                .bucket(Bucket::UserFiles.to_bucket_name())
+                .delete(delete)
+                .send()
+                .await
+                .map_err(map_sdk_err)?;
+            // Quiet mode only reports the objects which couldn't be deleted
+            let failed: Vec<_> = resp
+                .errors
+                .unwrap_or_default()
+                .into_iter()
+                .filter_map(|err| err.key)
+                .collect();
+            if !failed.is_empty() {
+                return Err(FilesystemError::Other(Box::new(DeleteObjectsError(failed))));
+            }
+        }
+        Ok(())
+    }
+
+    async fn mkdir(&mut self, path: &str) -> SResult<()> {
+        // An empty object, whose key ends with the delimiter, marks the folder
+        let key = format!("{path}\\");
+        let sse = self.customer_key(&key).await?;
         self.client
-            .delete_object()
+            .put_object()
             .bucket(Bucket::UserFiles.to_bucket_name())
-            .key(path)
+            .key(key)
+            .set_sse_customer_algorithm(sse.algorithm())
+            .set_sse_customer_key(sse.key())
+            .set_sse_customer_key_md5(sse.key_md5())
+            .body(ByteStream::from_static(b""))
+            .send()
+            .await
+            .map_err(map_sdk_err)?;
+        Ok(())
+    }
+
+    async fn exists(&self, path: &str) -> SResult<bool> {
+        if self.head_key(path).await? {
+            return Ok(true);
+        }
+        // Folders exist as long as they contain at least one object, e.g. their marker
+        let resp = self
+            .client
+            .list_objects_v2()
+            .bucket(Bucket::UserFiles.to_bucket_name())
+            .prefix(format!("{path}\\"))
+            .max_keys(1)
             .send()
             .await
             .map_err(map_sdk_err)?;
+        Ok(resp.contents.is_some_and(|contents| !contents.is_empty()))
+    }
+
+    async fn copy(&mut self, from: &str, to: &str) -> SResult<()> {
+        if self.exists(to).await? {
+            return Err(FilesystemError::FileAlreadyExists(to.to_owned()));
+        }
+        let keys = self.keys_below(from).await?;
+        if keys.is_empty() {
+            return Err(FilesystemError::NotFound(from.to_owned()));
+        }
+        for key in keys {
+            let Some(target) = moved_key(&key, from, to) else {
+                continue;
+            };
+            // Large objects are copied in parts
+            self.copy_object(Bucket::UserFiles, &key, &target)
+                .await
+                .map_err(map_file_err)?;
+        }
         Ok(())
     }
+
+    async fn rename(&mut self, from: &str, to: &str) -> SResult<()> {
+        // S3 can't move objects, so they are copied before the originals are deleted
+        self.copy(from, to).await?;
+        self.delete(from).await
+    }
 }
@@ -2,11 +2,18 @@ use std::{error::Error, fmt::Debug};
 
 use async_trait::async_trait;
 use aws_config::meta::region::RegionProviderChain;
-use aws_sdk_s3::{types::SdkError, Client};
+use aws_sdk_s3::{types::SdkError, Client, Region};
 
-use crate::stores::{
-    files::storage::{Bucket, FileError},
-    Reset, Setup,
+use crate::{
+    config::S3Config,
+    connectors::local::presign::Presigner,
+    stores::{
+        files::{
+            encryption::{EncryptionError, Keyring},
+            storage::{Bucket, FileError, BUCKETS},
+        },
+        Reset, Setup,
+    },
 };
 
 pub mod database;
@@ -16,11 +23,40 @@ pub mod storage;
 #[derive(Clone)]
 pub struct S3Store {
     client: Client,
+    encryption: Option<Encryption>,
+}
+
+/// Server-side encryption with customer-provided keys (SSE-C). Requests on encrypted objects
+/// have to carry the key, so presigned urls point to this server, which forwards them to S3.
+#[derive(Clone)]
+struct Encryption {
+    keyring: Keyring,
+    presigner: Presigner,
+}
+
+/// The SSE-C parameters of a request, which are unset if objects aren't encrypted.
+#[derive(Default)]
+struct CustomerKey(Option<(String, String)>);
+
+impl CustomerKey {
+    fn algorithm(&self) -> Option<String> {
+        self.0.as_ref().map(|_| "AES256".to_owned())
+    }
+
+    fn key(&self) -> Option<String> {
+        self.0.as_ref().map(|(key, _)| key.clone())
+    }
+
+    fn key_md5(&self) -> Option<String> {
+        self.0.as_ref().map(|(_, key_md5)| key_md5.clone())
+    }
 }
 
 // TODO: Move the error code into a separate file
 // TODO: Properly match sdk errors, look at aws-sdk-rust changelog for more inforation
-fn map_sdk_err<E: Error + 'static, R: Debug + 'static>(err: SdkError<E, R>) -> FileError {
+fn map_sdk_err<E: Error + Send + Sync + 'static, R: Debug + Send + Sync + 'static>(
+    err: SdkError<E, R>,
+) -> FileError {
     match err {
         SdkError::TimeoutError(_) => FileError::Connection(Box::new(err)),
         _ => FileError::Other(Box::new(err)),
@@ -57,25 +93,42 @@ impl S3Store {
         resp.map(|_| ()).map_err(map_sdk_err)
     }
 
-    // TODO: Give server config here
-    pub async fn new() -> Self {
-        let region_provider = RegionProviderChain::default_provider().or_else("us-east-1");
+    pub async fn new(s3_config: &S3Config) -> Self {
+        let region_provider =
+            RegionProviderChain::first_try(s3_config.region.clone().map(Region::new))
+                .or_default_provider()
+                .or_else("us-east-1");
         let config = aws_config::from_env()
             .region(region_provider)
-            .endpoint_url("http://127.0.0.1:9000")
+            .endpoint_url(&s3_config.endpoint)
             .load()
             .await;
         let client = Client::new(&config);
-        Self { client }
+        Self {
+            client,
+            encryption: None,
+        }
+    }
+
+    /// Encrypts every object, which is stored afterwards, with the data key of its owner. The
+    /// presigner signs the urls, which replace S3 presigned urls.
+    #[must_use]
+    pub fn with_encryption(mut self, keyring: Keyring, presigner: Presigner) -> Self {
+        self.encryption = Some(Encryption { keyring, presigner });
+        self
     }
-}
 
-const BUCKETS: [Bucket; 4] = [
-    Bucket::UserFiles,
-    Bucket::VideoFiles,
-    Bucket::NotebookFiles,
-    Bucket::ProfileImages,
-];
+    /// Returns the SSE-C parameters of requests on the object at `key`.
+    async fn customer_key(&self, key: &str) -> Result<CustomerKey, EncryptionError> {
+        match &self.encryption {
+            Some(encryption) => {
+                let data_key = encryption.keyring.object_key(key).await?;
+                Ok(CustomerKey(Some(data_key.customer_key())))
+            }
+            None => Ok(CustomerKey::default()),
+        }
+    }
+}
 
 #[async_trait]
 impl Reset for S3Store {
@@ -1,19 +1,277 @@
-use std::time::Duration;
+use std::{ops::Range, time::Duration};
 
 use aws_sdk_s3::{
-    model::{CompletedMultipartUpload, CompletedPart},
+    model::{
+        ChecksumAlgorithm as S3ChecksumAlgorithm, ChecksumMode, CompletedMultipartUpload,
+        CompletedPart,
+    },
     presigning::config::PresigningConfig,
     types::{ByteStream, SdkError},
 };
+use aws_smithy_types_convert::date_time::DateTimeExt;
+use bytes::Bytes;
+use futures::stream;
+use tokio_util::io::ReaderStream;
 use tracing::error;
 
-use crate::stores::files::{
-    storage::{Bucket, FileError, InvalidPartSize, Part, PresignError},
-    FileStorage,
+use crate::{
+    connectors::{local::presign::PresignedMethod, urlencode},
+    stores::files::{
+        storage::{
+            chunk_count, Bucket, ChecksumAlgorithm, FileError, InvalidPartSize, Object, ObjectMeta,
+            ObjectStream, Part, PartChecksums, PresignError, MAX_PART_SIZE,
+        },
+        FileStorage,
+    },
 };
 
 use super::{map_sdk_err, S3Store};
 
+const fn s3_algorithm(algorithm: ChecksumAlgorithm) -> S3ChecksumAlgorithm {
+    match algorithm {
+        ChecksumAlgorithm::Sha256 => S3ChecksumAlgorithm::Sha256,
+        ChecksumAlgorithm::Crc32c => S3ChecksumAlgorithm::Crc32C,
+    }
+}
+
+/// Returns the declared checksum of a part, if it was computed with `algorithm`. S3 has a
+/// separate field for every algorithm.
+fn part_checksum(
+    checksums: Option<&PartChecksums>,
+    algorithm: ChecksumAlgorithm,
+    part_number: i32,
+) -> Option<String> {
+    checksums
+        .filter(|checksums| checksums.algorithm == algorithm)
+        .and_then(|checksums| checksums.part(part_number))
+        .map(ToOwned::to_owned)
+}
+
+impl S3Store {
+    async fn start_upload(
+        &self,
+        bucket: Bucket,
+        file: &str,
+        algorithm: Option<ChecksumAlgorithm>,
+    ) -> Result<String, FileError> {
+        let sse = self.customer_key(file).await?;
+        let multipart_upload = self
+            .client
+            .create_multipart_upload()
+            .bucket(bucket.to_bucket_name())
+            .key(file)
+            .set_checksum_algorithm(algorithm.map(s3_algorithm))
+            .set_sse_customer_algorithm(sse.algorithm())
+            .set_sse_customer_key(sse.key())
+            .set_sse_customer_key_md5(sse.key_md5())
+            .send()
+            .await
+            .map_err(map_sdk_err)?;
+        let Some(upload_id) = multipart_upload.upload_id() else {
+            error!("Failed to retrive upload id");
+            return Err(FileError::Other(Box::new(NoUploadId)));
+        };
+        Ok(upload_id.into())
+    }
+
+    /// Presigns the upload of a part. The declared checksum is sent as a signed header, so S3
+    /// rejects parts which don't match it.
+    async fn presign_upload_part(
+        &self,
+        bucket: Bucket,
+        file: &str,
+        upload_id: &str,
+        part_number: i32,
+        checksums: Option<&PartChecksums>,
+    ) -> Result<String, FileError> {
+        // Presigned urls can't carry the encryption key, so encrypted parts are sent through
+        // this server
+        if let Some(encryption) = &self.encryption {
+            return encryption.presigner.upload_part_url(
+                bucket,
+                file,
+                upload_id,
+                part_number,
+                checksums,
+            );
+        }
+        let presign_res = self
+            .client
+            .upload_part()
+            .key(file)
+            .bucket(bucket.to_bucket_name())
+            .upload_id(upload_id)
+            .part_number(part_number)
+            .set_checksum_sha256(part_checksum(
+                checksums,
+                ChecksumAlgorithm::Sha256,
+                part_number,
+            ))
+            .set_checksum_crc32_c(part_checksum(
+                checksums,
+                ChecksumAlgorithm::Crc32c,
+                part_number,
+            ))
+            .presigned(PresigningConfig::expires_in(Duration::from_secs(1800)).unwrap())
+            .await;
+        match presign_res {
+            Ok(res) => Ok(res.uri().to_string()),
+            Err(e) => new_presign_err(e),
+        }
+    }
+
+    /// Streams the content of an object, or only the bytes of the `Range` header value `range`.
+    async fn fetch_object(
+        &self,
+        bucket: Bucket,
+        name: &str,
+        range: Option<String>,
+    ) -> Result<Object, FileError> {
+        let sse = self.customer_key(name).await?;
+        let res = self
+            .client
+            .get_object()
+            .bucket(bucket.to_bucket_name())
+            .key(name)
+            .set_range(range)
+            .set_sse_customer_algorithm(sse.algorithm())
+            .set_sse_customer_key(sse.key())
+            .set_sse_customer_key_md5(sse.key_md5())
+            .send()
+            .await;
+        let object = match res {
+            Ok(object) => object,
+            Err(SdkError::ServiceError(err)) if err.err().is_no_such_key() => {
+                return Err(FileError::NotFound(name.to_owned()))
+            }
+            // The range starts behind the end of the object
+            Err(SdkError::ServiceError(err)) if err.err().code() == Some("InvalidRange") => {
+                return Ok(Object {
+                    size: 0,
+                    body: Box::pin(stream::empty()),
+                })
+            }
+            Err(e) => return Err(map_sdk_err(e)),
+        };
+        Ok(Object {
+            size: object.content_length().try_into().unwrap_or_default(),
+            body: Box::pin(ReaderStream::new(object.body.into_async_read())),
+        })
+    }
+
+    /// Uploads a part, which is sent through this server. Parts of uploads, which were started
+    /// with checksums, have to carry their checksum.
+    async fn send_part(
+        &self,
+        bucket: Bucket,
+        file: &str,
+        upload_id: &str,
+        part_number: i32,
+        data: Bytes,
+        checksum: Option<&(ChecksumAlgorithm, String)>,
+    ) -> Result<String, FileError> {
+        let sse = self.customer_key(file).await?;
+        let checksum_of = |algorithm| {
+            checksum
+                .filter(|(a, _)| *a == algorithm)
+                .map(|(_, checksum)| checksum.clone())
+        };
+        let res = self
+            .client
+            .upload_part()
+            .bucket(bucket.to_bucket_name())
+            .key(file)
+            .upload_id(upload_id)
+            .part_number(part_number)
+            .set_checksum_sha256(checksum_of(ChecksumAlgorithm::Sha256))
+            .set_checksum_crc32_c(checksum_of(ChecksumAlgorithm::Crc32c))
+            .set_sse_customer_algorithm(sse.algorithm())
+            .set_sse_customer_key(sse.key())
+            .set_sse_customer_key_md5(sse.key_md5())
+            .body(ByteStream::from(data))
+            .send()
+            .await;
+        match res {
+            Ok(resp) => Ok(resp.e_tag.unwrap_or_default()),
+            Err(SdkError::ServiceError(err)) if err.err().code() == Some("NoSuchUpload") => {
+                Err(FileError::NotFound(upload_id.to_owned()))
+            }
+            Err(e) => Err(map_sdk_err(e)),
+        }
+    }
+
+    /// Copies an object of `size` bytes with a multipart upload, whose parts are copied from
+    /// ranges of the source. `CopyObject` only copies objects up to [`MAX_PART_SIZE`] bytes.
+    async fn copy_in_parts(
+        &self,
+        bucket: Bucket,
+        from: &str,
+        to: &str,
+        size: u64,
+    ) -> Result<(), FileError> {
+        let upload_id = self.start_upload(bucket, to, None).await?;
+        let res = match self.copy_parts(bucket, from, to, &upload_id, size).await {
+            Ok(parts) => {
+                self.finish_multipart_upload(bucket, to, &upload_id, parts, None)
+                    .await
+            }
+            Err(e) => Err(e),
+        };
+        if res.is_err() {
+            if let Err(e) = self.abort_multipart_upload(bucket, to, &upload_id).await {
+                error!("unable to abort the copy of {from} to {to}: {e:?}");
+            }
+        }
+        res
+    }
+
+    async fn copy_parts(
+        &self,
+        bucket: Bucket,
+        from: &str,
+        to: &str,
+        upload_id: &str,
+        size: u64,
+    ) -> Result<Vec<Part>, FileError> {
+        let source_sse = self.customer_key(from).await?;
+        let sse = self.customer_key(to).await?;
+        let bucket = bucket.to_bucket_name();
+        let part_count = chunk_count(size, MAX_PART_SIZE);
+        let mut parts = Vec::new();
+        for index in 0..part_count {
+            let start = index * MAX_PART_SIZE;
+            let end = size.min(start + MAX_PART_SIZE) - 1;
+            let part_number = i32::try_from(index + 1).unwrap_or(i32::MAX);
+            let resp = self
+                .client
+                .upload_part_copy()
+                .bucket(bucket)
+                .key(to)
+                .upload_id(upload_id)
+                .part_number(part_number)
+                .copy_source(format!("{bucket}/{}", urlencode(from)))
+                .copy_source_range(format!("bytes={start}-{end}"))
+                .set_copy_source_sse_customer_algorithm(source_sse.algorithm())
+                .set_copy_source_sse_customer_key(source_sse.key())
+                .set_copy_source_sse_customer_key_md5(source_sse.key_md5())
+                .set_sse_customer_algorithm(sse.algorithm())
+                .set_sse_customer_key(sse.key())
+                .set_sse_customer_key_md5(sse.key_md5())
+                .send()
+                .await
+                .map_err(map_sdk_err)?;
+            parts.push(Part {
+                e_tag: resp
+                    .copy_part_result
+                    .and_then(|result| result.e_tag)
+                    .unwrap_or_default(),
+                part_number,
+            });
+        }
+        Ok(parts)
+    }
+}
+
 #[async_trait::async_trait]
 impl FileStorage for S3Store {
     async fn delete_file(&mut self, bucket: Bucket, name: &str) -> Result<(), FileError> {
@@ -28,6 +286,9 @@ impl FileStorage for S3Store {
     }
 
     async fn get_download_url(&self, bucket: Bucket, name: &str) -> Result<String, FileError> {
+        if let Some(encryption) = &self.encryption {
+            return encryption.presigner.download_url(bucket, name);
+        }
         let res = self
             .client
             .get_object()
@@ -45,50 +306,104 @@ impl FileStorage for S3Store {
         file: &str,
         file_size: u64,
         chunk_size: u64,
+        checksums: Option<&PartChecksums>,
     ) -> Result<(Vec<String>, String), FileError> {
-        let mut chunk_count = (file_size / chunk_size) + 1;
-        let size_of_last_chunk = file_size % chunk_size;
-
-        if chunk_count > 1 && size_of_last_chunk == 0 {
-            chunk_count -= 1;
-        }
-
+        let chunk_count = chunk_count(file_size, chunk_size);
         let mut upload_parts = Vec::new();
 
-        let multipart_upload = self
-            .client
-            .create_multipart_upload()
-            .bucket(bucket.to_bucket_name())
-            .key(file)
-            .send()
-            .await
-            .map_err(map_sdk_err)?;
-        let Some(upload_id) = multipart_upload.upload_id() else {
-            error!("Failed to retrive upload id");
-            return Err(FileError::Other(Box::new(NoUploadId)));
-        };
+        let algorithm = checksums.map(|checksums| checksums.algorithm);
+        let upload_id = self.start_upload(bucket, file, algorithm).await?;
 
         for chunk_index in 0..chunk_count {
             let chunk_index: i32 = chunk_index
                 .try_into()
                 .map_err(|_| FileError::Other(InvalidPartSize.into()))?;
             let part_number = chunk_index + 1;
-            let presign_res = self
+            upload_parts.push(
+                self.presign_upload_part(bucket, file, &upload_id, part_number, checksums)
+                    .await?,
+            );
+        }
+        Ok((upload_parts, upload_id))
+    }
+
+    async fn create_multipart_upload(
+        &self,
+        bucket: Bucket,
+        file: &str,
+    ) -> Result<String, FileError> {
+        self.start_upload(bucket, file, None).await
+    }
+
+    async fn upload_part(
+        &self,
+        bucket: Bucket,
+        file: &str,
+        upload_id: &str,
+        part_number: i32,
+        data: Bytes,
+    ) -> Result<String, FileError> {
+        self.send_part(bucket, file, upload_id, part_number, data, None)
+            .await
+    }
+
+    async fn list_parts(
+        &self,
+        bucket: Bucket,
+        file: &str,
+        upload_id: &str,
+    ) -> Result<Vec<Part>, FileError> {
+        let sse = self.customer_key(file).await?;
+        let mut parts = Vec::new();
+        let mut part_number_marker = None;
+        loop {
+            let res = self
                 .client
-                .upload_part()
-                .key(file)
+                .list_parts()
                 .bucket(bucket.to_bucket_name())
+                .key(file)
                 .upload_id(upload_id)
-                .part_number(part_number)
-                .presigned(PresigningConfig::expires_in(Duration::from_secs(1800)).unwrap())
+                .set_part_number_marker(part_number_marker)
+                .set_sse_customer_algorithm(sse.algorithm())
+                .set_sse_customer_key(sse.key())
+                .set_sse_customer_key_md5(sse.key_md5())
+                .send()
                 .await;
-            let presign_res = match presign_res {
-                Ok(res) => res,
-                Err(e) => return new_presign_err(e),
+            let resp = match res {
+                Ok(resp) => resp,
+                // ListParts has no modeled error for a missing upload
+                Err(SdkError::ServiceError(err)) if err.err().code() == Some("NoSuchUpload") => {
+                    return Err(FileError::NotFound(upload_id.to_owned()))
+                }
+                Err(e) => return Err(map_sdk_err(e)),
             };
-            upload_parts.push(presign_res.uri().to_string());
+            parts.extend(resp.parts.unwrap_or_default().into_iter().map(|part| Part {
+                e_tag: part.e_tag.unwrap_or_default(),
+                part_number: part.part_number,
+            }));
+            part_number_marker = resp.next_part_number_marker;
+            if !resp.is_truncated || part_number_marker.is_none() {
+                return Ok(parts);
+            }
+        }
+    }
+
+    async fn get_upload_part_urls(
+        &self,
+        bucket: Bucket,
+        file: &str,
+        upload_id: &str,
+        part_numbers: &[i32],
+        checksums: Option<&PartChecksums>,
+    ) -> Result<Vec<String>, FileError> {
+        let mut uris = Vec::with_capacity(part_numbers.len());
+        for part_number in part_numbers {
+            uris.push(
+                self.presign_upload_part(bucket, file, upload_id, *part_number, checksums)
+                    .await?,
+            );
         }
-        Ok((upload_parts, upload_id.into()))
+        Ok(uris)
     }
 
     async fn finish_multipart_upload(
@@ -97,13 +412,26 @@ impl FileStorage for S3Store {
         file: &str,
         upload_id: &str,
         parts: Vec<Part>,
+        checksums: Option<&PartChecksums>,
     ) -> Result<(), FileError> {
+        let sse = self.customer_key(file).await?;
+        // Uploads with checksums can only be completed with the checksum of every part
         let completed_multipart_upload = CompletedMultipartUpload::builder()
             .set_parts(Some(
                 parts
                     .into_iter()
                     .map(|part| {
                         CompletedPart::builder()
+                            .set_checksum_sha256(part_checksum(
+                                checksums,
+                                ChecksumAlgorithm::Sha256,
+                                part.part_number,
+                            ))
+                            .set_checksum_crc32_c(part_checksum(
+                                checksums,
+                                ChecksumAlgorithm::Crc32c,
+                                part.part_number,
+                            ))
                             .set_e_tag(Some(part.e_tag))
                             .set_part_number(Some(part.part_number))
                             .build()
@@ -117,29 +445,198 @@ impl FileStorage for S3Store {
             .key(file)
             .upload_id(upload_id)
             .multipart_upload(completed_multipart_upload)
+            .set_sse_customer_algorithm(sse.algorithm())
+            .set_sse_customer_key(sse.key())
+            .set_sse_customer_key_md5(sse.key_md5())
             .send()
             .await
             .map(|_| ())
             .map_err(map_sdk_err)
     }
 
+    async fn abort_multipart_upload(
+        &self,
+        bucket: Bucket,
+        file: &str,
+        upload_id: &str,
+    ) -> Result<(), FileError> {
+        let res = self
+            .client
+            .abort_multipart_upload()
+            .bucket(bucket.to_bucket_name())
+            .key(file)
+            .upload_id(upload_id)
+            .send()
+            .await;
+        match res {
+            Ok(_) => Ok(()),
+            Err(SdkError::ServiceError(err)) if err.err().is_no_such_upload() => {
+                Err(FileError::NotFound(upload_id.to_owned()))
+            }
+            Err(e) => Err(map_sdk_err(e)),
+        }
+    }
+
     async fn upload(&mut self, bucket: Bucket, name: &str, data: Vec<u8>) -> Result<(), FileError> {
+        let sse = self.customer_key(name).await?;
         self.client
             .put_object()
             .bucket(bucket.to_bucket_name())
             .key(name)
+            .set_sse_customer_algorithm(sse.algorithm())
+            .set_sse_customer_key(sse.key())
+            .set_sse_customer_key_md5(sse.key_md5())
             .body(ByteStream::from(data))
             .send()
             .await
             .map(|_| ())
             .map_err(map_sdk_err)
     }
+
+    async fn upload_stream(
+        &mut self,
+        bucket: Bucket,
+        name: &str,
+        data: ObjectStream,
+        size: u64,
+    ) -> Result<(), FileError> {
+        let sse = self.customer_key(name).await?;
+        // S3 rejects the object if the body doesn't match the content length
+        self.client
+            .put_object()
+            .bucket(bucket.to_bucket_name())
+            .key(name)
+            .content_length(size.try_into().unwrap_or(i64::MAX))
+            .set_sse_customer_algorithm(sse.algorithm())
+            .set_sse_customer_key(sse.key())
+            .set_sse_customer_key_md5(sse.key_md5())
+            .body(ByteStream::from(hyper::Body::wrap_stream(data)))
+            .send()
+            .await
+            .map(|_| ())
+            .map_err(map_sdk_err)
+    }
+
+    async fn copy_object(&mut self, bucket: Bucket, from: &str, to: &str) -> Result<(), FileError> {
+        let size = self.head_object(bucket, from).await?.size;
+        if size > MAX_PART_SIZE {
+            return self.copy_in_parts(bucket, from, to, size).await;
+        }
+        // S3 decrypts the source and encrypts the copy, if their keys belong to different owners
+        let source_sse = self.customer_key(from).await?;
+        let sse = self.customer_key(to).await?;
+        let bucket = bucket.to_bucket_name();
+        let res = self
+            .client
+            .copy_object()
+            .bucket(bucket)
+            .copy_source(format!("{bucket}/{}", urlencode(from)))
+            .set_copy_source_sse_customer_algorithm(source_sse.algorithm())
+            .set_copy_source_sse_customer_key(source_sse.key())
+            .set_copy_source_sse_customer_key_md5(source_sse.key_md5())
+            .key(to)
+            .set_sse_customer_algorithm(sse.algorithm())
+            .set_sse_customer_key(sse.key())
+            .set_sse_customer_key_md5(sse.key_md5())
+            .send()
+            .await;
+        match res {
+            Ok(_) => Ok(()),
+            // CopyObject has no modeled error for a missing source
+            Err(SdkError::ServiceError(err)) if err.err().code() == Some("NoSuchKey") => {
+                Err(FileError::NotFound(from.to_owned()))
+            }
+            Err(e) => Err(map_sdk_err(e)),
+        }
+    }
+
+    async fn get_object(&self, bucket: Bucket, name: &str) -> Result<Object, FileError> {
+        self.fetch_object(bucket, name, None).await
+    }
+
+    async fn get_object_range(
+        &self,
+        bucket: Bucket,
+        name: &str,
+        range: Range<u64>,
+    ) -> Result<Object, FileError> {
+        // S3 can't return an empty range
+        if range.is_empty() {
+            return Ok(Object {
+                size: 0,
+                body: Box::pin(stream::empty()),
+            });
+        }
+        let range = format!("bytes={}-{}", range.start, range.end - 1);
+        self.fetch_object(bucket, name, Some(range)).await
+    }
+
+    async fn head_object(&self, bucket: Bucket, name: &str) -> Result<ObjectMeta, FileError> {
+        let sse = self.customer_key(name).await?;
+        let res = self
+            .client
+            .head_object()
+            .bucket(bucket.to_bucket_name())
+            .key(name)
+            .checksum_mode(ChecksumMode::Enabled)
+            .set_sse_customer_algorithm(sse.algorithm())
+            .set_sse_customer_key(sse.key())
+            .set_sse_customer_key_md5(sse.key_md5())
+            .send()
+            .await;
+        let object = match res {
+            Ok(object) => object,
+            Err(SdkError::ServiceError(err)) if err.err().is_not_found() => {
+                return Err(FileError::NotFound(name.to_owned()))
+            }
+            Err(e) => return Err(map_sdk_err(e)),
+        };
+        Ok(ObjectMeta {
+            size: object.content_length().try_into().unwrap_or_default(),
+            last_modified: object.last_modified().and_then(|t| t.to_time().ok()),
+            e_tag: object.e_tag().map(ToOwned::to_owned),
+            checksum: object
+                .checksum_sha256()
+                .or_else(|| object.checksum_crc32_c())
+                .map(ToOwned::to_owned),
+        })
+    }
+
+    async fn put_signed(&self, token: &str, data: Bytes) -> Result<String, FileError> {
+        let Some(encryption) = &self.encryption else {
+            return Err(FileError::Presigning(PresignError::Unsupported));
+        };
+        let claims = encryption.presigner.verify(token, PresignedMethod::Put)?;
+        let (Some(upload_id), Some(part_number)) = (&claims.upload_id, claims.part_number) else {
+            return Err(FileError::Presigning(PresignError::InvalidToken));
+        };
+        claims.verify_checksum(&data)?;
+        self.send_part(
+            claims.bucket,
+            &claims.key,
+            upload_id,
+            part_number,
+            data,
+            claims.checksum.as_ref(),
+        )
+        .await
+    }
+
+    async fn get_signed(&self, token: &str) -> Result<Object, FileError> {
+        let Some(encryption) = &self.encryption else {
+            return Err(FileError::Presigning(PresignError::Unsupported));
+        };
+        let claims = encryption.presigner.verify(token, PresignedMethod::Get)?;
+        self.get_object(claims.bucket, &claims.key).await
+    }
 }
 
 #[derive(Debug, thiserror::Error)]
 #[error("no upload id was returned from store")]
 struct NoUploadId;
 
-fn new_presign_err<U, T: std::error::Error + 'static>(e: SdkError<T>) -> Result<U, FileError> {
+fn new_presign_err<U, T: std::error::Error + Send + Sync + 'static>(
+    e: SdkError<T>,
+) -> Result<U, FileError> {
     Err(FileError::Presigning(PresignError::Other(Box::new(e))))
 }
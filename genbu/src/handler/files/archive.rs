@@ -0,0 +1,639 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    io,
+};
+
+use bytes::{BufMut, Bytes, BytesMut};
+use futures::{stream, TryStreamExt};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use time::{OffsetDateTime, UtcOffset};
+use utoipa::ToSchema;
+
+use crate::stores::{
+    files::{
+        database::{DBFileError, DBFileStore},
+        filesystem::{Filesystem, FilesystemError},
+        storage::{Bucket, FileError, ObjectStream},
+    },
+    Uuid,
+};
+
+use super::{
+    blobs::content_key,
+    userfiles::{build_path, is_valid_path},
+};
+
+#[derive(Debug, Error)]
+pub enum ArchiveAPIError {
+    #[error("filesystem error")]
+    Filesystem(#[from] FilesystemError),
+
+    #[error("file storage error")]
+    StorageError(#[from] FileError),
+
+    #[error("database error")]
+    DatabaseError(#[from] DBFileError),
+
+    #[error("invalid path `{0}`")]
+    InvalidPath(String),
+}
+
+pub type ArchiveAPIResult<T> = std::result::Result<T, ArchiveAPIError>;
+type Result<T> = ArchiveAPIResult<T>;
+
+/// Sizes and offsets from this value on are stored in the zip64 extension.
+const ZIP64_LIMIT: u64 = 0xFFFF_FFFF;
+
+/// Archives with at least this many entries need the zip64 end of central directory record.
+const ZIP64_ENTRY_LIMIT: u64 = 0xFFFF;
+
+/// Version 4.5 of the ZIP format added zip64, 2.0 folders and data descriptors.
+const VERSION_ZIP64: u16 = 45;
+const VERSION_DEFAULT: u16 = 20;
+
+/// The checksum and size of an entry follow its content in a data descriptor, because they're
+/// only known once it's streamed. Names are encoded in UTF-8.
+const FLAGS: u16 = 1 << 3 | 1 << 11;
+
+const LOCAL_HEADER_SIGNATURE: u32 = 0x0403_4b50;
+const DATA_DESCRIPTOR_SIGNATURE: u32 = 0x0807_4b50;
+const CENTRAL_HEADER_SIGNATURE: u32 = 0x0201_4b50;
+const ZIP64_END_SIGNATURE: u32 = 0x0606_4b50;
+const ZIP64_LOCATOR_SIGNATURE: u32 = 0x0706_4b50;
+const END_SIGNATURE: u32 = 0x0605_4b50;
+
+/// Header id of the zip64 extended information extra field.
+const ZIP64_EXTRA_ID: u16 = 0x0001;
+
+/// MS-DOS attribute of folders.
+const DIRECTORY_ATTRIBUTE: u32 = 0x10;
+
+#[derive(Clone, Debug, Serialize, Deserialize, ToSchema)]
+pub struct ArchiveRequest {
+    /// Files and folders relative to the folder of the user, which are put into the archive
+    pub paths: Vec<String>,
+}
+
+/// A ZIP archive, which is built while it's streamed.
+pub struct Archive {
+    /// File name of the archive
+    pub name: String,
+    pub body: ObjectStream,
+}
+
+/// A file or folder in the archive.
+#[derive(Debug)]
+struct ArchiveEntry {
+    /// Path inside the archive, folders end with `/`
+    name: String,
+    /// Key of the content of a file, `None` for folders
+    key: Option<String>,
+    last_modified: Option<OffsetDateTime>,
+}
+
+/// Returns the time and date of an entry in the MS-DOS format of ZIP archives, which only covers
+/// the years from 1980 to 2107.
+fn dos_date_time(last_modified: Option<OffsetDateTime>) -> (u16, u16) {
+    let Some(t) = last_modified
+        .map(|t| t.to_offset(UtcOffset::UTC))
+        .filter(|t| (1980..=2107).contains(&t.year()))
+    else {
+        // 1980-01-01 00:00:00
+        return (0, (1 << 5) | 1);
+    };
+    let time =
+        (u16::from(t.hour()) << 11) | (u16::from(t.minute()) << 5) | (u16::from(t.second()) / 2);
+    let date = (((t.year() - 1980) as u16) << 9)
+        | (u16::from(u8::from(t.month())) << 5)
+        | u16::from(t.day());
+    (time, date)
+}
+
+/// An entry, as it's recorded in the central directory at the end of the archive.
+#[derive(Debug)]
+struct CentralEntry {
+    name: String,
+    is_folder: bool,
+    time: u16,
+    date: u16,
+    crc32: u32,
+    size: u64,
+    /// Offset of the local header of the entry
+    offset: u64,
+    /// Whether the local header and the data descriptor use the zip64 extension
+    zip64: bool,
+}
+
+impl CentralEntry {
+    fn version(&self) -> u16 {
+        if self.zip64 || self.offset >= ZIP64_LIMIT {
+            VERSION_ZIP64
+        } else {
+            VERSION_DEFAULT
+        }
+    }
+
+    fn local_header(&self) -> BytesMut {
+        // The sizes are in the data descriptor, an entry which needs zip64 announces this with
+        // an extra field in the local header
+        let extra_len = if self.zip64 { 20 } else { 0 };
+        let mut header = BytesMut::with_capacity(30 + self.name.len() + extra_len);
+        header.put_u32_le(LOCAL_HEADER_SIGNATURE);
+        header.put_u16_le(self.version());
+        header.put_u16_le(FLAGS);
+        // Entries are stored without compression
+        header.put_u16_le(0);
+        header.put_u16_le(self.time);
+        header.put_u16_le(self.date);
+        header.put_u32_le(0);
+        let size = if self.zip64 { ZIP64_LIMIT as u32 } else { 0 };
+        header.put_u32_le(size);
+        header.put_u32_le(size);
+        header.put_u16_le(self.name.len() as u16);
+        header.put_u16_le(extra_len as u16);
+        header.put_slice(self.name.as_bytes());
+        if self.zip64 {
+            header.put_u16_le(ZIP64_EXTRA_ID);
+            header.put_u16_le(16);
+            header.put_u64_le(0);
+            header.put_u64_le(0);
+        }
+        header
+    }
+
+    fn data_descriptor(&self) -> BytesMut {
+        let mut descriptor = BytesMut::with_capacity(24);
+        descriptor.put_u32_le(DATA_DESCRIPTOR_SIGNATURE);
+        descriptor.put_u32_le(self.crc32);
+        if self.zip64 {
+            descriptor.put_u64_le(self.size);
+            descriptor.put_u64_le(self.size);
+        } else {
+            descriptor.put_u32_le(self.size as u32);
+            descriptor.put_u32_le(self.size as u32);
+        }
+        descriptor
+    }
+
+    fn central_header(&self) -> BytesMut {
+        let mut extra = BytesMut::new();
+        if self.zip64 {
+            extra.put_u64_le(self.size);
+            extra.put_u64_le(self.size);
+        }
+        if self.offset >= ZIP64_LIMIT {
+            extra.put_u64_le(self.offset);
+        }
+        let extra_len = if extra.is_empty() { 0 } else { extra.len() + 4 };
+
+        let mut header = BytesMut::with_capacity(46 + self.name.len() + extra_len);
+        header.put_u32_le(CENTRAL_HEADER_SIGNATURE);
+        header.put_u16_le(VERSION_ZIP64);
+        header.put_u16_le(self.version());
+        header.put_u16_le(FLAGS);
+        header.put_u16_le(0);
+        header.put_u16_le(self.time);
+        header.put_u16_le(self.date);
+        header.put_u32_le(self.crc32);
+        let size = if self.zip64 { ZIP64_LIMIT } else { self.size };
+        header.put_u32_le(size as u32);
+        header.put_u32_le(size as u32);
+        header.put_u16_le(self.name.len() as u16);
+        header.put_u16_le(extra_len as u16);
+        // Comment, disk and internal attributes
+        header.put_u16_le(0);
+        header.put_u16_le(0);
+        header.put_u16_le(0);
+        header.put_u32_le(if self.is_folder {
+            DIRECTORY_ATTRIBUTE
+        } else {
+            0
+        });
+        header.put_u32_le(self.offset.min(ZIP64_LIMIT) as u32);
+        header.put_slice(self.name.as_bytes());
+        if !extra.is_empty() {
+            header.put_u16_le(ZIP64_EXTRA_ID);
+            header.put_u16_le(extra.len() as u16);
+            header.put_slice(&extra);
+        }
+        header
+    }
+}
+
+/// Returns the records which end an archive, whose central directory of `size` bytes with
+/// `count` entries starts at `offset`.
+fn end_of_central_directory(count: u64, offset: u64, size: u64) -> BytesMut {
+    let mut end = BytesMut::with_capacity(98);
+    if count >= ZIP64_ENTRY_LIMIT || offset >= ZIP64_LIMIT || size >= ZIP64_LIMIT {
+        end.put_u32_le(ZIP64_END_SIGNATURE);
+        // Size of the remaining record
+        end.put_u64_le(44);
+        end.put_u16_le(VERSION_ZIP64);
+        end.put_u16_le(VERSION_ZIP64);
+        end.put_u32_le(0);
+        end.put_u32_le(0);
+        end.put_u64_le(count);
+        end.put_u64_le(count);
+        end.put_u64_le(size);
+        end.put_u64_le(offset);
+
+        end.put_u32_le(ZIP64_LOCATOR_SIGNATURE);
+        end.put_u32_le(0);
+        end.put_u64_le(offset + size);
+        end.put_u32_le(1);
+    }
+    end.put_u32_le(END_SIGNATURE);
+    end.put_u16_le(0);
+    end.put_u16_le(0);
+    end.put_u16_le(count.min(ZIP64_ENTRY_LIMIT) as u16);
+    end.put_u16_le(count.min(ZIP64_ENTRY_LIMIT) as u16);
+    end.put_u32_le(size.min(ZIP64_LIMIT) as u32);
+    end.put_u32_le(offset.min(ZIP64_LIMIT) as u32);
+    // Comment
+    end.put_u16_le(0);
+    end
+}
+
+/// The entry whose content is being streamed.
+struct CurrentEntry {
+    entry: CentralEntry,
+    body: ObjectStream,
+    hasher: crc32fast::Hasher,
+    /// Size of the object when it was opened
+    expected_size: u64,
+}
+
+/// Writes the archive entry by entry, so only the central directory is kept in memory.
+struct ArchiveWriter<F> {
+    file_storage: F,
+    entries: VecDeque<ArchiveEntry>,
+    current: Option<CurrentEntry>,
+    central_directory: BytesMut,
+    count: u64,
+    /// Bytes of the archive written so far
+    offset: u64,
+    finished: bool,
+}
+
+impl<F: Filesystem> ArchiveWriter<F> {
+    fn emit(&mut self, data: impl Into<Bytes>) -> Bytes {
+        let data = data.into();
+        self.offset += data.len() as u64;
+        data
+    }
+
+    async fn open(&mut self, entry: ArchiveEntry) -> io::Result<Bytes> {
+        let (time, date) = dos_date_time(entry.last_modified);
+        let (body, expected_size): (ObjectStream, _) = match &entry.key {
+            Some(key) => {
+                let object = self
+                    .file_storage
+                    .get_object(Bucket::UserFiles, key)
+                    .await
+                    .map_err(|e| io::Error::other(format!("{key}: {e}")))?;
+                (object.body, object.size)
+            }
+            None => (Box::pin(stream::empty()), 0),
+        };
+        let entry = CentralEntry {
+            is_folder: entry.key.is_none(),
+            name: entry.name,
+            time,
+            date,
+            crc32: 0,
+            size: 0,
+            offset: self.offset,
+            zip64: expected_size >= ZIP64_LIMIT,
+        };
+        let header = entry.local_header();
+        self.current = Some(CurrentEntry {
+            entry,
+            body,
+            hasher: crc32fast::Hasher::new(),
+            expected_size,
+        });
+        Ok(self.emit(header))
+    }
+
+    async fn next_chunk(&mut self) -> io::Result<Option<Bytes>> {
+        if let Some(current) = &mut self.current {
+            if let Some(chunk) = current.body.try_next().await? {
+                current.hasher.update(&chunk);
+                current.entry.size += chunk.len() as u64;
+                return Ok(Some(self.emit(chunk)));
+            }
+            let Some(CurrentEntry {
+                mut entry,
+                hasher,
+                expected_size,
+                ..
+            }) = self.current.take()
+            else {
+                return Ok(None);
+            };
+            // The header of an entry without zip64 can't describe a larger entry
+            if entry.size != expected_size {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    format!("{} changed while it was archived", entry.name),
+                ));
+            }
+            entry.crc32 = hasher.finalize();
+            self.central_directory.put(entry.central_header());
+            self.count += 1;
+            return Ok(Some(self.emit(entry.data_descriptor())));
+        }
+        if let Some(entry) = self.entries.pop_front() {
+            return self.open(entry).await.map(Some);
+        }
+        if self.finished {
+            return Ok(None);
+        }
+        self.finished = true;
+        let central_directory = std::mem::take(&mut self.central_directory);
+        let end = end_of_central_directory(self.count, self.offset, central_directory.len() as u64);
+        let mut tail = central_directory;
+        tail.put(end);
+        Ok(Some(self.emit(tail)))
+    }
+}
+
+/// Adds a folder with everything inside it to the entries of an archive. Deduplicated files are
+/// read from their blob.
+async fn add_folder(
+    filesystem: &impl Filesystem,
+    user_id: Uuid,
+    key: String,
+    name: String,
+    blob_keys: &HashMap<String, String>,
+    entries: &mut Vec<ArchiveEntry>,
+) -> Result<()> {
+    let mut folders = vec![(key, name)];
+    while let Some((folder, name)) = folders.pop() {
+        entries.push(ArchiveEntry {
+            name: format!("{name}/"),
+            key: None,
+            last_modified: None,
+        });
+        let mut files = filesystem.list(user_id, &format!("{folder}\\")).await?;
+        files.sort_by(|a, b| a.name.cmp(&b.name));
+        for file in files {
+            let key = file.name.trim_end_matches('\\');
+            let file_name = key.rsplit('\\').next().unwrap_or_default();
+            let name = format!("{name}/{file_name}");
+            if file.is_folder {
+                folders.push((key.to_owned(), name));
+            } else {
+                entries.push(ArchiveEntry {
+                    name,
+                    key: Some(blob_keys.get(key).cloned().unwrap_or(file.name)),
+                    last_modified: file.last_modified,
+                });
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Returns `name`, unless another selected path has this name in the archive already. Then the
+/// first free name of `name (1)`, `name (2)` and so on is returned, with the number in front of
+/// the extension of a file.
+fn unique_name(names: &mut HashSet<String>, name: String, is_folder: bool) -> String {
+    if names.insert(name.clone()) {
+        return name;
+    }
+    let (stem, extension) = match name.rsplit_once('.') {
+        Some((stem, extension)) if !is_folder && !stem.is_empty() => {
+            (stem, format!(".{extension}"))
+        }
+        _ => (name.as_str(), String::new()),
+    };
+    (1..)
+        .map(|i| format!("{stem} ({i}){extension}"))
+        .find(|name| names.insert(name.clone()))
+        .unwrap_or_default()
+}
+
+/// Streams a ZIP archive of files and folders of the user, which is built while it's sent, so
+/// it's never stored. Every path is put into the root of the archive, folders with everything
+/// inside them. Selected paths with the same name get unique names in the archive. Large archives
+/// use the zip64 extension.
+#[tracing::instrument(skip(filesystem, file_db))]
+pub async fn archive<F: Filesystem>(
+    filesystem: F,
+    file_db: impl DBFileStore,
+    user_id: Uuid,
+    req: ArchiveRequest,
+) -> Result<Archive> {
+    if req.paths.is_empty() {
+        return Err(ArchiveAPIError::InvalidPath(String::new()));
+    }
+    let mut entries = Vec::new();
+    let mut names = HashSet::new();
+    for path in &req.paths {
+        if !is_valid_path(path) {
+            return Err(ArchiveAPIError::InvalidPath(path.clone()));
+        }
+        let key = build_path(user_id, path);
+        let name = path.rsplit('\\').next().unwrap_or_default().to_owned();
+        let blob_keys: HashMap<_, _> = file_db
+            .get_dbfiles_below(Bucket::UserFiles, &key)
+            .await?
+            .into_iter()
+            .filter(|f| f.blob_id.is_some())
+            .map(|f| (f.path.clone(), content_key(&f)))
+            .collect();
+
+        let last_modified = match filesystem.head_object(Bucket::UserFiles, &key).await {
+            Ok(meta) => Some(meta.last_modified),
+            Err(FileError::NotFound(_)) => None,
+            Err(e) => return Err(e.into()),
+        };
+        match last_modified {
+            Some(last_modified) => entries.push(ArchiveEntry {
+                name: unique_name(&mut names, name, false),
+                key: Some(blob_keys.get(&key).cloned().unwrap_or(key)),
+                last_modified,
+            }),
+            None if filesystem.exists(&key).await? => {
+                let name = unique_name(&mut names, name, true);
+                add_folder(&filesystem, user_id, key, name, &blob_keys, &mut entries).await?;
+            }
+            None => return Err(FilesystemError::NotFound(path.clone()).into()),
+        }
+    }
+
+    let name = match req.paths.as_slice() {
+        [path] => format!("{}.zip", path.rsplit('\\').next().unwrap_or_default()),
+        _ => "download.zip".to_owned(),
+    };
+    let writer = ArchiveWriter {
+        file_storage: filesystem,
+        entries: entries.into(),
+        current: None,
+        central_directory: BytesMut::new(),
+        count: 0,
+        offset: 0,
+        finished: false,
+    };
+    let body = stream::try_unfold(writer, |mut writer| async move {
+        let chunk = writer.next_chunk().await?;
+        Ok(chunk.map(|chunk| (chunk, writer)))
+    });
+    Ok(Archive {
+        name,
+        body: Box::pin(body),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        connectors::memory::MemStore,
+        handler::files::{blobs::deduplicate_files, catalog::register_object},
+        stores::files::FileStorage,
+    };
+
+    use super::*;
+
+    fn u16_at(data: &[u8], at: usize) -> u16 {
+        u16::from_le_bytes(data[at..at + 2].try_into().unwrap())
+    }
+
+    fn u32_at(data: &[u8], at: usize) -> u32 {
+        u32::from_le_bytes(data[at..at + 4].try_into().unwrap())
+    }
+
+    /// Reads the name and content of every entry from the central directory of an archive.
+    fn read_archive(data: &[u8]) -> Vec<(String, Vec<u8>)> {
+        let end = data.len() - 22;
+        assert_eq!(u32_at(data, end), END_SIGNATURE);
+        let count = u16_at(data, end + 10);
+        let mut at = u32_at(data, end + 16) as usize;
+        let mut entries = Vec::new();
+        for _ in 0..count {
+            assert_eq!(u32_at(data, at), CENTRAL_HEADER_SIGNATURE);
+            let (crc32, size) = (u32_at(data, at + 16), u32_at(data, at + 24) as usize);
+            let name_len = u16_at(data, at + 28) as usize;
+            let name = String::from_utf8(data[at + 46..at + 46 + name_len].to_vec()).unwrap();
+            let offset = u32_at(data, at + 42) as usize;
+            assert_eq!(u32_at(data, offset), LOCAL_HEADER_SIGNATURE);
+            let start = offset
+                + 30
+                + u16_at(data, offset + 26) as usize
+                + u16_at(data, offset + 28) as usize;
+            let content = data[start..start + size].to_vec();
+            assert_eq!(crc32fast::hash(&content), crc32, "{name}");
+            assert_eq!(u32_at(data, start + size), DATA_DESCRIPTOR_SIGNATURE);
+            entries.push((name, content));
+            at += 46 + name_len + u16_at(data, at + 30) as usize;
+        }
+        entries
+    }
+
+    async fn write(store: &MemStore, user_id: Uuid, path: &str, data: &[u8]) {
+        let key = build_path(user_id, path);
+        store
+            .clone()
+            .upload(Bucket::UserFiles, &key, data.to_vec())
+            .await
+            .unwrap();
+        register_object(store, &mut store.clone(), Bucket::UserFiles, &key, user_id)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn archive_folders_and_files() {
+        let user_id = Uuid::new_v4();
+        let store = MemStore::new();
+        write(&store, user_id, "docs\\a.txt", b"first file").await;
+        write(&store, user_id, "docs\\sub\\b.txt", b"second file").await;
+        write(&store, user_id, "other\\a.txt", b"first file").await;
+        write(&store, user_id, "c.txt", b"").await;
+        // Deduplicated files are read from their blob
+        deduplicate_files(store.clone(), store.clone(), 1)
+            .await
+            .unwrap();
+
+        let req = ArchiveRequest {
+            paths: vec!["docs".to_owned(), "c.txt".to_owned()],
+        };
+        let zip = archive(store.clone(), store.clone(), user_id, req)
+            .await
+            .unwrap();
+        assert_eq!(zip.name, "download.zip");
+        let data: Vec<_> = zip.body.try_collect().await.unwrap();
+        assert_eq!(
+            read_archive(&data.concat()),
+            [
+                ("docs/".to_owned(), Vec::new()),
+                ("docs/a.txt".to_owned(), b"first file".to_vec()),
+                ("docs/sub/".to_owned(), Vec::new()),
+                ("docs/sub/b.txt".to_owned(), b"second file".to_vec()),
+                ("c.txt".to_owned(), Vec::new()),
+            ]
+        );
+
+        // Selected paths with the same name are kept under unique names
+        let req = ArchiveRequest {
+            paths: vec![
+                "docs\\a.txt".to_owned(),
+                "other\\a.txt".to_owned(),
+                "docs\\sub".to_owned(),
+                "docs\\sub".to_owned(),
+            ],
+        };
+        let zip = archive(store.clone(), store.clone(), user_id, req)
+            .await
+            .unwrap();
+        let data: Vec<_> = zip.body.try_collect().await.unwrap();
+        assert_eq!(
+            read_archive(&data.concat()),
+            [
+                ("a.txt".to_owned(), b"first file".to_vec()),
+                ("a (1).txt".to_owned(), b"first file".to_vec()),
+                ("sub/".to_owned(), Vec::new()),
+                ("sub/b.txt".to_owned(), b"second file".to_vec()),
+                ("sub (1)/".to_owned(), Vec::new()),
+                ("sub (1)/b.txt".to_owned(), b"second file".to_vec()),
+            ]
+        );
+
+        let req = ArchiveRequest {
+            paths: vec!["missing".to_owned()],
+        };
+        assert!(matches!(
+            archive(store.clone(), store, user_id, req).await,
+            Err(ArchiveAPIError::Filesystem(FilesystemError::NotFound(_)))
+        ));
+    }
+
+    #[test]
+    fn zip64_entries() {
+        let entry = CentralEntry {
+            name: "large.bin".to_owned(),
+            is_folder: false,
+            time: 0,
+            date: 0,
+            crc32: 7,
+            size: 5 * ZIP64_LIMIT,
+            offset: 2 * ZIP64_LIMIT,
+            zip64: true,
+        };
+        let header = entry.central_header();
+        assert_eq!(u32_at(&header, 20), ZIP64_LIMIT as u32);
+        assert_eq!(u32_at(&header, 42), ZIP64_LIMIT as u32);
+        // Both sizes and the offset are in the extra field
+        assert_eq!(u16_at(&header, 30), 28);
+        assert_eq!(u16_at(&header, 46 + 9), ZIP64_EXTRA_ID);
+        assert_eq!(entry.data_descriptor().len(), 24);
+
+        let end = end_of_central_directory(ZIP64_ENTRY_LIMIT + 1, ZIP64_LIMIT, 100);
+        assert_eq!(u32_at(&end, 0), ZIP64_END_SIGNATURE);
+        assert_eq!(u32_at(&end, 56), ZIP64_LOCATOR_SIGNATURE);
+        assert_eq!(u32_at(&end, 76), END_SIGNATURE);
+        assert_eq!(u16_at(&end, 86), ZIP64_ENTRY_LIMIT as u16);
+    }
+}
@@ -0,0 +1,328 @@
+use futures::TryStreamExt;
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+use tracing::error;
+
+use crate::stores::files::{
+    blobs::{blob_key, Blob, BlobStore},
+    database::{DBFile, DBFileError, DBFileStore},
+    storage::{Bucket, FileError},
+    FileStorage,
+};
+
+use super::catalog;
+
+#[derive(Debug, Error)]
+pub enum BlobAPIError {
+    #[error("file storage error")]
+    StorageError(#[from] FileError),
+
+    #[error("database error")]
+    DatabaseError(#[from] DBFileError),
+}
+
+pub type BlobAPIResult<T> = std::result::Result<T, BlobAPIError>;
+type Result<T> = BlobAPIResult<T>;
+
+/// Returns the key of the stored content of a file. Deduplicated files only leave an empty
+/// object at their own path, their content is read from their blob.
+#[must_use]
+pub fn content_key(file: &DBFile) -> String {
+    file.blob_id.map_or_else(|| file.path.clone(), blob_key)
+}
+
+/// Returns the hex encoded SHA-256 digest of the content of an object.
+async fn hash_object(
+    file_storage: &impl FileStorage,
+    bucket: Bucket,
+    key: &str,
+) -> std::result::Result<String, FileError> {
+    let mut body = file_storage.get_object(bucket, key).await?.body;
+    let mut hasher = Sha256::new();
+    while let Some(chunk) = body
+        .try_next()
+        .await
+        .map_err(|e| FileError::Other(Box::new(e)))?
+    {
+        hasher.update(&chunk);
+    }
+    Ok(hex::encode(hasher.finalize()))
+}
+
+/// Adds a reference to the blob with the given content hash, if there is one.
+async fn find_blob(store: &mut impl BlobStore, bucket: Bucket, hash: &str) -> Result<Option<Blob>> {
+    let Some(blob) = store.get_blob_by_hash(bucket, hash).await? else {
+        return Ok(None);
+    };
+    // The blob could have been collected since it was found
+    Ok(store.reference_blob(blob.id).await?)
+}
+
+/// Adds a reference to the blob with the content of `file`, which was hashed to `hash`. If there
+/// is no such blob yet, the content is copied into a new one. Returns `None` if the content was
+/// changed while it was copied.
+async fn acquire_blob(
+    file_storage: &mut impl FileStorage,
+    store: &mut impl BlobStore,
+    file: &DBFile,
+    hash: &str,
+) -> Result<Option<Blob>> {
+    if let Some(blob) = find_blob(store, file.bucket, hash).await? {
+        return Ok(Some(blob));
+    }
+    let blob = Blob::new(file.bucket, hash.to_owned(), file.size);
+    file_storage
+        .copy_object(file.bucket, &file.path, &blob.key())
+        .await?;
+    // The object could have been overwritten since it was hashed, so the copy is hashed as well
+    if hash_object(file_storage, blob.bucket, &blob.key()).await? != hash {
+        file_storage.delete_file(blob.bucket, &blob.key()).await?;
+        return Ok(None);
+    }
+    let added = store.add_blob(&blob).await?;
+    if added.id != blob.id {
+        // Another file with the same content got its blob in the meantime
+        file_storage.delete_file(blob.bucket, &blob.key()).await?;
+    }
+    Ok(Some(added))
+}
+
+/// Points a file at a blob it holds a reference to and empties the object at its path. The
+/// catalog entry is only updated if the file still has the `checksum` it was hashed with, and
+/// the object is only emptied after that, so a file which was changed in the meantime keeps its
+/// content and the reference is given back.
+async fn swap_blob(
+    file_storage: &mut impl FileStorage,
+    store: &mut (impl DBFileStore + BlobStore),
+    file: &DBFile,
+    checksum: &str,
+    blob: &Blob,
+) -> Result<Option<DBFile>> {
+    let Some(file) = store.set_dbfile_blob(file.id.0, checksum, blob.id).await? else {
+        store.release_blob(blob.id).await?;
+        return Ok(None);
+    };
+    file_storage
+        .upload(file.bucket, &file.path, Vec::new())
+        .await?;
+    Ok(Some(file))
+}
+
+/// Moves the content of a file into the blob for its content, which is shared by every file with
+/// the same content, and empties the object at the path of the file. The content is hashed here
+/// instead of when the file is stored, so only this job reads it. Returns `None` if the file was
+/// changed since it was registered, so it's left as it is.
+#[tracing::instrument(skip(file_storage, store), err(Debug))]
+pub async fn deduplicate(
+    file_storage: &mut impl FileStorage,
+    store: &mut (impl DBFileStore + BlobStore),
+    file: &DBFile,
+) -> Result<Option<DBFile>> {
+    if file.is_folder || file.blob_id.is_some() || file.is_locked() {
+        return Ok(None);
+    }
+    // Without a checksum, a change of the content couldn't be noticed
+    let Some(checksum) = file.checksum.as_deref() else {
+        return Ok(None);
+    };
+    let meta = file_storage.head_object(file.bucket, &file.path).await?;
+    if catalog::stored_checksum(&meta).as_deref() != Some(checksum) {
+        return Ok(None);
+    }
+    let hash = hash_object(file_storage, file.bucket, &file.path).await?;
+    let Some(blob) = acquire_blob(file_storage, store, file, &hash).await? else {
+        return Ok(None);
+    };
+    swap_blob(file_storage, store, file, checksum, &blob).await
+}
+
+/// Deduplicates every file in the `UserFiles` bucket, which is at least `min_size` bytes large
+/// and isn't deduplicated yet. A file which can't be deduplicated is skipped, so it's retried on
+/// the next run. Returns the number of deduplicated files.
+#[tracing::instrument(skip(file_storage, store))]
+pub async fn deduplicate_files(
+    mut file_storage: impl FileStorage,
+    mut store: impl DBFileStore + BlobStore,
+    min_size: u64,
+) -> Result<usize> {
+    let min_size = min_size.try_into().unwrap_or(i64::MAX);
+    let mut deduplicated = 0;
+    let files = store
+        .get_dbfiles_without_blob(Bucket::UserFiles, min_size)
+        .await?;
+    for file in files {
+        match deduplicate(&mut file_storage, &mut store, &file).await {
+            Ok(Some(_)) => deduplicated += 1,
+            Ok(None) => {}
+            Err(e) => error!("unable to deduplicate file {}: {e:?}", file.id.0),
+        }
+    }
+    Ok(deduplicated)
+}
+
+/// Adds a reference to the blob of a file, which was copied with its blob.
+pub async fn reference(store: &mut impl BlobStore, file: &DBFile) -> Result<()> {
+    if let Some(id) = file.blob_id {
+        store.reference_blob(id).await?;
+    }
+    Ok(())
+}
+
+/// Removes the reference of a file from its blob, once the file doesn't point at it anymore.
+pub async fn release(store: &mut impl BlobStore, file: &DBFile) -> Result<()> {
+    if let Some(id) = file.blob_id {
+        store.release_blob(id).await?;
+    }
+    Ok(())
+}
+
+/// Deletes every blob without references together with its content. A blob which can't be
+/// deleted is skipped, so it's retried on the next run. Returns the number of deleted blobs.
+#[tracing::instrument(skip(file_storage, store))]
+pub async fn collect_garbage(
+    mut file_storage: impl FileStorage,
+    mut store: impl BlobStore,
+) -> Result<usize> {
+    let mut collected = 0;
+    let blobs = store.get_unreferenced_blobs().await?;
+    for blob in blobs {
+        // The blob is gone before its content, so a file can't get a reference to a blob whose
+        // content is being deleted
+        let deleted = match store.delete_blob(blob.id).await {
+            Ok(deleted) => deleted,
+            Err(e) => {
+                error!("unable to delete blob {}: {e:?}", blob.id);
+                continue;
+            }
+        };
+        // The blob was referenced again in the meantime
+        let Some(deleted) = deleted else {
+            continue;
+        };
+        match file_storage
+            .delete_file(deleted.bucket, &deleted.key())
+            .await
+        {
+            Ok(()) => collected += 1,
+            Err(e) => error!("unable to delete blob {}: {e:?}", blob.id),
+        }
+    }
+    Ok(collected)
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::TryStreamExt;
+
+    use crate::{
+        connectors::memory::MemStore,
+        handler::files::{
+            catalog::register_object,
+            trash::{move_to_trash, purge},
+            userfiles::build_path,
+        },
+        stores::{files::storage::Object, Uuid},
+    };
+
+    use super::*;
+
+    async fn read(object: Object) -> Vec<u8> {
+        let mut body = object.body;
+        let mut data = Vec::new();
+        while let Some(chunk) = body.try_next().await.unwrap() {
+            data.extend_from_slice(&chunk);
+        }
+        data
+    }
+
+    async fn write(store: &MemStore, user_id: Uuid, path: &str, data: &[u8]) -> DBFile {
+        let path = build_path(user_id, path);
+        store
+            .clone()
+            .upload(Bucket::UserFiles, &path, data.to_vec())
+            .await
+            .unwrap();
+        register_object(store, &mut store.clone(), Bucket::UserFiles, &path, user_id)
+            .await
+            .unwrap()
+    }
+
+    async fn get_file(store: &MemStore, file: &DBFile) -> DBFile {
+        store.get_dbfile(file.id.0).await.unwrap().unwrap()
+    }
+
+    #[tokio::test]
+    async fn share_and_collect_blobs() {
+        let (alice, bob) = (Uuid::new_v4(), Uuid::new_v4());
+        let store = MemStore::new();
+        let content = b"the same large file";
+        let first = write(&store, alice, "a.bin", content).await;
+        let second = write(&store, bob, "b.bin", content).await;
+        let small = write(&store, bob, "c.txt", b"small").await;
+
+        let min_size = content.len() as u64;
+        let deduplicated = deduplicate_files(store.clone(), store.clone(), min_size).await;
+        assert_eq!(deduplicated.unwrap(), 2);
+        let (first, second) = (
+            get_file(&store, &first).await,
+            get_file(&store, &second).await,
+        );
+        assert!(first.blob_id.is_some());
+        assert_eq!(first.blob_id, second.blob_id);
+        assert!(get_file(&store, &small).await.blob_id.is_none());
+        assert_eq!(first.size, content.len() as i64);
+        let placeholder = store.get_object(Bucket::UserFiles, &first.path).await;
+        assert_eq!(placeholder.unwrap().size, 0);
+        let key = content_key(&second);
+        let blob = store.get_object(Bucket::UserFiles, &key);
+        assert_eq!(read(blob.await.unwrap()).await, content);
+
+        // A new file with the same content is linked to the existing blob
+        let third = write(&store, alice, "c.bin", content).await;
+        let linked = deduplicate(&mut store.clone(), &mut store.clone(), &third).await;
+        assert_eq!(linked.unwrap().unwrap().blob_id, first.blob_id);
+        let placeholder = store.get_object(Bucket::UserFiles, &third.path).await;
+        assert_eq!(placeholder.unwrap().size, 0);
+
+        // Overwriting files releases their blob, the other file still points at it
+        let first = write(&store, alice, "a.bin", b"new content").await;
+        assert!(first.blob_id.is_none());
+        write(&store, alice, "c.bin", b"new content").await;
+        assert_eq!(
+            collect_garbage(store.clone(), store.clone()).await.unwrap(),
+            0
+        );
+
+        // A file which was changed since it was registered keeps its content
+        let stale = write(&store, bob, "d.bin", content).await;
+        write(&store, bob, "d.bin", b"other content").await;
+        let res = deduplicate(&mut store.clone(), &mut store.clone(), &stale).await;
+        assert!(res.unwrap().is_none());
+        let changed = store.get_object(Bucket::UserFiles, &stale.path).await;
+        assert_eq!(read(changed.unwrap()).await, b"other content");
+        assert!(get_file(&store, &stale).await.blob_id.is_none());
+
+        let path = build_path(bob, "b.bin");
+        let entry = move_to_trash(&mut store.clone(), &mut store.clone(), bob, &path, bob)
+            .await
+            .unwrap();
+        // Files in the trash keep their blob, until they're purged
+        assert_eq!(
+            collect_garbage(store.clone(), store.clone()).await.unwrap(),
+            0
+        );
+        purge(store.clone(), store.clone(), bob, entry.id)
+            .await
+            .unwrap();
+        assert_eq!(
+            collect_garbage(store.clone(), store.clone()).await.unwrap(),
+            1
+        );
+        assert!(matches!(
+            store
+                .head_object(Bucket::UserFiles, &content_key(&second))
+                .await,
+            Err(FileError::NotFound(_))
+        ));
+    }
+}
@@ -0,0 +1,325 @@
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use time::OffsetDateTime;
+use utoipa::ToSchema;
+
+use crate::stores::{
+    files::{
+        blobs::BlobStore,
+        database::{DBFile, DBFileError, DBFileStore, LeaseID},
+        quota::QuotaStore,
+        storage::{Bucket, FileError, ObjectMeta},
+        FileStorage,
+    },
+    Uuid,
+};
+
+use super::{
+    blobs::{self, content_key, BlobAPIError},
+    quota,
+    userfiles::build_path,
+};
+
+#[derive(Debug, Error)]
+pub enum CatalogAPIError {
+    #[error("file storage error")]
+    StorageError(#[from] FileError),
+
+    #[error("database error")]
+    DatabaseError(#[from] DBFileError),
+
+    #[error("blob error")]
+    BlobError(#[from] BlobAPIError),
+
+    #[error("file {0} not found")]
+    NotFound(Uuid),
+}
+
+pub type CatalogAPIResult<T> = std::result::Result<T, CatalogAPIError>;
+type Result<T> = CatalogAPIResult<T>;
+
+/// Metadata of a file in the catalog, as it is shown to its users.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct FileInfo {
+    pub id: LeaseID,
+    /// Path of the file relative to the folder of the user
+    pub path: String,
+    pub bucket: Bucket,
+    pub size: i64,
+    pub mime_type: Option<String>,
+    pub checksum: Option<String>,
+    pub is_folder: bool,
+    pub parent_id: Option<LeaseID>,
+    pub created_by: Uuid,
+    #[serde(with = "time::serde::iso8601")]
+    pub created_at: OffsetDateTime,
+}
+
+impl FileInfo {
+    #[must_use]
+    pub fn new(file: DBFile, user_id: Uuid) -> Self {
+        let user_folder = build_path(user_id, "");
+        let path = match file.path.strip_prefix(&user_folder) {
+            Some(path) => path.to_owned(),
+            None => file.path,
+        };
+        Self {
+            id: file.id,
+            path,
+            bucket: file.bucket,
+            size: file.size,
+            mime_type: file.mime_type,
+            checksum: file.checksum,
+            is_folder: file.is_folder,
+            parent_id: file.parent_id,
+            created_by: file.created_by,
+            created_at: file.created_at,
+        }
+    }
+}
+
+/// Guesses the MIME type of a file from the extension of its path.
+#[must_use]
+pub fn guess_mime_type(path: &str) -> Option<String> {
+    let name = path.rsplit('\\').next().unwrap_or(path);
+    mime_guess::from_path(name)
+        .first_raw()
+        .map(ToOwned::to_owned)
+}
+
+/// Returns the checksum of a stored object as the file storage reports it, without reading its
+/// content. This is the checksum of the declared part checksums for verified uploads and the
+/// `ETag` otherwise, so it changes with the content.
+#[must_use]
+pub fn stored_checksum(meta: &ObjectMeta) -> Option<String> {
+    meta.checksum.clone().or_else(|| {
+        meta.e_tag
+            .as_ref()
+            .map(|e_tag| e_tag.trim_matches('"').to_owned())
+    })
+}
+
+/// Returns the folder containing the entry at `path`, or `None` for the folder of a user.
+#[must_use]
+pub fn parent_path(path: &str) -> Option<&str> {
+    path.rsplit_once('\\').map(|(parent, _)| parent)
+}
+
+/// Fails if the entry at `path` or a file inside it is locked through WOPI, so it isn't moved away
+/// from under an editing session.
+pub async fn check_unlocked(file_db: &impl DBFileStore, bucket: Bucket, path: &str) -> Result<()> {
+    let files = file_db.get_dbfiles_below(bucket, path).await?;
+    if let Some(lock) = files.iter().find_map(DBFile::current_lock) {
+        return Err(DBFileError::Locked(Some(lock.clone())).into());
+    }
+    Ok(())
+}
+
+/// Returns the id of the folder at `path`, after adding it and every missing folder above it to
+/// the catalog.
+#[tracing::instrument(skip(file_db), err(Debug))]
+pub async fn ensure_folder(
+    file_db: &mut impl DBFileStore,
+    bucket: Bucket,
+    path: &str,
+    owner: Uuid,
+) -> Result<LeaseID> {
+    let mut parent_id = None;
+    let mut end = 0;
+    loop {
+        end = path[end..].find('\\').map_or(path.len(), |i| end + i);
+        let folder_path = &path[..end];
+        let folder = match file_db.get_dbfile_by_path(bucket, folder_path).await? {
+            Some(folder) => folder,
+            None => {
+                let folder = DBFile {
+                    id: LeaseID(Uuid::new_v4()),
+                    path: folder_path.to_owned(),
+                    bucket,
+                    size: 0,
+                    mime_type: None,
+                    checksum: None,
+                    is_folder: true,
+                    parent_id,
+                    lock: None,
+                    lock_expires_at: None,
+                    created_by: owner,
+                    created_at: OffsetDateTime::now_utc(),
+                    blob_id: None,
+                };
+                file_db.register_dbfile(&folder).await?
+            }
+        };
+        if end == path.len() {
+            return Ok(folder.id);
+        }
+        parent_id = Some(folder.id);
+        end += 1;
+    }
+}
+
+/// Adds a stored object to the catalog, or updates its catalog entry if it's already registered.
+/// The metadata and the checksum are read from the file storage, so they match the stored
+/// content without reading it. The owner of the file is charged for the change in size. The new
+/// content is stored at the path of the file, so a deduplicated file doesn't point at its blob
+/// anymore.
+#[tracing::instrument(skip(file_storage, file_db), err(Debug))]
+pub async fn register_object(
+    file_storage: &impl FileStorage,
+    file_db: &mut (impl DBFileStore + QuotaStore + BlobStore),
+    bucket: Bucket,
+    path: &str,
+    owner: Uuid,
+) -> Result<DBFile> {
+    let meta = file_storage.head_object(bucket, path).await?;
+    let previous = file_db.get_dbfile_by_path(bucket, path).await?;
+    let previous_size = previous.as_ref().map_or(0, |f| f.size);
+    let parent_id = match parent_path(path) {
+        Some(parent) => Some(ensure_folder(file_db, bucket, parent, owner).await?),
+        None => None,
+    };
+    let file = DBFile {
+        id: LeaseID(Uuid::new_v4()),
+        path: path.to_owned(),
+        bucket,
+        size: meta.size.try_into().unwrap_or(i64::MAX),
+        mime_type: guess_mime_type(path),
+        checksum: stored_checksum(&meta),
+        is_folder: false,
+        parent_id,
+        lock: None,
+        lock_expires_at: None,
+        created_by: owner,
+        created_at: OffsetDateTime::now_utc(),
+        blob_id: None,
+    };
+    // An overwritten file keeps its owner, who is charged instead of the writer
+    let file = file_db.register_dbfile(&file).await?;
+    if let Some(previous) = previous {
+        blobs::release(file_db, &previous).await?;
+    }
+    quota::charge(file_db, file.created_by, file.size - previous_size).await?;
+    Ok(file)
+}
+
+/// Returns a file the user has access to. Files without access are reported as missing, so
+/// their existence isn't leaked.
+pub async fn get_accessible(
+    file_db: &impl DBFileStore,
+    user_id: Uuid,
+    file_id: Uuid,
+) -> Result<DBFile> {
+    file_db
+        .get_dbfile(file_id)
+        .await?
+        .filter(|f| f.permission(user_id).is_some())
+        .ok_or(CatalogAPIError::NotFound(file_id))
+}
+
+#[tracing::instrument(skip(file_db))]
+pub async fn get_file_info(
+    file_db: impl DBFileStore,
+    user_id: Uuid,
+    file_id: Uuid,
+) -> Result<FileInfo> {
+    let file = get_accessible(&file_db, user_id, file_id).await?;
+    Ok(FileInfo::new(file, user_id))
+}
+
+/// Returns a download url for the file with the given id.
+#[tracing::instrument(skip(file_storage, file_db))]
+pub async fn download_file(
+    file_storage: impl FileStorage,
+    file_db: impl DBFileStore,
+    user_id: Uuid,
+    file_id: Uuid,
+) -> Result<String> {
+    let file = get_accessible(&file_db, user_id, file_id).await?;
+    Ok(file_storage
+        .get_download_url(file.bucket, &content_key(&file))
+        .await?)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{connectors::memory::MemStore, stores::users::User};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn register_keeps_id() {
+        let mut store = MemStore::new();
+        let user = User::template();
+        let path = build_path(user.id, "docs\\a.txt");
+        store
+            .upload(Bucket::UserFiles, &path, b"first".to_vec())
+            .await
+            .unwrap();
+        let file = register_object(
+            &store,
+            &mut store.clone(),
+            Bucket::UserFiles,
+            &path,
+            user.id,
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            (file.size, file.mime_type.as_deref()),
+            (5, Some("text/plain"))
+        );
+
+        store
+            .upload(Bucket::UserFiles, &path, b"second".to_vec())
+            .await
+            .unwrap();
+        let updated = register_object(
+            &store,
+            &mut store.clone(),
+            Bucket::UserFiles,
+            &path,
+            user.id,
+        )
+        .await
+        .unwrap();
+        assert_eq!((updated.id, updated.size), (file.id, 6));
+        let meta = store.head_object(Bucket::UserFiles, &path).await.unwrap();
+        assert_eq!(updated.checksum, stored_checksum(&meta));
+        assert_ne!(updated.checksum, file.checksum);
+        // The owner is only charged for the current size
+        assert_eq!(store.get_usage(user.id).await.unwrap(), 6);
+
+        let info = get_file_info(store.clone(), user.id, file.id.0)
+            .await
+            .unwrap();
+        assert_eq!(info.path, "docs\\a.txt");
+        let folder = store
+            .get_dbfile_by_path(Bucket::UserFiles, &build_path(user.id, "docs"))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(folder.is_folder);
+        assert_eq!(info.parent_id, Some(folder.id));
+        // An object at the path of a folder doesn't replace the folder
+        let folder_path = build_path(user.id, "docs");
+        store
+            .upload(Bucket::UserFiles, &folder_path, b"file".to_vec())
+            .await
+            .unwrap();
+        assert!(matches!(
+            register_object(
+                &store,
+                &mut store.clone(),
+                Bucket::UserFiles,
+                &folder_path,
+                user.id
+            )
+            .await,
+            Err(CatalogAPIError::DatabaseError(DBFileError::Conflict(_)))
+        ));
+        assert!(matches!(
+            get_file_info(store, Uuid::new_v4(), file.id.0).await,
+            Err(CatalogAPIError::NotFound(_))
+        ));
+    }
+}
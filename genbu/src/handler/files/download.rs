@@ -1,17 +1,23 @@
 use serde::{Deserialize, Serialize};
-use std::fmt::Debug;
+use std::{fmt::Debug, ops::Range};
 use thiserror::Error;
+use time::OffsetDateTime;
 use utoipa::{IntoParams, ToSchema};
 
 use crate::stores::{
     files::{
-        storage::{Bucket, FileError},
+        database::{DBFile, DBFileError, DBFileStore},
+        storage::{Bucket, FileError, Object, ObjectMeta},
         FileStorage,
     },
     Uuid,
 };
 
-use super::userfiles::build_path;
+use super::{
+    blobs::content_key,
+    catalog::{get_accessible, CatalogAPIError},
+    userfiles::build_path,
+};
 
 pub type DownloadAPIResult<T> = std::result::Result<T, DownloadAPIError>;
 type Result<T> = DownloadAPIResult<T>;
@@ -21,6 +27,12 @@ pub enum DownloadAPIError {
     #[error("file storage error")]
     StorageError(#[from] FileError),
 
+    #[error("database error")]
+    DatabaseError(#[from] DBFileError),
+
+    #[error("catalog error")]
+    CatalogError(#[from] CatalogAPIError),
+
     #[error("file not found")]
     NotFound(Box<dyn Debug + Send + Sync>),
 
@@ -34,15 +46,345 @@ pub struct StartDownloadRequest {
     bucket: Bucket,
 }
 
-#[tracing::instrument(skip(file_storage))]
+/// Returns a download url for a file of the user. The content of deduplicated files is
+/// downloaded from their blob.
+#[tracing::instrument(skip(file_storage, file_db))]
 pub async fn start_download(
     file_storage: impl FileStorage,
+    file_db: impl DBFileStore,
     user_id: Uuid,
     req: StartDownloadRequest,
 ) -> Result<String> {
     let path = build_path(user_id, &req.file_path);
+    let key = file_db
+        .get_dbfile_by_path(req.bucket, &path)
+        .await?
+        .map_or(path, |file| content_key(&file));
     Ok(match req.bucket {
-        Bucket::UserFiles => file_storage.get_download_url(req.bucket, &path).await?,
+        Bucket::UserFiles => file_storage.get_download_url(req.bucket, &key).await?,
         _ => unimplemented!(),
     })
 }
+
+/// Streams the object behind a presigned download url of a file storage without native
+/// presigning.
+#[tracing::instrument(skip_all)]
+pub async fn get_signed(file_storage: impl FileStorage, token: &str) -> Result<Object> {
+    Ok(file_storage.get_signed(token).await?)
+}
+
+/// How a browser presents a downloaded file.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Disposition {
+    /// Shown inside the browser, e.g. as a preview or in a video player
+    Inline,
+    /// Saved as a file
+    #[default]
+    Attachment,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema, IntoParams)]
+pub struct ProxyDownloadRequest {
+    #[serde(default)]
+    pub disposition: Disposition,
+}
+
+/// The conditional and range headers of a download request, as they were sent.
+#[derive(Debug, Clone, Default)]
+pub struct DownloadConditions {
+    pub if_none_match: Option<String>,
+    pub if_modified_since: Option<String>,
+    pub if_range: Option<String>,
+    pub range: Option<String>,
+}
+
+/// Metadata of the content of a file, which is sent along with the content.
+#[derive(Debug, Clone)]
+pub struct ContentInfo {
+    /// Name of the file without its folder
+    pub name: String,
+    pub mime_type: Option<String>,
+    /// Size of the whole content
+    pub size: u64,
+    /// Strong entity tag of the content without quotes
+    pub e_tag: String,
+    pub last_modified: Option<OffsetDateTime>,
+}
+
+impl ContentInfo {
+    fn new(file: &DBFile, meta: ObjectMeta) -> Self {
+        // File storages without `ETag`s get one from the size and modification time, like most
+        // web servers do for static files
+        let e_tag = meta.e_tag.map_or_else(
+            || {
+                let modified = meta
+                    .last_modified
+                    .map_or(0, OffsetDateTime::unix_timestamp_nanos);
+                format!("{:x}-{modified:x}", meta.size)
+            },
+            |e_tag| e_tag.trim_matches('"').to_owned(),
+        );
+        Self {
+            name: file.path.rsplit('\\').next().unwrap_or_default().to_owned(),
+            mime_type: file.mime_type.clone(),
+            size: meta.size,
+            e_tag,
+            last_modified: meta.last_modified,
+        }
+    }
+}
+
+pub enum ProxiedContent {
+    /// The cached content of the client is still up to date
+    NotModified,
+    Full(Object),
+    /// The requested range of the content, which is cut off at the end of the content
+    Partial(Range<u64>, Object),
+    /// The requested range starts behind the end of the content
+    Unsatisfiable,
+}
+
+pub struct ProxiedDownload {
+    pub info: ContentInfo,
+    pub content: ProxiedContent,
+}
+
+struct UnsatisfiableRange;
+
+fn parse_http_date(date: &str) -> Option<OffsetDateTime> {
+    httpdate::parse_http_date(date.trim())
+        .ok()
+        .map(OffsetDateTime::from)
+}
+
+/// Compares the entity tags of an `If-None-Match` header with the weak comparison, which ignores
+/// the `W/` prefix.
+fn matches_e_tag(e_tags: &str, e_tag: &str) -> bool {
+    e_tags.trim() == "*"
+        || e_tags.split(',').any(|tag| {
+            let tag = tag.trim();
+            tag.strip_prefix("W/").unwrap_or(tag).trim_matches('"') == e_tag
+        })
+}
+
+/// Parses the value of a `Range` header. Returns `Ok(None)` if the whole content has to be sent,
+/// because the header is malformed or asks for several ranges, which would need a multipart
+/// response.
+fn byte_range(
+    range: &str,
+    size: u64,
+) -> std::result::Result<Option<Range<u64>>, UnsatisfiableRange> {
+    let Some((first, last)) = range
+        .trim()
+        .strip_prefix("bytes=")
+        .filter(|spec| !spec.contains(','))
+        .and_then(|spec| spec.split_once('-'))
+    else {
+        return Ok(None);
+    };
+    let range = match (first.trim(), last.trim()) {
+        // The last `len` bytes
+        ("", len) => match len.parse::<u64>() {
+            Ok(0) => return Err(UnsatisfiableRange),
+            Ok(len) => size.saturating_sub(len)..size,
+            Err(_) => return Ok(None),
+        },
+        (first, "") => match first.parse::<u64>() {
+            Ok(first) => first..size,
+            Err(_) => return Ok(None),
+        },
+        (first, last) => match (first.parse::<u64>(), last.parse::<u64>()) {
+            (Ok(first), Ok(last)) if first <= last => first..last.saturating_add(1).min(size),
+            _ => return Ok(None),
+        },
+    };
+    if range.start >= size {
+        return Err(UnsatisfiableRange);
+    }
+    Ok(Some(range))
+}
+
+impl DownloadConditions {
+    /// Checks whether the cached content of the client is still up to date. `If-Modified-Since`
+    /// is only evaluated without `If-None-Match`.
+    fn not_modified(&self, info: &ContentInfo) -> bool {
+        if let Some(e_tags) = &self.if_none_match {
+            return matches_e_tag(e_tags, &info.e_tag);
+        }
+        let since = self.if_modified_since.as_deref().and_then(parse_http_date);
+        match (since, info.last_modified) {
+            (Some(since), Some(modified)) => modified.unix_timestamp() <= since.unix_timestamp(),
+            _ => false,
+        }
+    }
+
+    /// Checks the `If-Range` header, which only allows a range of the content if the client
+    /// still has the same content. Weak entity tags never match.
+    fn range_applies(&self, info: &ContentInfo) -> bool {
+        match self.if_range.as_deref().map(str::trim) {
+            None => true,
+            Some(e_tag) if e_tag.starts_with("W/") => false,
+            Some(e_tag) if e_tag.starts_with('"') => e_tag.trim_matches('"') == info.e_tag,
+            Some(date) => parse_http_date(date)
+                .zip(info.last_modified)
+                .is_some_and(|(date, modified)| date.unix_timestamp() == modified.unix_timestamp()),
+        }
+    }
+}
+
+/// Streams the content of a file through the server instead of redirecting to the file storage,
+/// so clients which can't reach the file storage can download it. Conditional requests are
+/// answered with [`ProxiedContent::NotModified`] and ranges are read from the file storage
+/// without the rest of the content, which lets browsers cache files and seek in videos.
+#[tracing::instrument(skip(file_storage, file_db))]
+pub async fn proxy_download(
+    file_storage: impl FileStorage,
+    file_db: impl DBFileStore,
+    user_id: Uuid,
+    file_id: Uuid,
+    conditions: DownloadConditions,
+) -> Result<ProxiedDownload> {
+    let file = get_accessible(&file_db, user_id, file_id).await?;
+    if file.is_folder {
+        return Err(DownloadAPIError::NotFound(Box::new(file_id)));
+    }
+    let key = content_key(&file);
+    let meta = file_storage.head_object(file.bucket, &key).await?;
+    let info = ContentInfo::new(&file, meta);
+    if conditions.not_modified(&info) {
+        return Ok(ProxiedDownload {
+            info,
+            content: ProxiedContent::NotModified,
+        });
+    }
+    let range = match conditions.range.as_deref() {
+        Some(range) if conditions.range_applies(&info) => byte_range(range, info.size),
+        _ => Ok(None),
+    };
+    let content = match range {
+        Ok(Some(range)) => {
+            let object = file_storage
+                .get_object_range(file.bucket, &key, range.clone())
+                .await?;
+            ProxiedContent::Partial(range, object)
+        }
+        Ok(None) => ProxiedContent::Full(file_storage.get_object(file.bucket, &key).await?),
+        Err(UnsatisfiableRange) => ProxiedContent::Unsatisfiable,
+    };
+    Ok(ProxiedDownload { info, content })
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::TryStreamExt;
+
+    use crate::{connectors::memory::MemStore, handler::files::catalog::register_object};
+
+    use super::*;
+
+    async fn read(object: Object) -> Vec<u8> {
+        let data: Vec<_> = object.body.try_collect().await.unwrap();
+        data.concat()
+    }
+
+    #[test]
+    fn parse_byte_ranges() {
+        for (range, expected) in [
+            ("bytes=0-3", Some(0..4)),
+            ("bytes=5-", Some(5..10)),
+            ("bytes=-3", Some(7..10)),
+            ("bytes=-30", Some(0..10)),
+            ("bytes=8-20", Some(8..10)),
+            ("bytes=4-2", None),
+            ("bytes=0-1,4-5", None),
+            ("items=0-3", None),
+        ] {
+            assert_eq!(byte_range(range, 10).ok(), Some(expected), "{range}");
+        }
+        for range in ["bytes=10-", "bytes=-0"] {
+            assert!(byte_range(range, 10).is_err(), "{range}");
+        }
+    }
+
+    #[tokio::test]
+    async fn conditional_range_downloads() {
+        let user_id = Uuid::new_v4();
+        let mut store = MemStore::new();
+        let path = build_path(user_id, "video.mp4");
+        store
+            .upload(Bucket::UserFiles, &path, b"0123456789".to_vec())
+            .await
+            .unwrap();
+        let file = register_object(
+            &store,
+            &mut store.clone(),
+            Bucket::UserFiles,
+            &path,
+            user_id,
+        )
+        .await
+        .unwrap();
+        let download = |conditions| {
+            proxy_download(store.clone(), store.clone(), user_id, file.id.0, conditions)
+        };
+
+        let full = download(DownloadConditions::default()).await.unwrap();
+        assert_eq!(full.info.name, "video.mp4");
+        let ProxiedContent::Full(object) = full.content else {
+            panic!("expected the whole content");
+        };
+        assert_eq!(read(object).await, b"0123456789");
+
+        let e_tag = format!("\"{}\"", full.info.e_tag);
+        let cached = download(DownloadConditions {
+            if_none_match: Some(format!("\"other\", W/{e_tag}")),
+            ..Default::default()
+        });
+        assert!(matches!(
+            cached.await.unwrap().content,
+            ProxiedContent::NotModified
+        ));
+
+        let partial = download(DownloadConditions {
+            range: Some("bytes=2-4".to_owned()),
+            if_range: Some(e_tag),
+            ..Default::default()
+        });
+        let ProxiedContent::Partial(range, object) = partial.await.unwrap().content else {
+            panic!("expected a range of the content");
+        };
+        assert_eq!((range, read(object).await), (2..5, b"234".to_vec()));
+
+        // The client has an outdated version, so it gets the whole content
+        let outdated = download(DownloadConditions {
+            range: Some("bytes=2-4".to_owned()),
+            if_range: Some("\"outdated\"".to_owned()),
+            ..Default::default()
+        });
+        assert!(matches!(
+            outdated.await.unwrap().content,
+            ProxiedContent::Full(_)
+        ));
+
+        let unsatisfiable = download(DownloadConditions {
+            range: Some("bytes=10-".to_owned()),
+            ..Default::default()
+        });
+        assert!(matches!(
+            unsatisfiable.await.unwrap().content,
+            ProxiedContent::Unsatisfiable
+        ));
+        assert!(matches!(
+            proxy_download(
+                store.clone(),
+                store,
+                Uuid::new_v4(),
+                file.id.0,
+                DownloadConditions::default()
+            )
+            .await,
+            Err(DownloadAPIError::CatalogError(CatalogAPIError::NotFound(_)))
+        ));
+    }
+}
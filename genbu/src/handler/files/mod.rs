@@ -1,4 +1,11 @@
+pub mod archive;
+pub mod blobs;
+pub mod catalog;
 pub mod download;
+pub mod quota;
+pub mod trash;
+pub mod tus;
 pub mod upload;
 pub mod userfiles;
+pub mod versions;
 pub mod wopi;
@@ -0,0 +1,238 @@
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use utoipa::ToSchema;
+
+use crate::{
+    config::QuotaConfig,
+    stores::{
+        files::{
+            database::{DBFileError, FileResult},
+            quota::QuotaStore,
+        },
+        groups::GroupStore,
+        users::UserError,
+        Uuid,
+    },
+};
+
+#[derive(Debug, Error)]
+pub enum QuotaAPIError {
+    #[error("database error")]
+    DatabaseError(#[from] DBFileError),
+
+    #[error("unable to read the groups of the user")]
+    GroupError(#[from] UserError),
+
+    #[error("{0} bytes exceed the available storage of {1} bytes")]
+    QuotaExceeded(u64, u64),
+
+    #[error("quota of {0} bytes is too large")]
+    InvalidQuota(u64),
+
+    #[error("only members of the admin group may set quotas")]
+    Forbidden,
+}
+
+/// Storage usage of a user in bytes. Files in the trash count towards the usage, until they're
+/// purged.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct StorageUsage {
+    pub used: u64,
+    /// `None` if the user has no quota
+    pub quota: Option<u64>,
+    /// Bytes the user may still upload, `None` if the user has no quota
+    pub available: Option<u64>,
+}
+
+impl StorageUsage {
+    /// Returns whether `size` more bytes fit into the quota.
+    #[must_use]
+    pub fn fits(&self, size: u64) -> bool {
+        self.available.is_none_or(|available| size <= available)
+    }
+}
+
+#[tracing::instrument(skip(store, config))]
+pub async fn get_usage(
+    store: &impl QuotaStore,
+    user_id: Uuid,
+    config: &QuotaConfig,
+) -> FileResult<StorageUsage> {
+    // Usage can only become negative if it was edited by hand
+    let used: u64 = store.get_usage(user_id).await?.try_into().unwrap_or(0);
+    let quota: Option<u64> = match store.get_quota(user_id).await? {
+        Some(quota) => Some(quota.try_into().unwrap_or(0)),
+        None => Some(config.default_bytes).filter(|quota| *quota > 0),
+    };
+    Ok(StorageUsage {
+        used,
+        quota,
+        available: quota.map(|quota| quota.saturating_sub(used)),
+    })
+}
+
+/// Checks that a user may store `delta` more bytes, before a write which is charged with
+/// [`charge`]. A negative `delta` frees storage, so it always fits.
+pub async fn check(
+    store: &impl QuotaStore,
+    user_id: Uuid,
+    delta: i64,
+    config: &QuotaConfig,
+) -> Result<(), QuotaAPIError> {
+    let Ok(size) = u64::try_from(delta) else {
+        return Ok(());
+    };
+    let usage = get_usage(store, user_id, config).await?;
+    if !usage.fits(size) {
+        return Err(QuotaAPIError::QuotaExceeded(
+            size,
+            usage.available.unwrap_or_default(),
+        ));
+    }
+    Ok(())
+}
+
+/// Quota, which is set for a user or a group.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct SetQuotaRequest {
+    /// Quota in bytes, `None` removes the quota
+    pub max_bytes: Option<u64>,
+}
+
+/// User or group, whose quota is set.
+#[derive(Debug, Clone, Copy)]
+pub enum QuotaOwner {
+    User(Uuid),
+    Group(Uuid),
+}
+
+/// Sets or removes the quota of a user or a group. Only members of the configured admin group
+/// may change quotas.
+#[tracing::instrument(skip(store, config), err(Debug))]
+pub async fn set_quota(
+    mut store: impl QuotaStore + GroupStore,
+    user_id: Uuid,
+    owner: QuotaOwner,
+    req: SetQuotaRequest,
+    config: &QuotaConfig,
+) -> Result<(), QuotaAPIError> {
+    let Some(admin_group) = &config.admin_group else {
+        return Err(QuotaAPIError::Forbidden);
+    };
+    let groups = store.get_group_names(user_id).await?;
+    if !groups
+        .iter()
+        .any(|group| group.eq_ignore_ascii_case(admin_group))
+    {
+        return Err(QuotaAPIError::Forbidden);
+    }
+    let max_bytes = req
+        .max_bytes
+        .map(|max_bytes| {
+            i64::try_from(max_bytes).map_err(|_| QuotaAPIError::InvalidQuota(max_bytes))
+        })
+        .transpose()?;
+    match owner {
+        QuotaOwner::User(id) => store.set_user_quota(id, max_bytes).await?,
+        QuotaOwner::Group(id) => store.set_group_quota(id, max_bytes).await?,
+    }
+    Ok(())
+}
+
+/// Charges the owner of a file for `delta` more bytes, a negative `delta` frees storage.
+pub async fn charge(store: &mut impl QuotaStore, owner: Uuid, delta: i64) -> FileResult<()> {
+    if delta != 0 {
+        store.add_usage(owner, delta).await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::connectors::memory::MemStore;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn usage_within_quota() {
+        let user_id = Uuid::new_v4();
+        let mut store = MemStore::new();
+        let config = QuotaConfig {
+            default_bytes: 100,
+            ..QuotaConfig::default()
+        };
+        charge(&mut store, user_id, 60).await.unwrap();
+
+        let usage = get_usage(&store, user_id, &config).await.unwrap();
+        assert_eq!(
+            (usage.used, usage.quota, usage.available),
+            (60, Some(100), Some(40))
+        );
+        assert!(usage.fits(40));
+        assert!(!usage.fits(41));
+        assert!(check(&store, user_id, 40, &config).await.is_ok());
+        assert!(matches!(
+            check(&store, user_id, 41, &config).await,
+            Err(QuotaAPIError::QuotaExceeded(41, 40))
+        ));
+        // Writes which free storage always fit
+        assert!(check(&store, user_id, -1, &config).await.is_ok());
+
+        // The quota of a user takes precedence over the default quota
+        store.set_user_quota(user_id, Some(50)).await.unwrap();
+        let usage = get_usage(&store, user_id, &config).await.unwrap();
+        assert_eq!(usage.available, Some(0));
+
+        store.set_user_quota(user_id, None).await.unwrap();
+        let usage = get_usage(&store, user_id, &QuotaConfig::default())
+            .await
+            .unwrap();
+        assert!(usage.quota.is_none() && usage.fits(u64::MAX));
+    }
+
+    #[tokio::test]
+    async fn set_quotas_as_admin() {
+        let (admin, user) = (Uuid::new_v4(), Uuid::new_v4());
+        let group_id = Uuid::new_v4();
+        let store = MemStore::new();
+        store.add_group_member(Uuid::new_v4(), "Admins", admin);
+        store.add_group_member(group_id, "staff", user);
+        let config = QuotaConfig {
+            admin_group: Some("admins".to_owned()),
+            ..QuotaConfig::default()
+        };
+        let req = |max_bytes| SetQuotaRequest { max_bytes };
+
+        let owner = QuotaOwner::Group(group_id);
+        assert!(matches!(
+            set_quota(store.clone(), user, owner, req(Some(10)), &config).await,
+            Err(QuotaAPIError::Forbidden)
+        ));
+        set_quota(store.clone(), admin, owner, req(Some(10)), &config)
+            .await
+            .unwrap();
+        // The quota of a group applies to its members
+        assert_eq!(store.get_quota(user).await.unwrap(), Some(10));
+
+        let owner = QuotaOwner::User(user);
+        assert!(matches!(
+            set_quota(store.clone(), admin, owner, req(Some(u64::MAX)), &config).await,
+            Err(QuotaAPIError::InvalidQuota(_))
+        ));
+        set_quota(store.clone(), admin, owner, req(Some(20)), &config)
+            .await
+            .unwrap();
+        assert_eq!(store.get_quota(user).await.unwrap(), Some(20));
+        set_quota(store.clone(), admin, owner, req(None), &config)
+            .await
+            .unwrap();
+        assert_eq!(store.get_quota(user).await.unwrap(), Some(10));
+
+        // Without an admin group, nobody may set quotas
+        let config = QuotaConfig::default();
+        assert!(matches!(
+            set_quota(store.clone(), admin, owner, req(None), &config).await,
+            Err(QuotaAPIError::Forbidden)
+        ));
+    }
+}
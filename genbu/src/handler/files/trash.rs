@@ -0,0 +1,365 @@
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use time::{Duration, OffsetDateTime};
+use tracing::error;
+use utoipa::ToSchema;
+
+use crate::stores::{
+    files::{
+        blobs::BlobStore,
+        database::{DBFileError, DBFileStore},
+        filesystem::{Filesystem, FilesystemError},
+        quota::QuotaStore,
+        storage::{Bucket, FileError},
+        trash::{TrashEntry, TrashStore},
+        versions::VersionStore,
+    },
+    Uuid,
+};
+
+use super::{
+    blobs::{self, BlobAPIError},
+    catalog::{check_unlocked, ensure_folder, parent_path, CatalogAPIError},
+    quota,
+    userfiles::build_path,
+    versions::{self, VersionAPIError},
+};
+
+#[derive(Debug, Error)]
+pub enum TrashAPIError {
+    #[error("filesystem error")]
+    Filesystem(#[from] FilesystemError),
+
+    #[error("file storage error")]
+    StorageError(#[from] FileError),
+
+    #[error("database error")]
+    DatabaseError(#[from] DBFileError),
+
+    #[error("catalog error")]
+    CatalogError(#[from] CatalogAPIError),
+
+    #[error("unable to delete the versions of a file")]
+    VersionError(#[from] VersionAPIError),
+
+    #[error("blob error")]
+    BlobError(#[from] BlobAPIError),
+
+    #[error("trash entry {0} not found")]
+    NotFound(Uuid),
+}
+
+pub type TrashAPIResult<T> = std::result::Result<T, TrashAPIError>;
+type Result<T> = TrashAPIResult<T>;
+
+/// A file or folder in the trash, as it is shown to its owner.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct TrashItem {
+    pub id: Uuid,
+    /// Path of the file before it was deleted, relative to the folder of the user
+    pub path: String,
+    pub is_folder: bool,
+    pub deleted_by: Uuid,
+    #[serde(with = "time::serde::iso8601")]
+    pub deleted_at: OffsetDateTime,
+}
+
+impl From<TrashEntry> for TrashItem {
+    fn from(entry: TrashEntry) -> Self {
+        let user_folder = build_path(entry.owner, "");
+        let path = match entry.original_path.strip_prefix(&user_folder) {
+            Some(path) => path.to_owned(),
+            None => entry.original_path,
+        };
+        Self {
+            id: entry.id,
+            path,
+            is_folder: entry.is_folder,
+            deleted_by: entry.deleted_by,
+            deleted_at: entry.deleted_at,
+        }
+    }
+}
+
+/// Moves the file or folder at `path` with its catalog entries into the trash of `owner`. Files
+/// which are locked through WOPI can't be deleted.
+#[tracing::instrument(skip(filesystem, store), err(Debug))]
+pub async fn move_to_trash(
+    filesystem: &mut impl Filesystem,
+    store: &mut (impl DBFileStore + TrashStore),
+    owner: Uuid,
+    path: &str,
+    deleted_by: Uuid,
+) -> Result<TrashEntry> {
+    if !filesystem.exists(path).await? {
+        return Err(FilesystemError::NotFound(path.to_owned()).into());
+    }
+    let is_folder = match filesystem.head_object(Bucket::UserFiles, path).await {
+        Ok(_) => false,
+        Err(FileError::NotFound(_)) => true,
+        Err(e) => return Err(e.into()),
+    };
+    check_unlocked(store, Bucket::UserFiles, path).await?;
+    let entry = TrashEntry::new(owner, path.to_owned(), is_folder, deleted_by);
+    filesystem.rename(path, &entry.trash_path).await?;
+    store
+        .move_dbfiles(Bucket::UserFiles, path, &entry.trash_path, None)
+        .await?;
+    Ok(store.add_trash_entry(&entry).await?)
+}
+
+/// Returns a trash entry of the user. Entries of other users are reported as missing.
+async fn get_own_entry(store: &impl TrashStore, user_id: Uuid, id: Uuid) -> Result<TrashEntry> {
+    store
+        .get_trash_entry(id)
+        .await?
+        .filter(|entry| entry.owner == user_id)
+        .ok_or(TrashAPIError::NotFound(id))
+}
+
+#[tracing::instrument(skip(store))]
+pub async fn list_trash(store: impl TrashStore, user_id: Uuid) -> Result<Vec<TrashItem>> {
+    Ok(store
+        .get_trash_by_owner(user_id)
+        .await?
+        .into_iter()
+        .map(TrashItem::from)
+        .collect())
+}
+
+/// Moves a trash entry back to its original path, which fails if the path is taken by now.
+#[tracing::instrument(skip(filesystem, store))]
+pub async fn restore(
+    mut filesystem: impl Filesystem,
+    mut store: impl DBFileStore + TrashStore,
+    user_id: Uuid,
+    id: Uuid,
+) -> Result<()> {
+    let entry = get_own_entry(&store, user_id, id).await?;
+    filesystem
+        .rename(&entry.trash_path, &entry.original_path)
+        .await?;
+    let parent_id = match parent_path(&entry.original_path) {
+        Some(parent) => {
+            Some(ensure_folder(&mut store, Bucket::UserFiles, parent, entry.owner).await?)
+        }
+        None => None,
+    };
+    store
+        .move_dbfiles(
+            Bucket::UserFiles,
+            &entry.trash_path,
+            &entry.original_path,
+            parent_id,
+        )
+        .await?;
+    store.delete_trash_entry(id).await?;
+    Ok(())
+}
+
+/// Deletes a trash entry with its content, the versions of its files and its catalog entries for
+/// good. Only then the owners of the files get their storage back and the blobs of the files lose
+/// their references.
+async fn purge_entry(
+    filesystem: &mut impl Filesystem,
+    store: &mut (impl DBFileStore + QuotaStore + TrashStore + VersionStore + BlobStore),
+    entry: &TrashEntry,
+) -> Result<()> {
+    let files = store
+        .get_dbfiles_below(Bucket::UserFiles, &entry.trash_path)
+        .await?;
+    for file in &files {
+        versions::delete_versions(filesystem, store, file.id).await?;
+    }
+    filesystem.delete(&entry.trash_path).await?;
+    store
+        .delete_dbfiles(Bucket::UserFiles, &entry.trash_path)
+        .await?;
+    for file in files {
+        quota::charge(store, file.created_by, -file.size).await?;
+        blobs::release(store, &file).await?;
+    }
+    store.delete_trash_entry(entry.id).await?;
+    Ok(())
+}
+
+#[tracing::instrument(skip(filesystem, store))]
+pub async fn purge(
+    mut filesystem: impl Filesystem,
+    mut store: impl DBFileStore + QuotaStore + TrashStore + VersionStore + BlobStore,
+    user_id: Uuid,
+    id: Uuid,
+) -> Result<()> {
+    let entry = get_own_entry(&store, user_id, id).await?;
+    purge_entry(&mut filesystem, &mut store, &entry).await
+}
+
+/// Purges every entry in the trash of the user.
+#[tracing::instrument(skip(filesystem, store))]
+pub async fn empty_trash(
+    mut filesystem: impl Filesystem,
+    mut store: impl DBFileStore + QuotaStore + TrashStore + VersionStore + BlobStore,
+    user_id: Uuid,
+) -> Result<()> {
+    let entries = store.get_trash_by_owner(user_id).await?;
+    for entry in entries {
+        purge_entry(&mut filesystem, &mut store, &entry).await?;
+    }
+    Ok(())
+}
+
+/// Purges the entries of every user, which are older than `retention`. An entry which can't be
+/// purged is skipped, so it's retried on the next run. Returns the number of purged entries.
+#[tracing::instrument(skip(filesystem, store))]
+pub async fn purge_expired(
+    mut filesystem: impl Filesystem,
+    mut store: impl DBFileStore + QuotaStore + TrashStore + VersionStore + BlobStore,
+    retention: Duration,
+) -> Result<usize> {
+    let expired = store
+        .get_expired_trash(OffsetDateTime::now_utc() - retention)
+        .await?;
+    let mut purged = 0;
+    for entry in expired {
+        match purge_entry(&mut filesystem, &mut store, &entry).await {
+            Ok(()) => purged += 1,
+            Err(e) => error!("unable to purge trash entry {}: {e:?}", entry.id),
+        }
+    }
+    Ok(purged)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        connectors::memory::MemStore,
+        handler::files::catalog::{get_accessible, register_object},
+        stores::files::{database::FileLock, FileStorage},
+    };
+
+    use super::*;
+
+    #[tokio::test]
+    async fn restore_and_purge() {
+        let user_id = Uuid::new_v4();
+        let mut store = MemStore::new();
+        let path = build_path(user_id, "docs\\a.txt");
+        store
+            .upload(Bucket::UserFiles, &path, b"content".to_vec())
+            .await
+            .unwrap();
+        let file = register_object(
+            &store,
+            &mut store.clone(),
+            Bucket::UserFiles,
+            &path,
+            user_id,
+        )
+        .await
+        .unwrap();
+
+        let docs = build_path(user_id, "docs");
+        let entry = move_to_trash(
+            &mut store.clone(),
+            &mut store.clone(),
+            user_id,
+            &docs,
+            user_id,
+        )
+        .await
+        .unwrap();
+        assert!(entry.is_folder);
+        assert!(!store.exists(&path).await.unwrap());
+        let items = list_trash(store.clone(), user_id).await.unwrap();
+        assert_eq!(items[0].path, "docs");
+
+        // The catalog entry keeps its id while it's in the trash
+        restore(store.clone(), store.clone(), user_id, entry.id)
+            .await
+            .unwrap();
+        assert!(store.exists(&path).await.unwrap());
+        assert_eq!(
+            store.get_dbfile(file.id.0).await.unwrap().unwrap().path,
+            path
+        );
+        assert!(list_trash(store.clone(), user_id).await.unwrap().is_empty());
+
+        let entry = move_to_trash(
+            &mut store.clone(),
+            &mut store.clone(),
+            user_id,
+            &path,
+            user_id,
+        )
+        .await
+        .unwrap();
+        assert!(matches!(
+            purge(store.clone(), store.clone(), Uuid::new_v4(), entry.id).await,
+            Err(TrashAPIError::NotFound(_))
+        ));
+        // Files in the trash take up storage until they're purged
+        assert_eq!(store.get_usage(user_id).await.unwrap(), 7);
+        let purged = purge_expired(store.clone(), store.clone(), Duration::ZERO)
+            .await
+            .unwrap();
+        assert_eq!(purged, 1);
+        assert!(store.get_dbfile(file.id.0).await.unwrap().is_none());
+        assert!(!store.exists(&entry.trash_path).await.unwrap());
+        assert_eq!(store.get_usage(user_id).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn keep_locked_and_hide_trashed_files() {
+        let user_id = Uuid::new_v4();
+        let mut store = MemStore::new();
+        let path = build_path(user_id, "docs\\a.txt");
+        store
+            .upload(Bucket::UserFiles, &path, b"content".to_vec())
+            .await
+            .unwrap();
+        let file = register_object(
+            &store,
+            &mut store.clone(),
+            Bucket::UserFiles,
+            &path,
+            user_id,
+        )
+        .await
+        .unwrap();
+        let lock = FileLock::new();
+        store.lock(file.id.0, lock.clone()).await.unwrap();
+
+        // A locked file can't be deleted, not even with its folder
+        let docs = build_path(user_id, "docs");
+        assert!(matches!(
+            move_to_trash(
+                &mut store.clone(),
+                &mut store.clone(),
+                user_id,
+                &docs,
+                user_id
+            )
+            .await,
+            Err(TrashAPIError::CatalogError(CatalogAPIError::DatabaseError(
+                DBFileError::Locked(_)
+            )))
+        ));
+        assert!(store.exists(&path).await.unwrap());
+
+        store.unlock(file.id.0, lock).await.unwrap();
+        move_to_trash(
+            &mut store.clone(),
+            &mut store.clone(),
+            user_id,
+            &docs,
+            user_id,
+        )
+        .await
+        .unwrap();
+        // The catalog entry is kept, but it can't be reached by its id anymore
+        assert!(matches!(
+            get_accessible(&store, user_id, file.id.0).await,
+            Err(CatalogAPIError::NotFound(_))
+        ));
+    }
+}
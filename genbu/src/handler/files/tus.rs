@@ -0,0 +1,677 @@
+use std::{collections::HashMap, sync::Arc};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use bytes::BytesMut;
+use futures::TryStreamExt;
+use parking_lot::Mutex;
+use ring::digest;
+use thiserror::Error;
+use time::{Duration, OffsetDateTime};
+use tracing::error;
+
+use crate::{
+    config::GenbuConfig,
+    stores::{
+        files::{
+            blobs::BlobStore,
+            database::{DBFileStore, LeaseID},
+            quota::QuotaStore,
+            storage::{self, FileError, ObjectStream, Part},
+            versions::VersionStore,
+            FileStorage, UploadLease, UploadLeaseStore,
+        },
+        groups::GroupStore,
+        Uuid,
+    },
+};
+
+use super::{
+    upload::{self, UploadAPIError},
+    userfiles,
+};
+
+/// Version of the tus protocol, which is implemented by these handlers.
+pub const TUS_VERSION: &str = "1.0.0";
+
+/// Extensions of the tus protocol, which are supported besides the core protocol.
+pub const TUS_EXTENSIONS: &str = "creation,termination,checksum";
+
+/// Algorithms, which can be used for the `Upload-Checksum` header.
+pub const CHECKSUM_ALGORITHMS: &str = "sha1,sha256,sha512";
+
+pub type TusAPIResult<T> = std::result::Result<T, TusAPIError>;
+
+#[derive(Debug, Error)]
+pub enum TusAPIError {
+    #[error("upload error")]
+    UploadError(#[from] UploadAPIError),
+
+    #[error("tus version `{0}` is not supported")]
+    UnsupportedVersion(String),
+
+    #[error("header `{0}` is missing or invalid")]
+    InvalidHeader(&'static str),
+
+    #[error("content type `{0}` is not supported")]
+    InvalidContentType(String),
+
+    #[error("offset {0} doesn't match the offset {1} of the upload")]
+    OffsetMismatch(u64, u64),
+
+    #[error("data exceeds the upload length {0}")]
+    ExceedsLength(u64),
+
+    #[error("checksum algorithm `{0}` is not supported")]
+    UnsupportedChecksum(String),
+
+    #[error("checksum doesn't match the received data")]
+    ChecksumMismatch,
+
+    #[error("another request continues the upload")]
+    Locked,
+}
+
+impl From<FileError> for TusAPIError {
+    fn from(value: FileError) -> Self {
+        Self::UploadError(value.into())
+    }
+}
+
+type Result<T> = TusAPIResult<T>;
+
+/// Checksum of the data of a `PATCH` request, as sent in the `Upload-Checksum` header.
+#[derive(Debug, Clone)]
+pub struct Checksum {
+    pub algorithm: String,
+    pub digest: Vec<u8>,
+}
+
+impl Checksum {
+    /// Parses the value of the `Upload-Checksum` header, which consists of the algorithm and the
+    /// base64 encoded digest.
+    pub fn parse(value: &str) -> Result<Self> {
+        let invalid = || TusAPIError::InvalidHeader("Upload-Checksum");
+        let (algorithm, digest) = value.split_once(' ').ok_or_else(invalid)?;
+        Ok(Self {
+            algorithm: algorithm.to_owned(),
+            digest: STANDARD.decode(digest).map_err(|_| invalid())?,
+        })
+    }
+
+    /// Starts the digest of the data, which is checked against this checksum by `verify`.
+    fn start(&self) -> Result<digest::Context> {
+        let algorithm = match self.algorithm.as_str() {
+            "sha1" => &digest::SHA1_FOR_LEGACY_USE_ONLY,
+            "sha256" => &digest::SHA256,
+            "sha512" => &digest::SHA512,
+            _ => return Err(TusAPIError::UnsupportedChecksum(self.algorithm.clone())),
+        };
+        Ok(digest::Context::new(algorithm))
+    }
+
+    fn verify(&self, context: digest::Context) -> Result<()> {
+        if context.finish().as_ref() == self.digest.as_slice() {
+            Ok(())
+        } else {
+            Err(TusAPIError::ChecksumMismatch)
+        }
+    }
+}
+
+/// Parses the value of the `Upload-Metadata` header, which is a comma separated list of keys and
+/// their base64 encoded values. Keys may appear without a value.
+pub fn parse_metadata(value: &str) -> Result<HashMap<String, String>> {
+    let invalid = || TusAPIError::InvalidHeader("Upload-Metadata");
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (key, value) = pair.split_once(' ').unwrap_or((pair, ""));
+            let value = STANDARD.decode(value).map_err(|_| invalid())?;
+            let value = String::from_utf8(value).map_err(|_| invalid())?;
+            Ok((key.to_owned(), value))
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone)]
+pub struct CreateRequest {
+    /// Size of the whole file in bytes, from the `Upload-Length` header
+    pub length: u64,
+    pub metadata: HashMap<String, String>,
+}
+
+/// Offset and length of an upload, which are reported in the `Upload-Offset` and `Upload-Length`
+/// headers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UploadProgress {
+    pub offset: u64,
+    pub length: u64,
+}
+
+pub struct PatchRequest {
+    /// Offset of the data in the file, from the `Upload-Offset` header
+    pub offset: u64,
+    pub checksum: Option<Checksum>,
+    /// Size of the data in bytes, from the `Content-Length` header
+    pub size: u64,
+    /// Data of the request, which is streamed into a segment of the upload
+    pub data: ObjectStream,
+}
+
+/// Creates the lease of a tus upload into the folder of the user. The name of the file is taken
+/// from the `filename` or `name` metadata, which clients commonly send.
+#[tracing::instrument(skip(file_storage, lease_store, config), err(Debug))]
+pub async fn create(
+    file_storage: impl FileStorage,
+    mut lease_store: impl UploadLeaseStore + GroupStore + QuotaStore,
+    user_id: Uuid,
+    req: CreateRequest,
+    config: &GenbuConfig,
+) -> Result<LeaseID> {
+    let name = req
+        .metadata
+        .get("filename")
+        .or_else(|| req.metadata.get("name"))
+        .ok_or(TusAPIError::InvalidHeader("Upload-Metadata"))?;
+    if !userfiles::is_valid_path(name) {
+        return Err(UploadAPIError::InvalidPath(name.clone()).into());
+    }
+    upload::check_file_size(&lease_store, user_id, req.length, config).await?;
+    let size = req
+        .length
+        .try_into()
+        .map_err(|_| TusAPIError::InvalidHeader("Upload-Length"))?;
+
+    let mut lease = UploadLease {
+        owner: user_id,
+        size,
+        name: user_id.to_string() + "\\" + name,
+        // The part size is fixed, so the segments of the upload are found without the config
+        chunk_size: Some(storage::chunk_size(req.length, config.upload.chunk_size) as i64),
+        ..UploadLease::template()
+    };
+    lease.s3_upload_id = file_storage
+        .create_multipart_upload(lease.bucket, &lease.name)
+        .await?;
+    if let Err(e) = lease_store.add(&lease).await {
+        // Without a lease nobody could finish or abort the upload
+        let _ = file_storage
+            .abort_multipart_upload(lease.bucket, &lease.name, &lease.s3_upload_id)
+            .await;
+        return Err(UploadAPIError::from(e).into());
+    }
+    Ok(lease.id)
+}
+
+/// Returns the offset and size of every segment, which is recorded for a lease. Every `PATCH`
+/// request stores its data as a segment, until the segments fill a whole part. A request could
+/// fail after it stored parts from the segments, but before it recorded the rest. Then the
+/// recorded segments don't begin at `start`, the end of the stored parts, and none of them is used.
+fn recorded_segments(lease: &UploadLease, start: u64) -> Vec<(u64, u64)> {
+    let offsets: Vec<u64> = lease
+        .segments
+        .iter()
+        .filter_map(|offset| u64::try_from(*offset).ok())
+        .collect();
+    if offsets.first().is_some_and(|offset| *offset != start) {
+        return Vec::new();
+    }
+    offsets
+        .windows(2)
+        .map(|pair| (pair[0], pair[1].saturating_sub(pair[0])))
+        .collect()
+}
+
+/// Returns the offsets, which record `segments` in a lease, see `UploadLease::segments`.
+fn segment_offsets(segments: &[(u64, u64)]) -> Vec<i64> {
+    let mut offsets: Vec<i64> = segments.iter().map(|(offset, _)| *offset as i64).collect();
+    if let Some((offset, size)) = segments.last() {
+        offsets.push((offset + size) as i64);
+    }
+    offsets
+}
+
+/// Deletes the segments of a tus upload, which weren't stored as a part yet.
+pub(crate) async fn delete_segments(
+    file_storage: &mut impl FileStorage,
+    lease: &UploadLease,
+) -> std::result::Result<(), FileError> {
+    let Some((_, offsets)) = lease.segments.split_last() else {
+        return Ok(());
+    };
+    for offset in offsets
+        .iter()
+        .filter_map(|offset| u64::try_from(*offset).ok())
+    {
+        file_storage
+            .delete_file(lease.bucket, &lease.segment_key(offset))
+            .await?;
+    }
+    Ok(())
+}
+
+/// Returns the parts, which are stored for a lease, the segments after them and the number of
+/// bytes which were received for it. Every part but the last one is exactly `chunk_size` bytes
+/// large. The segments are recorded in the lease, so they're found without asking the file
+/// storage.
+async fn get_progress(
+    file_storage: &impl FileStorage,
+    lease: &UploadLease,
+    chunk_size: u64,
+    length: u64,
+) -> Result<(Vec<Part>, Vec<(u64, u64)>, u64)> {
+    let parts = file_storage
+        .list_parts(lease.bucket, &lease.name, &lease.s3_upload_id)
+        .await?;
+    let start = parts.len() as u64 * chunk_size;
+    let segments = recorded_segments(lease, start);
+    let offset = segments
+        .last()
+        .map_or(start, |(offset, size)| offset + size);
+    Ok((parts, segments, offset.min(length)))
+}
+
+/// Returns how many bytes of a tus upload of the user were received.
+#[tracing::instrument(skip(file_storage, lease_store, config), err(Debug))]
+pub async fn head(
+    file_storage: impl FileStorage,
+    lease_store: impl UploadLeaseStore,
+    user_id: Uuid,
+    lease_id: LeaseID,
+    config: &GenbuConfig,
+) -> Result<UploadProgress> {
+    let lease = upload::get_open_lease(&lease_store, user_id, lease_id).await?;
+    let (length, chunk_size) = upload::part_layout(&lease, &config.upload)?;
+    let (_, _, offset) = get_progress(&file_storage, &lease, chunk_size, length).await?;
+    Ok(UploadProgress { offset, length })
+}
+
+/// Time after which a `PATCH` request, which didn't end its patch, is taken as failed, so another
+/// request can continue the upload.
+const PATCH_TIMEOUT: Duration = Duration::hours(1);
+
+/// Marks a lease as being patched, until the patch is ended. Requests, which continue the same
+/// upload at the same time, would both write the data after the current offset. A request which
+/// fails or is dropped, because its client disconnected, ends the patch without recording
+/// segments.
+struct PatchGuard<S: UploadLeaseStore> {
+    store: S,
+    lease_id: LeaseID,
+    ended: bool,
+}
+
+impl<S: UploadLeaseStore> PatchGuard<S> {
+    /// Starts the patch of a lease and returns the lease as it's recorded at its start.
+    async fn start(mut store: S, lease_id: LeaseID) -> Result<(Self, UploadLease)> {
+        let until = OffsetDateTime::now_utc() + PATCH_TIMEOUT;
+        let lease = store
+            .start_patch(&lease_id, until)
+            .await
+            .map_err(UploadAPIError::from)?
+            .ok_or(TusAPIError::Locked)?;
+        let guard = Self {
+            store,
+            lease_id,
+            ended: false,
+        };
+        Ok((guard, lease))
+    }
+
+    /// Ends the patch and records the segments of the upload.
+    async fn end(mut self, segments: &[(u64, u64)]) -> Result<()> {
+        self.ended = true;
+        self.store
+            .end_patch(&self.lease_id, Some(&segment_offsets(segments)))
+            .await
+            .map_err(UploadAPIError::from)?;
+        Ok(())
+    }
+}
+
+impl<S: UploadLeaseStore> Drop for PatchGuard<S> {
+    fn drop(&mut self) {
+        if self.ended {
+            return;
+        }
+        let mut store = self.store.clone();
+        let lease_id = self.lease_id;
+        tokio::spawn(async move {
+            if let Err(e) = store.end_patch(&lease_id, None).await {
+                error!("unable to end the patch of lease {lease_id:?}: {e:?}");
+            }
+        });
+    }
+}
+
+/// Stores the segments of an upload as parts, once they fill a whole part or the last part is
+/// complete. The rest of the data is kept as a single segment. Every segment is read only once,
+/// when it's stored as a part. Returns the segments, which are left after the parts.
+async fn store_parts(
+    file_storage: &mut impl FileStorage,
+    lease: &UploadLease,
+    parts: &mut Vec<Part>,
+    segments: &[(u64, u64)],
+    chunk_size: u64,
+    is_last: bool,
+) -> Result<Vec<(u64, u64)>> {
+    let pending: u64 = segments.iter().map(|(_, size)| size).sum();
+    if pending < chunk_size && !(is_last && (pending > 0 || parts.is_empty())) {
+        return Ok(segments.to_vec());
+    }
+    let mut data = BytesMut::new();
+    for (offset, _) in segments {
+        let mut body = file_storage
+            .get_object(lease.bucket, &lease.segment_key(*offset))
+            .await?
+            .body;
+        while let Some(chunk) = body
+            .try_next()
+            .await
+            .map_err(|e| FileError::Other(Box::new(e)))?
+        {
+            data.extend_from_slice(&chunk);
+        }
+    }
+    let mut data = data.freeze();
+
+    let part_size = usize::try_from(chunk_size).map_err(|_| UploadAPIError::Unknown)?;
+    while data.len() >= part_size || (is_last && (!data.is_empty() || parts.is_empty())) {
+        let part = data.split_to(part_size.min(data.len()));
+        let part_number = i32::try_from(parts.len() + 1).map_err(|_| UploadAPIError::Unknown)?;
+        let e_tag = file_storage
+            .upload_part(
+                lease.bucket,
+                &lease.name,
+                &lease.s3_upload_id,
+                part_number,
+                part,
+            )
+            .await?;
+        parts.push(Part { e_tag, part_number });
+    }
+    // The rest begins right after the stored parts. If the request fails before it's stored,
+    // the offset falls back to the end of the stored parts and the client sends it again.
+    let start = parts.len() as u64 * chunk_size;
+    if !data.is_empty() {
+        file_storage
+            .upload(lease.bucket, &lease.segment_key(start), data.to_vec())
+            .await?;
+    }
+    for (offset, _) in segments {
+        if *offset != start || data.is_empty() {
+            file_storage
+                .delete_file(lease.bucket, &lease.segment_key(*offset))
+                .await?;
+        }
+    }
+    Ok(if data.is_empty() {
+        Vec::new()
+    } else {
+        vec![(start, data.len() as u64)]
+    })
+}
+
+/// Stores the data of a `PATCH` request, which continues a tus upload of the user at its current
+/// offset. Once all data was received, the upload is completed like a presigned upload.
+#[tracing::instrument(skip(file_storage, lease_store, file_db, req, config), err(Debug))]
+pub async fn patch(
+    mut file_storage: impl FileStorage,
+    mut lease_store: impl UploadLeaseStore,
+    mut file_db: impl DBFileStore + QuotaStore + UploadLeaseStore + VersionStore + BlobStore,
+    user_id: Uuid,
+    lease_id: LeaseID,
+    req: PatchRequest,
+    config: &GenbuConfig,
+) -> Result<UploadProgress> {
+    let lease = upload::get_open_lease(&lease_store, user_id, lease_id).await?;
+    let (guard, lease) = PatchGuard::start(lease_store.clone(), lease.id).await?;
+    let (length, chunk_size) = upload::part_layout(&lease, &config.upload)?;
+    let (mut parts, mut segments, offset) =
+        get_progress(&file_storage, &lease, chunk_size, length).await?;
+    if req.offset != offset {
+        return Err(TusAPIError::OffsetMismatch(req.offset, offset));
+    }
+    let end = offset + req.size;
+    if end > length {
+        return Err(TusAPIError::ExceedsLength(length));
+    }
+
+    if req.size > 0 {
+        // The data is checked while it's streamed into its segment
+        let context = req
+            .checksum
+            .as_ref()
+            .map(Checksum::start)
+            .transpose()?
+            .map(|context| Arc::new(Mutex::new(context)));
+        let mut data = req.data;
+        if let Some(context) = &context {
+            let context = Arc::clone(context);
+            data = Box::pin(data.inspect_ok(move |chunk| context.lock().update(chunk)));
+        }
+        file_storage
+            .upload_stream(lease.bucket, &lease.segment_key(offset), data, req.size)
+            .await?;
+        // A segment, which isn't recorded, is overwritten by the next request at its offset
+        if let (Some(checksum), Some(context)) = (&req.checksum, context) {
+            checksum.verify(context.lock().clone())?;
+        }
+        segments.push((offset, req.size));
+    }
+    let is_last = end == length;
+    let segments = store_parts(
+        &mut file_storage,
+        &lease,
+        &mut parts,
+        &segments,
+        chunk_size,
+        is_last,
+    )
+    .await?;
+
+    if is_last {
+        // Like a presigned upload, the lease is only completed once its upload was verified
+        let res =
+            upload::complete_lease(&mut file_storage, &mut file_db, &lease, parts, config).await;
+        if let Err(e) = res {
+            // The client can send an empty `PATCH` at the end of the upload to complete it again
+            if e.keeps_lease() {
+                guard.end(&segments).await?;
+            } else if let Err(e) =
+                upload::abort_lease(&mut file_storage, &mut lease_store, &lease).await
+            {
+                error!("unable to abort the upload of lease {:?}: {e:?}", lease.id);
+            }
+            return Err(e.into());
+        }
+        lease_store
+            .mark_completed(&lease.id)
+            .await
+            .map_err(UploadAPIError::from)?;
+    }
+    guard.end(&segments).await?;
+    Ok(UploadProgress {
+        offset: end,
+        length,
+    })
+}
+
+/// Cancels a tus upload of the user, which is the termination extension of the protocol.
+#[tracing::instrument(skip(file_storage, lease_store), err(Debug))]
+pub async fn terminate(
+    file_storage: impl FileStorage,
+    lease_store: impl UploadLeaseStore,
+    user_id: Uuid,
+    lease_id: LeaseID,
+) -> Result<()> {
+    Ok(upload::abort_upload(file_storage, lease_store, user_id, lease_id).await?)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        connectors::memory::MemStore,
+        stores::files::storage::{Bucket, MIN_PART_SIZE},
+    };
+
+    use super::*;
+
+    fn patch_request(offset: u64, data: &[u8]) -> PatchRequest {
+        let chunk = Ok(bytes::Bytes::copy_from_slice(data));
+        PatchRequest {
+            offset,
+            checksum: None,
+            size: data.len() as u64,
+            data: Box::pin(futures::stream::iter([chunk])),
+        }
+    }
+
+    #[test]
+    fn metadata() {
+        let metadata =
+            parse_metadata("filename d29ybGRfZG9taW5hdGlvbl9wbGFuLnBkZg==,is_confidential")
+                .unwrap();
+        assert_eq!(
+            metadata.get("filename").map(String::as_str),
+            Some("world_domination_plan.pdf")
+        );
+        assert_eq!(
+            metadata.get("is_confidential").map(String::as_str),
+            Some("")
+        );
+        assert!(parse_metadata("filename !").is_err());
+    }
+
+    #[test]
+    fn checksums() {
+        let verify = |checksum: &Checksum, data: &[u8]| {
+            let mut context = checksum.start()?;
+            context.update(data);
+            checksum.verify(context)
+        };
+        // sha1 of "hello"
+        let checksum = Checksum::parse("sha1 qvTGHdzF6KLavt4PO0gs2a6pQ00=").unwrap();
+        assert!(verify(&checksum, b"hello").is_ok());
+        assert!(matches!(
+            verify(&checksum, b"world"),
+            Err(TusAPIError::ChecksumMismatch)
+        ));
+        let checksum = Checksum::parse("md5 XUFAKrxLKna5cZ2REBfFkg==").unwrap();
+        assert!(matches!(
+            verify(&checksum, b"hello"),
+            Err(TusAPIError::UnsupportedChecksum(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn upload_in_small_chunks() {
+        let user_id = Uuid::new_v4();
+        let store = MemStore::new();
+        // Parts of the minimum size keep the uploaded file small
+        let mut config = GenbuConfig::default();
+        config.upload.chunk_size = 0;
+        // Two whole parts and a few bytes, which are sent in chunks below the part size
+        let size = 2 * MIN_PART_SIZE as usize + 5;
+        let content: Vec<u8> = (0..size).map(|i| (i % 251) as u8).collect();
+        let req = CreateRequest {
+            length: content.len() as u64,
+            metadata: HashMap::from([("filename".to_owned(), "a.bin".to_owned())]),
+        };
+        let lease_id = create(store.clone(), store.clone(), user_id, req, &config)
+            .await
+            .unwrap();
+
+        let mut offset = 0;
+        for chunk in content.chunks(3 * 1024 * 1024) {
+            let progress = patch(
+                store.clone(),
+                store.clone(),
+                store.clone(),
+                user_id,
+                lease_id,
+                patch_request(offset, chunk),
+                &config,
+            )
+            .await
+            .unwrap();
+            offset = progress.offset;
+            if offset < content.len() as u64 {
+                let head = head(store.clone(), store.clone(), user_id, lease_id, &config)
+                    .await
+                    .unwrap();
+                assert_eq!(head.offset, offset);
+            }
+        }
+        assert_eq!(offset, content.len() as u64);
+
+        let path = format!("{user_id}\\a.bin");
+        let mut body = store
+            .get_object(Bucket::UserFiles, &path)
+            .await
+            .unwrap()
+            .body;
+        let mut stored = Vec::new();
+        while let Some(chunk) = body.try_next().await.unwrap() {
+            stored.extend_from_slice(&chunk);
+        }
+        assert_eq!(stored, content);
+        assert!(store
+            .get_dbfile_by_path(Bucket::UserFiles, &path)
+            .await
+            .unwrap()
+            .is_some());
+    }
+
+    #[tokio::test]
+    async fn terminate_with_segments() {
+        let user_id = Uuid::new_v4();
+        let store = MemStore::new();
+        let config = GenbuConfig::default();
+        let req = CreateRequest {
+            length: 2 * MIN_PART_SIZE,
+            metadata: HashMap::from([("filename".to_owned(), "a.bin".to_owned())]),
+        };
+        let lease_id = create(store.clone(), store.clone(), user_id, req, &config)
+            .await
+            .unwrap();
+        let patch_first = || {
+            patch(
+                store.clone(),
+                store.clone(),
+                store.clone(),
+                user_id,
+                lease_id,
+                patch_request(0, b"first"),
+                &config,
+            )
+        };
+        // Requests which continue the same upload at the same time are rejected
+        let until = OffsetDateTime::now_utc() + PATCH_TIMEOUT;
+        store.clone().start_patch(&lease_id, until).await.unwrap();
+        assert!(matches!(patch_first().await, Err(TusAPIError::Locked)));
+        store.clone().end_patch(&lease_id, None).await.unwrap();
+        assert_eq!(patch_first().await.unwrap().offset, 5);
+
+        // The segment is recorded in the lease
+        let lease = store.get(&lease_id).await.unwrap().unwrap();
+        assert_eq!(lease.segments, vec![0, 5]);
+        let segment = lease.segment_key(0);
+        assert_eq!(
+            store
+                .head_object(lease.bucket, &segment)
+                .await
+                .unwrap()
+                .size,
+            5
+        );
+        terminate(store.clone(), store.clone(), user_id, lease_id)
+            .await
+            .unwrap();
+        assert!(matches!(
+            store.head_object(lease.bucket, &segment).await,
+            Err(FileError::NotFound(_))
+        ));
+    }
+}
@@ -1,23 +1,50 @@
 use std::fmt::Debug;
 
+use bytes::Bytes;
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
-use utoipa::ToSchema;
+use time::OffsetDateTime;
+use tracing::error;
+use utoipa::{IntoParams, ToSchema};
 
-use crate::stores::{
-    files::{
-        database::LeaseID,
-        storage::{FileError, Part},
-        FileStorage, UploadLease, UploadLeaseError, UploadLeaseStore,
+use crate::{
+    config::{GenbuConfig, QuotaConfig, UploadConfig},
+    stores::{
+        files::{
+            blobs::BlobStore,
+            database::{DBFile, DBFileError, DBFileStore, LeaseID},
+            quota::QuotaStore,
+            storage::{
+                self, Bucket, FileError, InvalidPartSize, ObjectStream, Part, PartChecksums,
+            },
+            versions::VersionStore,
+            FileStorage, UploadLease, UploadLeaseError, UploadLeaseStore,
+        },
+        groups::GroupStore,
+        users::UserError,
+        Uuid,
     },
-    Uuid,
+};
+
+use super::{
+    blobs::BlobAPIError,
+    catalog::{self, CatalogAPIError, FileInfo},
+    quota::{self, QuotaAPIError},
+    tus, userfiles,
+    versions::{self, VersionAPIError},
 };
 
 pub type UploadAPIResult<T> = std::result::Result<T, UploadAPIError>;
 
-// TODO: Make this configurable?
-static MAX_FILE_SIZE: u64 = 1_000_000_000;
-static CHUNK_SIZE: u64 = 10_000_000;
+/// Mismatch between the object of a finished upload and what the client declared for its lease.
+#[derive(Debug, Error)]
+pub enum IntegrityError {
+    #[error("uploaded file has {0} bytes instead of {1}")]
+    Size(u64, u64),
+
+    #[error("checksum `{0}` of the uploaded file doesn't match the declared `{1}`")]
+    Checksum(String, String),
+}
 
 #[derive(Debug, Error)]
 pub enum UploadAPIError {
@@ -27,25 +54,93 @@ pub enum UploadAPIError {
     #[error("lease store error")]
     DatabaseError(#[from] UploadLeaseError),
 
+    #[error("unable to register the uploaded file")]
+    CatalogError(#[from] CatalogAPIError),
+
+    #[error("unable to keep the previous version of the file")]
+    VersionError(#[from] VersionAPIError),
+
+    #[error("blob error")]
+    BlobError(#[from] BlobAPIError),
+
     #[error("file too large, {0} exceeds {1}")]
     FileTooLarge(u64, u64),
 
+    #[error("{0} bytes exceed the available storage of {1} bytes")]
+    QuotaExceeded(u64, u64),
+
+    #[error("unable to read the storage usage")]
+    UsageError(#[from] DBFileError),
+
+    #[error("unable to read the roles of the user")]
+    RoleError(#[from] UserError),
+
     #[error("file not found")]
     NotFound(Box<dyn Debug + Send + Sync>),
 
+    #[error("lease {0:?} belongs to another user")]
+    Forbidden(LeaseID),
+
     #[error("size {0} is negative")]
     NegativeSize(i64),
 
+    #[error("size of the content is unknown")]
+    LengthRequired,
+
+    #[error("invalid path `{0}`")]
+    InvalidPath(String),
+
+    #[error("`{0}` is a folder")]
+    IsFolder(String),
+
+    #[error("part size {0} is outside of the part limits")]
+    InvalidChunkSize(u64),
+
+    #[error("declared checksums don't match the parts of the upload")]
+    InvalidChecksums,
+
+    #[error("uploaded file doesn't match its lease")]
+    IntegrityError(#[from] IntegrityError),
+
     #[error("unknown api error")]
     Unknown,
 }
 
+impl From<QuotaAPIError> for UploadAPIError {
+    fn from(value: QuotaAPIError) -> Self {
+        match value {
+            QuotaAPIError::QuotaExceeded(size, available) => Self::QuotaExceeded(size, available),
+            QuotaAPIError::DatabaseError(e) => Self::UsageError(e),
+            QuotaAPIError::GroupError(e) => Self::RoleError(e),
+            QuotaAPIError::InvalidQuota(_) | QuotaAPIError::Forbidden => Self::Unknown,
+        }
+    }
+}
+
+impl UploadAPIError {
+    /// Whether an upload, whose completion failed with this error, can still be completed later.
+    /// These errors are raised before the uploaded parts are assembled, so the lease is kept and
+    /// the client can finish it again once the file is unlocked or storage was freed.
+    pub(crate) const fn keeps_lease(&self) -> bool {
+        matches!(
+            self,
+            Self::UsageError(DBFileError::Locked(_)) | Self::IsFolder(_) | Self::QuotaExceeded(..)
+        )
+    }
+}
+
 type Result<T> = UploadAPIResult<T>;
 
 #[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
 pub struct UploadFileRequest {
     pub name: String,
     pub size: u64,
+    /// Part size in bytes, which the declared checksums were computed for. The configured part
+    /// size is used if it's missing.
+    pub chunk_size: Option<u64>,
+    /// Checksums of the parts, which the uploaded parts and the finished file are verified
+    /// against
+    pub checksums: Option<PartChecksums>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
@@ -53,41 +148,94 @@ pub struct UploadFileResponse {
     pub lease_id: LeaseID,
     pub upload_id: Option<String>,
     pub uris: Vec<String>,
+    /// Size in bytes of every part but the last one, which is sent to one of the `uris` each
+    pub chunk_size: u64,
+}
+
+/// Returns the bytes, which the open leases of a user besides `except` reserve for their
+/// uploads. Uploads are checked against the quota together with these, so concurrent uploads
+/// can't exceed it together.
+async fn reserved_size(
+    lease_store: &impl UploadLeaseStore,
+    user_id: Uuid,
+    except: Option<LeaseID>,
+) -> Result<i64> {
+    let now = OffsetDateTime::now_utc();
+    let leases = lease_store.get_by_user(&user_id).await?;
+    Ok(leases
+        .iter()
+        .filter(|lease| !lease.completed && lease.expires_at > now && Some(lease.id) != except)
+        .map(|lease| lease.size.max(0))
+        .fold(0, i64::saturating_add))
+}
+
+/// Checks that a file of `size` bytes is within the upload limits of the user and of the file
+/// storage and fits into their storage quota next to their open uploads.
+pub(crate) async fn check_file_size(
+    store: &(impl GroupStore + QuotaStore + UploadLeaseStore),
+    user_id: Uuid,
+    size: u64,
+    config: &GenbuConfig,
+) -> Result<()> {
+    let roles = store.get_group_names(user_id).await?;
+    let max_file_size = config
+        .upload
+        .file_size_limit(Bucket::UserFiles, &roles)
+        .min(storage::MAX_UPLOAD_SIZE);
+    if size > max_file_size {
+        return Err(UploadAPIError::FileTooLarge(size, max_file_size));
+    }
+    let size = i64::try_from(size).unwrap_or(i64::MAX);
+    let reserved = reserved_size(store, user_id, None).await?;
+    quota::check(store, user_id, size.saturating_add(reserved), &config.quota).await?;
+    Ok(())
 }
 
 /// The handler for direct uploads to the userfiles bucket. This can't be used
 /// for uploads to other buckets like videofiles or notebookfiles.
-#[tracing::instrument(skip(file_storage, lease_store))]
+#[tracing::instrument(skip(file_storage, lease_store, config))]
 pub async fn post(
     file_storage: impl FileStorage,
-    mut lease_store: impl UploadLeaseStore,
+    mut lease_store: impl UploadLeaseStore + GroupStore + QuotaStore,
     user_id: Uuid,
     upload_req: UploadFileRequest,
+    config: &GenbuConfig,
 ) -> Result<UploadFileResponse> {
-    if upload_req.size > MAX_FILE_SIZE {
-        return Err(UploadAPIError::FileTooLarge(upload_req.size, MAX_FILE_SIZE));
+    if !userfiles::is_valid_path(&upload_req.name) {
+        return Err(UploadAPIError::InvalidPath(upload_req.name));
     }
+    check_file_size(&lease_store, user_id, upload_req.size, config).await?;
+    let chunk_size = upload_req
+        .chunk_size
+        .map(|chunk_size| check_chunk_size(upload_req.size, chunk_size))
+        .transpose()?;
 
     let size = upload_req
         .size
         .try_into()
         .map_err(|_| UploadAPIError::Unknown)?;
 
-    let lease = lease_store
-        .add(&UploadLease {
-            owner: user_id,
-            size,
-            name: user_id.to_string() + "\\" + &upload_req.name,
-            ..UploadLease::template()
-        })
-        .await?;
-
-    let (uris, upload_id) = get_presigned_upload_urls(file_storage, &lease).await?;
-    Ok(UploadFileResponse {
-        upload_id,
-        uris,
-        lease_id: lease.id,
-    })
+    let mut lease = UploadLease {
+        owner: user_id,
+        size,
+        name: user_id.to_string() + "\\" + &upload_req.name,
+        chunk_size,
+        checksum_algorithm: upload_req.checksums.as_ref().map(|c| c.algorithm),
+        part_checksums: upload_req.checksums.map(|c| c.parts).unwrap_or_default(),
+        ..UploadLease::template()
+    };
+    check_checksums(&lease, &config.upload)?;
+    let resp = get_presigned_upload_urls(&file_storage, &lease, &config.upload).await?;
+    // The upload id is kept, so the upload can still be aborted once the lease expires
+    lease.s3_upload_id = resp.upload_id.clone().unwrap_or_default();
+    if let Err(e) = lease_store.add(&lease).await {
+        // Without a lease nobody could finish or abort the upload
+        let _ = file_storage
+            .abort_multipart_upload(lease.bucket, &lease.name, &lease.s3_upload_id)
+            .await;
+        return Err(e.into());
+    }
+    Ok(resp)
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
@@ -95,67 +243,1014 @@ pub struct GetUrisRequest {
     lease_id: LeaseID,
 }
 
+/// Returns the lease with the given id, if it belongs to the user.
+async fn get_own_lease(
+    lease_store: &impl UploadLeaseStore,
+    user_id: Uuid,
+    lease_id: LeaseID,
+) -> Result<UploadLease> {
+    match lease_store.get(&lease_id).await? {
+        Some(lease) if lease.owner == user_id => Ok(lease),
+        Some(_) => Err(UploadAPIError::Forbidden(lease_id)),
+        None => Err(UploadAPIError::NotFound(Box::new(lease_id))),
+    }
+}
+
+/// Returns the lease of an upload, which the user can still send parts to.
+pub(crate) async fn get_open_lease(
+    lease_store: &impl UploadLeaseStore,
+    user_id: Uuid,
+    lease_id: LeaseID,
+) -> Result<UploadLease> {
+    let lease = get_own_lease(lease_store, user_id, lease_id).await?;
+    // Leases created before the upload id was stored can't be continued either
+    if lease.completed || lease.s3_upload_id.is_empty() {
+        return Err(UploadAPIError::NotFound(Box::new(lease_id)));
+    }
+    if lease.expires_at <= OffsetDateTime::now_utc() {
+        return Err(UploadLeaseError::LeaseExpired(lease_id).into());
+    }
+    Ok(lease)
+}
+
+/// Returns the size of the leased file and the size of its parts. Unless the client chose the
+/// part size, it grows with the file, so the upload stays within the part limits of S3.
+pub(crate) fn part_layout(lease: &UploadLease, config: &UploadConfig) -> Result<(u64, u64)> {
+    let size = lease
+        .size
+        .try_into()
+        .map_err(|_| UploadAPIError::NegativeSize(lease.size))?;
+    let chunk_size = match lease.chunk_size {
+        Some(chunk_size) => chunk_size
+            .try_into()
+            .map_err(|_| UploadAPIError::NegativeSize(chunk_size))?,
+        None => storage::chunk_size(size, config.chunk_size),
+    };
+    Ok((size, chunk_size))
+}
+
+/// Checks a part size, which the client chose, against the part limits of S3 and of the
+/// presigned urls served by genbu.
+fn check_chunk_size(size: u64, chunk_size: u64) -> Result<i64> {
+    if !(storage::MIN_PART_SIZE..=storage::MAX_UPLOAD_PART_SIZE).contains(&chunk_size)
+        || storage::chunk_count(size, chunk_size) > storage::MAX_PART_COUNT
+    {
+        return Err(UploadAPIError::InvalidChunkSize(chunk_size));
+    }
+    chunk_size
+        .try_into()
+        .map_err(|_| UploadAPIError::InvalidChunkSize(chunk_size))
+}
+
+/// Checks that the declared checksums are valid and that there is one for every part.
+fn check_checksums(lease: &UploadLease, config: &UploadConfig) -> Result<()> {
+    let Some(checksums) = lease.checksums() else {
+        return Ok(());
+    };
+    let (size, chunk_size) = part_layout(lease, config)?;
+    if !checksums.is_valid()
+        || checksums.parts.len() as u64 != storage::chunk_count(size, chunk_size)
+    {
+        return Err(UploadAPIError::InvalidChecksums);
+    }
+    Ok(())
+}
+
+/// Returns the numbers of all parts of an upload, starting with 1 like S3 does.
+fn part_numbers(size: u64, chunk_size: u64) -> Result<Vec<i32>> {
+    (1..=storage::chunk_count(size, chunk_size))
+        .map(|part_number| {
+            i32::try_from(part_number)
+                .map_err(|_| UploadAPIError::StorageError(FileError::Other(InvalidPartSize.into())))
+        })
+        .collect()
+}
+
 /// The handler for getting the uploads urls for any upload that was previously registered
-/// with an ```UploadLease```
-#[tracing::instrument(skip(file_storage, lease_store), err(Debug))]
+/// with an ```UploadLease``` by the user. The urls point to the upload stored on the lease, so
+/// parts which were already sent are kept.
+#[tracing::instrument(skip(file_storage, lease_store, config), err(Debug))]
 pub async fn get(
     file_storage: impl FileStorage,
     lease_store: impl UploadLeaseStore,
+    user_id: Uuid,
     start_req: GetUrisRequest,
+    config: &UploadConfig,
 ) -> Result<UploadFileResponse> {
-    let lease_id = start_req.lease_id;
-    let lease = lease_store
-        .get(&lease_id)
-        .await?
-        .ok_or(UploadAPIError::NotFound(Box::new(lease_id)))?;
-    let (uris, upload_id) = get_presigned_upload_urls(file_storage, &lease).await?;
+    let lease = get_open_lease(&lease_store, user_id, start_req.lease_id).await?;
+    let (size, chunk_size) = part_layout(&lease, config)?;
+    let numbers = part_numbers(size, chunk_size)?;
+    let uris = file_storage
+        .get_upload_part_urls(
+            lease.bucket,
+            &lease.name,
+            &lease.s3_upload_id,
+            &numbers,
+            lease.checksums().as_ref(),
+        )
+        .await?;
     Ok(UploadFileResponse {
-        upload_id,
+        lease_id: lease.id,
+        upload_id: Some(lease.s3_upload_id),
         uris,
+        chunk_size,
+    })
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct MissingPart {
+    pub part_number: i32,
+    pub uri: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct ResumeUploadResponse {
+    pub lease_id: LeaseID,
+    pub upload_id: String,
+    /// Size in bytes of every part but the last one
+    pub chunk_size: u64,
+    /// Parts which were already uploaded, these are passed to finish the upload as well
+    pub parts: Vec<Part>,
+    /// Parts which still have to be uploaded to their presigned url
+    pub missing: Vec<MissingPart>,
+}
+
+/// Lists the parts of an interrupted upload which were already uploaded and presigns new urls
+/// for the remaining ones, so the client doesn't have to start over.
+#[tracing::instrument(skip(file_storage, lease_store, config), err(Debug))]
+pub async fn resume(
+    file_storage: impl FileStorage,
+    lease_store: impl UploadLeaseStore,
+    user_id: Uuid,
+    lease_id: LeaseID,
+    config: &UploadConfig,
+) -> Result<ResumeUploadResponse> {
+    let lease = get_open_lease(&lease_store, user_id, lease_id).await?;
+    let (size, chunk_size) = part_layout(&lease, config)?;
+    let parts = file_storage
+        .list_parts(lease.bucket, &lease.name, &lease.s3_upload_id)
+        .await?;
+    let mut missing_numbers = part_numbers(size, chunk_size)?;
+    missing_numbers.retain(|number| !parts.iter().any(|part| part.part_number == *number));
+    let uris = file_storage
+        .get_upload_part_urls(
+            lease.bucket,
+            &lease.name,
+            &lease.s3_upload_id,
+            &missing_numbers,
+            lease.checksums().as_ref(),
+        )
+        .await?;
+    Ok(ResumeUploadResponse {
         lease_id: lease.id,
+        upload_id: lease.s3_upload_id,
+        chunk_size,
+        parts,
+        missing: missing_numbers
+            .into_iter()
+            .zip(uris)
+            .map(|(part_number, uri)| MissingPart { part_number, uri })
+            .collect(),
     })
 }
 
-#[tracing::instrument(skip(file_storage), err(Debug))]
+/// Starts the multipart upload of a lease.
+#[tracing::instrument(skip(file_storage, config), err(Debug))]
 async fn get_presigned_upload_urls(
-    file_storage: impl FileStorage,
+    file_storage: &impl FileStorage,
     lease: &UploadLease,
-) -> Result<(Vec<String>, Option<String>)> {
-    let size = lease
-        .size
-        .try_into()
-        .map_err(|_| UploadAPIError::NegativeSize(lease.size))?;
+    config: &UploadConfig,
+) -> Result<UploadFileResponse> {
+    let (size, chunk_size) = part_layout(lease, config)?;
     let (uris, upload_id) = file_storage
-        .get_presigned_upload_urls(lease.bucket, &lease.name, size, CHUNK_SIZE)
+        .get_presigned_upload_urls(
+            lease.bucket,
+            &lease.name,
+            size,
+            chunk_size,
+            lease.checksums().as_ref(),
+        )
         .await?;
-    Ok((uris, Some(upload_id)))
+    Ok(UploadFileResponse {
+        lease_id: lease.id,
+        upload_id: Some(upload_id),
+        uris,
+        chunk_size,
+    })
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
 pub struct FinishUploadRequest {
     lease_id: LeaseID,
-    upload_id: String,
     parts: Vec<Part>,
 }
 
-#[tracing::instrument(skip(file_storage, lease_store), err(Debug))]
+/// Completes the multipart upload of a lease and registers the uploaded object in the catalog.
+/// The content the upload replaces is kept as a version of the file. An upload onto a locked file
+/// or beyond the quota keeps its lease, so it can be finished later. Any other upload which can't
+/// be completed is aborted together with its lease.
+#[tracing::instrument(skip(file_storage, lease_store, file_db, config), err(Debug))]
 pub async fn finish_upload(
-    file_storage: impl FileStorage,
+    mut file_storage: impl FileStorage,
     mut lease_store: impl UploadLeaseStore,
+    mut file_db: impl DBFileStore + QuotaStore + UploadLeaseStore + VersionStore + BlobStore,
+    user_id: Uuid,
     finish_req: FinishUploadRequest,
-) -> Result<()> {
-    let lease_id = finish_req.lease_id;
-    let Some(lease) = lease_store.mark_completed(&lease_id).await? else {
-        return Err(UploadAPIError::NotFound(Box::new(lease_id)))
+    config: &GenbuConfig,
+) -> Result<FileInfo> {
+    let lease = get_open_lease(&lease_store, user_id, finish_req.lease_id).await?;
+    let res = complete_lease(
+        &mut file_storage,
+        &mut file_db,
+        &lease,
+        finish_req.parts,
+        config,
+    )
+    .await;
+    if let Err(e) = &res {
+        if !e.keeps_lease() {
+            if let Err(e) = abort_lease(&mut file_storage, &mut lease_store, &lease).await {
+                error!("unable to abort the upload of lease {:?}: {e:?}", lease.id);
+            }
+        }
+        return res;
+    }
+    lease_store.mark_completed(&lease.id).await?;
+    res
+}
+
+/// Checks that the object at `key` can be replaced by a write and returns the file, which is
+/// replaced. Folders can't be overwritten and files which are edited through WOPI can only be
+/// changed by the lock holder.
+async fn check_writable(
+    file_db: &impl DBFileStore,
+    bucket: Bucket,
+    key: &str,
+) -> Result<Option<DBFile>> {
+    let file = file_db.get_dbfile_by_path(bucket, key).await?;
+    let Some(file) = file else {
+        return Ok(None);
     };
+    if file.is_folder {
+        return Err(UploadAPIError::IsFolder(key.to_owned()));
+    }
+    if let Some(lock) = file.current_lock() {
+        return Err(DBFileError::Locked(Some(lock.clone())).into());
+    }
+    Ok(Some(file))
+}
 
-    file_storage
+/// Checks that the upload of a lease still fits into the quota of the owner of the file it
+/// replaces. Other writes could have taken up storage since the lease was created, so this is
+/// checked again before the upload is completed.
+async fn check_lease_quota(
+    store: &(impl QuotaStore + UploadLeaseStore),
+    lease: &UploadLease,
+    replaced: Option<&DBFile>,
+    config: &QuotaConfig,
+) -> Result<()> {
+    // The owner of an overwritten file is charged, like `catalog::register_object` does
+    let owner = replaced.map_or(lease.owner, |file| file.created_by);
+    let delta = lease.size - replaced.map_or(0, |file| file.size);
+    let reserved = reserved_size(store, owner, Some(lease.id)).await?;
+    quota::check(store, owner, delta.saturating_add(reserved), config).await?;
+    Ok(())
+}
+
+/// Checks the object of a finished upload against the size and the checksums, which were
+/// declared for its lease.
+async fn verify_upload(
+    file_storage: &impl FileStorage,
+    lease: &UploadLease,
+    checksums: Option<&PartChecksums>,
+) -> Result<()> {
+    let meta = file_storage.head_object(lease.bucket, &lease.name).await?;
+    let size = lease
+        .size
+        .try_into()
+        .map_err(|_| UploadAPIError::NegativeSize(lease.size))?;
+    if meta.size != size {
+        return Err(IntegrityError::Size(meta.size, size).into());
+    }
+    // File storages which don't report checksums verify the parts while finishing the upload
+    if let (Some(checksums), Some(checksum)) = (checksums, meta.checksum) {
+        let expected = checksums
+            .object_checksum()
+            .ok_or(UploadAPIError::InvalidChecksums)?;
+        if checksum != expected {
+            return Err(IntegrityError::Checksum(checksum, expected).into());
+        }
+    }
+    Ok(())
+}
+
+/// Completes the multipart upload of a lease and registers the uploaded object in the catalog,
+/// unless the file at its path can't be replaced. An object which doesn't match the lease is
+/// replaced by the previous content of the file again, which is taken from its new version.
+pub(crate) async fn complete_lease(
+    file_storage: &mut impl FileStorage,
+    file_db: &mut (impl DBFileStore + QuotaStore + UploadLeaseStore + VersionStore + BlobStore),
+    lease: &UploadLease,
+    parts: Vec<Part>,
+    config: &GenbuConfig,
+) -> Result<FileInfo> {
+    let replaced = check_writable(file_db, lease.bucket, &lease.name).await?;
+    check_lease_quota(file_db, lease, replaced.as_ref(), &config.quota).await?;
+    // The replaced content is kept as a version, which is put back if the upload turns out broken
+    let version = match &replaced {
+        Some(file) => versions::snapshot(file_storage, file_db, file, &config.versions).await?,
+        None => None,
+    };
+    let checksums = lease.checksums();
+    let res = file_storage
         .finish_multipart_upload(
             lease.bucket,
             &lease.name,
-            &finish_req.upload_id,
-            finish_req.parts,
+            &lease.s3_upload_id,
+            parts,
+            checksums.as_ref(),
         )
-        .await?;
+        .await;
+    let verified = match res {
+        Ok(()) => verify_upload(file_storage, lease, checksums.as_ref()).await,
+        Err(e) => Err(e.into()),
+    };
+    if verified.is_err() {
+        let res = match (&replaced, &version) {
+            (Some(file), Some(version)) => {
+                versions::roll_back(file_storage, file_db, file, version)
+                    .await
+                    .map_err(UploadAPIError::from)
+            }
+            (None, _) => file_storage
+                .delete_file(lease.bucket, &lease.name)
+                .await
+                .map_err(UploadAPIError::from),
+            // Without a version there is nothing to restore the replaced content from
+            (Some(_), None) => Ok(()),
+        };
+        if let Err(e) = res {
+            error!(
+                "unable to replace the object of lease {:?}: {e:?}",
+                lease.id
+            );
+        }
+    }
+    verified?;
+    let file = catalog::register_object(
+        file_storage,
+        file_db,
+        lease.bucket,
+        &lease.name,
+        lease.owner,
+    )
+    .await?;
+    Ok(FileInfo::new(file, lease.owner))
+}
+
+/// Aborts the multipart upload of a lease, so its parts don't take up storage, and deletes the
+/// lease.
+pub(crate) async fn abort_lease(
+    file_storage: &mut impl FileStorage,
+    lease_store: &mut impl UploadLeaseStore,
+    lease: &UploadLease,
+) -> Result<()> {
+    // Completed uploads have nothing left to abort
+    if !lease.completed && !lease.s3_upload_id.is_empty() {
+        tus::delete_segments(file_storage, lease).await?;
+        let res = file_storage
+            .abort_multipart_upload(lease.bucket, &lease.name, &lease.s3_upload_id)
+            .await;
+        match res {
+            Ok(()) | Err(FileError::NotFound(_)) => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    lease_store.delete(&lease.id).await?;
     Ok(())
 }
+
+/// Cancels an upload of the user.
+#[tracing::instrument(skip(file_storage, lease_store), err(Debug))]
+pub async fn abort_upload(
+    mut file_storage: impl FileStorage,
+    mut lease_store: impl UploadLeaseStore,
+    user_id: Uuid,
+    lease_id: LeaseID,
+) -> Result<()> {
+    let lease = get_own_lease(&lease_store, user_id, lease_id).await?;
+    abort_lease(&mut file_storage, &mut lease_store, &lease).await
+}
+
+/// Aborts the uploads of every incomplete lease, which has expired, and deletes the leases. A
+/// lease which can't be aborted is skipped, so it's retried on the next run. Returns the number
+/// of aborted leases.
+#[tracing::instrument(skip(file_storage, lease_store))]
+pub async fn sweep_expired_leases(
+    mut file_storage: impl FileStorage,
+    mut lease_store: impl UploadLeaseStore,
+) -> Result<usize> {
+    let mut swept = 0;
+    let expired = lease_store.get_expired(OffsetDateTime::now_utc()).await?;
+    for lease in expired {
+        match abort_lease(&mut file_storage, &mut lease_store, &lease).await {
+            Ok(()) => swept += 1,
+            Err(e) => error!("unable to abort the upload of lease {:?}: {e:?}", lease.id),
+        }
+    }
+    Ok(swept)
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, IntoParams, ToSchema)]
+pub struct PutContentRequest {
+    /// Path of the file inside the folder of the user
+    pub path: String,
+}
+
+/// Stores a file of `size` bytes at a path of the user, whose content is streamed into the file
+/// storage. This is meant for small files, which don't need a multipart upload. The content the
+/// upload replaces is kept as a version of the file.
+#[tracing::instrument(skip(file_storage, store, data, config), err(Debug))]
+pub async fn put_content(
+    mut file_storage: impl FileStorage,
+    mut store: impl DBFileStore + GroupStore + QuotaStore + UploadLeaseStore + VersionStore + BlobStore,
+    user_id: Uuid,
+    req: PutContentRequest,
+    size: Option<u64>,
+    data: ObjectStream,
+    config: &GenbuConfig,
+) -> Result<FileInfo> {
+    // Without the size, the limits could only be checked after storing the content
+    let size = size.ok_or(UploadAPIError::LengthRequired)?;
+    if size > config.upload.max_content_size {
+        return Err(UploadAPIError::FileTooLarge(
+            size,
+            config.upload.max_content_size,
+        ));
+    }
+    check_file_size(&store, user_id, size, config).await?;
+    if !userfiles::is_valid_path(&req.path) {
+        return Err(UploadAPIError::InvalidPath(req.path));
+    }
+    let path = userfiles::build_path(user_id, &req.path);
+    check_writable(&store, Bucket::UserFiles, &path).await?;
+    versions::snapshot_path(
+        &mut file_storage,
+        &mut store,
+        Bucket::UserFiles,
+        &path,
+        &config.versions,
+    )
+    .await?;
+    file_storage
+        .upload_stream(Bucket::UserFiles, &path, data, size)
+        .await?;
+    let file =
+        catalog::register_object(&file_storage, &mut store, Bucket::UserFiles, &path, user_id)
+            .await?;
+    Ok(FileInfo::new(file, user_id))
+}
+
+/// Stores a part sent to a presigned upload url of a file storage without native presigning and
+/// returns its `ETag`.
+#[tracing::instrument(skip_all, err(Debug))]
+pub async fn put_signed(
+    file_storage: impl FileStorage,
+    token: &str,
+    data: Bytes,
+) -> Result<String> {
+    Ok(file_storage.put_signed(token, data).await?)
+}
+
+#[cfg(test)]
+mod tests {
+    use time::Duration;
+
+    use crate::{
+        connectors::memory::MemStore,
+        stores::files::{database::FileLock, storage::ChecksumAlgorithm},
+    };
+
+    use super::*;
+
+    #[tokio::test]
+    async fn abort_and_sweep_leases() {
+        let user_id = Uuid::new_v4();
+        let store = MemStore::new();
+        let req = UploadFileRequest {
+            name: "a.txt".to_owned(),
+            size: 1,
+            chunk_size: None,
+            checksums: None,
+        };
+        let resp = post(
+            store.clone(),
+            store.clone(),
+            user_id,
+            req,
+            &GenbuConfig::default(),
+        )
+        .await
+        .unwrap();
+        let lease = store.get(&resp.lease_id).await.unwrap().unwrap();
+        assert_eq!(Some(&lease.s3_upload_id), resp.upload_id.as_ref());
+
+        let other = Uuid::new_v4();
+        let config = UploadConfig::default();
+        let get_req = GetUrisRequest { lease_id: lease.id };
+        assert!(matches!(
+            get(store.clone(), store.clone(), other, get_req, &config).await,
+            Err(UploadAPIError::Forbidden(_))
+        ));
+        let finish_req = FinishUploadRequest {
+            lease_id: lease.id,
+            parts: vec![],
+        };
+        assert!(matches!(
+            finish_upload(
+                store.clone(),
+                store.clone(),
+                store.clone(),
+                other,
+                finish_req,
+                &GenbuConfig::default()
+            )
+            .await,
+            Err(UploadAPIError::Forbidden(_))
+        ));
+        assert!(!store.get(&lease.id).await.unwrap().unwrap().completed);
+        assert!(matches!(
+            abort_upload(store.clone(), store.clone(), other, lease.id).await,
+            Err(UploadAPIError::Forbidden(_))
+        ));
+        abort_upload(store.clone(), store.clone(), user_id, lease.id)
+            .await
+            .unwrap();
+        assert!(store.get(&lease.id).await.unwrap().is_none());
+        assert!(matches!(
+            store
+                .finish_multipart_upload(
+                    lease.bucket,
+                    &lease.name,
+                    &lease.s3_upload_id,
+                    vec![],
+                    None
+                )
+                .await,
+            Err(FileError::NotFound(_))
+        ));
+
+        let (_, upload_id) = store
+            .get_presigned_upload_urls(Bucket::UserFiles, "b.txt", 1, storage::MIN_PART_SIZE, None)
+            .await
+            .unwrap();
+        let expired = UploadLease {
+            s3_upload_id: upload_id.clone(),
+            name: "b.txt".to_owned(),
+            expires_at: OffsetDateTime::now_utc() - Duration::minutes(1),
+            ..UploadLease::template()
+        };
+        store.clone().add(&expired).await.unwrap();
+        let swept = sweep_expired_leases(store.clone(), store.clone())
+            .await
+            .unwrap();
+        assert_eq!(swept, 1);
+        assert!(store.get(&expired.id).await.unwrap().is_none());
+        assert!(store
+            .abort_multipart_upload(Bucket::UserFiles, "b.txt", &upload_id)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn resume_upload() {
+        let user_id = Uuid::new_v4();
+        let store = MemStore::new();
+        let config = GenbuConfig::default();
+        let req = UploadFileRequest {
+            name: "a.txt".to_owned(),
+            size: config.upload.chunk_size + 1,
+            chunk_size: None,
+            checksums: None,
+        };
+        let resp = post(store.clone(), store.clone(), user_id, req, &config)
+            .await
+            .unwrap();
+        assert_eq!(resp.uris.len(), 2);
+        let (_, token) = resp.uris[0].split_once("token=").unwrap();
+        let e_tag = store
+            .put_signed(token, Bytes::from_static(b"a"))
+            .await
+            .unwrap();
+
+        let resumed = resume(
+            store.clone(),
+            store.clone(),
+            user_id,
+            resp.lease_id,
+            &config.upload,
+        )
+        .await
+        .unwrap();
+        assert_eq!(Some(&resumed.upload_id), resp.upload_id.as_ref());
+        assert_eq!(resumed.parts.len(), 1);
+        assert_eq!(resumed.parts[0].part_number, 1);
+        assert_eq!(resumed.parts[0].e_tag, e_tag);
+        assert_eq!(resumed.missing.len(), 1);
+        assert_eq!(resumed.missing[0].part_number, 2);
+
+        // The urls of an existing lease belong to the upload which was already started
+        let get_req = GetUrisRequest {
+            lease_id: resp.lease_id,
+        };
+        let uris = get(
+            store.clone(),
+            store.clone(),
+            user_id,
+            get_req,
+            &config.upload,
+        )
+        .await
+        .unwrap();
+        assert_eq!(uris.upload_id, resp.upload_id);
+        assert_eq!(uris.uris.len(), 2);
+    }
+
+    async fn finish(
+        store: &MemStore,
+        user_id: Uuid,
+        lease_id: LeaseID,
+        parts: Vec<Part>,
+    ) -> Result<FileInfo> {
+        let req = FinishUploadRequest { lease_id, parts };
+        finish_upload(
+            store.clone(),
+            store.clone(),
+            store.clone(),
+            user_id,
+            req,
+            &GenbuConfig::default(),
+        )
+        .await
+    }
+
+    /// Sends every part to its presigned url.
+    async fn put_parts(store: &MemStore, uris: &[String], data: &[&[u8]]) -> Vec<Part> {
+        let mut parts = Vec::new();
+        for (part_number, (uri, data)) in (1..).zip(uris.iter().zip(data)) {
+            let (_, token) = uri.split_once("token=").unwrap();
+            let e_tag = store
+                .put_signed(token, Bytes::copy_from_slice(data))
+                .await
+                .unwrap();
+            parts.push(Part { e_tag, part_number });
+        }
+        parts
+    }
+
+    #[tokio::test]
+    async fn verify_finished_uploads() {
+        let user_id = Uuid::new_v4();
+        let store = MemStore::new();
+        let config = GenbuConfig::default();
+        let chunk_size = storage::MIN_PART_SIZE;
+        let content: Vec<u8> = (0..chunk_size + 3).map(|i| (i % 251) as u8).collect();
+        let (first, second) = content.split_at(chunk_size as usize);
+        let algorithm = ChecksumAlgorithm::Crc32c;
+        let checksums = PartChecksums {
+            algorithm,
+            parts: vec![
+                algorithm.encoded_digest(first),
+                algorithm.encoded_digest(second),
+            ],
+        };
+        let request = |checksums: Option<PartChecksums>, chunk_size| UploadFileRequest {
+            name: "a.bin".to_owned(),
+            size: content.len() as u64,
+            chunk_size: Some(chunk_size),
+            checksums,
+        };
+
+        assert!(matches!(
+            post(
+                store.clone(),
+                store.clone(),
+                user_id,
+                request(None, 1),
+                &config
+            )
+            .await,
+            Err(UploadAPIError::InvalidChunkSize(1))
+        ));
+        let missing_part = PartChecksums {
+            algorithm,
+            parts: checksums.parts[..1].to_vec(),
+        };
+        assert!(matches!(
+            post(
+                store.clone(),
+                store.clone(),
+                user_id,
+                request(Some(missing_part), chunk_size),
+                &config
+            )
+            .await,
+            Err(UploadAPIError::InvalidChecksums)
+        ));
+
+        // The previous content is kept while an upload doesn't match its checksums
+        let path = format!("{user_id}\\a.bin");
+        store
+            .clone()
+            .upload(Bucket::UserFiles, &path, b"previous".to_vec())
+            .await
+            .unwrap();
+        let file = catalog::register_object(
+            &store,
+            &mut store.clone(),
+            Bucket::UserFiles,
+            &path,
+            user_id,
+        )
+        .await
+        .unwrap();
+
+        let resp = post(
+            store.clone(),
+            store.clone(),
+            user_id,
+            request(Some(checksums.clone()), chunk_size),
+            &config,
+        )
+        .await
+        .unwrap();
+        let (_, token) = resp.uris[1].split_once("token=").unwrap();
+        assert!(matches!(
+            store.put_signed(token, Bytes::from_static(b"abc")).await,
+            Err(FileError::BadDigest(2))
+        ));
+        let (_, token) = resp.uris[0].split_once("token=").unwrap();
+        let first_tag = store
+            .put_signed(token, Bytes::copy_from_slice(first))
+            .await
+            .unwrap();
+        // Parts which aren't sent to a presigned url skip its checksum
+        let upload_id = resp.upload_id.unwrap();
+        let e_tag = store
+            .upload_part(
+                Bucket::UserFiles,
+                &path,
+                &upload_id,
+                2,
+                Bytes::from_static(b"abc"),
+            )
+            .await
+            .unwrap();
+        let parts = vec![
+            Part {
+                e_tag: first_tag,
+                part_number: 1,
+            },
+            Part {
+                e_tag,
+                part_number: 2,
+            },
+        ];
+        assert!(matches!(
+            finish(&store, user_id, resp.lease_id, parts).await,
+            Err(UploadAPIError::IntegrityError(IntegrityError::Checksum(..)))
+        ));
+        let meta = store.head_object(Bucket::UserFiles, &path).await.unwrap();
+        assert_eq!(meta.size, 8);
+        // The version, which kept the previous content, is gone again
+        assert!(store.get_versions(file.id).await.unwrap().is_empty());
+
+        let resp = post(
+            store.clone(),
+            store.clone(),
+            user_id,
+            request(Some(checksums.clone()), chunk_size),
+            &config,
+        )
+        .await
+        .unwrap();
+        let parts = put_parts(&store, &resp.uris, &[first, second]).await;
+        // A part which is left out doesn't add up to the size of the lease
+        assert!(matches!(
+            finish(&store, user_id, resp.lease_id, parts[..1].to_vec()).await,
+            Err(UploadAPIError::IntegrityError(IntegrityError::Size(..)))
+        ));
+
+        let resp = post(
+            store.clone(),
+            store.clone(),
+            user_id,
+            request(Some(checksums.clone()), chunk_size),
+            &config,
+        )
+        .await
+        .unwrap();
+        let parts = put_parts(&store, &resp.uris, &[first, second]).await;
+        let info = finish(&store, user_id, resp.lease_id, parts).await.unwrap();
+        assert_eq!(info.size, content.len() as i64);
+        let meta = store.head_object(Bucket::UserFiles, &path).await.unwrap();
+        assert_eq!(meta.checksum, checksums.object_checksum());
+    }
+
+    #[tokio::test]
+    async fn keep_leases_of_uploads_onto_locked_files() {
+        let user_id = Uuid::new_v4();
+        let mut store = MemStore::new();
+        let path = format!("{user_id}\\a.txt");
+        store
+            .clone()
+            .upload(Bucket::UserFiles, &path, b"previous".to_vec())
+            .await
+            .unwrap();
+        let file = catalog::register_object(
+            &store,
+            &mut store.clone(),
+            Bucket::UserFiles,
+            &path,
+            user_id,
+        )
+        .await
+        .unwrap();
+        let lock = FileLock::new();
+        store.lock(file.id.0, lock.clone()).await.unwrap();
+
+        let req = UploadFileRequest {
+            name: "a.txt".to_owned(),
+            size: 3,
+            chunk_size: None,
+            checksums: None,
+        };
+        let config = GenbuConfig::default();
+        let resp = post(store.clone(), store.clone(), user_id, req, &config)
+            .await
+            .unwrap();
+        let parts = put_parts(&store, &resp.uris, &[b"new"]).await;
+        assert!(matches!(
+            finish(&store, user_id, resp.lease_id, parts.clone()).await,
+            Err(UploadAPIError::UsageError(DBFileError::Locked(_)))
+        ));
+        // The lease is kept open and the file keeps its content
+        let lease = store.get(&resp.lease_id).await.unwrap().unwrap();
+        assert!(!lease.completed);
+        let meta = store.head_object(Bucket::UserFiles, &path).await.unwrap();
+        assert_eq!(meta.size, 8);
+
+        // Once the file is unlocked, the upload can be finished
+        store.unlock(file.id.0, lock).await.unwrap();
+        let info = finish(&store, user_id, resp.lease_id, parts).await.unwrap();
+        assert_eq!(info.size, 3);
+    }
+
+    #[tokio::test]
+    async fn quota_counts_open_leases() {
+        let user_id = Uuid::new_v4();
+        let store = MemStore::new();
+        let mut config = GenbuConfig::default();
+        config.quota.default_bytes = 5;
+        let req = |name: &str| UploadFileRequest {
+            name: name.to_owned(),
+            size: 3,
+            chunk_size: None,
+            checksums: None,
+        };
+        let resp = post(store.clone(), store.clone(), user_id, req("a.txt"), &config)
+            .await
+            .unwrap();
+        // The open lease reserves its size, so both uploads together can't exceed the quota
+        assert!(matches!(
+            post(store.clone(), store.clone(), user_id, req("b.txt"), &config).await,
+            Err(UploadAPIError::QuotaExceeded(6, 5))
+        ));
+
+        // Another write took up storage since the lease was created
+        quota::charge(&mut store.clone(), user_id, 3).await.unwrap();
+        let parts = put_parts(&store, &resp.uris, &[b"abc"]).await;
+        let req = FinishUploadRequest {
+            lease_id: resp.lease_id,
+            parts,
+        };
+        assert!(matches!(
+            finish_upload(
+                store.clone(),
+                store.clone(),
+                store.clone(),
+                user_id,
+                req.clone(),
+                &config
+            )
+            .await,
+            Err(UploadAPIError::QuotaExceeded(3, 2))
+        ));
+        // The lease is kept, so the upload can be finished once storage was freed
+        quota::charge(&mut store.clone(), user_id, -3)
+            .await
+            .unwrap();
+        finish_upload(
+            store.clone(),
+            store.clone(),
+            store.clone(),
+            user_id,
+            req,
+            &config,
+        )
+        .await
+        .unwrap();
+    }
+
+    fn content_stream(chunks: &[&'static [u8]]) -> ObjectStream {
+        let chunks: Vec<std::io::Result<Bytes>> = chunks
+            .iter()
+            .map(|chunk| Ok(Bytes::from_static(chunk)))
+            .collect();
+        Box::pin(futures::stream::iter(chunks))
+    }
+
+    #[tokio::test]
+    async fn put_small_file() {
+        let user_id = Uuid::new_v4();
+        let store = MemStore::new();
+        let config = GenbuConfig::default();
+        let req = PutContentRequest {
+            path: "a.txt".to_owned(),
+        };
+        let info = put_content(
+            store.clone(),
+            store.clone(),
+            user_id,
+            req.clone(),
+            Some(11),
+            content_stream(&[b"hello ", b"world"]),
+            &config,
+        )
+        .await
+        .unwrap();
+        assert_eq!(info.size, 11);
+        let path = userfiles::build_path(user_id, "a.txt");
+        let meta = store.head_object(Bucket::UserFiles, &path).await.unwrap();
+        assert_eq!(meta.size, 11);
+
+        // Content which doesn't match the announced size replaces nothing
+        assert!(put_content(
+            store.clone(),
+            store.clone(),
+            user_id,
+            req.clone(),
+            Some(3),
+            content_stream(&[b"too long"]),
+            &config,
+        )
+        .await
+        .is_err());
+        let meta = store.head_object(Bucket::UserFiles, &path).await.unwrap();
+        assert_eq!(meta.size, 11);
+
+        assert!(matches!(
+            put_content(
+                store.clone(),
+                store.clone(),
+                user_id,
+                req.clone(),
+                None,
+                content_stream(&[]),
+                &config,
+            )
+            .await,
+            Err(UploadAPIError::LengthRequired)
+        ));
+        let size = config.upload.max_content_size + 1;
+        assert!(matches!(
+            put_content(
+                store.clone(),
+                store.clone(),
+                user_id,
+                req,
+                Some(size),
+                content_stream(&[]),
+                &config,
+            )
+            .await,
+            Err(UploadAPIError::FileTooLarge(..))
+        ));
+        let req = PutContentRequest {
+            path: "a\\\\b.txt".to_owned(),
+        };
+        assert!(matches!(
+            put_content(
+                store.clone(),
+                store.clone(),
+                user_id,
+                req,
+                Some(0),
+                content_stream(&[]),
+                &config,
+            )
+            .await,
+            Err(UploadAPIError::InvalidPath(_))
+        ));
+    }
+}
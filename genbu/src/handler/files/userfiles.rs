@@ -1,17 +1,53 @@
 use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
 use utoipa::{IntoParams, ToSchema};
 
-use crate::stores::{
-    files::filesystem::{Filesystem, FilesystemError, Userfile},
-    Uuid,
+use crate::{
+    config::QuotaConfig,
+    stores::{
+        files::{
+            blobs::BlobStore,
+            database::{DBFile, DBFileError, DBFileStore, LeaseID},
+            filesystem::{moved_key, Filesystem, FilesystemError, Userfile},
+            quota::QuotaStore,
+            storage::Bucket,
+            trash::TrashStore,
+        },
+        Uuid,
+    },
+};
+use std::{collections::HashMap, fmt::Debug, ops::Deref};
+
+use super::{
+    blobs::{self, BlobAPIError},
+    catalog::{check_unlocked, ensure_folder, parent_path, CatalogAPIError, FileInfo},
+    quota::{self, QuotaAPIError},
+    trash::{move_to_trash, TrashAPIError},
 };
-use std::{fmt::Debug, ops::Deref};
 
 #[derive(Debug, thiserror::Error)]
 pub enum UserfilesAPIError {
     #[error("filesystem error")]
     Filesystem(#[from] FilesystemError),
 
+    #[error("database error")]
+    DatabaseError(#[from] DBFileError),
+
+    #[error("catalog error")]
+    CatalogError(#[from] CatalogAPIError),
+
+    #[error("trash error")]
+    TrashError(#[from] TrashAPIError),
+
+    #[error("blob error")]
+    BlobError(#[from] BlobAPIError),
+
+    #[error("quota error")]
+    QuotaError(#[from] QuotaAPIError),
+
+    #[error("invalid path `{0}`")]
+    InvalidPath(String),
+
     #[error("file {0:?} not found")]
     NotFound(Box<dyn Debug + Send + Sync>),
 }
@@ -29,14 +65,32 @@ pub struct GetUserfilesResponse {
     pub files: Vec<Userfile>,
 }
 
+/// Lists the files and folders inside a folder of the user. Deduplicated files are stored as
+/// empty objects, so the size of empty files is taken from the catalog.
 #[tracing::instrument(skip_all)]
 pub async fn get_userfiles(
     filesystem: impl Filesystem,
+    file_db: impl DBFileStore,
     user_id: Uuid,
     get_req: &GetUserfilesRequest,
 ) -> Result<GetUserfilesResponse> {
     let path = build_path(user_id, &get_req.base_path);
     let mut files = filesystem.list(user_id, &path).await?;
+    if files.iter().any(|f| !f.is_folder && f.size == Some(0)) {
+        let base_path = path.trim_end_matches('\\');
+        let sizes: HashMap<_, _> = file_db
+            .get_dbfiles_below(Bucket::UserFiles, base_path)
+            .await?
+            .into_iter()
+            .filter(|f| f.blob_id.is_some())
+            .map(|f| (f.path, f.size))
+            .collect();
+        for file in files.iter_mut().filter(|f| f.size == Some(0)) {
+            if let Some(size) = sizes.get(&file.name) {
+                file.size = Some(*size);
+            }
+        }
+    }
     files
         .iter_mut()
         .for_each(|f| f.name = f.name.split_off(build_path(user_id, "").len()));
@@ -48,16 +102,454 @@ pub struct DeleteUserfileRequest {
     path: String,
 }
 
+/// Moves a file, or a folder with everything inside it, into the trash of the user.
 pub async fn delete_userfile(
     mut filesystem: impl Filesystem,
+    mut store: impl DBFileStore + TrashStore,
     user_id: Uuid,
     delete_req: DeleteUserfileRequest,
 ) -> Result<()> {
-    let path = build_path(user_id, &delete_req.path);
-    filesystem.delete(&path).await?;
+    let path = checked_path(user_id, &delete_req.path)?;
+    move_to_trash(&mut filesystem, &mut store, user_id, &path, user_id).await?;
+    Ok(())
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, ToSchema)]
+pub struct MkdirRequest {
+    pub path: String,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, ToSchema)]
+pub struct MoveRequest {
+    pub from: String,
+    pub to: String,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, ToSchema)]
+pub struct RenameRequest {
+    pub path: String,
+    /// New name of the file or folder, without its parent folder
+    pub name: String,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, ToSchema)]
+pub struct CopyRequest {
+    pub from: String,
+    pub to: String,
+}
+
+/// Builds the key of a path inside the folder of the user. Empty paths, which would point to the
+/// folder of the user itself, and paths with empty segments are rejected.
+fn checked_path(user_id: Uuid, path: &str) -> Result<String> {
+    if !is_valid_path(path) {
+        return Err(UserfilesAPIError::InvalidPath(path.to_owned()));
+    }
+    Ok(build_path(user_id, path))
+}
+
+/// Builds the keys of a move or copy from `from` to `to`. A folder can't be moved into itself.
+fn transfer_paths(user_id: Uuid, from: &str, to: &str) -> Result<(String, String)> {
+    let (from_key, to_key) = (checked_path(user_id, from)?, checked_path(user_id, to)?);
+    if moved_key(&to_key, &from_key, "").is_some() {
+        return Err(UserfilesAPIError::InvalidPath(to.to_owned()));
+    }
+    Ok((from_key, to_key))
+}
+
+/// Returns the id of the folder which will contain the entry at `path`.
+async fn parent_folder(
+    file_db: &mut impl DBFileStore,
+    path: &str,
+    user_id: Uuid,
+) -> Result<Option<LeaseID>> {
+    Ok(match parent_path(path) {
+        Some(parent) => Some(ensure_folder(file_db, Bucket::UserFiles, parent, user_id).await?),
+        None => None,
+    })
+}
+
+/// Creates a folder, including every missing folder above it.
+#[tracing::instrument(skip(filesystem, file_db))]
+pub async fn mkdir(
+    mut filesystem: impl Filesystem,
+    mut file_db: impl DBFileStore,
+    user_id: Uuid,
+    req: MkdirRequest,
+) -> Result<FileInfo> {
+    let path = checked_path(user_id, &req.path)?;
+    if filesystem.exists(&path).await? {
+        return Err(FilesystemError::FileAlreadyExists(req.path).into());
+    }
+    filesystem.mkdir(&path).await?;
+    let id = ensure_folder(&mut file_db, Bucket::UserFiles, &path, user_id).await?;
+    let folder = file_db
+        .get_dbfile(id.0)
+        .await?
+        .ok_or(CatalogAPIError::NotFound(id.0))?;
+    Ok(FileInfo::new(folder, user_id))
+}
+
+/// Moves a file or a folder with its content. The catalog entries keep their ids. Files which
+/// are locked through WOPI can't be moved.
+#[tracing::instrument(skip(filesystem, file_db))]
+pub async fn move_userfile(
+    mut filesystem: impl Filesystem,
+    mut file_db: impl DBFileStore,
+    user_id: Uuid,
+    req: MoveRequest,
+) -> Result<()> {
+    let (from, to) = transfer_paths(user_id, &req.from, &req.to)?;
+    check_unlocked(&file_db, Bucket::UserFiles, &from).await?;
+    filesystem.rename(&from, &to).await?;
+    let parent_id = parent_folder(&mut file_db, &to, user_id).await?;
+    file_db
+        .move_dbfiles(Bucket::UserFiles, &from, &to, parent_id)
+        .await?;
     Ok(())
 }
 
+/// Renames a file or a folder, without moving it to another folder.
+pub async fn rename_userfile(
+    filesystem: impl Filesystem,
+    file_db: impl DBFileStore,
+    user_id: Uuid,
+    req: RenameRequest,
+) -> Result<()> {
+    if req.name.is_empty() || req.name.contains('\\') {
+        return Err(UserfilesAPIError::InvalidPath(req.name));
+    }
+    let to = match parent_path(&req.path) {
+        Some(parent) => format!("{parent}\\{}", req.name),
+        None => req.name,
+    };
+    let req = MoveRequest { from: req.path, to };
+    move_userfile(filesystem, file_db, user_id, req).await
+}
+
+/// Copies a file or a folder with its content. The copies are new catalog entries owned by the
+/// user, so they have to fit into the storage quota of the user.
+#[tracing::instrument(skip(filesystem, file_db, config))]
+pub async fn copy_userfile(
+    mut filesystem: impl Filesystem,
+    mut file_db: impl DBFileStore + QuotaStore + BlobStore,
+    user_id: Uuid,
+    req: CopyRequest,
+    config: &QuotaConfig,
+) -> Result<()> {
+    let (from, to) = transfer_paths(user_id, &req.from, &req.to)?;
+    let files = file_db.get_dbfiles_below(Bucket::UserFiles, &from).await?;
+    let size = files.iter().map(|file| file.size).sum();
+    quota::check(&file_db, user_id, size, config).await?;
+    filesystem.copy(&from, &to).await?;
+
+    // Entries are ordered by path, so every folder is copied before its content
+    let mut copied_ids = HashMap::new();
+    let mut copied_size = 0;
+    let parent_id = parent_folder(&mut file_db, &to, user_id).await?;
+    for file in files {
+        let Some(path) = moved_key(&file.path, &from, &to) else {
+            continue;
+        };
+        let copy = DBFile {
+            id: LeaseID(Uuid::new_v4()),
+            path,
+            parent_id: if file.path == from {
+                parent_id
+            } else {
+                file.parent_id.and_then(|id| copied_ids.get(&id).copied())
+            },
+            lock: None,
+            lock_expires_at: None,
+            created_by: user_id,
+            created_at: OffsetDateTime::now_utc(),
+            ..file
+        };
+        copied_ids.insert(file.id, copy.id);
+        copied_size += copy.size;
+        // Copies of deduplicated files share the blob of the original
+        file_db.add_dbfile(&copy).await?;
+        blobs::reference(&mut file_db, &copy).await?;
+    }
+    // The copies belong to the user who copied them
+    quota::charge(&mut file_db, user_id, copied_size).await?;
+    Ok(())
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum BulkOperation {
+    Delete,
+    Move,
+    Copy,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, ToSchema)]
+pub struct BulkRequest {
+    pub operation: BulkOperation,
+    pub paths: Vec<String>,
+    /// Folder the files are moved or copied into, the folder of the user if it's empty
+    #[serde(default)]
+    pub target: String,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, ToSchema)]
+pub struct BulkItemResult {
+    pub path: String,
+    /// HTTP status code of the operation on this path
+    pub status: u16,
+    pub error: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, ToSchema)]
+pub struct BulkResponse {
+    pub results: Vec<BulkItemResult>,
+}
+
+/// Applies one operation to many paths. A failure only affects its own path, so the result of
+/// every path is returned in the order of the request.
+#[tracing::instrument(skip(filesystem, file_db, config))]
+pub async fn bulk<F: Filesystem, D: DBFileStore + QuotaStore + TrashStore + BlobStore>(
+    filesystem: F,
+    file_db: D,
+    user_id: Uuid,
+    req: BulkRequest,
+    config: &QuotaConfig,
+) -> Vec<(String, Result<()>)> {
+    let mut results = Vec::with_capacity(req.paths.len());
+    for path in req.paths {
+        let name = path.rsplit('\\').next().unwrap_or_default();
+        let to = if req.target.is_empty() {
+            name.to_owned()
+        } else {
+            format!("{}\\{name}", req.target)
+        };
+        let (filesystem, file_db) = (filesystem.clone(), file_db.clone());
+        let res = match req.operation {
+            BulkOperation::Delete => {
+                let req = DeleteUserfileRequest { path: path.clone() };
+                delete_userfile(filesystem, file_db, user_id, req).await
+            }
+            BulkOperation::Move => {
+                let req = MoveRequest {
+                    from: path.clone(),
+                    to,
+                };
+                move_userfile(filesystem, file_db, user_id, req).await
+            }
+            BulkOperation::Copy => {
+                let req = CopyRequest {
+                    from: path.clone(),
+                    to,
+                };
+                copy_userfile(filesystem, file_db, user_id, req, config).await
+            }
+        };
+        results.push((path, res));
+    }
+    results
+}
+
+/// Checks that a path relative to the folder of a user doesn't contain empty segments or `.` and
+/// `..` segments, which would escape the folder the path points into.
+#[must_use]
+pub fn is_valid_path(path: &str) -> bool {
+    !path
+        .split('\\')
+        .any(|segment| segment.is_empty() || segment == "." || segment == "..")
+}
+
 pub fn build_path(user_id: Uuid, path: &str) -> String {
     format!("{}\\{}", user_id, path.deref())
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        connectors::memory::MemStore, handler::files::catalog::register_object,
+        stores::files::FileStorage,
+    };
+
+    use super::*;
+
+    async fn store_with_file(user_id: Uuid, path: &str) -> MemStore {
+        let mut store = MemStore::new();
+        let key = build_path(user_id, path);
+        store
+            .upload(Bucket::UserFiles, &key, b"content".to_vec())
+            .await
+            .unwrap();
+        register_object(&store, &mut store.clone(), Bucket::UserFiles, &key, user_id)
+            .await
+            .unwrap();
+        store
+    }
+
+    #[test]
+    fn valid_paths() {
+        assert!(is_valid_path("docs\\a.txt"));
+        assert!(is_valid_path("..a.txt"));
+        for path in [
+            "",
+            "docs\\",
+            "\\a.txt",
+            "docs\\\\a.txt",
+            "..\\a.txt",
+            "docs\\.\\a.txt",
+        ] {
+            assert!(!is_valid_path(path), "{path}");
+        }
+    }
+
+    #[tokio::test]
+    async fn move_and_copy_folders() {
+        let user_id = Uuid::new_v4();
+        let store = store_with_file(user_id, "docs\\a.txt").await;
+        let file = store
+            .get_dbfile_by_path(Bucket::UserFiles, &build_path(user_id, "docs\\a.txt"))
+            .await
+            .unwrap()
+            .unwrap();
+
+        let folder = mkdir(
+            store.clone(),
+            store.clone(),
+            user_id,
+            MkdirRequest {
+                path: "archive".to_owned(),
+            },
+        )
+        .await
+        .unwrap();
+        assert!(folder.is_folder);
+
+        let req = MoveRequest {
+            from: "docs".to_owned(),
+            to: "archive\\docs".to_owned(),
+        };
+        move_userfile(store.clone(), store.clone(), user_id, req)
+            .await
+            .unwrap();
+        let moved = store.get_dbfile(file.id.0).await.unwrap().unwrap();
+        assert_eq!(moved.path, build_path(user_id, "archive\\docs\\a.txt"));
+        let moved_folder = store.get_dbfile(moved.parent_id.unwrap().0).await.unwrap();
+        assert_eq!(moved_folder.unwrap().parent_id, Some(folder.id));
+
+        // The copy is charged to the user, who only has room for the original
+        let config = QuotaConfig {
+            default_bytes: 7,
+            ..QuotaConfig::default()
+        };
+        let req = CopyRequest {
+            from: "archive\\docs".to_owned(),
+            to: "docs".to_owned(),
+        };
+        assert!(matches!(
+            copy_userfile(store.clone(), store.clone(), user_id, req.clone(), &config).await,
+            Err(UserfilesAPIError::QuotaError(QuotaAPIError::QuotaExceeded(
+                7, 0
+            )))
+        ));
+        let config = QuotaConfig {
+            default_bytes: 14,
+            ..QuotaConfig::default()
+        };
+        copy_userfile(store.clone(), store.clone(), user_id, req, &config)
+            .await
+            .unwrap();
+        let copy = store
+            .get_dbfile_by_path(Bucket::UserFiles, &build_path(user_id, "docs\\a.txt"))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_ne!(copy.id, file.id);
+        let copied_folder = store.get_dbfile(copy.parent_id.unwrap().0).await.unwrap();
+        assert_eq!(copied_folder.unwrap().path, build_path(user_id, "docs"));
+        assert!(store
+            .exists(&build_path(user_id, "archive\\docs\\a.txt"))
+            .await
+            .unwrap());
+    }
+
+    #[tokio::test]
+    async fn reject_conflicts() {
+        let user_id = Uuid::new_v4();
+        let mut store = store_with_file(user_id, "a.txt").await;
+        store
+            .upload(
+                Bucket::UserFiles,
+                &build_path(user_id, "b.txt"),
+                b"other".to_vec(),
+            )
+            .await
+            .unwrap();
+
+        let req = RenameRequest {
+            path: "a.txt".to_owned(),
+            name: "b.txt".to_owned(),
+        };
+        assert!(matches!(
+            rename_userfile(store.clone(), store.clone(), user_id, req).await,
+            Err(UserfilesAPIError::Filesystem(
+                FilesystemError::FileAlreadyExists(_)
+            ))
+        ));
+        let req = MoveRequest {
+            from: "missing".to_owned(),
+            to: "c.txt".to_owned(),
+        };
+        assert!(matches!(
+            move_userfile(store.clone(), store.clone(), user_id, req).await,
+            Err(UserfilesAPIError::Filesystem(FilesystemError::NotFound(_)))
+        ));
+        let req = MoveRequest {
+            from: "docs".to_owned(),
+            to: "docs\\inner".to_owned(),
+        };
+        assert!(matches!(
+            move_userfile(store.clone(), store, user_id, req).await,
+            Err(UserfilesAPIError::InvalidPath(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn bulk_delete_is_recursive() {
+        let user_id = Uuid::new_v4();
+        let mut store = store_with_file(user_id, "docs\\sub\\a.txt").await;
+        store
+            .upload(
+                Bucket::UserFiles,
+                &build_path(user_id, "b.txt"),
+                b"other".to_vec(),
+            )
+            .await
+            .unwrap();
+
+        let config = QuotaConfig::default();
+        let req = BulkRequest {
+            operation: BulkOperation::Move,
+            paths: vec!["b.txt".to_owned(), "missing".to_owned()],
+            target: "docs".to_owned(),
+        };
+        let results = bulk(store.clone(), store.clone(), user_id, req, &config).await;
+        assert!(results[0].1.is_ok());
+        assert!(matches!(
+            results[1].1,
+            Err(UserfilesAPIError::Filesystem(FilesystemError::NotFound(_)))
+        ));
+
+        let req = BulkRequest {
+            operation: BulkOperation::Delete,
+            paths: vec!["docs".to_owned()],
+            target: String::new(),
+        };
+        let results = bulk(store.clone(), store.clone(), user_id, req, &config).await;
+        assert!(results[0].1.is_ok());
+        let docs = build_path(user_id, "docs");
+        assert!(!store.exists(&docs).await.unwrap());
+        assert!(store
+            .get_dbfiles_below(Bucket::UserFiles, &docs)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+}
@@ -0,0 +1,418 @@
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use time::{Duration, OffsetDateTime};
+use tracing::error;
+use utoipa::ToSchema;
+
+use crate::{
+    config::VersionsConfig,
+    stores::{
+        files::{
+            blobs::BlobStore,
+            database::{DBFile, DBFileError, DBFileStore, LeaseID},
+            quota::QuotaStore,
+            storage::{Bucket, FileError, FileStorage},
+            versions::{FileVersion, VersionStore},
+        },
+        Uuid,
+    },
+};
+
+use super::{
+    blobs::content_key,
+    catalog::{self, get_accessible, CatalogAPIError, FileInfo},
+};
+
+#[derive(Debug, Error)]
+pub enum VersionAPIError {
+    #[error("file storage error")]
+    StorageError(#[from] FileError),
+
+    #[error("database error")]
+    DatabaseError(#[from] DBFileError),
+
+    #[error("catalog error")]
+    CatalogError(#[from] CatalogAPIError),
+
+    #[error("version {0} not found")]
+    NotFound(Uuid),
+}
+
+pub type VersionAPIResult<T> = std::result::Result<T, VersionAPIError>;
+type Result<T> = VersionAPIResult<T>;
+
+/// A previous version of a file, as it is shown to its users.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct VersionInfo {
+    pub id: Uuid,
+    pub file_id: LeaseID,
+    pub size: i64,
+    pub checksum: Option<String>,
+    /// When the content of this version was written
+    #[serde(with = "time::serde::iso8601")]
+    pub modified_at: OffsetDateTime,
+    /// When this version was replaced by a newer one
+    #[serde(with = "time::serde::iso8601")]
+    pub created_at: OffsetDateTime,
+}
+
+impl From<FileVersion> for VersionInfo {
+    fn from(version: FileVersion) -> Self {
+        Self {
+            id: version.id,
+            file_id: version.file_id,
+            size: version.size,
+            checksum: version.checksum,
+            modified_at: version.modified_at,
+            created_at: version.created_at,
+        }
+    }
+}
+
+/// Copies the current content of a file into a new version. Files without stored content have
+/// nothing to keep, so `None` is returned for them.
+async fn keep_version(
+    file_storage: &mut impl FileStorage,
+    store: &mut impl VersionStore,
+    file: &DBFile,
+) -> Result<Option<FileVersion>> {
+    let key = content_key(file);
+    let meta = match file_storage.head_object(file.bucket, &key).await {
+        Ok(meta) => meta,
+        Err(FileError::NotFound(_)) => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let mut version = FileVersion::new(file.id, file.bucket);
+    version.size = meta.size.try_into().unwrap_or(i64::MAX);
+    version.checksum = file.checksum.clone();
+    version.modified_at = meta.last_modified.unwrap_or(file.created_at);
+
+    file_storage
+        .copy_object(file.bucket, &key, &version.path)
+        .await?;
+    Ok(Some(store.add_version(&version).await?))
+}
+
+/// Keeps the current content of a file as a version, before it's overwritten. The oldest
+/// versions are pruned first, so the new version is never pruned itself. `None` is returned if
+/// no version is kept, because versioning is disabled or the file has no stored content.
+#[tracing::instrument(skip(file_storage, store, config), err(Debug))]
+pub async fn snapshot(
+    file_storage: &mut impl FileStorage,
+    store: &mut impl VersionStore,
+    file: &DBFile,
+    config: &VersionsConfig,
+) -> Result<Option<FileVersion>> {
+    let max_count = usize::try_from(config.max_count).unwrap_or(usize::MAX);
+    // The new version takes the place of the oldest one
+    let kept = max_count.saturating_sub(1);
+    prune(file_storage, store, file.id, kept, config).await?;
+    if max_count == 0 {
+        return Ok(None);
+    }
+    keep_version(file_storage, store, file).await
+}
+
+/// Keeps the current content of the catalogued file at `path` as a version, before it's
+/// overwritten by a write to this path.
+pub async fn snapshot_path(
+    file_storage: &mut impl FileStorage,
+    store: &mut (impl DBFileStore + VersionStore),
+    bucket: Bucket,
+    path: &str,
+    config: &VersionsConfig,
+) -> Result<Option<FileVersion>> {
+    match store.get_dbfile_by_path(bucket, path).await? {
+        Some(file) if !file.is_folder => snapshot(file_storage, store, &file, config).await,
+        _ => Ok(None),
+    }
+}
+
+/// Deletes a version with its stored content.
+async fn delete_version(
+    file_storage: &mut impl FileStorage,
+    store: &mut impl VersionStore,
+    version: &FileVersion,
+) -> Result<()> {
+    file_storage
+        .delete_file(version.bucket, &version.path)
+        .await?;
+    store.delete_version(version.id).await?;
+    Ok(())
+}
+
+/// Puts the content a version was just kept from back in place, after the write which replaced it
+/// failed, and deletes the version again.
+pub(crate) async fn roll_back(
+    file_storage: &mut impl FileStorage,
+    store: &mut impl VersionStore,
+    file: &DBFile,
+    version: &FileVersion,
+) -> Result<()> {
+    if file.blob_id.is_some() {
+        // The content of a deduplicated file stays in its blob, so its own object is left empty
+        file_storage
+            .upload(file.bucket, &file.path, Vec::new())
+            .await?;
+    } else {
+        file_storage
+            .copy_object(file.bucket, &version.path, &file.path)
+            .await?;
+    }
+    delete_version(file_storage, store, version).await
+}
+
+fn is_expired(version: &FileVersion, config: &VersionsConfig, now: OffsetDateTime) -> bool {
+    config.max_age_days > 0 && version.created_at < now - Duration::days(config.max_age_days.into())
+}
+
+/// Deletes the versions of a file which exceed `max_count` or the maximum age.
+async fn prune(
+    file_storage: &mut impl FileStorage,
+    store: &mut impl VersionStore,
+    file_id: LeaseID,
+    max_count: usize,
+    config: &VersionsConfig,
+) -> Result<()> {
+    let now = OffsetDateTime::now_utc();
+    // Versions are ordered from the most recent one, so the oldest ones exceed the count
+    let versions = store.get_versions(file_id).await?;
+    for (i, version) in versions.iter().enumerate() {
+        if i >= max_count || is_expired(version, config, now) {
+            delete_version(file_storage, store, version).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Deletes the versions of a file which exceed the maximum count or age.
+pub async fn prune_versions(
+    file_storage: &mut impl FileStorage,
+    store: &mut impl VersionStore,
+    file_id: LeaseID,
+    config: &VersionsConfig,
+) -> Result<()> {
+    let max_count = usize::try_from(config.max_count).unwrap_or(usize::MAX);
+    prune(file_storage, store, file_id, max_count, config).await
+}
+
+/// Deletes the versions of every file, which are older than `max_age`. A version which can't be
+/// deleted is skipped, so it's retried on the next run. Returns the number of deleted versions.
+#[tracing::instrument(skip(file_storage, store))]
+pub async fn prune_expired_versions(
+    mut file_storage: impl FileStorage,
+    mut store: impl VersionStore,
+    max_age: Duration,
+) -> Result<usize> {
+    let created_before = OffsetDateTime::now_utc() - max_age;
+    let mut pruned = 0;
+    let expired = store.get_versions_before(created_before).await?;
+    for version in expired {
+        match delete_version(&mut file_storage, &mut store, &version).await {
+            Ok(()) => pruned += 1,
+            Err(e) => error!("unable to prune version {}: {e:?}", version.id),
+        }
+    }
+    Ok(pruned)
+}
+
+/// Deletes every version of a file, before the file itself is deleted for good.
+pub async fn delete_versions(
+    file_storage: &mut impl FileStorage,
+    store: &mut impl VersionStore,
+    file_id: LeaseID,
+) -> Result<()> {
+    let versions = store.get_versions(file_id).await?;
+    for version in versions {
+        delete_version(file_storage, store, &version).await?;
+    }
+    Ok(())
+}
+
+/// Returns a version of a file the user has access to.
+async fn get_own_version(
+    store: &(impl DBFileStore + VersionStore),
+    user_id: Uuid,
+    file_id: Uuid,
+    version_id: Uuid,
+) -> Result<(DBFile, FileVersion)> {
+    let file = get_accessible(store, user_id, file_id).await?;
+    let version = store
+        .get_version(version_id)
+        .await?
+        .filter(|version| version.file_id == file.id)
+        .ok_or(VersionAPIError::NotFound(version_id))?;
+    Ok((file, version))
+}
+
+#[tracing::instrument(skip(store))]
+pub async fn list_versions(
+    store: impl DBFileStore + VersionStore,
+    user_id: Uuid,
+    file_id: Uuid,
+) -> Result<Vec<VersionInfo>> {
+    let file = get_accessible(&store, user_id, file_id).await?;
+    Ok(store
+        .get_versions(file.id)
+        .await?
+        .into_iter()
+        .map(VersionInfo::from)
+        .collect())
+}
+
+/// Returns a download url for a version of a file.
+#[tracing::instrument(skip(file_storage, store))]
+pub async fn download_version(
+    file_storage: impl FileStorage,
+    store: impl DBFileStore + VersionStore,
+    user_id: Uuid,
+    file_id: Uuid,
+    version_id: Uuid,
+) -> Result<String> {
+    let (_, version) = get_own_version(&store, user_id, file_id, version_id).await?;
+    Ok(file_storage
+        .get_download_url(version.bucket, &version.path)
+        .await?)
+}
+
+/// Makes a version the current content of its file. The replaced content is kept as a new
+/// version, so restoring can be undone.
+#[tracing::instrument(skip(file_storage, store, config))]
+pub async fn restore_version(
+    mut file_storage: impl FileStorage,
+    mut store: impl DBFileStore + QuotaStore + VersionStore + BlobStore,
+    user_id: Uuid,
+    file_id: Uuid,
+    version_id: Uuid,
+    config: &VersionsConfig,
+) -> Result<FileInfo> {
+    let (file, version) = get_own_version(&store, user_id, file_id, version_id).await?;
+    // Files which are edited through WOPI can only be changed by the lock holder
+    if let Some(lock) = file.current_lock() {
+        return Err(DBFileError::Locked(Some(lock.clone())).into());
+    }
+    // The versions are only pruned after the restore, as the restored version could be pruned
+    keep_version(&mut file_storage, &mut store, &file).await?;
+    file_storage
+        .copy_object(file.bucket, &version.path, &file.path)
+        .await?;
+    prune_versions(&mut file_storage, &mut store, file.id, config).await?;
+    let file = catalog::register_object(
+        &file_storage,
+        &mut store,
+        file.bucket,
+        &file.path,
+        file.created_by,
+    )
+    .await?;
+    Ok(FileInfo::new(file, user_id))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{connectors::memory::MemStore, handler::files::userfiles::build_path};
+
+    use super::*;
+
+    async fn write(store: &mut MemStore, path: &str, data: &[u8], config: &VersionsConfig) {
+        snapshot_path(
+            &mut store.clone(),
+            &mut store.clone(),
+            Bucket::UserFiles,
+            path,
+            config,
+        )
+        .await
+        .unwrap();
+        store
+            .upload(Bucket::UserFiles, path, data.to_vec())
+            .await
+            .unwrap();
+        catalog::register_object(
+            &store.clone(),
+            &mut store.clone(),
+            Bucket::UserFiles,
+            path,
+            Uuid::nil(),
+        )
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn restore_and_prune_versions() {
+        let config = VersionsConfig {
+            max_count: 2,
+            ..VersionsConfig::default()
+        };
+        let user_id = Uuid::nil();
+        let mut store = MemStore::new();
+        let path = build_path(user_id, "a.txt");
+        for data in [b"one".as_slice(), b"two", b"three", b"four"] {
+            write(&mut store, &path, data, &config).await;
+        }
+        let file = store
+            .get_dbfile_by_path(Bucket::UserFiles, &path)
+            .await
+            .unwrap()
+            .unwrap();
+
+        // Only the two most recent of the three previous contents are kept
+        let versions = list_versions(store.clone(), user_id, file.id.0)
+            .await
+            .unwrap();
+        assert_eq!(
+            versions.iter().map(|v| v.size).collect::<Vec<_>>(),
+            vec![5, 3]
+        );
+
+        let restored = restore_version(
+            store.clone(),
+            store.clone(),
+            user_id,
+            file.id.0,
+            versions[1].id,
+            &config,
+        )
+        .await
+        .unwrap();
+        assert_eq!((restored.id, restored.size), (file.id, 3));
+        assert_eq!(
+            list_versions(store.clone(), user_id, file.id.0)
+                .await
+                .unwrap()[0]
+                .size,
+            4
+        );
+        assert!(matches!(
+            download_version(
+                store.clone(),
+                store,
+                Uuid::new_v4(),
+                file.id.0,
+                versions[0].id
+            )
+            .await,
+            Err(VersionAPIError::CatalogError(CatalogAPIError::NotFound(_)))
+        ));
+    }
+
+    #[tokio::test]
+    async fn keep_no_versions() {
+        let config = VersionsConfig {
+            max_count: 0,
+            ..VersionsConfig::default()
+        };
+        let mut store = MemStore::new();
+        let path = build_path(Uuid::nil(), "a.txt");
+        write(&mut store, &path, b"one", &config).await;
+        let file = store
+            .get_dbfile_by_path(Bucket::UserFiles, &path)
+            .await
+            .unwrap()
+            .unwrap();
+        let version = snapshot(&mut store.clone(), &mut store.clone(), &file, &config).await;
+        assert!(version.unwrap().is_none());
+        assert!(store.get_versions(file.id).await.unwrap().is_empty());
+    }
+}
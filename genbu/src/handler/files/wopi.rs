@@ -1,104 +1,916 @@
-use bytes::Bytes;
+use bytes::{Bytes, BytesMut};
+use futures::TryStreamExt;
+use genbu_auth::authn::{decode_claims, encode_claims, JWTError};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use time::{format_description::well_known::Rfc3339, Duration, OffsetDateTime};
 use tracing::error;
+use utoipa::{IntoParams, ToSchema};
 use wopi_rs::{
     file::{
-        CheckFileInfoRequest, CheckFileInfoResponse, FileRequest, FileRequestType, LockRequest,
-        LockResponse, PutRelativeFileRequest, PutRelativeFileResponse,
+        CheckFileInfoResponse, DeleteFileRequest, FileRequest, FileRequestType, GetFileRequest,
+        GetFileResponse, GetLockRequest, GetLockResponse, LockRequest, LockResponse,
+        PutFileRequest, PutRelativeFileRequest, PutRelativeFileResponse, RefreshLockRequest,
+        RenameFileRequest, RenameFileResponse, UnlockAndRelockRequest, UnlockRequest,
     },
     FileBody, WopiResponse,
 };
 
-use crate::stores::{
-    files::{
-        database::{DBFileError, DBFileStore},
-        filesystem::Filesystem,
+use crate::{
+    config::{GenbuConfig, QuotaConfig},
+    connectors::discovery::{DiscoveryError, WopiDiscovery},
+    handler::files::{
+        blobs::content_key,
+        catalog,
+        quota::{self, QuotaAPIError},
+        trash, versions,
+    },
+    stores::{
+        files::{
+            blobs::BlobStore,
+            database::{DBFile, DBFileError, DBFileStore, FileLock, FilePermission, FileResult},
+            filesystem::{Filesystem, FilesystemError},
+            quota::QuotaStore,
+            storage::{Bucket, FileError, Object, ObjectMeta},
+            trash::TrashStore,
+            versions::VersionStore,
+        },
+        users::User,
+        Uuid,
     },
-    users::User,
-    Uuid,
 };
 
+/// Every file, which is opened through WOPI, is stored in this bucket under its `DBFile` path.
+const WOPI_BUCKET: Bucket = Bucket::UserFiles;
+
+/// Claims of a WOPI access token, which grants a single user access to a single file.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AccessTokenClaims {
+    pub sub: Uuid,
+    pub file_id: Uuid,
+    pub exp: i64,
+}
+
+/// A WOPI request which was authenticated with an access token.
+#[derive(Clone, Debug)]
+pub struct WopiAccess {
+    pub user: User,
+    pub file_id: Uuid,
+}
+
+#[derive(Debug, Error)]
+pub enum WopiAPIError {
+    #[error("file {0} not found")]
+    NotFound(Uuid),
+
+    #[error("user isn't allowed to {1} file {0}")]
+    Forbidden(Uuid, String),
+
+    #[error("database error")]
+    DatabaseError(#[from] DBFileError),
+
+    #[error("unable to create access token")]
+    TokenError(#[from] JWTError),
+
+    #[error("office server discovery failed")]
+    DiscoveryError(#[from] DiscoveryError),
+
+    #[error("office server doesn't support `{0}` files")]
+    UnsupportedFileType(String),
+}
+
+pub type WopiAPIResult<T> = std::result::Result<T, WopiAPIError>;
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct AccessTokenRequest {
+    pub file_id: Uuid,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct AccessTokenResponse {
+    pub access_token: String,
+    /// Expiry of the access token in milliseconds since the unix epoch, as expected by WOPI
+    /// clients
+    pub access_token_ttl: i64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema, IntoParams)]
+pub struct LaunchRequest {
+    pub file_id: Uuid,
+    /// Discovery action the file is opened with. Defaults to `edit` if the user may write the
+    /// file and to `view` otherwise
+    pub action: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct LaunchResponse {
+    /// Url of the office server, the access token has to be POSTed to as form data
+    pub url: String,
+    pub access_token: String,
+    /// Expiry of the access token in milliseconds since the unix epoch
+    pub access_token_ttl: i64,
+}
+
+/// Discovery actions which only need read access.
+const READ_ACTIONS: [&str; 3] = ["view", "embedview", "mobileView"];
+
+/// Returns a file together with the permission of the user. Files without access are reported
+/// as missing, so their existence isn't leaked.
+async fn accessible_file(
+    file_db: &impl DBFileStore,
+    user_id: Uuid,
+    file_id: Uuid,
+) -> WopiAPIResult<(DBFile, FilePermission)> {
+    file_db
+        .get_dbfile(file_id)
+        .await?
+        .and_then(|f| f.permission(user_id).map(|p| (f, p)))
+        .ok_or(WopiAPIError::NotFound(file_id))
+}
+
+fn issue_access_token(
+    config: &GenbuConfig,
+    user_id: Uuid,
+    file_id: Uuid,
+) -> WopiAPIResult<AccessTokenResponse> {
+    let lifetime = Duration::seconds(config.wopi.access_token_lifetime.into());
+    let expires_at = OffsetDateTime::now_utc() + lifetime;
+    let claims = AccessTokenClaims {
+        sub: user_id,
+        file_id,
+        exp: expires_at.unix_timestamp(),
+    };
+    Ok(AccessTokenResponse {
+        access_token: encode_claims(&claims, &config.auth.jwt_secret)?,
+        access_token_ttl: claims.exp * 1000,
+    })
+}
+
+/// Returns the `WOPISrc` of a file, i.e. the url under which WOPI clients reach it.
+fn wopi_src(config: &GenbuConfig, file_id: Uuid) -> String {
+    format!(
+        "{}/api/wopi/files/{file_id}",
+        config.server.public_url.trim_end_matches('/')
+    )
+}
+
+/// Issues an access token for a file the user has access to.
+#[tracing::instrument(skip(config, file_db))]
+pub async fn create_access_token(
+    config: &GenbuConfig,
+    file_db: impl DBFileStore,
+    user_id: Uuid,
+    req: AccessTokenRequest,
+) -> WopiAPIResult<AccessTokenResponse> {
+    accessible_file(&file_db, user_id, req.file_id).await?;
+    issue_access_token(config, user_id, req.file_id)
+}
+
+/// Looks up the office server action for a file and returns everything a client needs to open
+/// the file in the office server.
+#[tracing::instrument(skip(config, discovery, file_db))]
+pub async fn launch(
+    config: &GenbuConfig,
+    discovery: &WopiDiscovery,
+    file_db: impl DBFileStore,
+    user_id: Uuid,
+    req: LaunchRequest,
+) -> WopiAPIResult<LaunchResponse> {
+    let (db_file, permission) = accessible_file(&file_db, user_id, req.file_id).await?;
+    let action = match req.action {
+        Some(action) => action,
+        None if permission >= FilePermission::Write => "edit".to_owned(),
+        None => "view".to_owned(),
+    };
+    if permission < FilePermission::Write && !READ_ACTIONS.contains(&action.as_str()) {
+        return Err(WopiAPIError::Forbidden(req.file_id, action));
+    }
+
+    let ext = split_extension(split_path(&db_file.path).1)
+        .1
+        .trim_start_matches('.');
+    let discovery = discovery.get().await?;
+    let Some(action) = discovery.find_action(&action, Some(ext), db_file.mime_type.as_deref())
+    else {
+        return Err(WopiAPIError::UnsupportedFileType(ext.to_owned()));
+    };
+
+    let token = issue_access_token(config, user_id, req.file_id)?;
+    Ok(LaunchResponse {
+        url: action.launch_url(&wopi_src(config, req.file_id)),
+        access_token: token.access_token,
+        access_token_ttl: token.access_token_ttl,
+    })
+}
+
+/// Validates a WOPI access token and returns its claims.
+pub fn validate_access_token(
+    config: &GenbuConfig,
+    token: &str,
+) -> Result<AccessTokenClaims, JWTError> {
+    decode_claims(token, &config.auth.jwt_secret)
+}
+
+/// Returns the permission which is required for a WOPI operation.
+fn required_permission<T>(req: &FileRequestType<T>) -> FilePermission {
+    match req {
+        FileRequestType::CheckFileInfo(_)
+        | FileRequestType::GetFile(_)
+        | FileRequestType::GetLock(_) => FilePermission::Read,
+        _ => FilePermission::Write,
+    }
+}
+
 pub async fn wopi_file(
+    config: &GenbuConfig,
     filesystem: impl Filesystem,
-    file_db: impl DBFileStore,
-    user: &User,
+    file_db: impl DBFileStore + QuotaStore + TrashStore + VersionStore + BlobStore,
+    access: &WopiAccess,
     file_req: FileRequest<Bytes>,
 ) -> http::Response<Bytes> {
     let Ok(id) = Uuid::parse_str(&file_req.file_id) else {
         return WopiResponse::<LockResponse>::NotFound.into();
     };
+    // An access token is only valid for the file it was issued for
+    if id != access.file_id {
+        return WopiResponse::<LockResponse>::Unauthorized.into();
+    }
+    let db_file = match get_dbfile::<LockResponse>(&file_db, id).await {
+        Ok(f) => f,
+        Err(resp) => return resp.into(),
+    };
+    // Permissions are checked on every request, because they might have been revoked since the
+    // access token was issued
+    let Some(permission) = db_file
+        .permission(access.user.id)
+        .filter(|p| *p >= required_permission(&file_req.request))
+    else {
+        return WopiResponse::<LockResponse>::Unauthorized.into();
+    };
+
+    let user = &access.user;
     match file_req.request {
-        FileRequestType::CheckFileInfo(r) => {
-            handle_check_file_info(file_db, user, id, r).await.into()
+        FileRequestType::CheckFileInfo(_) => {
+            handle_check_file_info(filesystem, db_file, user, permission)
+                .await
+                .into()
+        }
+        FileRequestType::GetFile(r) => handle_get_file(filesystem, db_file, r).await.into(),
+        FileRequestType::PutFile(r) => handle_put_file(config, filesystem, file_db, db_file, r)
+            .await
+            .into(),
+        FileRequestType::Lock(r) => handle_lock(file_db, id, r).await.into(),
+        FileRequestType::GetLock(r) => handle_get_lock(db_file, r).await.into(),
+        FileRequestType::RefreshLock(r) => handle_refresh_lock(file_db, db_file, r).await.into(),
+        FileRequestType::Unlock(r) => handle_unlock(file_db, db_file, r).await.into(),
+        FileRequestType::UnlockAndRelock(r) => {
+            handle_unlock_and_relock(file_db, id, r).await.into()
         }
-        FileRequestType::Lock(r) => handle_lock(file_db, user, id, r).await.into(),
         FileRequestType::PutRelativeFile(r) => {
-            handle_put_relative(filesystem, user, r).await.into()
+            handle_put_relative(config, filesystem, file_db, user, db_file, r)
+                .await
+                .into()
         }
-        _ => todo!(),
+        FileRequestType::RenameFile(r) => {
+            handle_rename(filesystem, file_db, db_file, r).await.into()
+        }
+        FileRequestType::DeleteFile(r) => handle_delete(filesystem, file_db, user, db_file, r)
+            .await
+            .into(),
+        _ => WopiResponse::<LockResponse>::NotImplemented.into(),
     }
 }
 
 type Response<T> = WopiResponse<T>;
 
-#[tracing::instrument(skip(file_db))]
+/// Loads the `DBFile` with the given id or returns the matching error response.
+async fn get_dbfile<T>(file_db: &impl DBFileStore, id: Uuid) -> Result<DBFile, Response<T>> {
+    match file_db.get_dbfile(id).await {
+        // Files in the trash are hidden, even from access tokens issued before
+        Ok(Some(f)) if !f.is_trashed() => Ok(f),
+        Ok(_) => Err(Response::NotFound),
+        Err(e) => {
+            error!("error connecting to db: {:?}", e);
+            Err(Response::InternalServerError)
+        }
+    }
+}
+
+fn lock_conflict(lock: Option<&FileLock>, reason: &str) -> Response<LockResponse> {
+    Response::Ok(LockResponse::Conflict {
+        lock: lock.map(ToString::to_string).unwrap_or_default(),
+        lock_failure_reason: Some(reason.to_owned()),
+    })
+}
+
+/// Checks that the owner of a file has room for `delta` more bytes, before it's written. WOPI
+/// has no response for a full storage, so the write is rejected as a bad request.
+async fn check_quota<T>(
+    file_db: &impl QuotaStore,
+    owner: Uuid,
+    delta: i64,
+    config: &QuotaConfig,
+) -> Result<(), Response<T>> {
+    match quota::check(file_db, owner, delta, config).await {
+        Ok(()) => Ok(()),
+        Err(QuotaAPIError::QuotaExceeded(..)) => Err(Response::BadRequest),
+        Err(e) => {
+            error!("error while checking quota of user {owner}: {e:?}");
+            Err(Response::InternalServerError)
+        }
+    }
+}
+
+/// Maps the result of a lock operation of the `DBFileStore` to a WOPI response. A lock
+/// mismatch results in a conflict, which contains the current lock.
+fn lock_response(id: Uuid, res: FileResult<Option<()>>) -> Response<LockResponse> {
+    match res {
+        Ok(Some(())) => Response::Ok(LockResponse::Ok { item_version: None }),
+        Ok(None) => Response::NotFound,
+        Err(DBFileError::Locked(l)) => lock_conflict(l.as_ref(), "lock mismatch"),
+        Err(e) => {
+            error!(
+                "error while changing lock of file id: {}, error: {:?}",
+                id, e
+            );
+            Response::InternalServerError
+        }
+    }
+}
+
+async fn read_object(object: Object) -> std::io::Result<Bytes> {
+    let mut body = object.body;
+    let mut data = BytesMut::with_capacity(object.size.try_into().unwrap_or_default());
+    while let Some(chunk) = body.try_next().await? {
+        data.extend_from_slice(&chunk);
+    }
+    Ok(data.freeze())
+}
+
+/// Returns the content of a file, a missing object is treated like an empty file.
+async fn read_file(filesystem: &impl Filesystem, path: &str) -> Result<Bytes, FileError> {
+    match filesystem.get_object(WOPI_BUCKET, path).await {
+        Ok(object) => read_object(object)
+            .await
+            .map_err(|e| FileError::Other(Box::new(e))),
+        Err(FileError::NotFound(_)) => Ok(Bytes::new()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Splits a path into its parent folder (including the trailing delimiter) and the file name.
+fn split_path(path: &str) -> (&str, &str) {
+    path.rfind('\\')
+        .map_or(("", path), |i| (&path[..=i], &path[i + 1..]))
+}
+
+/// Splits a file name into its stem and extension (including the leading `.`).
+fn split_extension(name: &str) -> (&str, &str) {
+    match name.rfind('.') {
+        Some(i) if i > 0 => name.split_at(i),
+        _ => (name, ""),
+    }
+}
+
+fn is_valid_file_name(name: &str) -> bool {
+    !name.is_empty() && name != "." && name != ".." && !name.contains(['\\', '/'])
+}
+
+/// Returns the metadata of a file, a missing object is treated like an empty file which wasn't
+/// modified since the `DBFile` was created.
+async fn file_meta(
+    filesystem: &impl Filesystem,
+    db_file: &DBFile,
+) -> Result<ObjectMeta, FileError> {
+    match filesystem
+        .head_object(WOPI_BUCKET, &content_key(db_file))
+        .await
+    {
+        Err(FileError::NotFound(_)) => Ok(ObjectMeta {
+            size: 0,
+            last_modified: Some(db_file.created_at),
+            e_tag: None,
+            checksum: None,
+        }),
+        res => res,
+    }
+}
+
+/// Returns the version of a file, which changes whenever its content changes.
+fn file_version(meta: &ObjectMeta) -> String {
+    meta.e_tag.as_ref().map_or_else(
+        || {
+            meta.last_modified
+                .map(|t| t.unix_timestamp_nanos().to_string())
+                .unwrap_or_default()
+        },
+        |e_tag| e_tag.trim_matches('"').to_owned(),
+    )
+}
+
+#[tracing::instrument(skip(filesystem))]
 async fn handle_check_file_info(
-    mut file_db: impl DBFileStore,
+    filesystem: impl Filesystem,
+    db_file: DBFile,
     user: &User,
-    id: Uuid,
-    req: CheckFileInfoRequest,
+    permission: FilePermission,
 ) -> Response<CheckFileInfoResponse> {
-    let db_file = match file_db.get_dbfile(id).await {
-        Ok(Some(f)) => f,
-        Ok(None) => return WopiResponse::NotFound,
+    let name = split_path(&db_file.path).1;
+    if name.is_empty() {
+        return Response::NotFound;
+    }
+    let meta = match file_meta(&filesystem, &db_file).await {
+        Ok(meta) => meta,
         Err(e) => {
-            error!("error connecting to db: {:?}", e);
-            return WopiResponse::InternalServerError;
+            error!(
+                "error while reading metadata of file id: {}, error: {:?}",
+                db_file.id.0, e
+            );
+            return Response::InternalServerError;
         }
     };
-    let name = match db_file.path.split('/').last() {
-        None | Some("") => return WopiResponse::NotFound,
-        Some(a) => {
-            if let Some(n) = a.rfind('.') {
-                a.split_at(n).1
-            } else {
-                a
-            }
-        }
-    };
-    // TODO: Add version and size
-    let resp = CheckFileInfoResponse {
+
+    let can_write = permission >= FilePermission::Write;
+    Response::Ok(CheckFileInfoResponse {
         base_file_name: name.to_owned(),
-        owner_id: user.id.to_string(), // TODO: Update this if sharing is enabled
+        owner_id: db_file.created_by.to_string(),
         user_id: user.id.to_string(),
+        size: meta.size.try_into().unwrap_or(i64::MAX),
+        version: file_version(&meta),
+        last_modified_time: meta.last_modified.and_then(|t| t.format(&Rfc3339).ok()),
+        user_can_write: can_write,
+        read_only: !can_write,
+        supports_locks: true,
+        supports_update: true,
         ..CheckFileInfoResponse::default()
-    };
-    WopiResponse::Ok(resp)
+    })
+}
+
+#[tracing::instrument(skip(filesystem))]
+async fn handle_get_file(
+    filesystem: impl Filesystem,
+    db_file: DBFile,
+    req: GetFileRequest,
+) -> Response<GetFileResponse> {
+    match read_file(&filesystem, &content_key(&db_file)).await {
+        Ok(body) => Response::Ok(GetFileResponse {
+            body,
+            item_version: None,
+        }),
+        Err(e) => {
+            error!(
+                "error while reading file id: {}, error: {:?}",
+                db_file.id.0, e
+            );
+            Response::InternalServerError
+        }
+    }
+}
+
+/// Overwrites the content of a file. This requires the lock of the file, only empty files may be
+/// written without a lock, which is how WOPI clients create new documents.
+#[tracing::instrument(skip(config, filesystem, file_db, req))]
+async fn handle_put_file(
+    config: &GenbuConfig,
+    mut filesystem: impl Filesystem,
+    mut file_db: impl DBFileStore + QuotaStore + VersionStore + BlobStore,
+    db_file: DBFile,
+    req: FileBody<Bytes, PutFileRequest>,
+) -> Response<LockResponse> {
+    match (db_file.current_lock(), req.request.lock.map(FileLock::from)) {
+        (Some(current), Some(lock)) if *current == lock => {}
+        (Some(current), _) => return lock_conflict(Some(current), "lock mismatch"),
+        (None, _) => match filesystem
+            .head_object(WOPI_BUCKET, &content_key(&db_file))
+            .await
+        {
+            Ok(meta) if meta.size == 0 => {}
+            Err(FileError::NotFound(_)) => {}
+            Ok(_) => return lock_conflict(None, "file is not locked"),
+            Err(e) => {
+                error!(
+                    "error while reading metadata of file id: {}, error: {:?}",
+                    db_file.id.0, e
+                );
+                return Response::InternalServerError;
+            }
+        },
+    }
+
+    // The owner is charged for the change in size
+    let delta = i64::try_from(req.body.len()).unwrap_or(i64::MAX) - db_file.size;
+    if let Err(resp) = check_quota(&file_db, db_file.created_by, delta, &config.quota).await {
+        return resp;
+    }
+
+    // The overwritten content is kept as a version of the file
+    let res = versions::snapshot(&mut filesystem, &mut file_db, &db_file, &config.versions);
+    if let Err(e) = res.await {
+        error!(
+            "error while keeping version of file id: {}, error: {:?}",
+            db_file.id.0, e
+        );
+        return Response::InternalServerError;
+    }
+    if let Err(e) = filesystem
+        .upload(WOPI_BUCKET, &db_file.path, req.body.to_vec())
+        .await
+    {
+        error!(
+            "error while writing file id: {}, error: {:?}",
+            db_file.id.0, e
+        );
+        return Response::InternalServerError;
+    }
+    // Keeps the size and checksum in the catalog up to date
+    let res = catalog::register_object(
+        &filesystem,
+        &mut file_db,
+        WOPI_BUCKET,
+        &db_file.path,
+        db_file.created_by,
+    )
+    .await;
+    match res {
+        Ok(_) => Response::Ok(LockResponse::Ok { item_version: None }),
+        Err(e) => {
+            error!(
+                "error while updating file id: {}, error: {:?}",
+                db_file.id.0, e
+            );
+            Response::InternalServerError
+        }
+    }
 }
 
 async fn handle_lock(
     mut file_db: impl DBFileStore,
-    user: &User,
     id: Uuid,
     req: LockRequest,
 ) -> Response<LockResponse> {
-    match file_db.lock(id, req.lock.into()).await {
-        Ok(Some(f)) => Response::Ok(LockResponse::Ok { item_version: None }),
-        Ok(None) => Response::NotFound,
-        Err(DBFileError::Locked(l)) => Response::Ok(LockResponse::Conflict {
-            lock: l.unwrap_or_default().to_string(),
-            lock_failure_reason: None,
+    lock_response(id, file_db.lock(id, req.lock.into()).await)
+}
+
+async fn handle_get_lock(db_file: DBFile, _req: GetLockRequest) -> Response<GetLockResponse> {
+    // An unlocked file is reported with an empty lock
+    Response::Ok(GetLockResponse {
+        lock: db_file
+            .current_lock()
+            .map(ToString::to_string)
+            .unwrap_or_default(),
+    })
+}
+
+async fn handle_refresh_lock(
+    mut file_db: impl DBFileStore,
+    db_file: DBFile,
+    req: RefreshLockRequest,
+) -> Response<LockResponse> {
+    // In contrast to `DBFile::extend_lock`, refreshing the lock of an unlocked file is a conflict
+    if !db_file.is_locked() {
+        return lock_conflict(None, "file is not locked");
+    }
+    let id = db_file.id.0;
+    lock_response(id, file_db.extend_lock(id, req.lock.into()).await)
+}
+
+async fn handle_unlock(
+    mut file_db: impl DBFileStore,
+    db_file: DBFile,
+    req: UnlockRequest,
+) -> Response<LockResponse> {
+    if !db_file.is_locked() {
+        return lock_conflict(None, "file is not locked");
+    }
+    let id = db_file.id.0;
+    lock_response(id, file_db.unlock(id, req.lock.into()).await)
+}
+
+async fn handle_unlock_and_relock(
+    mut file_db: impl DBFileStore,
+    id: Uuid,
+    req: UnlockAndRelockRequest,
+) -> Response<LockResponse> {
+    let res = file_db
+        .relock(id, req.old_lock.into(), req.lock.into())
+        .await;
+    lock_response(id, res)
+}
+
+/// Creates a new file next to an existing one. With a suggested target the name may be changed
+/// to avoid conflicts, a relative target is used as is.
+#[tracing::instrument(skip(config, filesystem, file_db, req))]
+async fn handle_put_relative(
+    config: &GenbuConfig,
+    mut filesystem: impl Filesystem,
+    mut file_db: impl DBFileStore + QuotaStore + VersionStore + BlobStore,
+    user: &User,
+    db_file: DBFile,
+    req: FileBody<Bytes, PutRelativeFileRequest>,
+) -> Response<PutRelativeFileResponse> {
+    let (folder, current_name) = split_path(&db_file.path);
+
+    let (name, existing) = match (req.request.suggested_target, req.request.relative_target) {
+        (Some(suggested), None) => {
+            // A suggested target which starts with a `.` only specifies the extension
+            let name = if suggested.starts_with('.') {
+                format!("{}{suggested}", split_extension(current_name).0)
+            } else {
+                suggested
+            };
+            if !is_valid_file_name(&name) {
+                return Response::BadRequest;
+            }
+            match file_db
+                .get_dbfile_by_path(WOPI_BUCKET, &format!("{folder}{name}"))
+                .await
+            {
+                Ok(None) => (name, None),
+                Ok(Some(_)) => (unique_file_name(&name), None),
+                Err(e) => {
+                    error!("error connecting to db: {:?}", e);
+                    return Response::InternalServerError;
+                }
+            }
+        }
+        (None, Some(relative)) => {
+            if !is_valid_file_name(&relative) {
+                return Response::BadRequest;
+            }
+            match file_db
+                .get_dbfile_by_path(WOPI_BUCKET, &format!("{folder}{relative}"))
+                .await
+            {
+                Ok(None) => (relative, None),
+                Ok(Some(f)) if !req.request.overwrite_relative_target => {
+                    return Response::Ok(PutRelativeFileResponse::Conflict {
+                        valid_relative_target: Some(unique_file_name(&relative)),
+                        lock: f.current_lock().map(ToString::to_string),
+                    })
+                }
+                Ok(Some(f)) if f.is_locked() => {
+                    return Response::Ok(PutRelativeFileResponse::Conflict {
+                        valid_relative_target: None,
+                        lock: f.current_lock().map(ToString::to_string),
+                    })
+                }
+                Ok(Some(f)) => (relative, Some(f)),
+                Err(e) => {
+                    error!("error connecting to db: {:?}", e);
+                    return Response::InternalServerError;
+                }
+            }
+        }
+        // Both headers are mutually exclusive
+        (Some(_), Some(_)) => return Response::NotImplemented,
+        (None, None) => return Response::BadRequest,
+    };
+
+    let path = format!("{folder}{name}");
+    // An overwritten file keeps its catalog entry and therefore its id and owner
+    let owner = existing.as_ref().map_or(user.id, |f| f.created_by);
+    let delta =
+        i64::try_from(req.body.len()).unwrap_or(i64::MAX) - existing.as_ref().map_or(0, |f| f.size);
+    if let Err(resp) = check_quota(&file_db, owner, delta, &config.quota).await {
+        return resp;
+    }
+    if let Some(f) = &existing {
+        let res = versions::snapshot(&mut filesystem, &mut file_db, f, &config.versions);
+        if let Err(e) = res.await {
+            error!(
+                "error while keeping version of file {}, error: {:?}",
+                path, e
+            );
+            return Response::InternalServerError;
+        }
+    }
+    if let Err(e) = filesystem
+        .upload(WOPI_BUCKET, &path, req.body.to_vec())
+        .await
+    {
+        error!("error while writing file {}, error: {:?}", path, e);
+        return Response::InternalServerError;
+    }
+    let new_file = match catalog::register_object(
+        &filesystem,
+        &mut file_db,
+        WOPI_BUCKET,
+        &path,
+        owner,
+    )
+    .await
+    {
+        Ok(f) => f,
+        Err(e) => {
+            error!("error while registering file {}, error: {:?}", path, e);
+            return Response::InternalServerError;
+        }
+    };
+
+    // The returned url has to contain an access token for the new file
+    let token = match issue_access_token(config, user.id, new_file.id.0) {
+        Ok(token) => token,
+        Err(e) => {
+            error!("unable to create access token, error: {:?}", e);
+            return Response::InternalServerError;
+        }
+    };
+    Response::Ok(PutRelativeFileResponse::Ok {
+        name,
+        url: format!(
+            "{}?access_token={}",
+            wopi_src(config, new_file.id.0),
+            token.access_token
+        ),
+        host_view_url: None,
+        host_edit_url: None,
+    })
+}
+
+/// Makes a file name unique by appending a random suffix to its stem.
+fn unique_file_name(name: &str) -> String {
+    let (stem, extension) = split_extension(name);
+    let suffix = Uuid::new_v4().simple().to_string();
+    format!("{stem}-{}{extension}", &suffix[..8])
+}
+
+/// Renames a file while keeping its extension, the requested name doesn't contain one.
+#[tracing::instrument(skip(filesystem, file_db))]
+async fn handle_rename(
+    mut filesystem: impl Filesystem,
+    mut file_db: impl DBFileStore,
+    db_file: DBFile,
+    req: RenameFileRequest,
+) -> Response<RenameFileResponse> {
+    if let Some(current) = db_file.current_lock() {
+        if req.lock.map(FileLock::from).as_ref() != Some(current) {
+            return Response::Ok(RenameFileResponse::Conflict {
+                lock: current.to_string(),
+                lock_failure_reason: Some("lock mismatch".to_owned()),
+            });
+        }
+    }
+
+    let (folder, current_name) = split_path(&db_file.path);
+    let name = format!("{}{}", req.requested_name, split_extension(current_name).1);
+    let invalid_name = |reason: &str| {
+        Response::Ok(RenameFileResponse::BadRequest {
+            invalid_file_name_error: reason.to_owned(),
+        })
+    };
+    if !is_valid_file_name(&req.requested_name) {
+        return invalid_name("invalid file name");
+    }
+    let path = format!("{folder}{name}");
+    match file_db.get_dbfile_by_path(WOPI_BUCKET, &path).await {
+        Ok(None) => {}
+        Ok(Some(_)) => return invalid_name("a file with this name already exists"),
+        Err(e) => {
+            error!("error connecting to db: {:?}", e);
+            return Response::InternalServerError;
+        }
+    }
+
+    // A deduplicated file only moves its empty object, it keeps pointing at its blob
+    match filesystem.rename(&db_file.path, &path).await {
+        Ok(()) => {}
+        Err(FilesystemError::FileAlreadyExists(_)) => {
+            return invalid_name("a file with this name already exists")
+        }
+        Err(e) => {
+            error!(
+                "error while renaming file id: {}, error: {:?}",
+                db_file.id.0, e
+            );
+            return Response::InternalServerError;
+        }
+    }
+    let res = match file_db.rename_dbfile(db_file.id.0, &path).await {
+        Ok(Some(_)) => Response::Ok(RenameFileResponse::Ok {
+            name: req.requested_name,
         }),
+        Ok(None) => Response::NotFound,
         Err(e) => {
-            error!("error while locking file id: {}, error: {:?}", id, e);
+            error!(
+                "error while renaming file id: {}, error: {:?}",
+                db_file.id.0, e
+            );
             Response::InternalServerError
         }
+    };
+    // The object is moved back, if the catalog entry wasn't renamed
+    if !matches!(res, Response::Ok(_)) {
+        if let Err(e) = filesystem.rename(&path, &db_file.path).await {
+            error!(
+                "unable to move back renamed file {}, error: {:?}",
+                db_file.path, e
+            );
+        }
     }
+    res
 }
 
-async fn handle_put_relative(
+/// Moves the file into the trash of its owner, so it can still be restored.
+#[tracing::instrument(skip(filesystem, file_db))]
+async fn handle_delete(
     mut filesystem: impl Filesystem,
+    mut file_db: impl DBFileStore + TrashStore,
     user: &User,
-    req: FileBody<Bytes, PutRelativeFileRequest>,
-) -> Response<PutRelativeFileResponse> {
-    todo!()
+    db_file: DBFile,
+    _req: DeleteFileRequest,
+) -> Response<LockResponse> {
+    if let Some(current) = db_file.current_lock() {
+        return lock_conflict(Some(current), "file is locked");
+    }
+
+    let owner = db_file.created_by;
+    let res = trash::move_to_trash(&mut filesystem, &mut file_db, owner, &db_file.path, user.id);
+    match res.await {
+        Ok(_) => Response::Ok(LockResponse::Ok { item_version: None }),
+        Err(trash::TrashAPIError::Filesystem(FilesystemError::NotFound(_))) => Response::NotFound,
+        Err(e) => {
+            error!(
+                "error while deleting file id: {}, error: {:?}",
+                db_file.id.0, e
+            );
+            Response::InternalServerError
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use genbu_auth::authn::validate_jwt;
+    use secrecy::SecretString;
+
+    use crate::{connectors::memory::MemStore, stores::files::FileStorage};
+
+    use super::*;
+
+    #[test]
+    fn split_names() {
+        assert_eq!(split_path("user\\docs\\a.docx"), ("user\\docs\\", "a.docx"));
+        assert_eq!(split_path("a.docx"), ("", "a.docx"));
+        assert_eq!(
+            split_extension("report.final.docx"),
+            ("report.final", ".docx")
+        );
+        assert_eq!(split_extension(".hidden"), (".hidden", ""));
+    }
+
+    #[tokio::test]
+    async fn access_token_is_no_session() {
+        let mut config = GenbuConfig::default();
+        config.auth.jwt_secret = SecretString::new("a".repeat(32));
+        let mut store = MemStore::new();
+        let user = User::template();
+        let file = store
+            .add_dbfile(&DBFile::with_path_and_user("a.docx", &user))
+            .await
+            .unwrap();
+
+        let req = AccessTokenRequest { file_id: file.id.0 };
+        let resp = create_access_token(&config, store.clone(), user.id, req.clone())
+            .await
+            .unwrap();
+        let claims = validate_access_token(&config, &resp.access_token).unwrap();
+        assert_eq!((claims.sub, claims.file_id), (user.id, file.id.0));
+        assert!(validate_jwt(&resp.access_token, &config.auth.jwt_secret).is_err());
+
+        let other_user = Uuid::new_v4();
+        assert!(matches!(
+            create_access_token(&config, store, other_user, req).await,
+            Err(WopiAPIError::NotFound(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn version_changes_with_content() {
+        let mut store = MemStore::new();
+        let file = DBFile::with_path_and_user("user\\a.docx", &User::template());
+        let empty = file_meta(&store, &file).await.unwrap();
+        assert_eq!(empty.size, 0);
+
+        store
+            .upload(WOPI_BUCKET, &file.path, b"first".to_vec())
+            .await
+            .unwrap();
+        let first = file_meta(&store, &file).await.unwrap();
+        assert_eq!(first.size, 5);
+        assert_ne!(file_version(&empty), file_version(&first));
+
+        store
+            .upload(WOPI_BUCKET, &file.path, b"second".to_vec())
+            .await
+            .unwrap();
+        let second = file_meta(&store, &file).await.unwrap();
+        assert_ne!(file_version(&first), file_version(&second));
+    }
+
+    #[test]
+    fn unique_names_keep_extension() {
+        let name = unique_file_name("a.docx");
+        assert!(name.starts_with("a-") && name.ends_with(".docx"));
+        assert_ne!(name, unique_file_name("a.docx"));
+    }
 }
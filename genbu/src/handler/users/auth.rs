@@ -38,7 +38,7 @@ pub async fn login_password<US: UserStore>(
             |u| &u.hash,
         );
 
-        if authn::verify_password(&login_req.password, hash)? && let Some(u) = db_user {
+        if let (true, Some(u)) = (authn::verify_password(&login_req.password, hash)?, db_user) {
             return Ok(u.id);
         }
         Err(APIError::WrongCredentials)
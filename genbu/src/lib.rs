@@ -1,5 +1,6 @@
 #![feature(let_chains, is_some_and, type_alias_impl_trait)]
 #![allow(clippy::missing_errors_doc, clippy::module_name_repetitions)]
+pub mod config;
 pub mod connectors;
 pub mod handler;
 pub mod server;
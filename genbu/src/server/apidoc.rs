@@ -1,18 +1,28 @@
-use crate::handler::files::download::StartDownloadRequest;
+use crate::handler::files::archive::ArchiveRequest;
+use crate::handler::files::catalog::FileInfo;
+use crate::handler::files::download::{Disposition, ProxyDownloadRequest, StartDownloadRequest};
+use crate::handler::files::quota::{SetQuotaRequest, StorageUsage};
+use crate::handler::files::trash::TrashItem;
 use crate::handler::files::upload::{
-    FinishUploadRequest, GetUrisRequest, UploadFileRequest, UploadFileResponse,
+    FinishUploadRequest, GetUrisRequest, MissingPart, PutContentRequest, ResumeUploadResponse,
+    UploadFileRequest, UploadFileResponse,
 };
 use crate::handler::files::userfiles::{
-    DeleteUserfileRequest, GetUserfilesRequest, GetUserfilesResponse,
+    BulkItemResult, BulkOperation, BulkRequest, BulkResponse, CopyRequest, DeleteUserfileRequest,
+    GetUserfilesRequest, GetUserfilesResponse, MkdirRequest, MoveRequest, RenameRequest,
+};
+use crate::handler::files::versions::VersionInfo;
+use crate::handler::files::wopi::{
+    AccessTokenRequest, AccessTokenResponse, LaunchRequest, LaunchResponse,
 };
 use crate::handler::users::{auth::LoginRequest, CreateUserRequest};
 use crate::server::routes::{
-    files::{self, userfiles},
+    files::{self, trash, userfiles, versions, wopi},
     users::{self, UserResponse},
 };
 use crate::stores::files::database::LeaseID;
 use crate::stores::files::filesystem::Userfile;
-use crate::stores::files::storage::{Bucket, Part};
+use crate::stores::files::storage::{Bucket, ChecksumAlgorithm, Part, PartChecksums};
 use crate::stores::users::{User, UserAvatar};
 use utoipa::{
     openapi::security::{ApiKey, ApiKeyValue, SecurityScheme},
@@ -29,10 +39,35 @@ use utoipa::{
         users::register,
         users::login,
         files::upload_file_request,
+        files::get_upload_uris,
+        files::resume_upload,
         files::finish_upload,
+        files::abort_upload,
+        files::put_content,
         files::start_download,
+        files::get_file_info,
+        files::download_file,
+        files::proxy_download,
+        files::download_archive,
+        files::get_usage,
+        files::set_user_quota,
+        files::set_group_quota,
         userfiles::get_userfiles,
-        userfiles::delete_userfile
+        userfiles::delete_userfile,
+        userfiles::mkdir,
+        userfiles::move_userfile,
+        userfiles::rename_userfile,
+        userfiles::copy_userfile,
+        userfiles::bulk,
+        trash::list_trash,
+        trash::restore,
+        trash::purge,
+        trash::empty_trash,
+        versions::list_versions,
+        versions::download_version,
+        versions::restore_version,
+        wopi::create_access_token,
+        wopi::launch
     ),
     components(
         schemas(
@@ -44,15 +79,40 @@ use utoipa::{
             UploadFileRequest,
             UploadFileResponse,
             FinishUploadRequest,
+            FileInfo,
+            StorageUsage,
+            SetQuotaRequest,
             StartDownloadRequest,
+            ProxyDownloadRequest,
+            Disposition,
+            ArchiveRequest,
             GetUrisRequest,
+            ResumeUploadResponse,
+            MissingPart,
+            PutContentRequest,
             Part,
+            PartChecksums,
+            ChecksumAlgorithm,
             LeaseID,
             GetUserfilesRequest,
             DeleteUserfileRequest,
             GetUserfilesResponse,
+            MkdirRequest,
+            MoveRequest,
+            RenameRequest,
+            CopyRequest,
+            BulkOperation,
+            BulkRequest,
+            BulkItemResult,
+            BulkResponse,
+            TrashItem,
+            VersionInfo,
             Userfile,
-            Bucket
+            Bucket,
+            AccessTokenRequest,
+            AccessTokenResponse,
+            LaunchRequest,
+            LaunchResponse
         )
     ),
     modifiers(&SecurityAddon),
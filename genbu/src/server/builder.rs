@@ -1,6 +1,10 @@
-use std::{iter::once, time::Duration};
+use std::{iter::once, sync::Arc, time::Duration};
 
-use crate::stores::{files::filesystem::Filesystem, DataStore};
+use crate::{
+    config::GenbuConfig,
+    connectors::discovery::WopiDiscovery,
+    stores::{files::filesystem::Filesystem, DataStore},
+};
 use axum::{
     body::{Body, BoxBody},
     routing::get,
@@ -19,17 +23,21 @@ use utoipa_swagger_ui::SwaggerUi;
 
 use super::{
     apidoc::ApiDoc,
+    jobs,
     routes::{files, users},
 };
 
 pub struct GenbuServerBuilder<S: DataStore, F: Filesystem> {
     users: Option<S>,
     files: Option<F>,
+    config: Option<GenbuConfig>,
 }
 
 pub struct GenbuServer<S: DataStore, F: Filesystem> {
     users: S,
     files: F,
+    config: Arc<GenbuConfig>,
+    discovery: WopiDiscovery,
 }
 
 impl<S: DataStore, F: Filesystem + Send + Sync> GenbuServerBuilder<S, F> {
@@ -38,6 +46,7 @@ impl<S: DataStore, F: Filesystem + Send + Sync> GenbuServerBuilder<S, F> {
         Self {
             users: None,
             files: None,
+            config: None,
         }
     }
 
@@ -51,12 +60,20 @@ impl<S: DataStore, F: Filesystem + Send + Sync> GenbuServerBuilder<S, F> {
         self
     }
 
+    pub fn with_config(&mut self, config: GenbuConfig) -> &mut Self {
+        self.config = Some(config);
+        self
+    }
+
     #[must_use]
     pub fn build(&mut self) -> Option<GenbuServer<S, F>> {
         self.users.as_ref()?;
+        let config = self.config.take()?;
         Some(GenbuServer {
             users: self.users.take().unwrap(),
             files: self.files.take().unwrap(),
+            discovery: WopiDiscovery::new(&config.wopi),
+            config: Arc::new(config),
         })
     }
 }
@@ -99,7 +116,9 @@ impl<S: DataStore, F: Filesystem> GenbuServer<S, F> {
                     ),
             )
             .layer(Extension(self.users.clone()))
-            .layer(Extension(self.files.clone()));
+            .layer(Extension(self.files.clone()))
+            .layer(Extension(self.config.clone()))
+            .layer(Extension(self.discovery.clone()));
         if cfg!(any(test, feature = "testing")) {
             let (prometheus_layer, metric_handle) = PrometheusMetricLayer::pair();
             app = app
@@ -121,8 +140,16 @@ impl<S: DataStore, F: Filesystem> GenbuServer<S, F> {
     // TODO: Proper error handling
     pub async fn start(&self) -> Result<(), hyper::Error> {
         let app = self.app();
+        jobs::spawn_trash_purger(self.files.clone(), self.users.clone(), &self.config.trash);
+        jobs::spawn_version_pruner(
+            self.files.clone(),
+            self.users.clone(),
+            &self.config.versions,
+        );
+        jobs::spawn_lease_sweeper(self.files.clone(), self.users.clone(), &self.config.upload);
+        jobs::spawn_deduplicator(self.files.clone(), self.users.clone(), &self.config.dedup);
 
-        Server::bind(&"0.0.0.0:8080".parse().unwrap())
+        Server::bind(&self.config.server.addr())
             .serve(app.into_make_service())
             .await
     }
@@ -0,0 +1,131 @@
+//! Background jobs, which run for as long as the server is running.
+
+use std::future::Future;
+
+use time::Duration;
+use tokio::task::JoinHandle;
+use tracing::{error, info, info_span, Instrument};
+
+use crate::{
+    config::{DedupConfig, TrashConfig, UploadConfig, VersionsConfig},
+    handler::files::{blobs, trash, upload, versions},
+    stores::{
+        files::{filesystem::Filesystem, FileStorage},
+        DataStore,
+    },
+};
+
+/// Spawns a job, which runs `run` right away and then every `interval`. The next run waits for
+/// the previous one to finish, so runs never overlap.
+pub fn spawn_periodic<F, Fut>(
+    name: &str,
+    interval: std::time::Duration,
+    mut run: F,
+) -> JoinHandle<()>
+where
+    F: FnMut() -> Fut + Send + 'static,
+    Fut: Future<Output = ()> + Send,
+{
+    // Tokio panics on an empty interval
+    let mut interval = tokio::time::interval(interval.max(std::time::Duration::from_secs(1)));
+    let span = info_span!("job", name);
+    tokio::spawn(
+        async move {
+            loop {
+                interval.tick().await;
+                run().await;
+            }
+        }
+        .instrument(span),
+    )
+}
+
+/// Spawns the job which purges trash entries, once their retention period has ended.
+pub fn spawn_trash_purger<F: Filesystem, D: DataStore>(
+    filesystem: F,
+    store: D,
+    config: &TrashConfig,
+) -> JoinHandle<()> {
+    let retention = Duration::days(config.retention_days.into());
+    let interval = std::time::Duration::from_secs(config.purge_interval);
+    spawn_periodic("trash purger", interval, move || {
+        let (filesystem, store) = (filesystem.clone(), store.clone());
+        async move {
+            match trash::purge_expired(filesystem, store, retention).await {
+                Ok(0) => {}
+                Ok(purged) => info!("purged {purged} expired trash entries"),
+                Err(e) => error!("unable to purge expired trash entries {e:?}"),
+            }
+        }
+    })
+}
+
+/// Spawns the job which prunes versions, once they're older than the maximum age. No job is
+/// spawned if versions are kept regardless of their age.
+pub fn spawn_version_pruner<F: Filesystem, D: DataStore>(
+    file_storage: F,
+    store: D,
+    config: &VersionsConfig,
+) -> Option<JoinHandle<()>> {
+    if config.max_age_days == 0 {
+        return None;
+    }
+    let max_age = Duration::days(config.max_age_days.into());
+    let interval = std::time::Duration::from_secs(config.prune_interval);
+    Some(spawn_periodic("version pruner", interval, move || {
+        let (file_storage, store) = (file_storage.clone(), store.clone());
+        async move {
+            match versions::prune_expired_versions(file_storage, store, max_age).await {
+                Ok(0) => {}
+                Ok(pruned) => info!("pruned {pruned} expired file versions"),
+                Err(e) => error!("unable to prune expired file versions {e:?}"),
+            }
+        }
+    }))
+}
+
+/// Spawns the job which aborts the uploads of expired leases, so their parts don't take up
+/// storage.
+pub fn spawn_lease_sweeper<F: FileStorage, D: DataStore>(
+    file_storage: F,
+    store: D,
+    config: &UploadConfig,
+) -> JoinHandle<()> {
+    let interval = std::time::Duration::from_secs(config.sweep_interval);
+    spawn_periodic("lease sweeper", interval, move || {
+        let (file_storage, store) = (file_storage.clone(), store.clone());
+        async move {
+            match upload::sweep_expired_leases(file_storage, store).await {
+                Ok(0) => {}
+                Ok(swept) => info!("aborted {swept} expired uploads"),
+                Err(e) => error!("unable to abort expired uploads {e:?}"),
+            }
+        }
+    })
+}
+
+/// Spawns the job which moves the content of large files into blobs shared by every file with the
+/// same content, and deletes the blobs which aren't referenced anymore.
+pub fn spawn_deduplicator<F: FileStorage, D: DataStore>(
+    file_storage: F,
+    store: D,
+    config: &DedupConfig,
+) -> JoinHandle<()> {
+    let min_size = config.min_size;
+    let interval = std::time::Duration::from_secs(config.interval);
+    spawn_periodic("deduplicator", interval, move || {
+        let (file_storage, store) = (file_storage.clone(), store.clone());
+        async move {
+            match blobs::deduplicate_files(file_storage.clone(), store.clone(), min_size).await {
+                Ok(0) => {}
+                Ok(deduplicated) => info!("deduplicated {deduplicated} files"),
+                Err(e) => error!("unable to deduplicate files {e:?}"),
+            }
+            match blobs::collect_garbage(file_storage, store).await {
+                Ok(0) => {}
+                Ok(collected) => info!("deleted {collected} unreferenced blobs"),
+                Err(e) => error!("unable to delete unreferenced blobs {e:?}"),
+            }
+        }
+    })
+}
@@ -1,15 +1,21 @@
+use std::sync::Arc;
+
 use axum::{
     http::{Request, StatusCode},
     middleware::Next,
     response::Response,
+    Extension,
 };
 use axum_extra::extract::CookieJar;
 use genbu_auth::authn::validate_jwt;
 use tracing::{debug, warn, Instrument};
 
+use crate::config::GenbuConfig;
+
 #[allow(clippy::future_not_send)]
 #[tracing::instrument(skip_all)]
 pub async fn auth<B>(
+    Extension(config): Extension<Arc<GenbuConfig>>,
     cookie_jar: CookieJar,
     mut req: Request<B>,
     next: Next<B>,
@@ -19,7 +25,7 @@ pub async fn auth<B>(
         StatusCode::UNAUTHORIZED
     })?;
 
-    match validate_jwt(token_cookie.value()) {
+    match validate_jwt(token_cookie.value(), &config.auth.jwt_secret) {
         Ok(claims) => {
             req.extensions_mut().insert(claims);
             debug!("authn_token_accepted jwt validated");
@@ -0,0 +1,124 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::Query,
+    http::{HeaderMap, Request, StatusCode},
+    middleware::Next,
+    response::Response,
+    Extension,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde::Deserialize;
+use time::OffsetDateTime;
+use tracing::{debug, error, warn, Instrument};
+
+use crate::{
+    config::GenbuConfig,
+    connectors::discovery::{WopiDiscovery, WopiProof},
+    handler::files::wopi::{validate_access_token, WopiAccess},
+    stores::users::UserStore,
+};
+
+#[derive(Debug, Deserialize)]
+pub struct AccessTokenQuery {
+    access_token: String,
+}
+
+/// Authenticates requests of WOPI clients, which can't send the session cookie and instead pass
+/// an access token as query parameter.
+#[allow(clippy::future_not_send)]
+#[tracing::instrument(skip_all)]
+pub async fn wopi_auth<U: UserStore, B>(
+    Extension(config): Extension<Arc<GenbuConfig>>,
+    Extension(user_store): Extension<U>,
+    query: Option<Query<AccessTokenQuery>>,
+    mut req: Request<B>,
+    next: Next<B>,
+) -> Result<Response, StatusCode> {
+    let Some(Query(query)) = query else {
+        warn!("wopi_token_not_provided attempted unauthorized access");
+        return Err(StatusCode::UNAUTHORIZED);
+    };
+
+    let claims = validate_access_token(&config, &query.access_token).map_err(|e| {
+        warn!("wopi_token_invalid jwt error: {:?}", e);
+        StatusCode::UNAUTHORIZED
+    })?;
+    let user = match user_store.get(&claims.sub).await {
+        Ok(Some(user)) => user,
+        Ok(None) => {
+            warn!("wopi_token_invalid user {} doesn't exist", claims.sub);
+            return Err(StatusCode::UNAUTHORIZED);
+        }
+        Err(e) => {
+            error!("error while loading user {}: {:?}", claims.sub, e);
+            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    };
+
+    req.extensions_mut().insert(WopiAccess {
+        user,
+        file_id: claims.file_id,
+    });
+    debug!("wopi_token_accepted access token validated");
+    Ok(next
+        .run(req)
+        .instrument(tracing::info_span!("Authenticated WOPI Request"))
+        .await)
+}
+
+fn decode_proof_header(headers: &HeaderMap, name: &str) -> Option<Vec<u8>> {
+    STANDARD.decode(headers.get(name)?.to_str().ok()?).ok()
+}
+
+/// Rejects requests which weren't signed by the office server. The signature covers the access
+/// token, the full request url and the `X-WOPI-TimeStamp` header.
+#[allow(clippy::future_not_send)]
+#[tracing::instrument(skip_all)]
+pub async fn wopi_proof<B>(
+    Extension(config): Extension<Arc<GenbuConfig>>,
+    Extension(discovery): Extension<WopiDiscovery>,
+    query: Option<Query<AccessTokenQuery>>,
+    req: Request<B>,
+    next: Next<B>,
+) -> Result<Response, StatusCode> {
+    // The WOPI spec requires hosts to answer requests with an invalid proof with a 500
+    let headers = req.headers();
+    let (Some(Query(query)), Some(proof), Some(timestamp)) = (
+        query,
+        decode_proof_header(headers, "X-WOPI-Proof"),
+        headers
+            .get("X-WOPI-TimeStamp")
+            .and_then(|t| t.to_str().ok()?.parse().ok()),
+    ) else {
+        warn!("wopi_proof_missing request isn't signed by the office server");
+        return Err(StatusCode::INTERNAL_SERVER_ERROR);
+    };
+    let path = req.uri().path_and_query().map_or("", |p| p.as_str());
+    let proof = WopiProof {
+        access_token: query.access_token,
+        url: format!("{}{path}", config.server.public_url.trim_end_matches('/')),
+        timestamp,
+        proof,
+        proof_old: decode_proof_header(headers, "X-WOPI-ProofOld"),
+    };
+
+    if !proof.is_fresh(OffsetDateTime::now_utc()) {
+        warn!(
+            "wopi_proof_expired timestamp {} is too old",
+            proof.timestamp
+        );
+        return Err(StatusCode::INTERNAL_SERVER_ERROR);
+    }
+    match discovery.verify_proof(&proof).await {
+        Ok(true) => Ok(next.run(req).await),
+        Ok(false) => {
+            warn!("wopi_proof_invalid attempted forged WOPI request");
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+        Err(e) => {
+            error!("unable to load proof keys: {:?}", e);
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
@@ -1,4 +1,5 @@
 pub mod apidoc;
 pub mod builder;
+pub mod jobs;
 pub mod middlewares;
 pub mod routes;
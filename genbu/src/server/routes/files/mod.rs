@@ -1,57 +1,93 @@
+use std::{io, sync::Arc, time::SystemTime};
+
 use axum::{
-    extract::Query,
+    body::StreamBody,
+    extract::{BodyStream, Path, Query},
     middleware,
-    response::{IntoResponse, Redirect},
-    routing::{get, post},
+    response::{IntoResponse, Redirect, Response},
+    routing::{delete, get, post, put},
     Extension, Json, Router,
 };
-use bytes::Bytes;
+use futures::TryStreamExt;
 use genbu_auth::authn::Claims;
-use hyper::StatusCode;
+use hyper::{
+    header::{self, HeaderName, HeaderValue},
+    HeaderMap, StatusCode,
+};
 
 use serde_json::json;
 use tracing::error;
 
 use crate::{
+    config::GenbuConfig,
+    connectors::urlencode,
     handler::files::upload as handler,
     handler::files::{
+        archive::{self as archive_handler, ArchiveAPIError, ArchiveRequest},
+        blobs::BlobAPIError,
+        catalog::{self as catalog_handler, CatalogAPIError, FileInfo},
         download as download_handler,
-        download::{DownloadAPIError, StartDownloadRequest},
+        download::{
+            Disposition, DownloadAPIError, DownloadConditions, ProxiedContent,
+            ProxyDownloadRequest, StartDownloadRequest,
+        },
+        quota::{self as quota_handler, QuotaAPIError, QuotaOwner, SetQuotaRequest, StorageUsage},
+        trash::TrashAPIError,
+        tus::{self as tus_handler, TusAPIError},
         upload::UploadAPIError,
         userfiles::UserfilesAPIError,
-        wopi as wopi_handler,
+        versions::VersionAPIError,
+        wopi::WopiAPIError,
     },
     server::middlewares::auth::auth,
     stores::{
         files::{
-            database::DBFileStore,
+            database::{DBFileError, FileResult, LeaseID},
             filesystem::{Filesystem, FilesystemError},
-            storage::{FileError, FileStorage},
-            UploadLeaseError, UploadLeaseStore,
+            storage::{FileError, FileStorage, PresignError},
+            UploadLeaseError,
         },
-        users::User,
-        DataStore,
+        users::UserError,
+        DataStore, Uuid,
     },
 };
 
-use self::wopi::{Wopi, WopiResponse};
-
+pub mod storage;
+pub mod trash;
+pub mod tus;
 pub mod userfiles;
+pub mod versions;
 pub mod wopi;
 
 pub fn router<F: FileStorage + Filesystem, L: DataStore>() -> Router {
     Router::new()
-        .merge(userfiles::router::<F>())
-        .route("/api/files/download", get(start_download::<F>))
+        .merge(userfiles::router::<F, L>())
+        .merge(trash::router::<F, L>())
+        .merge(versions::router::<F, L>())
+        .merge(tus::router::<F, L>())
+        .route("/api/files/download", get(start_download::<F, L>))
+        .route("/api/files/usage", get(get_usage::<L>))
+        .route("/api/files/quota/users/:id", put(set_user_quota::<L>))
+        .route("/api/files/quota/groups/:id", put(set_group_quota::<L>))
+        .route("/api/files/content", put(put_content::<F, L>))
+        .route("/api/files/:id", get(get_file_info::<L>))
+        .route("/api/files/:id/download", get(download_file::<F, L>))
+        .route("/api/files/:id/content", get(proxy_download::<F, L>))
+        .route("/api/files/archive", post(download_archive::<F, L>))
         .route("/api/files/upload", post(upload_file_request::<F, L>)) // TODO: COnsider using put
         // instead of post,
+        .route("/api/files/upload/uris", post(get_upload_uris::<F, L>))
         .route("/api/files/upload/finish", post(finish_upload::<F, L>))
-        // .route("/api/wopi/files/:id/contents", get(todo!()))
+        .route("/api/files/upload/:lease_id", delete(abort_upload::<F, L>))
         .route(
-            "/api/wopi/files/:id",
-            get(wopi_check_file_info::<F, L>), // .post(todo!())
+            "/api/files/upload/:lease_id/parts",
+            get(resume_upload::<F, L>),
         )
+        .route("/api/wopi/token", post(wopi::create_access_token::<L>))
+        .route("/api/wopi/launch", get(wopi::launch::<L>))
         .route_layer(middleware::from_fn(auth))
+        .merge(wopi::router::<F, L>())
+        .merge(storage::router::<F>())
     // TODO: Add auth middleware back
 }
 
@@ -64,23 +100,180 @@ pub fn router<F: FileStorage + Filesystem, L: DataStore>() -> Router {
         (status = 307, description = "Redirect to file location")
     )
 )]
-pub async fn start_download<F: Filesystem>(
+pub async fn start_download<F: Filesystem, D: DataStore>(
     Extension(file_storage): Extension<F>,
+    Extension(file_db): Extension<D>,
     Extension(user): Extension<Claims>,
     Query(req): Query<StartDownloadRequest>,
 ) -> download_handler::DownloadAPIResult<Redirect> {
-    let redirect = download_handler::start_download(file_storage, user.sub, req).await?;
+    let redirect = download_handler::start_download(file_storage, file_db, user.sub, req).await?;
+    Ok(Redirect::temporary(&redirect))
+}
+
+#[utoipa::path(
+    get,
+    tag = "files",
+    path = "/api/files/{id}",
+    params(("id" = Uuid, Path, description = "Id of the file")),
+    responses(
+        (status = 200, description = "Metadata of the file", body = FileInfo),
+        (status = 404, description = "File doesn't exist or the user can't access it")
+    )
+)]
+pub async fn get_file_info<D: DataStore>(
+    Extension(file_db): Extension<D>,
+    Extension(user): Extension<Claims>,
+    Path(file_id): Path<Uuid>,
+) -> catalog_handler::CatalogAPIResult<Json<FileInfo>> {
+    Ok(Json(
+        catalog_handler::get_file_info(file_db, user.sub, file_id).await?,
+    ))
+}
+
+#[utoipa::path(
+    get,
+    tag = "files",
+    path = "/api/files/{id}/download",
+    params(("id" = Uuid, Path, description = "Id of the file")),
+    responses(
+        (status = 307, description = "Redirect to file location"),
+        (status = 404, description = "File doesn't exist or the user can't access it")
+    )
+)]
+pub async fn download_file<F: Filesystem, D: DataStore>(
+    Extension(file_storage): Extension<F>,
+    Extension(file_db): Extension<D>,
+    Extension(user): Extension<Claims>,
+    Path(file_id): Path<Uuid>,
+) -> catalog_handler::CatalogAPIResult<Redirect> {
+    let redirect = catalog_handler::download_file(file_storage, file_db, user.sub, file_id).await?;
     Ok(Redirect::temporary(&redirect))
 }
 
-pub async fn wopi_check_file_info<F: Filesystem, D: DBFileStore>(
+/// Sets a header, unless its value contains characters which aren't allowed in headers.
+fn set_header(headers: &mut HeaderMap, name: HeaderName, value: &str) {
+    if let Ok(value) = HeaderValue::from_str(value) {
+        headers.insert(name, value);
+    }
+}
+
+#[utoipa::path(
+    get,
+    tag = "files",
+    path = "/api/files/{id}/content",
+    params(("id" = Uuid, Path, description = "Id of the file"), ProxyDownloadRequest),
+    responses(
+        (status = 200, description = "Content of the file"),
+        (status = 206, description = "Requested range of the content"),
+        (status = 304, description = "Content didn't change since the client cached it"),
+        (status = 404, description = "File doesn't exist or the user can't access it"),
+        (status = 416, description = "Requested range starts behind the end of the content")
+    )
+)]
+pub async fn proxy_download<F: Filesystem, D: DataStore>(
     Extension(file_storage): Extension<F>,
-    Extension(db_file_store): Extension<D>,
-    Wopi(req): Wopi<Bytes>,
-) -> impl IntoResponse {
-    let user = User::template();
-    let resp = wopi_handler::wopi_file(file_storage, db_file_store, &user, req).await;
-    WopiResponse(resp)
+    Extension(file_db): Extension<D>,
+    Extension(user): Extension<Claims>,
+    Path(file_id): Path<Uuid>,
+    Query(req): Query<ProxyDownloadRequest>,
+    headers: HeaderMap,
+) -> download_handler::DownloadAPIResult<Response> {
+    let header_value = |name| {
+        headers
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .map(ToOwned::to_owned)
+    };
+    let conditions = DownloadConditions {
+        if_none_match: header_value(header::IF_NONE_MATCH),
+        if_modified_since: header_value(header::IF_MODIFIED_SINCE),
+        if_range: header_value(header::IF_RANGE),
+        range: header_value(header::RANGE),
+    };
+    let download =
+        download_handler::proxy_download(file_storage, file_db, user.sub, file_id, conditions)
+            .await?;
+    let info = download.info;
+
+    // The content belongs to a user, so it's only cached by the browser, which revalidates it
+    let mut headers = HeaderMap::new();
+    set_header(&mut headers, header::ETAG, &format!("\"{}\"", info.e_tag));
+    if let Some(last_modified) = info.last_modified {
+        let last_modified = httpdate::fmt_http_date(SystemTime::from(last_modified));
+        set_header(&mut headers, header::LAST_MODIFIED, &last_modified);
+    }
+    set_header(&mut headers, header::ACCEPT_RANGES, "bytes");
+    set_header(&mut headers, header::CACHE_CONTROL, "private, no-cache");
+
+    let (status, object) = match download.content {
+        ProxiedContent::NotModified => {
+            return Ok((StatusCode::NOT_MODIFIED, headers).into_response())
+        }
+        ProxiedContent::Unsatisfiable => {
+            let content_range = format!("bytes */{}", info.size);
+            set_header(&mut headers, header::CONTENT_RANGE, &content_range);
+            return Ok((StatusCode::RANGE_NOT_SATISFIABLE, headers).into_response());
+        }
+        ProxiedContent::Full(object) => (StatusCode::OK, object),
+        ProxiedContent::Partial(range, object) => {
+            let content_range = format!("bytes {}-{}/{}", range.start, range.end - 1, info.size);
+            set_header(&mut headers, header::CONTENT_RANGE, &content_range);
+            (StatusCode::PARTIAL_CONTENT, object)
+        }
+    };
+    let disposition = match req.disposition {
+        Disposition::Inline => "inline",
+        Disposition::Attachment => "attachment",
+    };
+    let content_disposition = format!("{disposition}; filename*=UTF-8''{}", urlencode(&info.name));
+    set_header(
+        &mut headers,
+        header::CONTENT_DISPOSITION,
+        &content_disposition,
+    );
+    let content_type = info
+        .mime_type
+        .as_deref()
+        .unwrap_or("application/octet-stream");
+    set_header(&mut headers, header::CONTENT_TYPE, content_type);
+    set_header(
+        &mut headers,
+        header::CONTENT_LENGTH,
+        &object.size.to_string(),
+    );
+    // Files shown inline must not run scripts with the origin of this server
+    set_header(&mut headers, header::X_CONTENT_TYPE_OPTIONS, "nosniff");
+    set_header(&mut headers, header::CONTENT_SECURITY_POLICY, "sandbox");
+    Ok((status, headers, StreamBody::new(object.body)).into_response())
+}
+
+#[utoipa::path(
+    post,
+    tag = "files",
+    path = "/api/files/archive",
+    request_body = ArchiveRequest,
+    responses(
+        (status = 200, description = "ZIP archive of the files and folders", content_type = "application/zip"),
+        (status = 400, description = "A path is invalid"),
+        (status = 404, description = "A file or folder doesn't exist")
+    )
+)]
+pub async fn download_archive<F: Filesystem, D: DataStore>(
+    Extension(filesystem): Extension<F>,
+    Extension(file_db): Extension<D>,
+    Extension(user): Extension<Claims>,
+    Json(req): Json<ArchiveRequest>,
+) -> archive_handler::ArchiveAPIResult<impl IntoResponse> {
+    let archive = archive_handler::archive(filesystem, file_db, user.sub, req).await?;
+    let mut headers = HeaderMap::new();
+    set_header(&mut headers, header::CONTENT_TYPE, "application/zip");
+    let content_disposition = format!("attachment; filename*=UTF-8''{}", urlencode(&archive.name));
+    set_header(
+        &mut headers,
+        header::CONTENT_DISPOSITION,
+        &content_disposition,
+    );
+    Ok((headers, StreamBody::new(archive.body)))
 }
 
 #[utoipa::path(
@@ -90,37 +283,227 @@ pub async fn wopi_check_file_info<F: Filesystem, D: DBFileStore>(
     request_body = UploadFileRequest,
     responses(
         (status = 200, description = "Upload request is valid and accepted", body = UploadFileResponse),
-        (status = 400, description = "Upload request is invalid (i.e. negative size)"),
-        (status = 409, description = "Upload request is forbidden (i.e. file is too large)")
+        (status = 400, description = "Upload request is invalid (i.e. negative size, invalid part size or checksums)"),
+        (status = 409, description = "Upload request is forbidden (i.e. file is too large)"),
+        (status = 507, description = "File doesn't fit into the storage quota of the user")
     )
 )]
-pub async fn upload_file_request<F: FileStorage, L: UploadLeaseStore>(
+pub async fn upload_file_request<F: FileStorage, L: DataStore>(
     Extension(file_storage): Extension<F>,
     Extension(lease_store): Extension<L>,
+    Extension(config): Extension<Arc<GenbuConfig>>,
     Extension(user): Extension<Claims>,
     Json(req): Json<handler::UploadFileRequest>,
 ) -> handler::UploadAPIResult<Json<handler::UploadFileResponse>> {
     Ok(Json(
-        handler::post(file_storage, lease_store, user.sub, req).await?,
+        handler::post(file_storage, lease_store, user.sub, req, &config).await?,
+    ))
+}
+
+#[utoipa::path(
+    post,
+    tag = "files",
+    path = "/api/files/upload/uris",
+    request_body = GetUrisRequest,
+    responses(
+        (status = 200, description = "Upload urls of every part of the lease", body = UploadFileResponse),
+        (status = 403, description = "Upload lease belongs to another user"),
+        (status = 404, description = "Upload lease doesn't exist or is already completed"),
+        (status = 410, description = "Upload lease expired")
+    )
+)]
+pub async fn get_upload_uris<F: FileStorage, L: DataStore>(
+    Extension(file_storage): Extension<F>,
+    Extension(lease_store): Extension<L>,
+    Extension(config): Extension<Arc<GenbuConfig>>,
+    Extension(user): Extension<Claims>,
+    Json(req): Json<handler::GetUrisRequest>,
+) -> handler::UploadAPIResult<Json<handler::UploadFileResponse>> {
+    Ok(Json(
+        handler::get(file_storage, lease_store, user.sub, req, &config.upload).await?,
+    ))
+}
+
+#[utoipa::path(
+    get,
+    tag = "files",
+    path = "/api/files/upload/{lease_id}/parts",
+    params(("lease_id" = LeaseID, Path, description = "Id of the upload lease")),
+    responses(
+        (status = 200, description = "Uploaded parts and upload urls of the missing ones", body = ResumeUploadResponse),
+        (status = 403, description = "Upload lease belongs to another user"),
+        (status = 404, description = "Upload lease doesn't exist or is already completed"),
+        (status = 410, description = "Upload lease expired")
+    )
+)]
+pub async fn resume_upload<F: FileStorage, L: DataStore>(
+    Extension(file_storage): Extension<F>,
+    Extension(lease_store): Extension<L>,
+    Extension(config): Extension<Arc<GenbuConfig>>,
+    Extension(user): Extension<Claims>,
+    Path(lease_id): Path<LeaseID>,
+) -> handler::UploadAPIResult<Json<handler::ResumeUploadResponse>> {
+    Ok(Json(
+        handler::resume(
+            file_storage,
+            lease_store,
+            user.sub,
+            lease_id,
+            &config.upload,
+        )
+        .await?,
+    ))
+}
+
+#[utoipa::path(
+    put,
+    tag = "files",
+    path = "/api/files/content",
+    params(handler::PutContentRequest),
+    request_body(content = Vec<u8>, content_type = "application/octet-stream"),
+    responses(
+        (status = 200, description = "File stored successfully", body = FileInfo),
+        (status = 400, description = "Path is invalid"),
+        (status = 403, description = "File is too large"),
+        (status = 409, description = "A folder with this path already exists"),
+        (status = 411, description = "Content-Length header is missing"),
+        (status = 423, description = "File is locked"),
+        (status = 507, description = "File doesn't fit into the storage quota of the user")
+    )
+)]
+pub async fn put_content<F: FileStorage, L: DataStore>(
+    Extension(file_storage): Extension<F>,
+    Extension(store): Extension<L>,
+    Extension(config): Extension<Arc<GenbuConfig>>,
+    Extension(user): Extension<Claims>,
+    Query(req): Query<handler::PutContentRequest>,
+    headers: HeaderMap,
+    body: BodyStream,
+) -> handler::UploadAPIResult<Json<FileInfo>> {
+    let size = headers
+        .get(header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse().ok());
+    // The body is passed on as it arrives, so large files don't have to fit into memory
+    let data = Box::pin(body.map_err(io::Error::other));
+    Ok(Json(
+        handler::put_content(file_storage, store, user.sub, req, size, data, &config).await?,
+    ))
+}
+
+#[utoipa::path(
+    get,
+    tag = "files",
+    path = "/api/files/usage",
+    responses(
+        (status = 200, description = "Storage usage and quota of the user", body = StorageUsage)
+    )
+)]
+pub async fn get_usage<D: DataStore>(
+    Extension(store): Extension<D>,
+    Extension(config): Extension<Arc<GenbuConfig>>,
+    Extension(user): Extension<Claims>,
+) -> FileResult<Json<StorageUsage>> {
+    Ok(Json(
+        quota_handler::get_usage(&store, user.sub, &config.quota).await?,
     ))
 }
 
+#[utoipa::path(
+    put,
+    tag = "files",
+    path = "/api/files/quota/users/{id}",
+    params(("id" = Uuid, Path, description = "Id of the user")),
+    request_body(content = SetQuotaRequest),
+    responses(
+        (status = 200, description = "Quota of the user was set"),
+        (status = 403, description = "User isn't a member of the admin group")
+    )
+)]
+pub async fn set_user_quota<D: DataStore>(
+    Extension(store): Extension<D>,
+    Extension(config): Extension<Arc<GenbuConfig>>,
+    Extension(user): Extension<Claims>,
+    Path(id): Path<Uuid>,
+    Json(req): Json<SetQuotaRequest>,
+) -> Result<(), QuotaAPIError> {
+    let owner = QuotaOwner::User(id);
+    quota_handler::set_quota(store, user.sub, owner, req, &config.quota).await
+}
+
+#[utoipa::path(
+    put,
+    tag = "files",
+    path = "/api/files/quota/groups/{id}",
+    params(("id" = Uuid, Path, description = "Id of the group")),
+    request_body(content = SetQuotaRequest),
+    responses(
+        (status = 200, description = "Quota of the group was set"),
+        (status = 403, description = "User isn't a member of the admin group")
+    )
+)]
+pub async fn set_group_quota<D: DataStore>(
+    Extension(store): Extension<D>,
+    Extension(config): Extension<Arc<GenbuConfig>>,
+    Extension(user): Extension<Claims>,
+    Path(id): Path<Uuid>,
+    Json(req): Json<SetQuotaRequest>,
+) -> Result<(), QuotaAPIError> {
+    let owner = QuotaOwner::Group(id);
+    quota_handler::set_quota(store, user.sub, owner, req, &config.quota).await
+}
+
 #[utoipa::path(
     post,
     tag = "files",
     path = "/api/files/upload/finish",
     request_body(content = FinishUploadRequest),
     responses(
-        (status = 200, description = "File uploaded finished successfully"),
+        (status = 200, description = "File uploaded finished successfully", body = FileInfo),
+        (status = 403, description = "Upload lease belongs to another user"),
+        (status = 404, description = "Upload lease doesn't exist"),
+        (status = 422, description = "Uploaded file doesn't match the declared size or checksums"),
         (status = 500, description = "An internal error occured while uploading")
     )
 )]
-pub async fn finish_upload<F: FileStorage, L: UploadLeaseStore>(
+pub async fn finish_upload<F: FileStorage, L: DataStore>(
     Extension(file_storage): Extension<F>,
     Extension(lease_store): Extension<L>,
+    Extension(config): Extension<Arc<GenbuConfig>>,
+    Extension(user): Extension<Claims>,
     Json(req): Json<handler::FinishUploadRequest>,
+) -> handler::UploadAPIResult<Json<FileInfo>> {
+    Ok(Json(
+        handler::finish_upload(
+            file_storage,
+            lease_store.clone(),
+            lease_store,
+            user.sub,
+            req,
+            &config,
+        )
+        .await?,
+    ))
+}
+
+#[utoipa::path(
+    delete,
+    tag = "files",
+    path = "/api/files/upload/{lease_id}",
+    params(("lease_id" = LeaseID, Path, description = "Id of the upload lease")),
+    responses(
+        (status = 200, description = "Upload aborted and its lease deleted"),
+        (status = 403, description = "Upload lease belongs to another user"),
+        (status = 404, description = "Upload lease doesn't exist")
+    )
+)]
+pub async fn abort_upload<F: FileStorage, L: DataStore>(
+    Extension(file_storage): Extension<F>,
+    Extension(lease_store): Extension<L>,
+    Extension(user): Extension<Claims>,
+    Path(lease_id): Path<LeaseID>,
 ) -> handler::UploadAPIResult<()> {
-    handler::finish_upload(file_storage, lease_store, req).await
+    handler::abort_upload(file_storage, lease_store, user.sub, lease_id).await
 }
 
 impl IntoResponse for FileError {
@@ -131,7 +514,15 @@ impl IntoResponse for FileError {
                 "Server failed to establish connection to database",
             ),
             Self::Other(_) => (StatusCode::INTERNAL_SERVER_ERROR, "Unknown internal error"),
+            Self::NotFound(_) => (StatusCode::NOT_FOUND, "File not found"),
+            Self::Presigning(PresignError::InvalidToken) => {
+                (StatusCode::FORBIDDEN, "Presigned url is invalid or expired")
+            }
             Self::Presigning(_) => (StatusCode::INTERNAL_SERVER_ERROR, "Error during presigning"),
+            Self::BadDigest(_) => (
+                StatusCode::BAD_REQUEST,
+                "Checksum doesn't match the uploaded data",
+            ),
         };
 
         let body = Json(json!({ "error": error_message }));
@@ -163,15 +554,55 @@ impl IntoResponse for UploadAPIError {
         match self {
             Self::StorageError(e) => e.into_response(),
             Self::DatabaseError(e) => e.into_response(),
+            Self::CatalogError(e) => e.into_response(),
+            Self::VersionError(e) => e.into_response(),
+            Self::BlobError(e) => e.into_response(),
             Self::FileTooLarge(size, max_size) => (
                 StatusCode::FORBIDDEN,
                 format!("file size {size} exceeds maximum {max_size}"),
             )
                 .into_response(),
             Self::NotFound(_) => (StatusCode::NOT_FOUND, "Upload lease not found").into_response(),
+            Self::Forbidden(_) => (
+                StatusCode::FORBIDDEN,
+                "Upload lease belongs to another user",
+            )
+                .into_response(),
+            Self::QuotaExceeded(size, available) => (
+                StatusCode::INSUFFICIENT_STORAGE,
+                format!("file size {size} exceeds the available storage {available}"),
+            )
+                .into_response(),
+            Self::UsageError(e) => e.into_response(),
+            Self::RoleError(e) => {
+                error!("unable to read the roles of a user {e:?}");
+                let status = match e {
+                    UserError::Connection(_) => StatusCode::BAD_GATEWAY,
+                    _ => StatusCode::INTERNAL_SERVER_ERROR,
+                };
+                (status, "Unable to read the roles of the user").into_response()
+            }
             Self::NegativeSize(_) => {
                 (StatusCode::BAD_REQUEST, "File size is negative").into_response()
             }
+            Self::LengthRequired => {
+                (StatusCode::LENGTH_REQUIRED, "Content-Length is missing").into_response()
+            }
+            Self::InvalidPath(_) => (StatusCode::BAD_REQUEST, "Path is invalid").into_response(),
+            Self::IsFolder(_) => (StatusCode::CONFLICT, "Path is a folder").into_response(),
+            Self::InvalidChunkSize(_) => {
+                (StatusCode::BAD_REQUEST, "Part size is invalid").into_response()
+            }
+            Self::InvalidChecksums => (
+                StatusCode::BAD_REQUEST,
+                "Checksums don't match the parts of the upload",
+            )
+                .into_response(),
+            Self::IntegrityError(e) => (
+                StatusCode::UNPROCESSABLE_ENTITY,
+                format!("Uploaded file doesn't match its lease: {e}"),
+            )
+                .into_response(),
             Self::Unknown => {
                 (StatusCode::INTERNAL_SERVER_ERROR, "Unknown internal error").into_response()
             }
@@ -179,12 +610,64 @@ impl IntoResponse for UploadAPIError {
     }
 }
 
+impl IntoResponse for TusAPIError {
+    fn into_response(self) -> axum::response::Response {
+        let mut resp = match self {
+            Self::UploadError(e) => e.into_response(),
+            Self::UnsupportedVersion(_) => (
+                StatusCode::PRECONDITION_FAILED,
+                [("tus-version", tus_handler::TUS_VERSION)],
+                "Tus version is not supported",
+            )
+                .into_response(),
+            Self::InvalidHeader(name) => {
+                (StatusCode::BAD_REQUEST, format!("Header {name} is invalid")).into_response()
+            }
+            Self::InvalidContentType(_) => (
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                "Content type is not supported",
+            )
+                .into_response(),
+            Self::OffsetMismatch(_, offset) => {
+                (StatusCode::CONFLICT, format!("Upload offset is {offset}")).into_response()
+            }
+            Self::ExceedsLength(_) => (
+                StatusCode::PAYLOAD_TOO_LARGE,
+                "Data exceeds the upload length",
+            )
+                .into_response(),
+            Self::UnsupportedChecksum(_) => (
+                StatusCode::BAD_REQUEST,
+                "Checksum algorithm is not supported",
+            )
+                .into_response(),
+            Self::Locked => {
+                (StatusCode::LOCKED, "Upload is continued by another request").into_response()
+            }
+            // tus defines its own status code for this case
+            Self::ChecksumMismatch => (
+                StatusCode::from_u16(460).unwrap_or(StatusCode::BAD_REQUEST),
+                "Checksum mismatch",
+            )
+                .into_response(),
+        };
+        resp.headers_mut().insert(
+            tus::TUS_RESUMABLE,
+            HeaderValue::from_static(tus_handler::TUS_VERSION),
+        );
+        resp
+    }
+}
+
 impl IntoResponse for FilesystemError {
     fn into_response(self) -> axum::response::Response {
         match self {
             Self::FileAlreadyExists(e) => {
                 (StatusCode::CONFLICT, format!("File {e} already exists")).into_response()
             }
+            Self::NotFound(e) => {
+                (StatusCode::NOT_FOUND, format!("File {e} not found")).into_response()
+            }
             Self::Connection(e) => {
                 error!("error while connecting to filesystem {e:?}");
                 (StatusCode::BAD_GATEWAY, "Unable to connect to database").into_response()
@@ -202,6 +685,30 @@ impl IntoResponse for UserfilesAPIError {
         match self {
             Self::NotFound(_) => (StatusCode::NOT_FOUND, "User file not found").into_response(),
             Self::Filesystem(e) => e.into_response(),
+            Self::DatabaseError(e) => e.into_response(),
+            Self::CatalogError(e) => e.into_response(),
+            Self::TrashError(e) => e.into_response(),
+            Self::BlobError(e) => e.into_response(),
+            Self::QuotaError(e) => e.into_response(),
+            Self::InvalidPath(path) => {
+                (StatusCode::BAD_REQUEST, format!("Path {path} is invalid")).into_response()
+            }
+        }
+    }
+}
+
+impl IntoResponse for ArchiveAPIError {
+    fn into_response(self) -> axum::response::Response {
+        match self {
+            Self::Filesystem(e) => e.into_response(),
+            Self::StorageError(e) => {
+                error!("file storage error {e:?}");
+                e.into_response()
+            }
+            Self::DatabaseError(e) => e.into_response(),
+            Self::InvalidPath(path) => {
+                (StatusCode::BAD_REQUEST, format!("Path {path} is invalid")).into_response()
+            }
         }
     }
 }
@@ -211,8 +718,10 @@ impl IntoResponse for DownloadAPIError {
         match self {
             DownloadAPIError::StorageError(e) => {
                 error!("file storage error {e:?}");
-                (StatusCode::INTERNAL_SERVER_ERROR, "Unknown internal error").into_response()
+                e.into_response()
             }
+            DownloadAPIError::DatabaseError(e) => e.into_response(),
+            DownloadAPIError::CatalogError(e) => e.into_response(),
             DownloadAPIError::NotFound(_) => {
                 (StatusCode::NOT_FOUND, "File not found").into_response()
             }
@@ -223,3 +732,135 @@ impl IntoResponse for DownloadAPIError {
         }
     }
 }
+
+impl IntoResponse for WopiAPIError {
+    fn into_response(self) -> axum::response::Response {
+        match self {
+            Self::NotFound(_) => (StatusCode::NOT_FOUND, "File not found").into_response(),
+            Self::Forbidden(_, action) => (
+                StatusCode::FORBIDDEN,
+                format!("Action {action} requires write access"),
+            )
+                .into_response(),
+            Self::UnsupportedFileType(ext) => (
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                format!("Office server doesn't support {ext} files"),
+            )
+                .into_response(),
+            Self::DiscoveryError(e) => {
+                error!("office server discovery failed {e:?}");
+                (StatusCode::BAD_GATEWAY, "Unable to reach office server").into_response()
+            }
+            Self::DatabaseError(e) => e.into_response(),
+            Self::TokenError(e) => {
+                error!("unable to create access token {e:?}");
+                (StatusCode::INTERNAL_SERVER_ERROR, "Unknown internal error").into_response()
+            }
+        }
+    }
+}
+
+impl IntoResponse for DBFileError {
+    fn into_response(self) -> axum::response::Response {
+        match self {
+            Self::Connection(e) => {
+                error!("error while connecting to database {e:?}");
+                (StatusCode::BAD_GATEWAY, "Unable to connect to database").into_response()
+            }
+            Self::Locked(_) => (StatusCode::LOCKED, "File is locked").into_response(),
+            Self::Conflict(_) => {
+                (StatusCode::CONFLICT, "Path is taken by a file or folder").into_response()
+            }
+            Self::Other(e) => {
+                error!("unknown database error {e:?}");
+                (StatusCode::INTERNAL_SERVER_ERROR, "Unknown internal error").into_response()
+            }
+        }
+    }
+}
+
+impl IntoResponse for CatalogAPIError {
+    fn into_response(self) -> axum::response::Response {
+        match self {
+            Self::NotFound(_) => (StatusCode::NOT_FOUND, "File not found").into_response(),
+            Self::StorageError(e) => {
+                error!("file storage error {e:?}");
+                e.into_response()
+            }
+            Self::DatabaseError(e) => e.into_response(),
+            Self::BlobError(e) => e.into_response(),
+        }
+    }
+}
+
+impl IntoResponse for BlobAPIError {
+    fn into_response(self) -> axum::response::Response {
+        match self {
+            Self::StorageError(e) => {
+                error!("file storage error {e:?}");
+                e.into_response()
+            }
+            Self::DatabaseError(e) => e.into_response(),
+        }
+    }
+}
+
+impl IntoResponse for QuotaAPIError {
+    fn into_response(self) -> axum::response::Response {
+        match self {
+            Self::DatabaseError(e) => e.into_response(),
+            Self::GroupError(e) => {
+                error!("unable to read the groups of a user {e:?}");
+                let status = match e {
+                    UserError::Connection(_) => StatusCode::BAD_GATEWAY,
+                    _ => StatusCode::INTERNAL_SERVER_ERROR,
+                };
+                (status, "Unable to read the groups of the user").into_response()
+            }
+            Self::QuotaExceeded(size, available) => (
+                StatusCode::INSUFFICIENT_STORAGE,
+                format!("file size {size} exceeds the available storage {available}"),
+            )
+                .into_response(),
+            Self::InvalidQuota(_) => {
+                (StatusCode::BAD_REQUEST, "Quota is too large").into_response()
+            }
+            Self::Forbidden => (
+                StatusCode::FORBIDDEN,
+                "Only members of the admin group may set quotas",
+            )
+                .into_response(),
+        }
+    }
+}
+
+impl IntoResponse for TrashAPIError {
+    fn into_response(self) -> axum::response::Response {
+        match self {
+            Self::NotFound(_) => (StatusCode::NOT_FOUND, "Trash entry not found").into_response(),
+            Self::Filesystem(e) => e.into_response(),
+            Self::StorageError(e) => {
+                error!("file storage error {e:?}");
+                e.into_response()
+            }
+            Self::DatabaseError(e) => e.into_response(),
+            Self::CatalogError(e) => e.into_response(),
+            Self::VersionError(e) => e.into_response(),
+            Self::BlobError(e) => e.into_response(),
+        }
+    }
+}
+
+impl IntoResponse for VersionAPIError {
+    fn into_response(self) -> axum::response::Response {
+        match self {
+            Self::NotFound(_) => (StatusCode::NOT_FOUND, "Version not found").into_response(),
+            Self::StorageError(e) => {
+                error!("file storage error {e:?}");
+                e.into_response()
+            }
+            Self::DatabaseError(e) => e.into_response(),
+            Self::CatalogError(e) => e.into_response(),
+        }
+    }
+}
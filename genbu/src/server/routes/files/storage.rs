@@ -0,0 +1,55 @@
+use axum::{
+    body::{Bytes, StreamBody},
+    extract::{DefaultBodyLimit, Query},
+    response::IntoResponse,
+    routing::get,
+    Extension, Router,
+};
+use hyper::header;
+use serde::Deserialize;
+
+use crate::{
+    connectors::local::presign::SIGNED_ROUTE,
+    handler::files::{download as download_handler, upload as upload_handler},
+    stores::files::{storage::MAX_SIGNED_PART_SIZE, FileStorage},
+};
+
+/// Routes which serve presigned urls for file storages without native presigning, or whose
+/// objects are encrypted with keys the clients mustn't know. These aren't protected by the auth
+/// middleware, because the signed token already authorizes the request.
+pub fn router<F: FileStorage>() -> Router {
+    Router::new().route(
+        SIGNED_ROUTE,
+        get(get_signed::<F>)
+            .put(put_signed::<F>)
+            .layer(DefaultBodyLimit::max(MAX_SIGNED_PART_SIZE as usize)),
+    )
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SignedRequest {
+    token: String,
+}
+
+pub async fn get_signed<F: FileStorage>(
+    Extension(file_storage): Extension<F>,
+    Query(req): Query<SignedRequest>,
+) -> download_handler::DownloadAPIResult<impl IntoResponse> {
+    let object = download_handler::get_signed(file_storage, &req.token).await?;
+    Ok((
+        [
+            (header::CONTENT_LENGTH, object.size.to_string()),
+            (header::CONTENT_DISPOSITION, "attachment".to_owned()),
+        ],
+        StreamBody::new(object.body),
+    ))
+}
+
+pub async fn put_signed<F: FileStorage>(
+    Extension(file_storage): Extension<F>,
+    Query(req): Query<SignedRequest>,
+    body: Bytes,
+) -> upload_handler::UploadAPIResult<impl IntoResponse> {
+    let e_tag = upload_handler::put_signed(file_storage, &req.token, body).await?;
+    Ok([(header::ETAG, e_tag)])
+}
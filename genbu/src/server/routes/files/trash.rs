@@ -0,0 +1,91 @@
+use axum::{
+    extract::Path,
+    routing::{delete, get, post},
+    Extension, Json, Router,
+};
+use genbu_auth::authn::Claims;
+
+use crate::{
+    handler::files::trash::{self as handler, TrashItem},
+    stores::{files::filesystem::Filesystem, DataStore, Uuid},
+};
+
+pub fn router<F: Filesystem, D: DataStore>() -> Router {
+    Router::new()
+        .route(
+            "/api/trash",
+            get(list_trash::<D>).delete(empty_trash::<F, D>),
+        )
+        .route("/api/trash/:id", delete(purge::<F, D>))
+        .route("/api/trash/:id/restore", post(restore::<F, D>))
+}
+
+#[utoipa::path(
+    get,
+    tag = "files",
+    path = "/api/trash",
+    responses(
+        (status = 200, description = "Trash of the user, most recently deleted first", body = [TrashItem])
+    )
+)]
+pub async fn list_trash<D: DataStore>(
+    Extension(store): Extension<D>,
+    Extension(claims): Extension<Claims>,
+) -> handler::TrashAPIResult<Json<Vec<TrashItem>>> {
+    Ok(Json(handler::list_trash(store, claims.sub).await?))
+}
+
+#[utoipa::path(
+    post,
+    tag = "files",
+    path = "/api/trash/{id}/restore",
+    params(("id" = Uuid, Path, description = "Id of the trash entry")),
+    responses(
+        (status = 200, description = "File or folder restored to its original path"),
+        (status = 404, description = "Trash entry doesn't exist"),
+        (status = 409, description = "Original path is taken by another file or folder")
+    )
+)]
+pub async fn restore<F: Filesystem, D: DataStore>(
+    Extension(filesystem): Extension<F>,
+    Extension(store): Extension<D>,
+    Extension(claims): Extension<Claims>,
+    Path(id): Path<Uuid>,
+) -> handler::TrashAPIResult<()> {
+    handler::restore(filesystem, store, claims.sub, id).await
+}
+
+#[utoipa::path(
+    delete,
+    tag = "files",
+    path = "/api/trash/{id}",
+    params(("id" = Uuid, Path, description = "Id of the trash entry")),
+    responses(
+        (status = 200, description = "Trash entry deleted for good"),
+        (status = 404, description = "Trash entry doesn't exist")
+    )
+)]
+pub async fn purge<F: Filesystem, D: DataStore>(
+    Extension(filesystem): Extension<F>,
+    Extension(store): Extension<D>,
+    Extension(claims): Extension<Claims>,
+    Path(id): Path<Uuid>,
+) -> handler::TrashAPIResult<()> {
+    handler::purge(filesystem, store, claims.sub, id).await
+}
+
+#[utoipa::path(
+    delete,
+    tag = "files",
+    path = "/api/trash",
+    responses(
+        (status = 200, description = "Every trash entry deleted for good")
+    )
+)]
+pub async fn empty_trash<F: Filesystem, D: DataStore>(
+    Extension(filesystem): Extension<F>,
+    Extension(store): Extension<D>,
+    Extension(claims): Extension<Claims>,
+) -> handler::TrashAPIResult<()> {
+    handler::empty_trash(filesystem, store, claims.sub).await
+}
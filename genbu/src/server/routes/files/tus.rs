@@ -0,0 +1,200 @@
+use std::{io, str::FromStr, sync::Arc};
+
+use axum::{
+    extract::{BodyStream, Path},
+    response::IntoResponse,
+    routing::{head, options},
+    Extension, Router,
+};
+use futures::TryStreamExt;
+use genbu_auth::authn::Claims;
+use http::{HeaderMap, StatusCode};
+use hyper::header;
+
+use crate::{
+    config::GenbuConfig,
+    handler::files::{
+        tus::{
+            self as handler, Checksum, CreateRequest, PatchRequest, TusAPIError, TusAPIResult,
+            UploadProgress,
+        },
+        upload::UploadAPIError,
+    },
+    stores::{
+        files::{database::LeaseID, FileStorage},
+        DataStore,
+    },
+};
+
+/// Media type of the body of `PATCH` requests.
+const OFFSET_OCTET_STREAM: &str = "application/offset+octet-stream";
+
+const CONTENT_TYPE: &str = "content-type";
+const CONTENT_LENGTH: &str = "content-length";
+
+pub const TUS_RESUMABLE: &str = "tus-resumable";
+const TUS_VERSION: &str = "tus-version";
+const TUS_EXTENSION: &str = "tus-extension";
+const TUS_MAX_SIZE: &str = "tus-max-size";
+const TUS_CHECKSUM_ALGORITHM: &str = "tus-checksum-algorithm";
+const UPLOAD_OFFSET: &str = "upload-offset";
+const UPLOAD_LENGTH: &str = "upload-length";
+const UPLOAD_METADATA: &str = "upload-metadata";
+const UPLOAD_CHECKSUM: &str = "upload-checksum";
+
+/// Routes of the tus resumable upload protocol, which is an alternative to presigned uploads for
+/// clients which already implement it.
+pub fn router<F: FileStorage, D: DataStore>() -> Router {
+    Router::new()
+        .route("/api/files/tus", options(tus_options).post(create::<F, D>))
+        .route(
+            "/api/files/tus/:lease_id",
+            head(head_upload::<F, D>)
+                .patch(patch_upload::<F, D>)
+                .delete(terminate::<F, D>),
+        )
+}
+
+/// Returns the value of a header, if it's present.
+fn get_header<T: FromStr>(headers: &HeaderMap, name: &'static str) -> TusAPIResult<Option<T>> {
+    headers
+        .get(name)
+        .map(|value| {
+            value
+                .to_str()
+                .ok()
+                .and_then(|value| value.parse().ok())
+                .ok_or(TusAPIError::InvalidHeader(name))
+        })
+        .transpose()
+}
+
+/// Rejects requests of clients, which speak another version of the protocol.
+fn check_version(headers: &HeaderMap) -> TusAPIResult<()> {
+    match get_header::<String>(headers, TUS_RESUMABLE)? {
+        Some(version) if version == handler::TUS_VERSION => Ok(()),
+        version => Err(TusAPIError::UnsupportedVersion(version.unwrap_or_default())),
+    }
+}
+
+fn progress_headers(progress: UploadProgress) -> [(&'static str, String); 3] {
+    [
+        (TUS_RESUMABLE, handler::TUS_VERSION.to_owned()),
+        (UPLOAD_OFFSET, progress.offset.to_string()),
+        (UPLOAD_LENGTH, progress.length.to_string()),
+    ]
+}
+
+pub async fn tus_options(Extension(config): Extension<Arc<GenbuConfig>>) -> impl IntoResponse {
+    (
+        StatusCode::NO_CONTENT,
+        [
+            (TUS_RESUMABLE, handler::TUS_VERSION.to_owned()),
+            (TUS_VERSION, handler::TUS_VERSION.to_owned()),
+            (TUS_EXTENSION, handler::TUS_EXTENSIONS.to_owned()),
+            (TUS_MAX_SIZE, config.upload.max_file_size.to_string()),
+            (
+                TUS_CHECKSUM_ALGORITHM,
+                handler::CHECKSUM_ALGORITHMS.to_owned(),
+            ),
+        ],
+    )
+}
+
+pub async fn create<F: FileStorage, D: DataStore>(
+    Extension(file_storage): Extension<F>,
+    Extension(store): Extension<D>,
+    Extension(config): Extension<Arc<GenbuConfig>>,
+    Extension(user): Extension<Claims>,
+    headers: HeaderMap,
+) -> TusAPIResult<impl IntoResponse> {
+    check_version(&headers)?;
+    // Uploads with a deferred length aren't supported
+    let length =
+        get_header(&headers, UPLOAD_LENGTH)?.ok_or(TusAPIError::InvalidHeader(UPLOAD_LENGTH))?;
+    let metadata = get_header::<String>(&headers, UPLOAD_METADATA)?.unwrap_or_default();
+    let req = CreateRequest {
+        length,
+        metadata: handler::parse_metadata(&metadata)?,
+    };
+    let lease_id = handler::create(file_storage, store, user.sub, req, &config).await?;
+    Ok((
+        StatusCode::CREATED,
+        [(header::LOCATION, format!("/api/files/tus/{}", lease_id.0))],
+        [(TUS_RESUMABLE, handler::TUS_VERSION)],
+    ))
+}
+
+pub async fn head_upload<F: FileStorage, D: DataStore>(
+    Extension(file_storage): Extension<F>,
+    Extension(store): Extension<D>,
+    Extension(config): Extension<Arc<GenbuConfig>>,
+    Extension(user): Extension<Claims>,
+    Path(lease_id): Path<LeaseID>,
+    headers: HeaderMap,
+) -> TusAPIResult<impl IntoResponse> {
+    check_version(&headers)?;
+    let progress = handler::head(file_storage, store, user.sub, lease_id, &config).await?;
+    Ok((
+        [(header::CACHE_CONTROL, "no-store")],
+        progress_headers(progress),
+    ))
+}
+
+pub async fn patch_upload<F: FileStorage, D: DataStore>(
+    Extension(file_storage): Extension<F>,
+    Extension(store): Extension<D>,
+    Extension(config): Extension<Arc<GenbuConfig>>,
+    Extension(user): Extension<Claims>,
+    Path(lease_id): Path<LeaseID>,
+    headers: HeaderMap,
+    body: BodyStream,
+) -> TusAPIResult<impl IntoResponse> {
+    check_version(&headers)?;
+    let content_type = get_header::<String>(&headers, CONTENT_TYPE)?;
+    if content_type.as_deref() != Some(OFFSET_OCTET_STREAM) {
+        return Err(TusAPIError::InvalidContentType(
+            content_type.unwrap_or_default(),
+        ));
+    }
+    let offset =
+        get_header(&headers, UPLOAD_OFFSET)?.ok_or(TusAPIError::InvalidHeader(UPLOAD_OFFSET))?;
+    let checksum = get_header::<String>(&headers, UPLOAD_CHECKSUM)?
+        .map(|value| Checksum::parse(&value))
+        .transpose()?;
+    let size = get_header(&headers, CONTENT_LENGTH)?.ok_or(UploadAPIError::LengthRequired)?;
+    // The body is passed on as it arrives, so large requests don't have to fit into memory
+    let data = Box::pin(body.map_err(io::Error::other));
+    let req = PatchRequest {
+        offset,
+        checksum,
+        size,
+        data,
+    };
+    let progress = handler::patch(
+        file_storage,
+        store.clone(),
+        store,
+        user.sub,
+        lease_id,
+        req,
+        &config,
+    )
+    .await?;
+    Ok((StatusCode::NO_CONTENT, progress_headers(progress)))
+}
+
+pub async fn terminate<F: FileStorage, D: DataStore>(
+    Extension(file_storage): Extension<F>,
+    Extension(store): Extension<D>,
+    Extension(user): Extension<Claims>,
+    Path(lease_id): Path<LeaseID>,
+    headers: HeaderMap,
+) -> TusAPIResult<impl IntoResponse> {
+    check_version(&headers)?;
+    handler::terminate(file_storage, store, user.sub, lease_id).await?;
+    Ok((
+        StatusCode::NO_CONTENT,
+        [(TUS_RESUMABLE, handler::TUS_VERSION)],
+    ))
+}
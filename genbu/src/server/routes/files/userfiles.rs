@@ -1,16 +1,37 @@
-use axum::{extract::Query, response::IntoResponse, routing::get, Extension, Json, Router};
+use std::{error::Error, sync::Arc};
+
+use axum::{
+    extract::Query,
+    response::IntoResponse,
+    routing::{get, post},
+    Extension, Json, Router,
+};
 use genbu_auth::authn::Claims;
+use hyper::StatusCode;
 
 use crate::{
-    handler::files::userfiles::{self as handler, DeleteUserfileRequest, GetUserfilesRequest},
-    stores::files::filesystem::Filesystem,
+    config::GenbuConfig,
+    handler::files::{
+        catalog::FileInfo,
+        userfiles::{
+            self as handler, BulkItemResult, BulkRequest, BulkResponse, CopyRequest,
+            DeleteUserfileRequest, GetUserfilesRequest, MkdirRequest, MoveRequest, RenameRequest,
+        },
+    },
+    stores::{files::filesystem::Filesystem, DataStore},
 };
 
-pub fn router<F: Filesystem>() -> Router {
-    Router::new().route(
-        "/api/filesystem",
-        get(get_userfiles::<F>).delete(delete_userfile::<F>),
-    )
+pub fn router<F: Filesystem, D: DataStore>() -> Router {
+    Router::new()
+        .route(
+            "/api/filesystem",
+            get(get_userfiles::<F, D>).delete(delete_userfile::<F, D>),
+        )
+        .route("/api/filesystem/folder", post(mkdir::<F, D>))
+        .route("/api/filesystem/move", post(move_userfile::<F, D>))
+        .route("/api/filesystem/rename", post(rename_userfile::<F, D>))
+        .route("/api/filesystem/copy", post(copy_userfile::<F, D>))
+        .route("/api/filesystem/bulk", post(bulk::<F, D>))
 }
 
 #[utoipa::path(
@@ -24,13 +45,14 @@ pub fn router<F: Filesystem>() -> Router {
         (status = 200, description = "List all userfiles successfully", body = GetUserfilesResponse)
     )
 )]
-pub async fn get_userfiles<F: Filesystem>(
+pub async fn get_userfiles<F: Filesystem, D: DataStore>(
     Extension(filesystem): Extension<F>,
+    Extension(file_db): Extension<D>,
     Extension(claims): Extension<Claims>,
     Query(req): Query<GetUserfilesRequest>,
 ) -> handler::UserfilesAPIResult<impl IntoResponse> {
     Ok(Json(
-        handler::get_userfiles(filesystem, claims.sub, &req).await?,
+        handler::get_userfiles(filesystem, file_db, claims.sub, &req).await?,
     ))
 }
 
@@ -43,11 +65,145 @@ pub async fn get_userfiles<F: Filesystem>(
         (status = 200, description = "File deleted successfully")
     )
 )]
-pub async fn delete_userfile<F: Filesystem>(
+pub async fn delete_userfile<F: Filesystem, D: DataStore>(
     Extension(filesystem): Extension<F>,
+    Extension(file_db): Extension<D>,
     Extension(claims): Extension<Claims>,
     Query(req): Query<DeleteUserfileRequest>,
 ) -> handler::UserfilesAPIResult<()> {
-    handler::delete_userfile(filesystem, claims.sub, req).await?;
+    handler::delete_userfile(filesystem, file_db, claims.sub, req).await?;
     Ok(())
 }
+
+#[utoipa::path(
+    post,
+    tag = "files",
+    path = "/api/filesystem/folder",
+    request_body = MkdirRequest,
+    responses(
+        (status = 200, description = "Folder created successfully", body = FileInfo),
+        (status = 400, description = "Path is invalid"),
+        (status = 409, description = "A file or folder with this path already exists")
+    )
+)]
+pub async fn mkdir<F: Filesystem, D: DataStore>(
+    Extension(filesystem): Extension<F>,
+    Extension(file_db): Extension<D>,
+    Extension(claims): Extension<Claims>,
+    Json(req): Json<MkdirRequest>,
+) -> handler::UserfilesAPIResult<Json<FileInfo>> {
+    Ok(Json(
+        handler::mkdir(filesystem, file_db, claims.sub, req).await?,
+    ))
+}
+
+#[utoipa::path(
+    post,
+    tag = "files",
+    path = "/api/filesystem/move",
+    request_body = MoveRequest,
+    responses(
+        (status = 200, description = "File or folder moved successfully"),
+        (status = 400, description = "Path is invalid or a folder would be moved into itself"),
+        (status = 404, description = "Source doesn't exist"),
+        (status = 409, description = "A file or folder with the target path already exists")
+    )
+)]
+pub async fn move_userfile<F: Filesystem, D: DataStore>(
+    Extension(filesystem): Extension<F>,
+    Extension(file_db): Extension<D>,
+    Extension(claims): Extension<Claims>,
+    Json(req): Json<MoveRequest>,
+) -> handler::UserfilesAPIResult<()> {
+    handler::move_userfile(filesystem, file_db, claims.sub, req).await
+}
+
+#[utoipa::path(
+    post,
+    tag = "files",
+    path = "/api/filesystem/rename",
+    request_body = RenameRequest,
+    responses(
+        (status = 200, description = "File or folder renamed successfully"),
+        (status = 400, description = "Path or name is invalid"),
+        (status = 404, description = "File or folder doesn't exist"),
+        (status = 409, description = "A file or folder with the new name already exists")
+    )
+)]
+pub async fn rename_userfile<F: Filesystem, D: DataStore>(
+    Extension(filesystem): Extension<F>,
+    Extension(file_db): Extension<D>,
+    Extension(claims): Extension<Claims>,
+    Json(req): Json<RenameRequest>,
+) -> handler::UserfilesAPIResult<()> {
+    handler::rename_userfile(filesystem, file_db, claims.sub, req).await
+}
+
+#[utoipa::path(
+    post,
+    tag = "files",
+    path = "/api/filesystem/copy",
+    request_body = CopyRequest,
+    responses(
+        (status = 200, description = "File or folder copied successfully"),
+        (status = 400, description = "Path is invalid or a folder would be copied into itself"),
+        (status = 404, description = "Source doesn't exist"),
+        (status = 409, description = "A file or folder with the target path already exists"),
+        (status = 507, description = "Copy doesn't fit into the storage quota of the user")
+    )
+)]
+pub async fn copy_userfile<F: Filesystem, D: DataStore>(
+    Extension(filesystem): Extension<F>,
+    Extension(file_db): Extension<D>,
+    Extension(claims): Extension<Claims>,
+    Extension(config): Extension<Arc<GenbuConfig>>,
+    Json(req): Json<CopyRequest>,
+) -> handler::UserfilesAPIResult<()> {
+    handler::copy_userfile(filesystem, file_db, claims.sub, req, &config.quota).await
+}
+
+#[utoipa::path(
+    post,
+    tag = "files",
+    path = "/api/filesystem/bulk",
+    request_body = BulkRequest,
+    responses(
+        (status = 200, description = "Result of the operation on every path", body = BulkResponse)
+    )
+)]
+pub async fn bulk<F: Filesystem, D: DataStore>(
+    Extension(filesystem): Extension<F>,
+    Extension(file_db): Extension<D>,
+    Extension(claims): Extension<Claims>,
+    Extension(config): Extension<Arc<GenbuConfig>>,
+    Json(req): Json<BulkRequest>,
+) -> Json<BulkResponse> {
+    let results = handler::bulk(filesystem, file_db, claims.sub, req, &config.quota)
+        .await
+        .into_iter()
+        .map(|(path, res)| bulk_item_result(path, res))
+        .collect();
+    Json(BulkResponse { results })
+}
+
+/// Reports an item with the status code and the error message a single request would have got.
+fn bulk_item_result(path: String, res: handler::UserfilesAPIResult<()>) -> BulkItemResult {
+    let Err(e) = res else {
+        return BulkItemResult {
+            path,
+            status: StatusCode::OK.as_u16(),
+            error: None,
+        };
+    };
+    let mut error = e.to_string();
+    let mut source = e.source();
+    while let Some(cause) = source {
+        error = format!("{error}: {cause}");
+        source = cause.source();
+    }
+    BulkItemResult {
+        path,
+        status: e.into_response().status().as_u16(),
+        error: Some(error),
+    }
+}
@@ -0,0 +1,109 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::Path,
+    response::Redirect,
+    routing::{get, post},
+    Extension, Json, Router,
+};
+use genbu_auth::authn::Claims;
+
+use crate::{
+    config::GenbuConfig,
+    handler::files::{
+        catalog::FileInfo,
+        versions::{self as handler, VersionInfo},
+    },
+    stores::{files::filesystem::Filesystem, DataStore, Uuid},
+};
+
+pub fn router<F: Filesystem, D: DataStore>() -> Router {
+    Router::new()
+        .route("/api/files/:id/versions", get(list_versions::<D>))
+        .route(
+            "/api/files/:id/versions/:version_id/download",
+            get(download_version::<F, D>),
+        )
+        .route(
+            "/api/files/:id/versions/:version_id/restore",
+            post(restore_version::<F, D>),
+        )
+}
+
+#[utoipa::path(
+    get,
+    tag = "files",
+    path = "/api/files/{id}/versions",
+    params(("id" = Uuid, Path, description = "Id of the file")),
+    responses(
+        (status = 200, description = "Previous versions of the file, most recent first", body = [VersionInfo]),
+        (status = 404, description = "File doesn't exist or the user can't access it")
+    )
+)]
+pub async fn list_versions<D: DataStore>(
+    Extension(store): Extension<D>,
+    Extension(claims): Extension<Claims>,
+    Path(file_id): Path<Uuid>,
+) -> handler::VersionAPIResult<Json<Vec<VersionInfo>>> {
+    Ok(Json(
+        handler::list_versions(store, claims.sub, file_id).await?,
+    ))
+}
+
+#[utoipa::path(
+    get,
+    tag = "files",
+    path = "/api/files/{id}/versions/{version_id}/download",
+    params(
+        ("id" = Uuid, Path, description = "Id of the file"),
+        ("version_id" = Uuid, Path, description = "Id of the version")
+    ),
+    responses(
+        (status = 307, description = "Redirect to the content of the version"),
+        (status = 404, description = "File or version doesn't exist or the user can't access it")
+    )
+)]
+pub async fn download_version<F: Filesystem, D: DataStore>(
+    Extension(file_storage): Extension<F>,
+    Extension(store): Extension<D>,
+    Extension(claims): Extension<Claims>,
+    Path((file_id, version_id)): Path<(Uuid, Uuid)>,
+) -> handler::VersionAPIResult<Redirect> {
+    let redirect =
+        handler::download_version(file_storage, store, claims.sub, file_id, version_id).await?;
+    Ok(Redirect::temporary(&redirect))
+}
+
+#[utoipa::path(
+    post,
+    tag = "files",
+    path = "/api/files/{id}/versions/{version_id}/restore",
+    params(
+        ("id" = Uuid, Path, description = "Id of the file"),
+        ("version_id" = Uuid, Path, description = "Id of the version")
+    ),
+    responses(
+        (status = 200, description = "Version is the current content of the file", body = FileInfo),
+        (status = 404, description = "File or version doesn't exist or the user can't access it"),
+        (status = 423, description = "File is locked by a WOPI client")
+    )
+)]
+pub async fn restore_version<F: Filesystem, D: DataStore>(
+    Extension(file_storage): Extension<F>,
+    Extension(store): Extension<D>,
+    Extension(config): Extension<Arc<GenbuConfig>>,
+    Extension(claims): Extension<Claims>,
+    Path((file_id, version_id)): Path<(Uuid, Uuid)>,
+) -> handler::VersionAPIResult<Json<FileInfo>> {
+    Ok(Json(
+        handler::restore_version(
+            file_storage,
+            store,
+            claims.sub,
+            file_id,
+            version_id,
+            &config.versions,
+        )
+        .await?,
+    ))
+}
@@ -1,9 +1,103 @@
-use axum::{body::Body, extract::FromRequest, response::IntoResponse};
+use std::sync::Arc;
+
+use axum::{
+    body::Body,
+    extract::{FromRequest, Query},
+    middleware,
+    response::IntoResponse,
+    routing::get,
+    Extension, Json, Router,
+};
 use bytes::Bytes;
+use genbu_auth::authn::Claims;
 use http::Request;
 use hyper::body::to_bytes;
 use tracing::error;
-use wopi_rs::file::{FileRequest, FileResponse};
+use wopi_rs::file::FileRequest;
+
+use crate::{
+    config::GenbuConfig,
+    connectors::discovery::WopiDiscovery,
+    handler::files::wopi::{
+        self as handler, AccessTokenRequest, AccessTokenResponse, LaunchRequest, LaunchResponse,
+        WopiAccess,
+    },
+    server::middlewares::wopi::{wopi_auth, wopi_proof},
+    stores::{files::filesystem::Filesystem, DataStore},
+};
+
+/// Routes called by WOPI clients. These are authenticated with an access token instead of the
+/// session cookie and only accepted if they are signed by the office server.
+pub fn router<F: Filesystem, D: DataStore>() -> Router {
+    Router::new()
+        .route(
+            "/api/wopi/files/:id",
+            get(wopi_file::<F, D>).post(wopi_file::<F, D>),
+        )
+        .route(
+            "/api/wopi/files/:id/contents",
+            get(wopi_file::<F, D>).post(wopi_file::<F, D>),
+        )
+        .route_layer(middleware::from_fn(wopi_auth::<D, _>))
+        .route_layer(middleware::from_fn(wopi_proof))
+}
+
+#[utoipa::path(
+    post,
+    tag = "files",
+    path = "/api/wopi/token",
+    request_body = AccessTokenRequest,
+    responses(
+        (status = 200, description = "Access token for the file", body = AccessTokenResponse),
+        (status = 404, description = "File doesn't exist or the user can't access it")
+    )
+)]
+pub async fn create_access_token<D: DataStore>(
+    Extension(config): Extension<Arc<GenbuConfig>>,
+    Extension(db_file_store): Extension<D>,
+    Extension(user): Extension<Claims>,
+    Json(req): Json<AccessTokenRequest>,
+) -> handler::WopiAPIResult<Json<AccessTokenResponse>> {
+    Ok(Json(
+        handler::create_access_token(&config, db_file_store, user.sub, req).await?,
+    ))
+}
+
+#[utoipa::path(
+    get,
+    tag = "files",
+    path = "/api/wopi/launch",
+    params(LaunchRequest),
+    responses(
+        (status = 200, description = "Office server url and access token for the file", body = LaunchResponse),
+        (status = 404, description = "File doesn't exist or the user can't access it"),
+        (status = 415, description = "Office server doesn't support the file type")
+    )
+)]
+pub async fn launch<D: DataStore>(
+    Extension(config): Extension<Arc<GenbuConfig>>,
+    Extension(discovery): Extension<WopiDiscovery>,
+    Extension(db_file_store): Extension<D>,
+    Extension(user): Extension<Claims>,
+    Query(req): Query<LaunchRequest>,
+) -> handler::WopiAPIResult<Json<LaunchResponse>> {
+    Ok(Json(
+        handler::launch(&config, &discovery, db_file_store, user.sub, req).await?,
+    ))
+}
+
+/// Handles every WOPI file operation, the operation is determined by the method, path and the
+/// `X-WOPI-Override` header of the request.
+pub async fn wopi_file<F: Filesystem, D: DataStore>(
+    Extension(config): Extension<Arc<GenbuConfig>>,
+    Extension(file_storage): Extension<F>,
+    Extension(db_file_store): Extension<D>,
+    Extension(access): Extension<WopiAccess>,
+    Wopi(req): Wopi<Bytes>,
+) -> impl IntoResponse {
+    let resp = handler::wopi_file(&config, file_storage, db_file_store, &access, req).await;
+    WopiResponse(resp)
+}
 
 pub struct Wopi<T>(pub FileRequest<T>);
 pub struct WopiResponse(pub http::Response<Bytes>);
@@ -1,3 +1,5 @@
+use std::sync::Arc;
+
 use axum::{
     extract::Path,
     http::HeaderValue,
@@ -14,6 +16,7 @@ use serde_json::json;
 use utoipa::ToSchema;
 
 use crate::{
+    config::GenbuConfig,
     handler,
     server::middlewares::auth::auth,
     stores::{
@@ -100,8 +103,11 @@ async fn create_user<DS: DataStore>(
 ///
 /// This function will return an error if a cryptographic error occurs during the creation of the
 /// JWT.
-fn start_session_response(id: Uuid) -> Result<impl IntoResponse, StatusCode> {
-    let token = authn::create_jwt(id)?;
+fn start_session_response(
+    config: &GenbuConfig,
+    id: Uuid,
+) -> Result<impl IntoResponse, StatusCode> {
+    let token = authn::create_jwt(id, &config.auth.jwt_secret)?;
 
     let cookie = Cookie::build("Token", token)
         .secure(true)
@@ -131,10 +137,11 @@ fn start_session_response(id: Uuid) -> Result<impl IntoResponse, StatusCode> {
 )]
 async fn register<DS: DataStore>(
     Extension(user_store): Extension<DS>,
+    Extension(config): Extension<Arc<GenbuConfig>>,
     Json(new_user): Json<handler::users::CreateUserRequest>,
 ) -> handler::users::UserAPIResult<impl IntoResponse> {
     let id = handler::users::auth::register_password(user_store, new_user).await?;
-    Ok(start_session_response(id))
+    Ok(start_session_response(&config, id))
 }
 
 // TODO: Better logging
@@ -152,10 +159,11 @@ async fn register<DS: DataStore>(
 )]
 async fn login<DS: DataStore>(
     Extension(user_store): Extension<DS>,
+    Extension(config): Extension<Arc<GenbuConfig>>,
     Json(login_req): Json<handler::users::auth::LoginRequest>,
 ) -> handler::users::UserAPIResult<impl IntoResponse> {
     let user_id = handler::users::auth::login_password(user_store, login_req).await?;
-    Ok(start_session_response(user_id))
+    Ok(start_session_response(&config, user_id))
 }
 
 // TODO: Better logging
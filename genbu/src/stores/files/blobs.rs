@@ -0,0 +1,66 @@
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+
+use crate::stores::Uuid;
+
+use super::{database::FileResult, storage::Bucket};
+
+/// Top level key prefix, below which the deduplicated contents of files are kept in their bucket.
+pub const BLOBS_PREFIX: &str = "blobs";
+
+/// Returns the key of the stored content of a blob.
+#[must_use]
+pub fn blob_key(id: Uuid) -> String {
+    format!("{BLOBS_PREFIX}\\{id}")
+}
+
+/// Content which is stored once for every file with the same content. Blobs are found by the
+/// hash of their content, but stored under their id, so a blob which is added again after its
+/// content was collected never shares the key of the collected one.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Blob {
+    pub id: Uuid,
+    pub bucket: Bucket,
+    /// Hex encoded SHA-256 digest of the content
+    pub hash: String,
+    pub size: i64,
+    /// Number of catalog entries, which point at this blob
+    pub ref_count: i64,
+    pub created_at: OffsetDateTime,
+}
+
+impl Blob {
+    /// Creates a blob with a single reference.
+    #[must_use]
+    pub fn new(bucket: Bucket, hash: String, size: i64) -> Self {
+        Self {
+            id: Uuid::new_v4(),
+            bucket,
+            hash,
+            size,
+            ref_count: 1,
+            created_at: OffsetDateTime::now_utc(),
+        }
+    }
+
+    #[must_use]
+    pub fn key(&self) -> String {
+        blob_key(self.id)
+    }
+}
+
+#[async_trait]
+pub trait BlobStore: Sized + Send + Sync + Clone + 'static {
+    async fn get_blob_by_hash(&self, bucket: Bucket, hash: &str) -> FileResult<Option<Blob>>;
+    /// Adds a blob. If a blob with the same content was added in the meantime, a reference to the
+    /// existing blob is added and it's returned instead.
+    async fn add_blob(&mut self, blob: &Blob) -> FileResult<Blob>;
+    /// Adds a reference to a blob. Returns `None` if the blob doesn't exist (anymore).
+    async fn reference_blob(&mut self, id: Uuid) -> FileResult<Option<Blob>>;
+    /// Removes a reference from a blob. Blobs without references are kept until they're deleted.
+    async fn release_blob(&mut self, id: Uuid) -> FileResult<Option<Blob>>;
+    async fn get_unreferenced_blobs(&self) -> FileResult<Vec<Blob>>;
+    /// Deletes a blob, unless it was referenced again in the meantime.
+    async fn delete_blob(&mut self, id: Uuid) -> FileResult<Option<Blob>>;
+}
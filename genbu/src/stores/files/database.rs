@@ -7,7 +7,10 @@ use time::{Duration, OffsetDateTime};
 
 use crate::stores::{users::User, Uuid};
 
-use super::storage::Bucket;
+use super::{
+    storage::{Bucket, ChecksumAlgorithm, PartChecksums},
+    trash::TRASH_PREFIX,
+};
 
 #[derive(
     Clone, Copy, Debug, Eq, PartialEq, Hash, Serialize, Deserialize, utoipa::ToSchema, sqlx::Type,
@@ -16,10 +19,14 @@ use super::storage::Bucket;
 #[serde(transparent)]
 pub struct LeaseID(pub Uuid);
 
+/// Top level key prefix, below which the data of uploads through the server, which doesn't fill
+/// a whole part yet, is kept in the bucket of the upload.
+pub const UPLOADS_PREFIX: &str = "uploads";
+
 #[derive(Debug, Error)]
 pub enum UploadLeaseError {
     #[error("unable to establish a file storage connection")]
-    Connection(#[source] Box<dyn Error>),
+    Connection(#[source] Box<dyn Error + Send + Sync>),
 
     #[error("invalid / no size specified")]
     InvalidSize,
@@ -28,7 +35,7 @@ pub enum UploadLeaseError {
     LeaseExpired(LeaseID),
 
     #[error("unknown internal error")]
-    Other(#[source] Box<dyn Error>),
+    Other(#[source] Box<dyn Error + Send + Sync>),
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -42,6 +49,18 @@ pub struct UploadLease {
     pub expires_at: OffsetDateTime,
     pub bucket: Bucket,
     pub name: String,
+    /// Part size in bytes, which the client chose for the upload. `None` if the configured part
+    /// size is used.
+    pub chunk_size: Option<i64>,
+    pub checksum_algorithm: Option<ChecksumAlgorithm>,
+    /// Base64 encoded checksums, which the client declared for the parts of the upload
+    pub part_checksums: Vec<String>,
+    /// Offsets, at which the segments of a tus upload begin, followed by the offset after the
+    /// last segment. Empty while all received data is stored as parts.
+    pub segments: Vec<i64>,
+    /// Time until which a `PATCH` request continues the tus upload, so other requests are
+    /// rejected
+    pub patched_until: Option<OffsetDateTime>,
 }
 
 impl UploadLease {
@@ -57,8 +76,29 @@ impl UploadLease {
             expires_at: OffsetDateTime::now_utc() + Duration::hours(6),
             bucket: Bucket::UserFiles,
             name: "template-file-name".to_owned(),
+            chunk_size: None,
+            checksum_algorithm: None,
+            part_checksums: Vec::new(),
+            segments: Vec::new(),
+            patched_until: None,
         }
     }
+
+    /// Returns the checksums, which the client declared for the parts of the upload.
+    #[must_use]
+    pub fn checksums(&self) -> Option<PartChecksums> {
+        self.checksum_algorithm.map(|algorithm| PartChecksums {
+            algorithm,
+            parts: self.part_checksums.clone(),
+        })
+    }
+
+    /// Key of the data sent for the lease from `offset` on, which wasn't stored as a part of its
+    /// upload yet.
+    #[must_use]
+    pub fn segment_key(&self, offset: u64) -> String {
+        format!("{UPLOADS_PREFIX}\\{}\\{offset}", self.id.0)
+    }
 }
 
 pub type SResult<T> = Result<T, UploadLeaseError>;
@@ -71,18 +111,46 @@ pub trait UploadLeaseStore: Sized + Send + Sync + Clone + 'static {
 
     async fn get(&self, id: &LeaseID) -> SResult<Option<UploadLease>>;
     async fn get_by_user(&self, id: &Uuid) -> SResult<Vec<UploadLease>>;
+    /// Returns the incomplete leases of every user, which expired before `expired_before`.
+    async fn get_expired(&self, expired_before: OffsetDateTime) -> SResult<Vec<UploadLease>>;
 
     async fn mark_completed(&mut self, id: &LeaseID) -> SResult<Option<UploadLease>>;
+
+    /// Marks an open lease as being patched until `until` and returns it. `None` is returned if
+    /// the lease is missing, completed or another request patches it already.
+    async fn start_patch(
+        &mut self,
+        id: &LeaseID,
+        until: OffsetDateTime,
+    ) -> SResult<Option<UploadLease>>;
+    /// Ends the patch of a lease, which was started by `start_patch`, and records the segments of
+    /// its upload if they're given.
+    async fn end_patch(&mut self, id: &LeaseID, segments: Option<&[i64]>) -> SResult<()>;
 }
 
+/// Catalog entry of a stored object, which links the object to an id.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct DBFile {
     pub id: LeaseID,
+    /// Key of the object in its bucket
     pub path: String,
+    pub bucket: Bucket,
+    pub size: i64,
+    pub mime_type: Option<String>,
+    /// Checksum of the stored content as the file storage reports it, the checksum of the
+    /// declared part checksums for verified uploads and the `ETag` otherwise. `None` for folders
+    /// and file storages which report neither.
+    pub checksum: Option<String>,
+    /// Whether this entry is a folder, whose path is the key prefix of its content
+    pub is_folder: bool,
+    /// Folder which contains this entry, `None` for the folder of a user
+    pub parent_id: Option<LeaseID>,
     pub lock: Option<FileLock>,
     pub lock_expires_at: Option<OffsetDateTime>,
     pub created_by: Uuid,
     pub created_at: OffsetDateTime,
+    /// Blob which holds the content of a deduplicated file, whose own object is left empty
+    pub blob_id: Option<Uuid>,
 }
 
 #[derive(
@@ -116,19 +184,54 @@ impl<T: Into<String>> From<T> for FileLock {
     }
 }
 
+/// Access rights of a user on a single file. The variants are ordered by the rights they grant.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum FilePermission {
+    Read,
+    Write,
+}
+
 impl DBFile {
     pub fn with_path_and_user(path: impl Into<String>, user: &User) -> Self {
         let now = OffsetDateTime::now_utc();
         DBFile {
             id: LeaseID(Uuid::new_v4()),
             path: path.into(),
+            bucket: Bucket::UserFiles,
+            size: 0,
+            mime_type: None,
+            checksum: None,
+            is_folder: false,
+            parent_id: None,
             lock: None,
             lock_expires_at: None,
             created_by: user.id,
             created_at: now,
+            blob_id: None,
         }
     }
 
+    /// Returns the permission `user_id` has on this file, or `None` if the user can't access it.
+    /// Files in the trash can't be accessed until they are restored.
+    pub fn permission(&self, user_id: Uuid) -> Option<FilePermission> {
+        // TODO: Grant access to other users once files can be shared
+        (self.created_by == user_id && !self.is_trashed()).then_some(FilePermission::Write)
+    }
+
+    /// Whether this entry was moved into the trash of a user.
+    pub fn is_trashed(&self) -> bool {
+        self.bucket == Bucket::UserFiles
+            && self
+                .path
+                .strip_prefix(TRASH_PREFIX)
+                .is_some_and(|rest| rest.starts_with('\\'))
+    }
+
+    /// Returns the lock of this file, unless it has expired.
+    pub fn current_lock(&self) -> Option<&FileLock> {
+        self.lock.as_ref().filter(|_| self.is_locked())
+    }
+
     pub fn is_locked(&self) -> bool {
         self.lock.is_some()
             && self
@@ -180,18 +283,31 @@ impl DBFile {
         }
         Err(self.lock.as_ref().unwrap())
     }
+
+    /// Replaces the lock of this file with `lock`. In contrast to the other lock operations this
+    /// fails if the file isn't locked at all.
+    pub fn relock(&mut self, old_lock: &FileLock, lock: FileLock) -> Result<(), Option<&FileLock>> {
+        if self.current_lock() == Some(old_lock) {
+            self.unchecked_lock(lock);
+            return Ok(());
+        }
+        Err(self.current_lock())
+    }
 }
 
 #[derive(Debug, Error)]
 pub enum DBFileError {
     #[error("unable to establish a file store connection")]
-    Connection(#[source] Box<dyn Error>),
+    Connection(#[source] Box<dyn Error + Send + Sync>),
 
     #[error("file is locked")]
     Locked(Option<FileLock>),
 
+    #[error("a file and a folder can't share the path `{0}`")]
+    Conflict(String),
+
     #[error("unknown internal error")]
-    Other(#[source] Box<dyn Error>),
+    Other(#[source] Box<dyn Error + Send + Sync>),
 }
 
 pub type FileResult<T> = Result<T, DBFileError>;
@@ -199,6 +315,7 @@ pub type FileResult<T> = Result<T, DBFileError>;
 #[async_trait::async_trait]
 pub trait DBFileStore: Sized + Send + Sync + Clone + 'static {
     async fn get_dbfile(&self, file_id: Uuid) -> FileResult<Option<DBFile>>;
+    async fn get_dbfile_by_path(&self, bucket: Bucket, path: &str) -> FileResult<Option<DBFile>>;
     async fn validate_lock(&self, file_id: Uuid, lock: FileLock) -> FileResult<Option<bool>> {
         let Some(file) = self.get_dbfile(file_id).await? else {
             return Ok(None);
@@ -206,9 +323,52 @@ pub trait DBFileStore: Sized + Send + Sync + Clone + 'static {
         Ok(Some(file.lock.is_some_and(|x| x == lock)))
     }
     async fn add_dbfile(&mut self, file: &DBFile) -> FileResult<DBFile>;
+    /// Adds a file to the catalog. If a file with the same bucket and path already exists, only
+    /// its size, MIME type, checksum and blob are updated, so it keeps its id. Folders are never
+    /// updated and a file never replaces a folder or the other way around, which fails with
+    /// [`DBFileError::Conflict`].
+    async fn register_dbfile(&mut self, file: &DBFile) -> FileResult<DBFile>;
     async fn lock(&mut self, file_id: Uuid, lock: FileLock) -> FileResult<Option<()>>;
     async fn unlock(&mut self, file_id: Uuid, lock: FileLock) -> FileResult<Option<()>>;
     async fn extend_lock(&mut self, file_id: Uuid, lock: FileLock) -> FileResult<Option<()>>;
+    async fn relock(
+        &mut self,
+        file_id: Uuid,
+        old_lock: FileLock,
+        lock: FileLock,
+    ) -> FileResult<Option<()>>;
+    async fn rename_dbfile(&mut self, file_id: Uuid, path: &str) -> FileResult<Option<DBFile>>;
+    /// Points an unlocked file without a blob at a blob, unless it was registered with other
+    /// content than the one with `checksum` in the meantime. Returns `None` if the file wasn't
+    /// updated.
+    async fn set_dbfile_blob(
+        &mut self,
+        file_id: Uuid,
+        checksum: &str,
+        blob_id: Uuid,
+    ) -> FileResult<Option<DBFile>>;
+    /// Returns the entry at `path` and, if it's a folder, every entry inside it.
+    async fn get_dbfiles_below(&self, bucket: Bucket, path: &str) -> FileResult<Vec<DBFile>>;
+    /// Returns the files in a bucket, which are at least `min_size` bytes large and whose content
+    /// isn't kept in a blob yet.
+    async fn get_dbfiles_without_blob(
+        &self,
+        bucket: Bucket,
+        min_size: i64,
+    ) -> FileResult<Vec<DBFile>>;
+    /// Moves the entry at `from` and everything inside it to `to`. Only the moved entry itself
+    /// gets `parent_id` as its new parent. Returns the number of moved entries.
+    async fn move_dbfiles(
+        &mut self,
+        bucket: Bucket,
+        from: &str,
+        to: &str,
+        parent_id: Option<LeaseID>,
+    ) -> FileResult<u64>;
+    async fn delete_dbfile(&mut self, file_id: Uuid) -> FileResult<Option<DBFile>>;
+    /// Deletes the entry at `path` and everything inside it. Returns the number of deleted
+    /// entries.
+    async fn delete_dbfiles(&mut self, bucket: Bucket, path: &str) -> FileResult<u64>;
 }
 
 #[cfg(test)]
@@ -243,4 +403,27 @@ mod tests {
         dbf.lock(valid_lock.clone()).expect("unable to lock dbf");
         assert_eq!(dbf.unlock(invalid_lock), Err(&valid_lock));
     }
+
+    #[test]
+    fn only_creator_has_access() {
+        let user = User::template();
+        let dbf = DBFile::with_path_and_user("/test", &user);
+        assert_eq!(dbf.permission(user.id), Some(FilePermission::Write));
+        assert_eq!(dbf.permission(Uuid::new_v4()), None);
+    }
+
+    #[test]
+    fn relock_requires_lock() {
+        let mut dbf = create_dbfile();
+        let old_lock: FileLock = "old".into();
+        let new_lock: FileLock = "new".into();
+        assert_eq!(dbf.relock(&old_lock, new_lock.clone()), Err(None));
+        dbf.lock(old_lock.clone()).expect("unable to lock dbf");
+        assert_eq!(
+            dbf.relock(&new_lock, new_lock.clone()),
+            Err(Some(&old_lock))
+        );
+        assert!(dbf.relock(&old_lock, new_lock.clone()).is_ok());
+        assert_eq!(dbf.current_lock(), Some(&new_lock));
+    }
 }
@@ -0,0 +1,435 @@
+use std::{collections::HashMap, fmt, sync::Arc};
+
+use async_trait::async_trait;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use md5::{Digest, Md5};
+use parking_lot::Mutex;
+use ring::{
+    aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN},
+    rand::{SecureRandom, SystemRandom},
+};
+use secrecy::ExposeSecret;
+use thiserror::Error;
+use time::OffsetDateTime;
+
+use crate::{config::EncryptionConfig, stores::Uuid};
+
+use super::{
+    database::{DBFileError, FileResult},
+    filesystem::FilesystemError,
+    storage::FileError,
+    trash::TRASH_PREFIX,
+};
+
+/// Length in bytes of master keys and data keys, which are AES-256 keys.
+pub const KEY_LEN: usize = 32;
+
+/// Owner of the data key, which encrypts the objects that don't belong to a single user by their
+/// key, like blobs, versions and pending uploads.
+pub const SERVER_KEY_OWNER: Uuid = Uuid::nil();
+
+#[derive(Debug, Error)]
+pub enum EncryptionError {
+    #[error("master key `{0}` is not configured")]
+    UnknownMasterKey(String),
+
+    #[error("master key `{0}` is not a base64 encoded {KEY_LEN} byte key")]
+    InvalidMasterKey(String),
+
+    #[error("unable to encrypt or decrypt data")]
+    Crypto,
+
+    #[error("database error")]
+    DatabaseError(#[from] DBFileError),
+}
+
+impl From<ring::error::Unspecified> for EncryptionError {
+    fn from(_: ring::error::Unspecified) -> Self {
+        Self::Crypto
+    }
+}
+
+impl From<EncryptionError> for FileError {
+    fn from(value: EncryptionError) -> Self {
+        Self::Other(Box::new(value))
+    }
+}
+
+impl From<EncryptionError> for FilesystemError {
+    fn from(value: EncryptionError) -> Self {
+        // Errors of the key store can't be sent between threads, so only their message is kept
+        Self::Other(format!("{value:?}").into())
+    }
+}
+
+/// Returns the owner of the data key, which encrypts the object at `key`. Objects below the
+/// folder or in the trash of a user belong to the user, every other object to the server.
+#[must_use]
+pub fn key_owner(key: &str) -> Uuid {
+    let mut segments = key.split('\\');
+    let first = segments.next().unwrap_or_default();
+    let owner = if first == TRASH_PREFIX {
+        segments.next().unwrap_or_default()
+    } else {
+        first
+    };
+    Uuid::parse_str(owner).unwrap_or(SERVER_KEY_OWNER)
+}
+
+fn aead_key(key: &[u8; KEY_LEN]) -> Result<LessSafeKey, EncryptionError> {
+    Ok(LessSafeKey::new(UnboundKey::new(&AES_256_GCM, key)?))
+}
+
+/// Key which encrypts the stored objects of one owner. It's only stored wrapped by a master key.
+#[derive(Clone, PartialEq, Eq)]
+pub struct DataKey([u8; KEY_LEN]);
+
+impl fmt::Debug for DataKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("DataKey(..)")
+    }
+}
+
+impl DataKey {
+    fn generate() -> Result<Self, EncryptionError> {
+        let mut key = [0; KEY_LEN];
+        SystemRandom::new().fill(&mut key)?;
+        Ok(Self(key))
+    }
+
+    /// Encrypts a chunk of an object in place and appends its tag. Every chunk has to be sealed
+    /// with another nonce. `last` marks the final chunk, so a truncated object can't be opened.
+    pub fn seal_chunk(
+        &self,
+        nonce: [u8; NONCE_LEN],
+        last: bool,
+        chunk: &mut Vec<u8>,
+    ) -> Result<(), EncryptionError> {
+        aead_key(&self.0)?.seal_in_place_append_tag(
+            Nonce::assume_unique_for_key(nonce),
+            Aad::from([u8::from(last)]),
+            chunk,
+        )?;
+        Ok(())
+    }
+
+    /// Decrypts a chunk, which was sealed by [`Self::seal_chunk`], in place and returns its
+    /// plaintext.
+    pub fn open_chunk<'a>(
+        &self,
+        nonce: [u8; NONCE_LEN],
+        last: bool,
+        chunk: &'a mut [u8],
+    ) -> Result<&'a mut [u8], EncryptionError> {
+        Ok(aead_key(&self.0)?.open_in_place(
+            Nonce::assume_unique_for_key(nonce),
+            Aad::from([u8::from(last)]),
+            chunk,
+        )?)
+    }
+
+    /// Returns the key and its MD5 digest in the base64 encoding, which S3 expects for
+    /// server-side encryption with customer-provided keys (SSE-C).
+    #[must_use]
+    pub fn customer_key(&self) -> (String, String) {
+        (
+            STANDARD.encode(self.0),
+            STANDARD.encode(Md5::digest(self.0)),
+        )
+    }
+}
+
+/// A data key wrapped by a master key, which is the only form data keys are stored in.
+#[derive(Debug, Clone)]
+pub struct WrappedKey {
+    /// User whose objects are encrypted with the key, or [`SERVER_KEY_OWNER`]
+    pub owner: Uuid,
+    /// Id of the master key, which wraps the key
+    pub master_key_id: String,
+    /// Nonce followed by the encrypted key and its tag
+    pub wrapped_key: Vec<u8>,
+    pub created_at: OffsetDateTime,
+    /// When the key was last wrapped with another master key
+    pub rotated_at: Option<OffsetDateTime>,
+}
+
+/// The configured master keys, which wrap the data keys.
+#[derive(Clone)]
+pub struct MasterKeys {
+    active: String,
+    keys: HashMap<String, [u8; KEY_LEN]>,
+}
+
+impl fmt::Debug for MasterKeys {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MasterKeys")
+            .field("active", &self.active)
+            .field("keys", &self.keys.keys())
+            .finish()
+    }
+}
+
+impl MasterKeys {
+    /// Parses the master keys of the configuration. Returns `None` if objects aren't encrypted.
+    pub fn from_config(config: &EncryptionConfig) -> Result<Option<Self>, EncryptionError> {
+        let Some(active) = &config.active_key else {
+            return Ok(None);
+        };
+        // Keys of the configuration are lowercased while it's loaded
+        let keys = config
+            .master_keys
+            .iter()
+            .map(|(id, key)| {
+                let key = STANDARD
+                    .decode(key.expose_secret())
+                    .ok()
+                    .and_then(|key| key.try_into().ok())
+                    .ok_or_else(|| EncryptionError::InvalidMasterKey(id.clone()))?;
+                Ok((id.to_lowercase(), key))
+            })
+            .collect::<Result<HashMap<_, _>, EncryptionError>>()?;
+        let active = active.to_lowercase();
+        if !keys.contains_key(&active) {
+            return Err(EncryptionError::UnknownMasterKey(active));
+        }
+        Ok(Some(Self { active, keys }))
+    }
+
+    #[must_use]
+    pub fn active_id(&self) -> &str {
+        &self.active
+    }
+
+    /// Wraps the data key of `owner` with the active master key. The owner is authenticated
+    /// together with the key, so a wrapped key can't be passed off as the key of another owner.
+    pub fn wrap_key(&self, owner: Uuid, key: &DataKey) -> Result<Vec<u8>, EncryptionError> {
+        let master = self
+            .keys
+            .get(&self.active)
+            .ok_or_else(|| EncryptionError::UnknownMasterKey(self.active.clone()))?;
+        let mut nonce = [0; NONCE_LEN];
+        SystemRandom::new().fill(&mut nonce)?;
+        let mut wrapped = key.0.to_vec();
+        aead_key(master)?.seal_in_place_append_tag(
+            Nonce::assume_unique_for_key(nonce),
+            Aad::from(owner.as_bytes()),
+            &mut wrapped,
+        )?;
+        Ok([nonce.as_slice(), &wrapped].concat())
+    }
+
+    /// Unwraps a data key with the master key it was wrapped with.
+    pub fn unwrap_key(&self, wrapped: &WrappedKey) -> Result<DataKey, EncryptionError> {
+        let master = self
+            .keys
+            .get(&wrapped.master_key_id)
+            .ok_or_else(|| EncryptionError::UnknownMasterKey(wrapped.master_key_id.clone()))?;
+        if wrapped.wrapped_key.len() < NONCE_LEN {
+            return Err(EncryptionError::Crypto);
+        }
+        let (nonce, sealed) = wrapped.wrapped_key.split_at(NONCE_LEN);
+        let mut sealed = sealed.to_vec();
+        let key = aead_key(master)?.open_in_place(
+            Nonce::try_assume_unique_for_key(nonce)?,
+            Aad::from(wrapped.owner.as_bytes()),
+            &mut sealed,
+        )?;
+        let key = <[u8; KEY_LEN]>::try_from(&*key).map_err(|_| EncryptionError::Crypto)?;
+        Ok(DataKey(key))
+    }
+}
+
+#[async_trait]
+pub trait KeyStore: Sized + Send + Sync + Clone + 'static {
+    async fn get_wrapped_key(&self, owner: Uuid) -> FileResult<Option<WrappedKey>>;
+    /// Adds the data key of an owner. If the owner got a key in the meantime, the existing key is
+    /// kept and returned instead.
+    async fn add_wrapped_key(&mut self, key: &WrappedKey) -> FileResult<WrappedKey>;
+    /// Returns every data key, which isn't wrapped by the master key `master_key_id`.
+    async fn get_keys_not_wrapped_by(&self, master_key_id: &str) -> FileResult<Vec<WrappedKey>>;
+    /// Replaces the stored data key of an owner with the same key wrapped by another master key.
+    async fn update_wrapped_key(&mut self, key: &WrappedKey) -> FileResult<Option<WrappedKey>>;
+}
+
+#[async_trait]
+trait KeySource: Send + Sync {
+    async fn data_key(&self, owner: Uuid) -> Result<DataKey, EncryptionError>;
+}
+
+struct StoredKeys<S> {
+    master_keys: MasterKeys,
+    store: S,
+    /// Unwrapped data keys, which stay the same when they're wrapped with another master key
+    cache: Mutex<HashMap<Uuid, DataKey>>,
+}
+
+#[async_trait]
+impl<S: KeyStore> KeySource for StoredKeys<S> {
+    async fn data_key(&self, owner: Uuid) -> Result<DataKey, EncryptionError> {
+        let cached = self.cache.lock().get(&owner).cloned();
+        if let Some(key) = cached {
+            return Ok(key);
+        }
+        let stored = self.store.get_wrapped_key(owner).await?;
+        let wrapped = match stored {
+            Some(wrapped) => wrapped,
+            None => {
+                let key = DataKey::generate()?;
+                let wrapped = WrappedKey {
+                    owner,
+                    master_key_id: self.master_keys.active.clone(),
+                    wrapped_key: self.master_keys.wrap_key(owner, &key)?,
+                    created_at: OffsetDateTime::now_utc(),
+                    rotated_at: None,
+                };
+                // The key which was added first wins, if objects of the owner are stored
+                // concurrently
+                self.store.clone().add_wrapped_key(&wrapped).await?
+            }
+        };
+        let key = self.master_keys.unwrap_key(&wrapped)?;
+        self.cache.lock().insert(owner, key.clone());
+        Ok(key)
+    }
+}
+
+/// Hands out the data keys of the owners of objects to the file storages, which encrypt them. A
+/// data key is created when the first object of its owner is stored.
+#[derive(Clone)]
+pub struct Keyring(Arc<dyn KeySource>);
+
+impl fmt::Debug for Keyring {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Keyring(..)")
+    }
+}
+
+impl Keyring {
+    #[must_use]
+    pub fn new(master_keys: MasterKeys, store: impl KeyStore) -> Self {
+        Self(Arc::new(StoredKeys {
+            master_keys,
+            store,
+            cache: Mutex::default(),
+        }))
+    }
+
+    pub async fn data_key(&self, owner: Uuid) -> Result<DataKey, EncryptionError> {
+        self.0.data_key(owner).await
+    }
+
+    /// Returns the data key, which encrypts the object at `key`.
+    pub async fn object_key(&self, key: &str) -> Result<DataKey, EncryptionError> {
+        self.data_key(key_owner(key)).await
+    }
+}
+
+/// Wraps every data key, which isn't wrapped by the active master key yet, with the active master
+/// key. Afterwards the previous master keys can be removed from the configuration. The data keys
+/// themselves stay the same, so the stored objects don't have to be encrypted again. Returns the
+/// number of wrapped keys.
+#[tracing::instrument(skip_all)]
+pub async fn rotate_master_key(
+    store: &mut impl KeyStore,
+    master_keys: &MasterKeys,
+) -> Result<usize, EncryptionError> {
+    let mut rotated = 0;
+    let keys = store.get_keys_not_wrapped_by(&master_keys.active).await?;
+    for wrapped in keys {
+        let key = master_keys.unwrap_key(&wrapped)?;
+        let wrapped = WrappedKey {
+            master_key_id: master_keys.active.clone(),
+            wrapped_key: master_keys.wrap_key(wrapped.owner, &key)?,
+            rotated_at: Some(OffsetDateTime::now_utc()),
+            ..wrapped
+        };
+        if store.update_wrapped_key(&wrapped).await?.is_some() {
+            rotated += 1;
+        }
+    }
+    Ok(rotated)
+}
+
+#[cfg(test)]
+mod tests {
+    use secrecy::SecretString;
+
+    use crate::connectors::memory::MemStore;
+
+    use super::*;
+
+    fn config(active: &str, ids: &[&str]) -> EncryptionConfig {
+        EncryptionConfig {
+            master_keys: ids
+                .iter()
+                .enumerate()
+                .map(|(i, id)| {
+                    let key = STANDARD.encode([u8::try_from(i).unwrap(); KEY_LEN]);
+                    ((*id).to_owned(), SecretString::new(key))
+                })
+                .collect(),
+            active_key: Some(active.to_owned()),
+        }
+    }
+
+    #[test]
+    fn owners_of_keys() {
+        let user_id = Uuid::new_v4();
+        assert_eq!(key_owner(&format!("{user_id}\\docs\\a.txt")), user_id);
+        assert_eq!(key_owner(&format!("trash\\{user_id}\\entry")), user_id);
+        assert_eq!(key_owner("blobs\\id"), SERVER_KEY_OWNER);
+        assert_eq!(key_owner("versions\\file\\id"), SERVER_KEY_OWNER);
+    }
+
+    #[test]
+    fn wrapped_keys_belong_to_their_owner() {
+        let master_keys = MasterKeys::from_config(&config("K1", &["k1"]))
+            .unwrap()
+            .unwrap();
+        let key = DataKey::generate().unwrap();
+        let mut wrapped = WrappedKey {
+            owner: Uuid::new_v4(),
+            master_key_id: master_keys.active_id().to_owned(),
+            wrapped_key: Vec::new(),
+            created_at: OffsetDateTime::now_utc(),
+            rotated_at: None,
+        };
+        wrapped.wrapped_key = master_keys.wrap_key(wrapped.owner, &key).unwrap();
+        assert_eq!(master_keys.unwrap_key(&wrapped).unwrap(), key);
+
+        wrapped.owner = Uuid::new_v4();
+        assert!(matches!(
+            master_keys.unwrap_key(&wrapped),
+            Err(EncryptionError::Crypto)
+        ));
+    }
+
+    #[tokio::test]
+    async fn rotate_keys() {
+        let mut store = MemStore::new();
+        let user_id = Uuid::new_v4();
+        let old_keys = MasterKeys::from_config(&config("k1", &["k1"]))
+            .unwrap()
+            .unwrap();
+        let key = Keyring::new(old_keys, store.clone())
+            .data_key(user_id)
+            .await
+            .unwrap();
+
+        let new_keys = MasterKeys::from_config(&config("k2", &["k1", "k2"]))
+            .unwrap()
+            .unwrap();
+        assert_eq!(rotate_master_key(&mut store, &new_keys).await.unwrap(), 1);
+        assert_eq!(rotate_master_key(&mut store, &new_keys).await.unwrap(), 0);
+        let wrapped = store.get_wrapped_key(user_id).await.unwrap().unwrap();
+        assert_eq!(wrapped.master_key_id, "k2");
+        assert!(wrapped.rotated_at.is_some());
+
+        // The previous master key isn't needed anymore
+        let mut config = config("k2", &["k1", "k2"]);
+        config.master_keys.remove("k1");
+        let new_keys = MasterKeys::from_config(&config).unwrap().unwrap();
+        let rotated = Keyring::new(new_keys, store).data_key(user_id).await;
+        assert_eq!(rotated.unwrap(), key);
+    }
+}
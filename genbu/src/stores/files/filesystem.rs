@@ -22,6 +22,9 @@ pub enum FilesystemError {
     #[error("a file with this path `{0}` already exists")]
     FileAlreadyExists(String),
 
+    #[error("no file or folder exists at `{0}`")]
+    NotFound(String),
+
     #[error("unable to establish a database connection")]
     Connection(#[source] Box<dyn Error + Send + Sync>),
 
@@ -31,8 +34,54 @@ pub enum FilesystemError {
 
 pub type SResult<T> = Result<T, FilesystemError>;
 
+/// File operations on the `UserFiles` bucket. Folders are key prefixes ending with the `\`
+/// delimiter, paths passed to these operations never end with the delimiter.
 #[async_trait::async_trait]
 pub trait Filesystem: FileStorage {
     async fn list(&self, user_id: Uuid, base_path: &str) -> SResult<Vec<Userfile>>;
+    /// Deletes the file or the folder at `path` with everything inside it. Deleting a path that
+    /// doesn't exist succeeds.
     async fn delete(&mut self, path: &str) -> SResult<()>;
+
+    /// Creates an empty folder, so it shows up in listings before it contains any file.
+    async fn mkdir(&mut self, path: &str) -> SResult<()>;
+
+    /// Returns whether a file or a folder exists at `path`.
+    async fn exists(&self, path: &str) -> SResult<bool>;
+
+    /// Copies the file or the folder (including its content) at `from` to `to`. Fails with
+    /// [`FilesystemError::FileAlreadyExists`] if something already exists at `to`.
+    async fn copy(&mut self, from: &str, to: &str) -> SResult<()>;
+
+    /// Moves the file or the folder at `from` to `to`, which covers moving and renaming. Fails
+    /// like [`Filesystem::copy`].
+    async fn rename(&mut self, from: &str, to: &str) -> SResult<()>;
+}
+
+/// Returns the key of `key` after the file or folder at `from` was moved to `to`, if `key`
+/// belongs to it.
+#[must_use]
+pub fn moved_key(key: &str, from: &str, to: &str) -> Option<String> {
+    if key == from {
+        return Some(to.to_owned());
+    }
+    key.strip_prefix(from)
+        .filter(|rest| rest.starts_with('\\'))
+        .map(|rest| format!("{to}{rest}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn moved_keys() {
+        assert_eq!(moved_key("u\\a", "u\\a", "u\\b").as_deref(), Some("u\\b"));
+        assert_eq!(
+            moved_key("u\\a\\x.txt", "u\\a", "u\\b\\c").as_deref(),
+            Some("u\\b\\c\\x.txt")
+        );
+        assert_eq!(moved_key("u\\ab", "u\\a", "u\\b"), None);
+        assert_eq!(moved_key("u", "u\\a", "u\\b"), None);
+    }
 }
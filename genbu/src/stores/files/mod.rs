@@ -1,6 +1,11 @@
+pub mod blobs;
 pub mod database;
+pub mod encryption;
 pub mod filesystem;
+pub mod quota;
 pub mod storage;
+pub mod trash;
+pub mod versions;
 
 pub use database::{UploadLease, UploadLeaseError, UploadLeaseStore};
 pub use storage::FileStorage;
@@ -0,0 +1,22 @@
+use async_trait::async_trait;
+
+use crate::stores::Uuid;
+
+use super::database::FileResult;
+
+/// Storage quotas of users and groups and the storage usage of users, all in bytes. The usage of
+/// a user is the size of every file they own.
+#[async_trait]
+pub trait QuotaStore: Sized + Send + Sync + Clone + 'static {
+    /// Returns the quota which applies to a user. A quota of the user itself takes precedence over
+    /// the quotas of their groups, of which the largest one applies.
+    async fn get_quota(&self, user_id: Uuid) -> FileResult<Option<i64>>;
+    /// Sets the quota of a user, `None` removes it.
+    async fn set_user_quota(&mut self, user_id: Uuid, max_bytes: Option<i64>) -> FileResult<()>;
+    /// Sets the quota of a group, `None` removes it.
+    async fn set_group_quota(&mut self, group_id: Uuid, max_bytes: Option<i64>) -> FileResult<()>;
+
+    async fn get_usage(&self, user_id: Uuid) -> FileResult<i64>;
+    /// Changes the usage of a user by `delta` and returns the new usage.
+    async fn add_usage(&mut self, user_id: Uuid, delta: i64) -> FileResult<i64>;
+}
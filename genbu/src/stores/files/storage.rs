@@ -1,7 +1,12 @@
-use std::error::Error;
+use std::{error::Error, io, ops::Range, pin::Pin};
 
+use base64::{engine::general_purpose::STANDARD, Engine};
+use bytes::Bytes;
+use futures::{stream, Stream, TryStreamExt};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use thiserror::Error;
+use time::OffsetDateTime;
 use utoipa::ToSchema;
 
 use crate::stores::{Reset, Setup};
@@ -12,29 +17,42 @@ pub enum PresignError {
     #[error("file store doesn't support presigning")]
     Unsupported,
 
+    #[error("presigned token is invalid or expired")]
+    InvalidToken,
+
     #[error("unknown presign error")]
-    Other(#[source] Box<dyn Error>),
+    Other(#[source] Box<dyn Error + Send + Sync>),
 }
 
 #[derive(Debug, Error)]
 #[error("part size exceeds i32::MAX")]
 pub struct InvalidPartSize;
 
+#[derive(Debug, Error)]
+#[error("received {0} bytes instead of the announced {1} bytes")]
+pub struct SizeMismatch(pub u64, pub u64);
+
 #[derive(Debug, Error)]
 #[non_exhaustive]
 pub enum FileError {
     #[error("unable to establish a file storage connection")]
-    Connection(#[source] Box<dyn Error>),
+    Connection(#[source] Box<dyn Error + Send + Sync>),
 
     #[error("unknown file storage error")]
-    Other(#[source] Box<dyn Error>),
+    Other(#[source] Box<dyn Error + Send + Sync>),
+
+    #[error("object `{0}` not found")]
+    NotFound(String),
 
     #[error("error while presigning operation")]
     Presigning(#[source] PresignError),
+
+    #[error("checksum of part {0} doesn't match the uploaded data")]
+    BadDigest(i32),
 }
 
 #[non_exhaustive]
-#[derive(Clone, Copy, Debug, Serialize, Deserialize, sqlx::Type, ToSchema)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize, sqlx::Type, ToSchema)]
 #[sqlx(type_name = "bucket", rename_all = "lowercase")]
 pub enum Bucket {
     ProfileImages,
@@ -55,6 +73,91 @@ impl Bucket {
     }
 }
 
+pub const BUCKETS: [Bucket; 4] = [
+    Bucket::UserFiles,
+    Bucket::VideoFiles,
+    Bucket::NotebookFiles,
+    Bucket::ProfileImages,
+];
+
+/// Algorithm of the checksums, which clients declare for the parts of an upload.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, sqlx::Type, ToSchema)]
+#[sqlx(type_name = "checksum_algorithm", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum ChecksumAlgorithm {
+    Sha256,
+    Crc32c,
+}
+
+impl ChecksumAlgorithm {
+    /// Returns the raw checksum of `data`. CRC32C checksums are big endian, like S3 encodes them.
+    #[must_use]
+    pub fn digest(self, data: &[u8]) -> Vec<u8> {
+        match self {
+            Self::Sha256 => Sha256::digest(data).to_vec(),
+            Self::Crc32c => crc32c::crc32c(data).to_be_bytes().to_vec(),
+        }
+    }
+
+    /// Returns the base64 encoded checksum of `data`, which is the format of declared checksums.
+    #[must_use]
+    pub fn encoded_digest(self, data: &[u8]) -> String {
+        STANDARD.encode(self.digest(data))
+    }
+
+    const fn digest_len(self) -> usize {
+        match self {
+            Self::Sha256 => 32,
+            Self::Crc32c => 4,
+        }
+    }
+}
+
+/// Checksums, which a client declared for the parts of a multipart upload. S3 only reports the
+/// checksum of the part checksums for objects uploaded in parts, so every part is declared
+/// instead of the whole file.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct PartChecksums {
+    pub algorithm: ChecksumAlgorithm,
+    /// Base64 encoded checksum of every part, ordered by part number
+    pub parts: Vec<String>,
+}
+
+impl PartChecksums {
+    /// Returns the declared checksum of a part, part numbers start with 1.
+    #[must_use]
+    pub fn part(&self, part_number: i32) -> Option<&str> {
+        let index = usize::try_from(part_number).ok()?.checked_sub(1)?;
+        self.parts.get(index).map(String::as_str)
+    }
+
+    /// Checks that every declared checksum is valid base64 of the length the algorithm produces.
+    #[must_use]
+    pub fn is_valid(&self) -> bool {
+        self.parts.iter().all(|part| {
+            STANDARD
+                .decode(part)
+                .is_ok_and(|digest| digest.len() == self.algorithm.digest_len())
+        })
+    }
+
+    /// Returns the checksum of the whole object in the form S3 reports it for multipart uploads,
+    /// which is the checksum of the concatenated part checksums followed by the part count.
+    /// Returns `None` if a declared checksum isn't valid base64.
+    #[must_use]
+    pub fn object_checksum(&self) -> Option<String> {
+        let mut digests = Vec::new();
+        for part in &self.parts {
+            digests.extend(STANDARD.decode(part).ok()?);
+        }
+        Some(format!(
+            "{}-{}",
+            self.algorithm.encoded_digest(&digests),
+            self.parts.len()
+        ))
+    }
+}
+
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize, utoipa::ToSchema)]
 pub struct Part {
     pub e_tag: String,
@@ -63,23 +166,293 @@ pub struct Part {
 
 pub type Result<T> = std::result::Result<T, FileError>;
 
+pub type ObjectStream = Pin<Box<dyn Stream<Item = io::Result<Bytes>> + Send>>;
+
+/// The content of a stored object, which is streamed instead of being loaded into memory.
+pub struct Object {
+    pub size: u64,
+    pub body: ObjectStream,
+}
+
+/// Cuts the bytes in `range` out of the content of an object. The content before the range is
+/// skipped and the stream ends after the range, so the rest of the object isn't read.
+#[must_use]
+pub fn slice_object(object: Object, range: Range<u64>) -> Object {
+    let (start, end) = (range.start.min(object.size), range.end.min(object.size));
+    let body = stream::try_unfold((object.body, 0), move |(mut body, mut offset)| async move {
+        while offset < end {
+            let Some(chunk) = body.try_next().await? else {
+                return Ok(None);
+            };
+            let chunk_start = offset;
+            offset += chunk.len() as u64;
+            let from = start.saturating_sub(chunk_start).min(chunk.len() as u64);
+            let to = end.saturating_sub(chunk_start).min(chunk.len() as u64);
+            if from < to {
+                let chunk = chunk.slice(from as usize..to as usize);
+                return Ok(Some((chunk, (body, offset))));
+            }
+        }
+        Ok(None)
+    });
+    Object {
+        size: end.saturating_sub(start),
+        body: Box::pin(body),
+    }
+}
+
+/// Metadata of a stored object.
+#[derive(Clone, Debug)]
+pub struct ObjectMeta {
+    pub size: u64,
+    pub last_modified: Option<OffsetDateTime>,
+    /// Changes whenever the content of the object changes, if the file storage supports it
+    pub e_tag: Option<String>,
+    /// Checksum of an object, which was uploaded with [`PartChecksums`], in the form returned by
+    /// [`PartChecksums::object_checksum`]. Only set if the file storage supports it.
+    pub checksum: Option<String>,
+}
+
+/// Maximum number of parts of a multipart upload, as S3 rejects uploads with more parts.
+pub const MAX_PART_COUNT: u64 = 10_000;
+
+/// Minimum size of every part but the last one of a multipart upload, as required by S3.
+pub const MIN_PART_SIZE: u64 = 5 * 1024 * 1024;
+
+/// Maximum size of a part of a multipart upload, as required by S3.
+pub const MAX_PART_SIZE: u64 = 5 * 1024 * 1024 * 1024;
+
+/// Maximum size of a part, which is sent to a presigned url served by genbu itself.
+pub const MAX_SIGNED_PART_SIZE: u64 = 64 * 1024 * 1024;
+
+/// Maximum size of a part of an upload through genbu. The parts can be sent to presigned urls
+/// served by genbu itself, so they're limited by the body limit of these urls as well.
+pub const MAX_UPLOAD_PART_SIZE: u64 = if MAX_SIGNED_PART_SIZE < MAX_PART_SIZE {
+    MAX_SIGNED_PART_SIZE
+} else {
+    MAX_PART_SIZE
+};
+
+/// Maximum size of a file uploaded through genbu, as larger files don't fit into
+/// [`MAX_PART_COUNT`] parts of [`MAX_UPLOAD_PART_SIZE`] bytes.
+pub const MAX_UPLOAD_SIZE: u64 = MAX_PART_COUNT * MAX_UPLOAD_PART_SIZE;
+
+/// Returns the part size of a multipart upload of `size` bytes. This is `preferred`, unless it's
+/// below the minimum part size or would split the upload into more than [`MAX_PART_COUNT`] parts.
+/// The part size never exceeds [`MAX_UPLOAD_PART_SIZE`], so uploads above [`MAX_UPLOAD_SIZE`]
+/// still need more parts.
+#[must_use]
+pub const fn chunk_size(size: u64, preferred: u64) -> u64 {
+    let mut min_for_count = size / MAX_PART_COUNT;
+    if !size.is_multiple_of(MAX_PART_COUNT) {
+        min_for_count += 1;
+    }
+    let chunk_size = if preferred > MIN_PART_SIZE {
+        preferred
+    } else {
+        MIN_PART_SIZE
+    };
+    let chunk_size = if chunk_size > min_for_count {
+        chunk_size
+    } else {
+        min_for_count
+    };
+    if chunk_size < MAX_UPLOAD_PART_SIZE {
+        chunk_size
+    } else {
+        MAX_UPLOAD_PART_SIZE
+    }
+}
+
+/// Returns the number of parts a multipart upload of `size` bytes is split into.
+#[must_use]
+pub const fn chunk_count(size: u64, chunk_size: u64) -> u64 {
+    let mut chunk_count = (size / chunk_size) + 1;
+    if chunk_count > 1 && size.is_multiple_of(chunk_size) {
+        chunk_count -= 1;
+    }
+    chunk_count
+}
+
 #[async_trait::async_trait]
 pub trait FileStorage: Reset + Setup + Clone + Sized + Send + Sync + 'static {
     async fn delete_file(&mut self, bucket: Bucket, name: &str) -> Result<()>;
     async fn get_download_url(&self, bucket: Bucket, name: &str) -> Result<String>;
+    /// Starts a multipart upload and presigns an upload url for each of its parts. If checksums
+    /// are declared, the urls only accept parts with the declared checksum.
     async fn get_presigned_upload_urls(
         &self,
         bucket: Bucket,
         name: &str,
         size: u64,
         chunk_size: u64,
+        checksums: Option<&PartChecksums>,
     ) -> Result<(Vec<String>, String)>;
+    /// Starts a multipart upload, whose parts are sent through the server, and returns its id.
+    async fn create_multipart_upload(&self, bucket: Bucket, name: &str) -> Result<String>;
+    /// Stores a part of an unfinished multipart upload and returns its `ETag`. Returns
+    /// [`FileError::NotFound`] if there is no such upload of the object.
+    async fn upload_part(
+        &self,
+        bucket: Bucket,
+        name: &str,
+        upload_id: &str,
+        part_number: i32,
+        data: Bytes,
+    ) -> Result<String>;
+    /// Completes a multipart upload from the given parts. The checksums, which were declared
+    /// when the upload was started, have to be passed again.
     async fn finish_multipart_upload(
         &self,
         bucket: Bucket,
         name: &str,
         upload_id: &str,
         parts: Vec<Part>,
+        checksums: Option<&PartChecksums>,
     ) -> Result<()>;
+    /// Aborts an unfinished multipart upload and deletes its uploaded parts. Returns
+    /// [`FileError::NotFound`] if there is no such upload of the object.
+    async fn abort_multipart_upload(
+        &self,
+        bucket: Bucket,
+        name: &str,
+        upload_id: &str,
+    ) -> Result<()>;
+    /// Returns the parts which were already uploaded to an unfinished multipart upload, ordered
+    /// by their part number. Returns [`FileError::NotFound`] if there is no such upload of the
+    /// object.
+    async fn list_parts(&self, bucket: Bucket, name: &str, upload_id: &str) -> Result<Vec<Part>>;
+    /// Presigns upload urls for the given parts of an unfinished multipart upload, so it can be
+    /// resumed. The urls bind the declared checksums like [`Self::get_presigned_upload_urls`].
+    async fn get_upload_part_urls(
+        &self,
+        bucket: Bucket,
+        name: &str,
+        upload_id: &str,
+        part_numbers: &[i32],
+        checksums: Option<&PartChecksums>,
+    ) -> Result<Vec<String>>;
     async fn upload(&mut self, bucket: Bucket, name: &str, data: Vec<u8>) -> Result<()>;
+
+    /// Stores an object of `size` bytes, whose content is streamed into the file storage instead
+    /// of being loaded into memory. Nothing is stored if the stream doesn't yield exactly `size`
+    /// bytes.
+    async fn upload_stream(
+        &mut self,
+        bucket: Bucket,
+        name: &str,
+        data: ObjectStream,
+        size: u64,
+    ) -> Result<()>;
+
+    /// Copies an object inside a bucket, replacing the object at `to` if it exists. Returns
+    /// [`FileError::NotFound`] if there is no object at `from`.
+    async fn copy_object(&mut self, bucket: Bucket, from: &str, to: &str) -> Result<()>;
+
+    /// Streams the content of an object. Returns [`FileError::NotFound`] if it doesn't exist.
+    async fn get_object(&self, bucket: Bucket, name: &str) -> Result<Object>;
+
+    /// Streams the bytes in `range` of the content of an object. The range is cut off at the end
+    /// of the object. Returns [`FileError::NotFound`] if it doesn't exist. File storages which
+    /// can't read parts of an object skip the content before the range.
+    async fn get_object_range(
+        &self,
+        bucket: Bucket,
+        name: &str,
+        range: Range<u64>,
+    ) -> Result<Object> {
+        Ok(slice_object(self.get_object(bucket, name).await?, range))
+    }
+
+    /// Returns the metadata of an object without its content. Returns [`FileError::NotFound`]
+    /// if it doesn't exist.
+    async fn head_object(&self, bucket: Bucket, name: &str) -> Result<ObjectMeta>;
+
+    /// Stores the data sent to a presigned upload url, which was created by this file storage.
+    /// Returns the `ETag` of the stored part. File storages which sign urls pointing to an
+    /// external service (like S3) don't need to implement this.
+    async fn put_signed(&self, _token: &str, _data: Bytes) -> Result<String> {
+        Err(FileError::Presigning(PresignError::Unsupported))
+    }
+
+    /// Returns the object a presigned download url, which was created by this file storage,
+    /// points to.
+    async fn get_signed(&self, _token: &str) -> Result<Object> {
+        Err(FileError::Presigning(PresignError::Unsupported))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn slice_objects() {
+        let object = || Object {
+            size: 10,
+            body: Box::pin(stream::iter(
+                [&b"abc"[..], b"", b"defg", b"hij"].map(|chunk| Ok(Bytes::from_static(chunk))),
+            )),
+        };
+        for (range, expected) in [
+            (0..10, &b"abcdefghij"[..]),
+            (2..5, b"cde"),
+            (3..7, b"defg"),
+            (8..20, b"ij"),
+            (12..20, b""),
+        ] {
+            let slice = slice_object(object(), range.clone());
+            assert_eq!(slice.size, expected.len() as u64, "{range:?}");
+            let data: Vec<Bytes> = slice.body.try_collect().await.unwrap();
+            assert_eq!(data.concat(), expected, "{range:?}");
+        }
+    }
+
+    #[test]
+    fn chunk_sizes_within_limits() {
+        assert_eq!(chunk_size(1, 10_000_000), 10_000_000);
+        assert_eq!(chunk_size(1, 1), MIN_PART_SIZE);
+        assert_eq!(chunk_size(1, MAX_PART_SIZE), MAX_UPLOAD_PART_SIZE);
+
+        // 500 GB doesn't fit into 10,000 parts of 10 MB
+        let size = 500_000_000_000;
+        let part_size = chunk_size(size, 10_000_000);
+        assert_eq!(part_size, 50_000_000);
+        assert_eq!(chunk_count(size, part_size), MAX_PART_COUNT);
+        let part_size = chunk_size(size + 1, 1);
+        assert_eq!(chunk_count(size + 1, part_size), MAX_PART_COUNT);
+
+        // Larger files than that can't be uploaded at all
+        let part_size = chunk_size(MAX_UPLOAD_SIZE, 1);
+        assert_eq!(chunk_count(MAX_UPLOAD_SIZE, part_size), MAX_PART_COUNT);
+        let part_size = chunk_size(MAX_UPLOAD_SIZE + 1, 1);
+        assert_eq!(part_size, MAX_UPLOAD_PART_SIZE);
+        assert!(chunk_count(MAX_UPLOAD_SIZE + 1, part_size) > MAX_PART_COUNT);
+    }
+
+    #[test]
+    fn object_checksums() {
+        let checksums = PartChecksums {
+            algorithm: ChecksumAlgorithm::Crc32c,
+            parts: vec![
+                ChecksumAlgorithm::Crc32c.encoded_digest(b"hello "),
+                ChecksumAlgorithm::Crc32c.encoded_digest(b"world"),
+            ],
+        };
+        assert!(checksums.is_valid());
+        assert_eq!(checksums.part(2), Some(checksums.parts[1].as_str()));
+        assert_eq!(checksums.part(0), None);
+
+        let mut digests = ChecksumAlgorithm::Crc32c.digest(b"hello ");
+        digests.extend(ChecksumAlgorithm::Crc32c.digest(b"world"));
+        let expected = format!("{}-2", ChecksumAlgorithm::Crc32c.encoded_digest(&digests));
+        assert_eq!(checksums.object_checksum(), Some(expected));
+
+        // A SHA-256 digest doesn't fit a CRC32C checksum
+        let checksums = PartChecksums {
+            algorithm: ChecksumAlgorithm::Crc32c,
+            parts: vec![ChecksumAlgorithm::Sha256.encoded_digest(b"hello")],
+        };
+        assert!(!checksums.is_valid());
+    }
 }
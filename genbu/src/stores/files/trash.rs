@@ -0,0 +1,56 @@
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+
+use crate::stores::Uuid;
+
+use super::database::FileResult;
+
+/// Top level key prefix in the `UserFiles` bucket, below which trashed files are kept. It can't
+/// collide with the folders of the users, which are named by their ids.
+pub const TRASH_PREFIX: &str = "trash";
+
+/// A file or folder in the trash of a user.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TrashEntry {
+    pub id: Uuid,
+    /// User whose trash contains the entry
+    pub owner: Uuid,
+    /// Key of the file or folder before it was deleted
+    pub original_path: String,
+    /// Key of the file or folder while it's in the trash
+    pub trash_path: String,
+    pub is_folder: bool,
+    pub deleted_by: Uuid,
+    pub deleted_at: OffsetDateTime,
+}
+
+impl TrashEntry {
+    #[must_use]
+    pub fn new(owner: Uuid, original_path: String, is_folder: bool, deleted_by: Uuid) -> Self {
+        let id = Uuid::new_v4();
+        Self {
+            id,
+            owner,
+            original_path,
+            trash_path: format!("{TRASH_PREFIX}\\{owner}\\{id}"),
+            is_folder,
+            deleted_by,
+            deleted_at: OffsetDateTime::now_utc(),
+        }
+    }
+}
+
+#[async_trait]
+pub trait TrashStore: Sized + Send + Sync + Clone + 'static {
+    async fn add_trash_entry(&mut self, entry: &TrashEntry) -> FileResult<TrashEntry>;
+    async fn get_trash_entry(&self, id: Uuid) -> FileResult<Option<TrashEntry>>;
+    /// Returns the trash of a user, the most recently deleted entries first.
+    async fn get_trash_by_owner(&self, owner: Uuid) -> FileResult<Vec<TrashEntry>>;
+    /// Returns the entries of every user which were deleted before `deleted_before`.
+    async fn get_expired_trash(
+        &self,
+        deleted_before: OffsetDateTime,
+    ) -> FileResult<Vec<TrashEntry>>;
+    async fn delete_trash_entry(&mut self, id: Uuid) -> FileResult<Option<TrashEntry>>;
+}
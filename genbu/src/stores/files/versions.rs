@@ -0,0 +1,61 @@
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+
+use crate::stores::Uuid;
+
+use super::{
+    database::{FileResult, LeaseID},
+    storage::Bucket,
+};
+
+/// Top level key prefix, below which the previous contents of files are kept in their bucket.
+pub const VERSIONS_PREFIX: &str = "versions";
+
+/// A previous content of a file, which was replaced by a write.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileVersion {
+    pub id: Uuid,
+    pub file_id: LeaseID,
+    pub bucket: Bucket,
+    /// Key of the stored content
+    pub path: String,
+    pub size: i64,
+    pub checksum: Option<String>,
+    /// When the content was written
+    pub modified_at: OffsetDateTime,
+    /// When the content was replaced
+    pub created_at: OffsetDateTime,
+}
+
+impl FileVersion {
+    #[must_use]
+    pub fn new(file_id: LeaseID, bucket: Bucket) -> Self {
+        let id = Uuid::new_v4();
+        let now = OffsetDateTime::now_utc();
+        Self {
+            id,
+            file_id,
+            bucket,
+            path: format!("{VERSIONS_PREFIX}\\{}\\{id}", file_id.0),
+            size: 0,
+            checksum: None,
+            modified_at: now,
+            created_at: now,
+        }
+    }
+}
+
+#[async_trait]
+pub trait VersionStore: Sized + Send + Sync + Clone + 'static {
+    async fn add_version(&mut self, version: &FileVersion) -> FileResult<FileVersion>;
+    async fn get_version(&self, id: Uuid) -> FileResult<Option<FileVersion>>;
+    /// Returns the versions of a file, the most recent version first.
+    async fn get_versions(&self, file_id: LeaseID) -> FileResult<Vec<FileVersion>>;
+    /// Returns the versions of every file, which were created before `created_before`.
+    async fn get_versions_before(
+        &self,
+        created_before: OffsetDateTime,
+    ) -> FileResult<Vec<FileVersion>>;
+    async fn delete_version(&mut self, id: Uuid) -> FileResult<Option<FileVersion>>;
+}
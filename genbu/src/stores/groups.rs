@@ -1 +1,8 @@
+use crate::stores::{users::SResult, Uuid};
 
+/// Read access to the groups users are members of.
+#[async_trait::async_trait]
+pub trait GroupStore: Sized + Send + Sync + Clone + 'static {
+    /// Returns the names of the groups the user is a member of.
+    async fn get_group_names(&self, user_id: Uuid) -> SResult<Vec<String>>;
+}
@@ -1,6 +1,8 @@
 use async_trait::async_trait;
 use std::error::Error;
 
+use crate::config::GenbuConfig;
+
 pub mod files;
 pub mod groups;
 pub mod users;
@@ -12,8 +14,14 @@ pub type OffsetDateTime = time::OffsetDateTime;
 #[async_trait]
 pub trait DataStore:
     users::UserStore
+    + groups::GroupStore
     + files::UploadLeaseStore
+    + files::blobs::BlobStore
     + files::database::DBFileStore
+    + files::encryption::KeyStore
+    + files::quota::QuotaStore
+    + files::trash::TrashStore
+    + files::versions::VersionStore
     + Reset
     + Setup
     + Sized
@@ -22,8 +30,7 @@ pub trait DataStore:
     + Clone
     + 'static
 {
-    // TODO: Replace this with server config
-    async fn new(arg: String) -> Result<Self, Box<dyn Error>>;
+    async fn new(config: &GenbuConfig) -> Result<Self, Box<dyn Error>>;
 }
 
 #[async_trait]
@@ -5,12 +5,20 @@ use axum::{
     http::{header, request, HeaderValue, Request, Response, StatusCode},
     Router,
 };
+#[cfg(feature = "memory")]
+use genbu_server::connectors::memory::MemStore;
 use genbu_server::{
-    connectors::{memory::MemStore, postgres::PgStore, s3},
+    config::{AuthConfig, DatabaseConfig, GenbuConfig},
     server::builder::GenbuServerBuilder,
-    stores::{DataStore, Reset, Setup, Uuid},
+    stores::Uuid,
+};
+#[cfg(not(feature = "memory"))]
+use genbu_server::{
+    connectors::{postgres::PgStore, s3},
+    stores::{DataStore, Reset, Setup},
 };
 use http_body::combinators::UnsyncBoxBody;
+use secrecy::SecretString;
 use serde_json::json;
 use tower::ServiceExt;
 
@@ -61,7 +69,6 @@ pub struct TestClient {
 
 impl TestClient {
     pub async fn new() -> Self {
-        // TODO: Use PgStore and MemStore based on feature flags
         let app = build_app().await;
         TestClient { app, token: None }
     }
@@ -105,6 +112,27 @@ impl TestClient {
         self.request_raw(req).await
     }
 
+    /// Uploads `data` to a presigned url and returns the `ETag` of the part. Relative urls are
+    /// served by the app itself, absolute urls point to the object store.
+    pub async fn put_presigned(&mut self, uri: &str, data: Vec<u8>) -> Result<String> {
+        if uri.starts_with('/') {
+            let resp = self
+                .request_raw(Request::put(uri).body(Body::from(data))?)
+                .await;
+            assert_eq!(resp.status(), StatusCode::OK);
+            return Ok(resp.headers()[header::ETAG].to_str()?.to_owned());
+        }
+
+        let resp = reqwest::Client::new()
+            .put(uri)
+            .body(data)
+            .header("Content-Type", "image/png")
+            .send()
+            .await?;
+        assert_eq!(resp.status(), StatusCode::OK);
+        Ok(resp.headers()["ETag"].to_str()?.to_owned())
+    }
+
     pub async fn request_raw(
         &mut self,
         req: Request<Body>,
@@ -113,26 +141,48 @@ impl TestClient {
     }
 }
 
+pub fn build_config() -> GenbuConfig {
+    GenbuConfig {
+        database: DatabaseConfig {
+            url: SecretString::new(build_connection_string(&Uuid::new_v4().to_string())),
+        },
+        auth: AuthConfig {
+            jwt_secret: SecretString::new("genbu-integration-test-jwt-secret".to_owned()),
+        },
+        ..GenbuConfig::default()
+    }
+}
+
+#[cfg(feature = "memory")]
 pub async fn build_app() -> Router {
-    let _mem_store = MemStore::new();
-    let _pg_store = PgStore::new(build_connection_string(&Uuid::new_v4().to_string()))
-        // TODO:
-        // Make
-        // this
-        // configurable
-        .await
-        .unwrap();
-    let mut store = _pg_store;
+    let config = build_config();
+    config.validate().expect("invalid test configuration");
+    let store = MemStore::new();
+    GenbuServerBuilder::new()
+        .with_store(store.clone())
+        .with_file_store(store)
+        .with_config(config)
+        .build()
+        .unwrap()
+        .app()
+}
+
+#[cfg(not(feature = "memory"))]
+pub async fn build_app() -> Router {
+    let config = build_config();
+    config.validate().expect("invalid test configuration");
+    let mut store = PgStore::new(&config).await.unwrap();
     store.reset().await.expect("Unable to reset store");
     store.setup().await.expect("Unable to setup store");
-    let mut file_store = s3::S3Store::new().await;
+    let mut file_store = s3::S3Store::new(&config.s3).await;
     file_store
         .setup()
         .await
         .expect("Unable to setup file_store");
     GenbuServerBuilder::new()
         .with_store(store.clone())
-        .with_file_store(s3::S3Store::new().await)
+        .with_file_store(s3::S3Store::new(&config.s3).await)
+        .with_config(config)
         .build()
         .unwrap()
         .app()
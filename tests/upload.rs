@@ -2,15 +2,16 @@ use std::{fs::File, io::Read};
 
 use axum::http::{Request, StatusCode};
 use common::TestClient;
-use genbu_server::handler::files::{upload::UploadFileResponse, userfiles::GetUserfilesResponse};
-use reqwest::Client;
+use genbu_server::handler::files::{
+    catalog::FileInfo, upload::UploadFileResponse, userfiles::GetUserfilesResponse,
+};
 use serde_json::json;
 
 use crate::common::{response_json, RequestBuilderExt, Result};
 
 mod common;
 
-async fn upload_small_file(mut client: TestClient) -> Result<()> {
+async fn upload_small_file(mut client: TestClient) -> Result<FileInfo> {
     let mut resp = client
         .request(Request::post("/api/files/upload").json(json! {{
             "name": "test.jpg",
@@ -27,14 +28,7 @@ async fn upload_small_file(mut client: TestClient) -> Result<()> {
 
     let buffer: Vec<u8> = vec![0; 2365];
     assert_eq!(buffer.len(), 2365);
-    let upload_resp = Client::new()
-        .put(&uris[0])
-        .body(buffer)
-        .header("Content-Type", "image/png")
-        .send()
-        .await?;
-    assert_eq!(upload_resp.status(), StatusCode::OK);
-    let e_tag = upload_resp.headers().get("ETag").unwrap().to_str().unwrap();
+    let e_tag = client.put_presigned(&uris[0], buffer).await?;
 
     let mut resp = client
         .request(Request::post("/api/files/upload/finish").json(json! {{
@@ -50,7 +44,7 @@ async fn upload_small_file(mut client: TestClient) -> Result<()> {
         .await;
     assert_eq!(resp.status(), StatusCode::OK);
 
-    Ok(())
+    Ok(serde_json::from_value(response_json(&mut resp).await)?)
 }
 
 #[tokio::test]
@@ -130,3 +124,21 @@ async fn get_userfiles() -> Result<()> {
     assert_eq!(resp.files[0].size, Some(2365));
     Ok(())
 }
+
+#[tokio::test]
+async fn finished_upload_is_registered() -> Result<()> {
+    let mut client = TestClient::new().await;
+    client.register_default().await;
+    let file = upload_small_file(client.clone()).await?;
+    assert_eq!(file.path, "test.jpg");
+    assert_eq!(file.size, 2365);
+    assert_eq!(file.mime_type.as_deref(), Some("image/jpeg"));
+
+    let mut resp = client
+        .request(Request::get(format!("/api/files/{}", file.id.0)).empty_body())
+        .await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let info: FileInfo = serde_json::from_value(response_json(&mut resp).await)?;
+    assert_eq!((info.id, info.size), (file.id, file.size));
+    Ok(())
+}